@@ -0,0 +1,200 @@
+//! Host side runner for the kernel
+//!
+//! Cargo invokes this with the path of the kernel ELF (see `.cargo/config.toml`).
+//! It wraps the kernel into a bootable image next to the ELF and boots it in QEMU:
+//!
+//! * `FOX_BOOT=bios|uefi|grub` picks the image, BIOS by default. `bios` and `uefi`
+//!   are `bootloader` disk images, `grub` is a GRUB rescue image that starts the
+//!   kernel through Multiboot2 (needs the `multiboot2` feature, `grub-mkrescue`
+//!   and `xorriso`).
+//! * `FOX_OVMF=<path>` is the OVMF firmware file QEMU boots UEFI images with. It
+//!   also makes the GRUB image boot through UEFI.
+//! * `FOX_RAMDISK=<path>` is loaded by the bootloader as the initial ramdisk.
+//! * `FOX_CMDLINE=<args>` is the kernel command line, GRUB only.
+//! * `FOX_CPUS=<n>` is the number of CPUs QEMU emulates, 4 by default.
+//!
+//! Test kernels get the `isa-debug-exit` device, a timeout and have their exit
+//! code mapped to the one cargo expects, see `project_fox::exit_qemu()`.
+
+use bootloader::{BiosBoot, UefiBoot};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// QEMU supports a special isa-debug-exit device
+// Which provides an easy way to exit QEMU from the guest system
+const TEST_ARGS: &[&str] = &[
+    "-device",
+    "isa-debug-exit,iobase=0xf4,iosize=0x04",
+    "-serial",
+    "stdio",
+    "-display",
+    "none",
+];
+// Map qemu exit success enum to cargo test success (by default any non-zero == fail),
+// QEMU exits with `(0x10 << 1) | 1`
+const TEST_SUCCESS_EXIT_CODE: i32 = 33;
+// CPUs to emulate unless `FOX_CPUS` says otherwise
+const DEFAULT_CPUS: &str = "4";
+// Exit qemu after timeout
+const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BootMethod {
+    Bios,
+    Uefi,
+    Grub,
+}
+
+fn main() {
+    let mut args = env::args_os().skip(1);
+    let kernel = PathBuf::from(args.next().unwrap_or_else(|| {
+        eprintln!("usage: boot <kernel elf> [qemu args...]");
+        process::exit(1);
+    }));
+    let method = match env::var("FOX_BOOT").as_deref() {
+        Ok("uefi") => BootMethod::Uefi,
+        Ok("grub") => BootMethod::Grub,
+        Ok("bios") | Err(_) => BootMethod::Bios,
+        Ok(other) => fail(&format!(
+            "unknown FOX_BOOT={other}, expected bios, uefi or grub"
+        )),
+    };
+    let ramdisk = env::var_os("FOX_RAMDISK").map(PathBuf::from);
+
+    let image = create_image(&kernel, method, ramdisk.as_deref());
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()));
+    let cpus = env::var("FOX_CPUS").unwrap_or_else(|_| DEFAULT_CPUS.to_string());
+    qemu.arg("-smp").arg(cpus);
+    match (method, env::var_os("FOX_OVMF")) {
+        (BootMethod::Uefi | BootMethod::Grub, Some(ovmf)) => {
+            qemu.arg("-bios").arg(ovmf);
+        }
+        (BootMethod::Uefi, None) => {
+            fail("FOX_OVMF must point to an OVMF firmware file to boot UEFI")
+        }
+        _ => {}
+    }
+
+    if is_test(&kernel) {
+        // Arguments after the kernel are meant for the test harness, not QEMU
+        qemu.args(TEST_ARGS);
+        process::exit(run_test(qemu));
+    }
+
+    qemu.args(args);
+    let status = qemu
+        .status()
+        .unwrap_or_else(|e| fail(&format!("failed to run QEMU: {e}")));
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// Build the image for `method` next to the kernel ELF
+fn create_image(kernel: &Path, method: BootMethod, ramdisk: Option<&Path>) -> PathBuf {
+    let result = match method {
+        BootMethod::Bios => {
+            let image = kernel.with_extension("bios.img");
+            let mut boot = BiosBoot::new(kernel);
+            if let Some(ramdisk) = ramdisk {
+                boot.set_ramdisk(ramdisk);
+            }
+            boot.create_disk_image(&image).map(|()| image)
+        }
+        BootMethod::Uefi => {
+            let image = kernel.with_extension("uefi.img");
+            let mut boot = UefiBoot::new(kernel);
+            if let Some(ramdisk) = ramdisk {
+                boot.set_ramdisk(ramdisk);
+            }
+            boot.create_disk_image(&image).map(|()| image)
+        }
+        BootMethod::Grub => Ok(create_grub_image(kernel, ramdisk)),
+    };
+    result.unwrap_or_else(|e| fail(&format!("failed to create {method:?} image: {e:#}")))
+}
+
+/// Build a GRUB image that loads the kernel (and ramdisk) with Multiboot2
+fn create_grub_image(kernel: &Path, ramdisk: Option<&Path>) -> PathBuf {
+    let image = kernel.with_extension("grub.iso");
+    let root = kernel.with_extension("grub.d");
+    write_grub_tree(&root, kernel, ramdisk)
+        .unwrap_or_else(|e| fail(&format!("failed to create GRUB image: {e}")));
+
+    let status = Command::new("grub-mkrescue")
+        .arg("-o")
+        .arg(&image)
+        .arg(&root)
+        .status()
+        .unwrap_or_else(|e| fail(&format!("failed to run grub-mkrescue: {e}")));
+    if !status.success() {
+        fail("grub-mkrescue failed");
+    }
+    image
+}
+
+/// Lay out the files of the GRUB image under `root`
+fn write_grub_tree(root: &Path, kernel: &Path, ramdisk: Option<&Path>) -> io::Result<()> {
+    let boot = root.join("boot");
+    fs::create_dir_all(boot.join("grub"))?;
+    fs::copy(kernel, boot.join("kernel.elf"))?;
+
+    let cmdline = env::var("FOX_CMDLINE").unwrap_or_default();
+    let mut entry = format!("    multiboot2 /boot/kernel.elf {cmdline}\n");
+    if let Some(ramdisk) = ramdisk {
+        fs::copy(ramdisk, boot.join("ramdisk"))?;
+        entry.push_str("    module2 /boot/ramdisk ramdisk\n");
+    }
+    let config =
+        format!("set timeout=0\nset default=0\nmenuentry \"fox\" {{\n{entry}    boot\n}}\n");
+    fs::write(boot.join("grub").join("grub.cfg"), config)
+}
+
+/// Test executables are built into `target/<target>/<profile>/deps/`
+fn is_test(kernel: &Path) -> bool {
+    kernel
+        .parent()
+        .and_then(Path::file_name)
+        .is_some_and(|dir| dir == "deps")
+}
+
+/// Run a test kernel to completion, returning the exit code for cargo
+fn run_test(mut qemu: Command) -> i32 {
+    let mut child = qemu
+        .spawn()
+        .unwrap_or_else(|e| fail(&format!("failed to run QEMU: {e}")));
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                return match status.code() {
+                    Some(TEST_SUCCESS_EXIT_CODE) => 0,
+                    Some(code) => {
+                        eprintln!("test failed, QEMU exited with {code}");
+                        1
+                    }
+                    None => 1,
+                };
+            }
+            Ok(None) if start.elapsed() > TEST_TIMEOUT => {
+                let _ = child.kill();
+                let _ = child.wait();
+                eprintln!("test timed out after {}s", TEST_TIMEOUT.as_secs());
+                return 1;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(50)),
+            Err(e) => fail(&format!("failed to wait for QEMU: {e}")),
+        }
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {msg}");
+    process::exit(1);
+}
@@ -0,0 +1,138 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Where user programs are linked, in the second level 4 entry: the first one
+// belongs to the kernel, see `kernel::memory`
+const USER_IMAGE_BASE: &str = "0x10000000000";
+
+fn main() {
+    // Multiboot2 loaders don't relocate the kernel, link it at a fixed physical
+    // address instead of as a position independent executable
+    if env::var_os("CARGO_FEATURE_MULTIBOOT2").is_some() {
+        let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rustc-link-arg=-T{dir}/multiboot2.ld");
+        println!("cargo:rustc-link-arg=--no-pie");
+        println!("cargo:rerun-if-changed=multiboot2.ld");
+    }
+
+    build_initrd();
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+/// Build the programs in `user/` and pack them into `$OUT_DIR/initrd.cpio`, which
+/// the kernel embeds, see `kernel::initrd`
+fn build_initrd() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let mut files = Vec::new();
+    let mut sources: Vec<_> = fs::read_dir("user")
+        .expect("build: can't read user/")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    sources.sort();
+    let runtime = out_dir.join("libfox.rlib");
+    build_runtime(&runtime);
+    for source in sources {
+        let name = source.file_stem().unwrap().to_str().unwrap().to_string();
+        let binary = out_dir.join(&name);
+        build_user_program(&source, &binary, &runtime);
+        files.push((format!("bin/{name}"), fs::read(&binary).unwrap()));
+    }
+    fs::write(out_dir.join("initrd.cpio"), cpio(&files)).unwrap();
+    println!("cargo:rerun-if-changed=user");
+}
+
+/// rustc set up to build for user space
+fn user_rustc() -> Command {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let mut command = Command::new(rustc);
+    command
+        .args(["--edition", "2021"])
+        .args(["--target", "x86_64-unknown-none"])
+        .args(["-C", "panic=abort", "-C", "opt-level=2"])
+        // Position independent code, as nothing is within 2 GiB of address 0
+        .args(["-C", "relocation-model=pic"])
+        // TLS is set up by the kernel (`PT_TLS`), there is no dynamic linker
+        .args(["-Z", "tls-model=local-exec"]);
+    command
+}
+
+fn run(mut command: Command, source: &Path) {
+    let status = command.status().expect("build: failed to run rustc");
+    assert!(status.success(), "build: failed to build {}", source.display());
+}
+
+/// The runtime library of user programs, `user/fox`
+fn build_runtime(output: &Path) {
+    let source = Path::new("user/fox/lib.rs");
+    let mut command = user_rustc();
+    command
+        .args(["--crate-type", "rlib", "--crate-name", "fox"])
+        .arg("-o")
+        .arg(output)
+        .arg(source);
+    run(command, source);
+}
+
+/// A static, freestanding executable from the single file program `source`,
+/// which may use the runtime library `runtime`
+fn build_user_program(source: &Path, output: &Path, runtime: &Path) {
+    let mut command = user_rustc();
+    command
+        .args(["--crate-type", "bin"])
+        // Linked into a plain executable
+        .args(["-C", "link-arg=--no-pie"])
+        .arg("-C")
+        .arg(format!("link-arg=--image-base={USER_IMAGE_BASE}"))
+        .arg("--extern")
+        .arg(format!("fox={}", runtime.display()))
+        .arg("-o")
+        .arg(output)
+        .arg(source);
+    run(command, source);
+}
+
+/// A `newc` cpio archive of `files`, the format Linux initramfs images use
+fn cpio(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = Vec::new();
+    let mut entry = |name: &str, mode: u32, data: &[u8]| {
+        let fields = [
+            0, // inode
+            mode,
+            0, // uid
+            0, // gid
+            1, // nlink
+            0, // mtime
+            data.len() as u32,
+            0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name.len() as u32 + 1,
+            0, // check
+        ];
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        pad(&mut archive);
+        archive.extend_from_slice(data);
+        pad(&mut archive);
+    };
+    for (name, data) in files {
+        // Regular file, rwxr-xr-x
+        entry(name, 0o100755, data);
+    }
+    entry("TRAILER!!!", 0, &[]);
+    archive
+}
+
+fn pad(archive: &mut Vec<u8>) {
+    while archive.len() % 4 != 0 {
+        archive.push(0);
+    }
+}
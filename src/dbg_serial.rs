@@ -1,13 +1,24 @@
+use crate::kernel::sync::{rank, IrqSafeSpinLock};
+use crate::kernel::task::stream::{IrqQueue, IrqStream};
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+// 0x3F8 ==  Standard port number for the first serial interface
+const COM1: u16 = 0x3F8;
+// Line status register, bit 0 is set while received data is waiting
+const COM1_LINE_STATUS: u16 = COM1 + 5;
+
+// Bytes received on COM1, see `receive_interrupt()`
+static RECEIVED: IrqQueue<u8> = IrqQueue::new("serial", 256);
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        // 0x3F8 ==  Standard port number for the first serial interface
-        let mut sp = unsafe { SerialPort::new(0x3F8) };
+    // Also used by the serial interrupt handler, hence interrupt safe
+    pub static ref SERIAL1: IrqSafeSpinLock<SerialPort> = {
+        // `init` also enables the "data available" interrupt (IRQ 4)
+        let mut sp = unsafe { SerialPort::new(COM1) };
         sp.init();
-        Mutex::new(sp)
+        IrqSafeSpinLock::with_rank("serial", rank::SERIAL, sp)
     };
 }
 
@@ -36,3 +47,20 @@ pub fn _print(args: ::core::fmt::Arguments) {
         .write_fmt(args)
         .expect("Failed to print to serial");
 }
+
+/// Called by the COM1 interrupt handler, queues everything received so far
+pub fn receive_interrupt() {
+    let mut serial = SERIAL1.lock();
+    let mut line_status: Port<u8> = Port::new(COM1_LINE_STATUS);
+    // The FIFO may hold several bytes by the time we get here
+    while unsafe { line_status.read() } & 1 != 0 {
+        RECEIVED.push(serial.receive());
+    }
+}
+
+/// Bytes received on the serial port
+///
+/// Panics if called more than once.
+pub fn byte_stream() -> IrqStream<u8> {
+    RECEIVED.stream()
+}
@@ -0,0 +1,108 @@
+use crate::drivers::display::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+// With UEFI (and with bootloader 0.11 on BIOS, which switches to a VESA mode)
+// there is no VGA text mode, only a linear framebuffer. The text console keeps
+// its character grid (see `vga::Writer`) and draws each cell it changes into
+// the framebuffer here.
+
+/// Each font row is drawn twice, giving VGA like 8x16 cells
+const CELL_HEIGHT: usize = GLYPH_HEIGHT * 2;
+
+/// RGB values of the 16 VGA text mode colours, indexed by `vga::Colour`
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xaa],
+    [0x00, 0xaa, 0x00],
+    [0x00, 0xaa, 0xaa],
+    [0xaa, 0x00, 0x00],
+    [0xaa, 0x00, 0xaa],
+    [0xaa, 0x55, 0x00],
+    [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xff],
+    [0x55, 0xff, 0x55],
+    [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0x55],
+    [0xff, 0x55, 0xff],
+    [0xff, 0xff, 0x55],
+    [0xff, 0xff, 0xff],
+];
+
+/// Byte order of a pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,
+    Bgr,
+    /// One byte of grayscale intensity
+    U8,
+}
+
+/// Geometry of a linear framebuffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    /// Visible width and height in pixels
+    pub width: usize,
+    pub height: usize,
+    /// Pixels per line, may be larger than `width`
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+    pub pixel_format: PixelFormat,
+}
+
+/// Draws text cells into a linear framebuffer
+pub struct FrameBufferConsole {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+}
+
+impl FrameBufferConsole {
+    pub fn new(buffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+        FrameBufferConsole { buffer, info }
+    }
+
+    /// Fill the whole framebuffer with palette colour `colour`
+    pub fn clear(&mut self, colour: u8) {
+        for y in 0..self.info.height {
+            for x in 0..self.info.width {
+                self.put_pixel(x, y, colour);
+            }
+        }
+    }
+
+    /// Draw `byte` into the text cell at `row`, `col` with palette colours `fg` on `bg`
+    ///
+    /// Cells that do not fit on the screen are skipped.
+    pub fn draw_char(&mut self, row: usize, col: usize, byte: u8, fg: u8, bg: u8) {
+        let (x0, y0) = (col * GLYPH_WIDTH, row * CELL_HEIGHT);
+        if x0 + GLYPH_WIDTH > self.info.width || y0 + CELL_HEIGHT > self.info.height {
+            return;
+        }
+        let glyph = font::glyph(byte);
+        for y in 0..CELL_HEIGHT {
+            let bits = glyph[y / 2];
+            for x in 0..GLYPH_WIDTH {
+                let colour = if bits & (1 << x) != 0 { fg } else { bg };
+                self.put_pixel(x0 + x, y0 + y, colour);
+            }
+        }
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, colour: u8) {
+        let [r, g, b] = PALETTE[colour as usize & 0xf];
+        let bpp = self.info.bytes_per_pixel;
+        let offset = (y * self.info.stride + x) * bpp;
+        let pixel = &mut self.buffer[offset..offset + bpp];
+        match self.info.pixel_format {
+            PixelFormat::Rgb => pixel[..3].copy_from_slice(&[r, g, b]),
+            PixelFormat::Bgr => pixel[..3].copy_from_slice(&[b, g, r]),
+            PixelFormat::U8 => pixel[0] = ((r as u16 + g as u16 + b as u16) / 3) as u8,
+        }
+    }
+}
+
+/// Mirror the text console into `buffer` from now on
+pub fn init(buffer: &'static mut [u8], info: FrameBufferInfo) {
+    crate::vga::WRITER
+        .lock()
+        .attach_framebuffer(FrameBufferConsole::new(buffer, info));
+}
@@ -1 +1,3 @@
+mod font;
+pub mod framebuffer;
 pub mod vga;
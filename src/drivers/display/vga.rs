@@ -1,8 +1,11 @@
+use crate::drivers::display::framebuffer::FrameBufferConsole;
+use crate::kernel::sync::{rank, IrqSafeSpinLock};
 use core::fmt;
 use core::fmt::Write;
+use core::ptr::addr_of_mut;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::VirtAddr;
 
 // Note: Statics are initialized at compile time
 // and  Rust’s const evaluator is not able to convert raw pointers to references at compile time (so far)
@@ -12,14 +15,29 @@ use volatile::Volatile;
 // Thus, the initialization happens at runtime, so arbitrarily complex initialization code is possible.
 //
 // Spin-locking: Since we have no mutex services, use a spinlock, this avoids us from having to use mutable statics!
+// Interrupt handlers print too, so it has to be an interrupt safe one.
+//
+// The bootloader leaves us in a graphics mode (UEFI has no text mode at all) and does not
+// map the VGA buffer at 0xb8000. So the character grid lives in RAM instead and every cell
+// we change is drawn into the framebuffer, once one is attached (see `framebuffer::init()`).
+// Multiboot2 loaders may leave us in text mode, then the grid moves to the real VGA
+// buffer (see `use_text_buffer()`).
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_pos: 0,
-        colour_code: ColourCode::new(Colour::White, Colour::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: IrqSafeSpinLock<Writer> = IrqSafeSpinLock::with_rank(
+        "vga",
+        rank::VGA,
+        Writer {
+            column_pos: 0,
+            colour_code: ColourCode::new(Colour::White, Colour::Black),
+            buffer: unsafe { &mut *addr_of_mut!(TEXT_BUFFER).cast::<Buffer>() },
+            framebuffer: None,
+        },
+    );
 }
 
+// Backing memory for the character grid, only ever accessed through `WRITER`
+static mut TEXT_BUFFER: [u16; BUFFER_X * BUFFER_Y] = [0; BUFFER_X * BUFFER_Y];
+
 // Modified implementation of the stdlib print macro
 #[macro_export]
 macro_rules! print {
@@ -33,6 +51,23 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)))
 }
 
+/// Move the console to the VGA text mode buffer at `addr`, keeping what is on screen
+///
+/// # Safety
+///
+/// `addr` must be the mapped VGA text buffer (physical address `0xb8000`), only
+/// available when a Multiboot2 loader left the display in text mode.
+pub unsafe fn use_text_buffer(addr: VirtAddr) {
+    let mut writer = WRITER.lock();
+    let buffer = &mut *addr.as_mut_ptr::<Buffer>();
+    for (to, from) in buffer.chars.iter_mut().zip(&writer.buffer.chars) {
+        for (to, from) in to.iter_mut().zip(from) {
+            to.write(from.read());
+        }
+    }
+    writer.buffer = buffer;
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
@@ -68,6 +103,14 @@ impl ColourCode {
     fn new(fg: Colour, bg: Colour) -> Self {
         Self((bg as u8) << 4 | (fg as u8))
     }
+
+    fn fg(self) -> u8 {
+        self.0 & 0xf
+    }
+
+    fn bg(self) -> u8 {
+        self.0 >> 4
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -94,9 +137,45 @@ pub struct Writer {
     column_pos: usize,
     colour_code: ColourCode,
     buffer: &'static mut Buffer,
+    framebuffer: Option<FrameBufferConsole>,
 }
 
 impl Writer {
+    /// Draw the console into `console` from now on, starting with what is on screen already
+    pub fn attach_framebuffer(&mut self, mut console: FrameBufferConsole) {
+        console.clear(Colour::Black as u8);
+        for (row, chars) in self.buffer.chars.iter().enumerate() {
+            for (col, c) in chars.iter().enumerate() {
+                let c = c.read();
+                console.draw_char(
+                    row,
+                    col,
+                    c.ascii_character,
+                    c.colour_code.fg(),
+                    c.colour_code.bg(),
+                );
+            }
+        }
+        self.framebuffer = Some(console);
+    }
+
+    fn put_char(&mut self, row: usize, col: usize, c: ScreenChar) {
+        // Scrolling rewrites every cell, only redraw the ones that actually change
+        if self.buffer.chars[row][col].read() == c {
+            return;
+        }
+        self.buffer.chars[row][col].write(c);
+        if let Some(console) = self.framebuffer.as_mut() {
+            console.draw_char(
+                row,
+                col,
+                c.ascii_character,
+                c.colour_code.fg(),
+                c.colour_code.bg(),
+            );
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
@@ -108,10 +187,14 @@ impl Writer {
                 let col = self.column_pos;
 
                 let colour_code = self.colour_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    colour_code,
-                });
+                self.put_char(
+                    row,
+                    col,
+                    ScreenChar {
+                        ascii_character: byte,
+                        colour_code,
+                    },
+                );
                 self.column_pos += 1;
             }
         }
@@ -123,7 +206,7 @@ impl Writer {
         for row in 1..BUFFER_Y {
             for col in 0..BUFFER_X {
                 let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+                self.put_char(row - 1, col, character);
             }
         }
         // Clear the last row
@@ -141,7 +224,7 @@ impl Writer {
         };
 
         for col in 0..BUFFER_X {
-            self.buffer.chars[row][col].write(blank);
+            self.put_char(row, col, blank);
         }
 
         Ok(())
@@ -0,0 +1,179 @@
+use crate::kernel::sync::IrqSafeSpinLock;
+use crate::kernel::task::stream::{IrqQueue, IrqStream};
+use crate::kernel::workqueue::{self, Work};
+use crate::print;
+use futures_util::stream::StreamExt;
+use spin::Mutex;
+
+// PS/2 keyboard. The interrupt handler only reads the scancode from the
+// controller and buffers it; `DECODE` turns the buffered scancodes into
+// characters on the `events` thread, and tasks read those. We only understand
+// scancode set 1 (what the controller translates to by default) with a US
+// layout.
+
+// Scancodes read by the keyboard interrupt handler, not decoded yet
+static SCANCODES: IrqSafeSpinLock<ScancodeBuffer> =
+    IrqSafeSpinLock::new("scancodes", ScancodeBuffer::new());
+// Decodes them, the decoder state is only touched from there
+static DECODE: Work = Work::new("keyboard decode", decode_scancodes);
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+// Characters typed
+static KEYS: IrqQueue<char> = IrqQueue::new("keys", 128);
+
+const BUFFER_SIZE: usize = 128;
+
+/// Scancodes between the interrupt handler and `DECODE`, without allocating
+struct ScancodeBuffer {
+    bytes: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl ScancodeBuffer {
+    const fn new() -> Self {
+        ScancodeBuffer {
+            bytes: [0; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, scancode: u8) -> bool {
+        if self.len == BUFFER_SIZE {
+            return false;
+        }
+        self.bytes[(self.start + self.len) % BUFFER_SIZE] = scancode;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let scancode = self.bytes[self.start];
+        self.start = (self.start + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        Some(scancode)
+    }
+}
+
+/// Set on the scancode of a key release
+const RELEASE: u8 = 0x80;
+/// Prefix of the two byte scancodes of extended keys (arrows, right ctrl...)
+const EXTENDED: u8 = 0xe0;
+
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const CAPS_LOCK: u8 = 0x3a;
+
+// Characters of the key press scancodes 0x00..0x3a, 0 for keys without one
+const KEYMAP: &[u8; 0x3a] =
+    b"\0\x1b1234567890-=\x08\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const KEYMAP_SHIFTED: &[u8; 0x3a] =
+    b"\0\x1b!@#$%^&*()_+\x08\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// Called by the keyboard interrupt handler
+pub fn add_scancode(scancode: u8) {
+    if !SCANCODES.lock().push(scancode) {
+        crate::serial_println!("WARNING: scancode buffer full, dropping input");
+        return;
+    }
+    workqueue::schedule_work(&DECODE);
+}
+
+/// Decode the buffered scancodes, runs on the `events` thread
+fn decode_scancodes() {
+    let mut decoder = DECODER.lock();
+    while let Some(scancode) = SCANCODES.lock().pop() {
+        if let Some(c) = decoder.decode(scancode) {
+            KEYS.push(c);
+        }
+    }
+}
+
+/// Characters as they are typed
+///
+/// Panics if called more than once.
+pub fn keys() -> IrqStream<char> {
+    KEYS.stream()
+}
+
+/// Turns scancodes into characters, keeping track of the modifier keys
+#[derive(Debug, Default)]
+pub struct Decoder {
+    shift: bool,
+    caps_lock: bool,
+    extended: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder {
+            shift: false,
+            caps_lock: false,
+            extended: false,
+        }
+    }
+
+    /// Feed the next scancode, returns the character typed if any
+    pub fn decode(&mut self, scancode: u8) -> Option<char> {
+        if scancode == EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        // None of the extended keys produce a character we know about
+        if core::mem::take(&mut self.extended) {
+            return None;
+        }
+
+        let released = scancode & RELEASE != 0;
+        match scancode & !RELEASE {
+            LEFT_SHIFT | RIGHT_SHIFT => self.shift = !released,
+            CAPS_LOCK if !released => self.caps_lock = !self.caps_lock,
+            key if !released && (key as usize) < KEYMAP.len() => {
+                let byte = KEYMAP[key as usize];
+                let shifted = if byte.is_ascii_alphabetic() {
+                    self.shift != self.caps_lock
+                } else {
+                    self.shift
+                };
+                let byte = if shifted {
+                    KEYMAP_SHIFTED[key as usize]
+                } else {
+                    byte
+                };
+                return (byte != 0).then_some(byte as char);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Echo typed characters to the console
+pub async fn print_keypresses() {
+    let mut keys = keys();
+    while let Some(c) = keys.next().await {
+        print!("{}", c);
+    }
+}
+
+#[test_case]
+fn test_decoder() {
+    let mut decoder = Decoder::new();
+    // 'a' pressed and released
+    assert_eq!(decoder.decode(0x1e), Some('a'));
+    assert_eq!(decoder.decode(0x1e | RELEASE), None);
+    // Shift + '1'
+    assert_eq!(decoder.decode(LEFT_SHIFT), None);
+    assert_eq!(decoder.decode(0x02), Some('!'));
+    assert_eq!(decoder.decode(LEFT_SHIFT | RELEASE), None);
+    // Caps lock only affects letters
+    decoder.decode(CAPS_LOCK);
+    assert_eq!(decoder.decode(0x1e), Some('A'));
+    assert_eq!(decoder.decode(0x02), Some('1'));
+    // Extended keys (here: right arrow) are skipped
+    assert_eq!(decoder.decode(EXTENDED), None);
+    assert_eq!(decoder.decode(0x4d), None);
+}
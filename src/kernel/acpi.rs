@@ -0,0 +1,183 @@
+use crate::kernel::{boot, memory};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr;
+use x86_64::PhysAddr;
+
+// ACPI tables, only what the kernel needs so far: the list of CPUs from the
+// MADT. The loader tells us where the root pointer (RSDP) is; it leads to the
+// root table (RSDT, or XSDT from ACPI 2.0 on) listing all the others.
+//
+// Tables are read in place through the physical memory map and never copied.
+
+/// Root system description pointer, ACPI 1.0 part
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    _checksum: u8,
+    _oem_id: [u8; 6],
+    revision: u8,
+    rsdt_addr: u32,
+}
+
+/// ACPI 2.0 extension of `Rsdp`
+#[repr(C, packed)]
+struct Rsdp2 {
+    rsdp: Rsdp,
+    _length: u32,
+    xsdt_addr: u64,
+    _extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+/// Header every system description table starts with
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    _checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// MADT entries follow the header, the local APIC address and flags
+const MADT_ENTRIES: usize = size_of::<SdtHeader>() + 8;
+
+// MADT entry types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_X2APIC: u8 = 9;
+// Local APIC entry flags
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// A CPU listed in the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub acpi_id: u32,
+    pub apic_id: u32,
+}
+
+/// Read a `T` at physical address `addr`
+unsafe fn read_phys<T>(addr: u64) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(PhysAddr::new(addr)).as_ptr())
+}
+
+/// Whether the `len` bytes at `addr` sum up to 0, as every table's must
+unsafe fn checksum_ok(addr: u64, len: usize) -> bool {
+    let bytes = core::slice::from_raw_parts(
+        memory::phys_to_virt(PhysAddr::new(addr)).as_ptr::<u8>(),
+        len,
+    );
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Physical addresses of all the tables the root table lists
+fn tables() -> Vec<u64> {
+    let Some(rsdp_addr) = boot::info().rsdp_addr.map(|addr| addr.as_u64()) else {
+        return Vec::new();
+    };
+    let rsdp: Rsdp = unsafe { read_phys(rsdp_addr) };
+    if &rsdp.signature != b"RSD PTR " || !unsafe { checksum_ok(rsdp_addr, size_of::<Rsdp>()) } {
+        return Vec::new();
+    }
+    // Prefer the XSDT, its entries are 64 bits wide
+    let (root, entry_size) = match rsdp.revision {
+        0 => (rsdp.rsdt_addr as u64, 4),
+        _ => {
+            let rsdp2: Rsdp2 = unsafe { read_phys(rsdp_addr) };
+            (rsdp2.xsdt_addr, 8)
+        }
+    };
+    let Some(header) = (unsafe { header(root) }) else {
+        return Vec::new();
+    };
+    let entries = (header.length as usize - size_of::<SdtHeader>()) / entry_size;
+    let first = root + size_of::<SdtHeader>() as u64;
+    (0..entries as u64)
+        .map(|i| unsafe {
+            match entry_size {
+                4 => read_phys::<u32>(first + i * 4) as u64,
+                _ => read_phys::<u64>(first + i * 8),
+            }
+        })
+        .collect()
+}
+
+/// The header of the table at `addr`, if its checksum is right
+unsafe fn header(addr: u64) -> Option<SdtHeader> {
+    let header: SdtHeader = read_phys(addr);
+    checksum_ok(addr, header.length as usize).then_some(header)
+}
+
+/// Physical address and header of the first table with `signature`
+pub fn find_table(signature: &[u8; 4]) -> Option<(PhysAddr, SdtHeader)> {
+    tables().into_iter().find_map(|addr| {
+        let header = unsafe { header(addr)? };
+        (&header.signature == signature).then_some((PhysAddr::new(addr), header))
+    })
+}
+
+/// The CPUs the firmware lists as usable, the boot CPU among them
+///
+/// Empty if there are no ACPI tables.
+pub fn processors() -> Vec<Processor> {
+    let Some((madt, header)) = find_table(b"APIC") else {
+        return Vec::new();
+    };
+    let mut processors = Vec::new();
+    let end = madt.as_u64() + header.length as u64;
+    let mut entry = madt.as_u64() + MADT_ENTRIES as u64;
+    while entry + 2 <= end {
+        let (kind, len): (u8, u8) = unsafe { (read_phys(entry), read_phys(entry + 1)) };
+        if len < 2 {
+            break;
+        }
+        let usable = |flags: u32| flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0;
+        match kind {
+            MADT_LOCAL_APIC => {
+                let (acpi_id, apic_id, flags): (u8, u8, u32) = unsafe {
+                    (
+                        read_phys(entry + 2),
+                        read_phys(entry + 3),
+                        read_phys(entry + 4),
+                    )
+                };
+                if usable(flags) {
+                    processors.push(Processor {
+                        acpi_id: acpi_id as u32,
+                        apic_id: apic_id as u32,
+                    });
+                }
+            }
+            MADT_LOCAL_X2APIC => {
+                let (apic_id, flags, acpi_id): (u32, u32, u32) = unsafe {
+                    (
+                        read_phys(entry + 4),
+                        read_phys(entry + 8),
+                        read_phys(entry + 12),
+                    )
+                };
+                if usable(flags) {
+                    processors.push(Processor { acpi_id, apic_id });
+                }
+            }
+            _ => {}
+        }
+        entry += len as u64;
+    }
+    processors
+}
+
+#[test_case]
+fn test_madt_lists_boot_cpu() {
+    // QEMU always provides ACPI tables
+    let processors = processors();
+    assert!(!processors.is_empty());
+    let boot_cpu = crate::kernel::cpu::apic_id(0).unwrap();
+    assert!(processors.iter().any(|p| p.apic_id == boot_cpu));
+}
@@ -0,0 +1,421 @@
+use crate::kernel::meminfo;
+use crate::kernel::memory::{self, MemoryManager, USER_END, USER_START};
+use alloc::vec::Vec;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// User address spaces.
+//
+// Each has its own level 4 table. Its kernel entries are copies of the kernel
+// table's and point to the same level 3 tables, so the kernel is mapped the
+// same in every address space (see `memory`). Its user entries start out
+// empty and everything mapped under them belongs to it: dropping the address
+// space frees the frames and the page tables.
+//
+// An address space doesn't need to be active to be changed, the page tables
+// and the memory are reached through the physical memory map. That's how the
+// ELF loader fills one in before anything runs on it.
+//
+// `fork()` copies an address space copy-on-write: both share every frame,
+// each mapping counting as an owner (see `MemoryManager::share_frame()`), and
+// the writable pages become read-only in both, marked `COPY_ON_WRITE`. The
+// first write to one faults, and `copy_on_write()` gives the writer a copy of
+// its own, or just makes the page writable again for the last owner. Pages
+// mapped with `map_shared()` are marked `SHARED` and stay shared as they are.
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Marks writable pages shared read-only since a `fork()`, one of the bits
+/// the CPU leaves to the OS
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks pages meant to be shared, like shared memory (see `ipc`), which
+/// `fork()` leaves writable
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+/// The page table in `frame`, through the physical memory map
+///
+/// # Safety
+///
+/// `frame` must hold a page table, and the memory manager must be locked.
+unsafe fn table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+/// The pages covering the `size` bytes at `start`, which must be within user space
+fn user_pages(start: VirtAddr, size: usize) -> impl Iterator<Item = Page> {
+    let end = start.as_u64().checked_add(size as u64);
+    assert!(
+        size > 0 && start.as_u64() >= USER_START && end.is_some_and(|end| end <= USER_END),
+        "address_space: {:#x} + {:#x} is not in user space",
+        start.as_u64(),
+        size
+    );
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + (size as u64 - 1));
+    Page::range_inclusive(first, last)
+}
+
+impl AddressSpace {
+    /// An address space with nothing mapped in user space
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let frame = memory::with_memory(|mm| {
+            let frame = mm.frame_allocator.allocate_frame()?;
+            unsafe {
+                let kernel = table(memory::kernel_page_table());
+                let new = table(frame);
+                *new = PageTable::new();
+                for (index, entry) in kernel.iter().enumerate() {
+                    if memory::is_kernel_entry(index) {
+                        new[index] = entry.clone();
+                    }
+                }
+            }
+            Some(frame)
+        })
+        .ok_or(MapToError::FrameAllocationFailed)?;
+        meminfo::page_table_alloc();
+        Ok(AddressSpace {
+            level_4_frame: frame,
+        })
+    }
+
+    /// The level 4 table, what CR3 holds while the address space is active
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Map `size` bytes at `start` to newly allocated, zeroed frames
+    pub fn map(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        memory::with_page_table(self.level_4_frame, |mm| {
+            for page in user_pages(start, size) {
+                let frame = mm.map_page(page, flags)?;
+                // Frames come with whatever the last owner left in them
+                unsafe {
+                    memory::phys_to_virt(frame.start_address())
+                        .as_mut_ptr::<u8>()
+                        .write_bytes(0, PAGE_SIZE as usize);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Map the page at `addr` to `frame`, which gets another owner for the
+    /// mapping (see `MemoryManager::share_frame()`), marked `SHARED`
+    ///
+    /// For frames mapped in several address spaces at once, like the signal
+    /// trampoline and shared memory.
+    pub fn map_shared(
+        &self,
+        addr: VirtAddr,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let page = user_pages(addr, 1).next().unwrap();
+        memory::with_page_table(self.level_4_frame, |mm| {
+            unsafe { mm.map_frame(page, frame, flags | SHARED) }?;
+            mm.share_frame(frame);
+            Ok(())
+        })
+    }
+
+    /// `map_shared()` each of `frames` in turn, from `start`
+    ///
+    /// Nothing stays mapped if it fails.
+    pub fn map_shared_frames(
+        &self,
+        start: VirtAddr,
+        frames: &[PhysFrame],
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        for (i, &frame) in frames.iter().enumerate() {
+            if let Err(err) = self.map_shared(start + i as u64 * PAGE_SIZE, frame, flags) {
+                if i > 0 {
+                    self.unmap(start, i * PAGE_SIZE as usize)
+                        .expect("address_space: mapped pages vanished");
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap `size` bytes at `start` and free the frames
+    pub fn unmap(&self, start: VirtAddr, size: usize) -> Result<(), UnmapError> {
+        let mut pages = user_pages(start, size);
+        let first = pages.next().unwrap();
+        let last = pages.last().unwrap_or(first);
+        memory::with_page_table(self.level_4_frame, |mm| {
+            mm.unmap_pages(Page::range_inclusive(first, last))
+        })
+    }
+
+    /// Change the flags of the `size` mapped bytes at `start`
+    pub fn protect(
+        &self,
+        start: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let mut pages = user_pages(start, size);
+        let first = pages.next().unwrap();
+        let last = pages.last().unwrap_or(first);
+        memory::with_page_table(self.level_4_frame, |mm| {
+            mm.protect_pages(Page::range_inclusive(first, last), flags)
+        })
+    }
+
+    /// A copy of the address space, sharing its frames until either writes
+    /// to them
+    pub fn fork(&self) -> Result<AddressSpace, MapToError<Size4KiB>> {
+        let child = AddressSpace::new()?;
+        let pages = memory::with_page_table(self.level_4_frame, |mm| {
+            let mut pages = unsafe { mapped_pages(self.level_4_frame) };
+            let mut protected = Vec::new();
+            for (page, frame, flags) in pages.iter_mut() {
+                if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                    *flags = (*flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    protected.push((*page, *flags));
+                }
+                mm.share_frame(*frame);
+            }
+            // All at once, rather than a shootdown per page
+            mm.protect_each(protected)
+                .expect("address_space: mapped page vanished");
+            pages
+        });
+        memory::with_page_table(child.level_4_frame, |mm| {
+            let mut pages = pages.into_iter();
+            while let Some((page, frame, flags)) = pages.next() {
+                if let Err(err) = unsafe { mm.map_frame(page, frame, flags) } {
+                    // Those not mapped in the child don't have the owner
+                    // they were counted for
+                    for (_, frame, _) in core::iter::once((page, frame, flags)).chain(pages) {
+                        unsafe { mm.release_frame(frame) };
+                    }
+                    return Err(err);
+                }
+            }
+            Ok(())
+        })?;
+        Ok(child)
+    }
+
+    /// Where `addr` is mapped to and with which flags
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        memory::with_page_table(self.level_4_frame, |mm| match mm.mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        })
+    }
+
+    /// Copy `bytes` to `addr`, whatever the pages' flags
+    ///
+    /// Returns false, having written what comes before, if part of the
+    /// destination isn't mapped. Writes go to shared frames as they are, this
+    /// is for address spaces being filled in.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> bool {
+        self.copy(addr, bytes.len(), |memory, offset| unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[offset..].as_ptr(),
+                memory.as_mut_ptr(),
+                memory.len(),
+            );
+        })
+    }
+
+    /// Fill `buf` from `addr`, whatever the pages' flags
+    ///
+    /// Returns false if part of the source isn't mapped.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> bool {
+        self.copy(addr, buf.len(), |memory, offset| {
+            buf[offset..offset + memory.len()].copy_from_slice(memory);
+        })
+    }
+
+    /// Call `f` with each page sized piece of the `len` bytes at `addr`, as
+    /// seen through the physical memory map, and its offset from `addr`
+    fn copy<F>(&self, addr: VirtAddr, len: usize, mut f: F) -> bool
+    where
+        F: FnMut(&mut [u8], usize),
+    {
+        let mut offset = 0;
+        while offset < len {
+            let Some((phys, _)) = self.translate(addr + offset as u64) else {
+                return false;
+            };
+            let chunk = (len - offset).min((PAGE_SIZE - phys.as_u64() % PAGE_SIZE) as usize);
+            let memory = unsafe {
+                core::slice::from_raw_parts_mut(
+                    memory::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    chunk,
+                )
+            };
+            f(memory, offset);
+            offset += chunk;
+        }
+        true
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            Cr3::read().0 != self.level_4_frame,
+            "address_space: dropped while active"
+        );
+        memory::with_memory(|mm| unsafe {
+            let level_4 = table(self.level_4_frame);
+            for (index, entry) in level_4.iter().enumerate() {
+                if !memory::is_kernel_entry(index) && !entry.is_unused() {
+                    free_table(mm, entry_frame(entry), 3);
+                }
+            }
+            mm.frame_allocator.deallocate_frame(self.level_4_frame);
+            meminfo::page_table_free();
+        });
+    }
+}
+
+fn entry_frame(entry: &PageTableEntry) -> PhysFrame {
+    entry
+        .frame()
+        .expect("address_space: huge page in user space")
+}
+
+/// Free the level `level` page table in `frame`, and drop the owners of what
+/// is mapped under it
+///
+/// # Safety
+///
+/// Nothing may use the table anymore.
+unsafe fn free_table(mm: &mut MemoryManager, frame: PhysFrame, level: u8) {
+    for entry in table(frame).iter().filter(|entry| !entry.is_unused()) {
+        if level > 1 {
+            free_table(mm, entry_frame(entry), level - 1);
+        } else if let Ok(frame) = entry.frame() {
+            mm.release_frame(frame);
+        }
+    }
+    mm.frame_allocator.deallocate_frame(frame);
+    meminfo::page_table_free();
+}
+
+/// Every page mapped in user space, with its frame and flags
+///
+/// # Safety
+///
+/// `level_4_frame` must hold a level 4 table, and the memory manager must be
+/// locked.
+unsafe fn mapped_pages(level_4_frame: PhysFrame) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+    let used = |frame| {
+        table(frame)
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_unused())
+    };
+    let mut pages = Vec::new();
+    for (i4, e4) in used(level_4_frame).filter(|&(index, _)| !memory::is_kernel_entry(index)) {
+        for (i3, e3) in used(entry_frame(e4)) {
+            for (i2, e2) in used(entry_frame(e3)) {
+                for (i1, e1) in used(entry_frame(e2)) {
+                    let Ok(frame) = e1.frame() else { continue };
+                    let addr = (i4 << 39) | (i3 << 30) | (i2 << 21) | (i1 << 12);
+                    let page = Page::containing_address(VirtAddr::new(addr as u64));
+                    pages.push((page, frame, e1.flags()));
+                }
+            }
+        }
+    }
+    pages
+}
+
+/// Give the active address space a copy of its own of the `COPY_ON_WRITE`
+/// page at `addr`, mapped writable
+///
+/// Returns false if there is no such page there, or no memory for the copy.
+/// For the page fault handler, and before the kernel writes to user memory.
+pub fn copy_on_write(addr: VirtAddr) -> bool {
+    if !(USER_START..USER_END).contains(&addr.as_u64()) {
+        return false;
+    }
+    let page = Page::<Size4KiB>::containing_address(addr);
+    memory::with_memory(|mm| {
+        let TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(shared),
+            flags,
+            ..
+        } = mm.mapper.translate(page.start_address())
+        else {
+            return false;
+        };
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+        let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if mm.frame_owners(shared) == 1 {
+            return mm
+                .protect_pages(Page::range_inclusive(page, page), flags)
+                .is_ok();
+        }
+        let Some(copy) = mm.frame_allocator.allocate_frame() else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                memory::phys_to_virt(shared.start_address()).as_ptr::<u8>(),
+                memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                PAGE_SIZE as usize,
+            );
+        }
+        // Drops this address space's share of the old frame, the page tables
+        // stay for the copy
+        mm.unmap_page(page)
+            .expect("address_space: mapped page vanished");
+        unsafe { mm.map_frame(page, copy, flags) }.is_ok()
+    })
+}
+
+#[test_case]
+fn test_address_space_isolation() {
+    let addr = VirtAddr::new(0x_5555_2000_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let before = meminfo::snapshot();
+
+    let space = AddressSpace::new().expect("AddressSpace::new failed");
+    space.map(addr, 2 * 4096, flags).expect("map failed");
+    assert!(space.write(addr + 4000u64, &[0xa5; 200]));
+    let (phys, mapped) = space.translate(addr + 4096u64).expect("not mapped");
+    assert_eq!(mapped, flags);
+    let first = unsafe { memory::phys_to_virt(phys).as_ptr::<[u8; 2]>().read() };
+    assert_eq!(first, [0xa5, 0xa5]);
+    // Only there, not in the kernel's page table
+    assert!(memory::with_memory(|mm| mm.mapper.translate_addr(addr)).is_none());
+    assert!(!space.write(addr + 2 * 4096u64, &[0]));
+
+    drop(space);
+    let delta = meminfo::snapshot().delta_since(&before);
+    assert_eq!((delta.frames, delta.page_tables), (0, 0));
+}
@@ -0,0 +1,151 @@
+use super::Locked;
+use crate::kernel::meminfo;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, ptr::NonNull};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// The block sizes (slab classes) to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Number of slab classes, see `BLOCK_SIZES`
+pub const SLAB_CLASSES: usize = BLOCK_SIZES.len();
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Slab style allocator: small allocations are rounded up to the next block size
+/// and served from a per size free list. Blocks are never merged or split, freed
+/// blocks simply go back on the list of their size. Anything larger than the
+/// biggest block size goes to the linked list fallback allocator.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; SLAB_CLASSES],
+    fallback_allocator: linked_list_allocator::Heap,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; SLAB_CLASSES],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the given heap bounds are valid
+    /// and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator
+            .init(heap_start as *mut u8, heap_size);
+    }
+
+    /// Allocate a block for `layout`, returns null if the heap is exhausted
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                let ptr = match self.list_heads[index].take() {
+                    Some(node) => {
+                        self.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // No block exists in list => allocate new block
+                        let block_size = BLOCK_SIZES[index];
+                        // Only works if all block sizes are a power of 2
+                        let block_align = block_size;
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        self.fallback_alloc(layout)
+                    }
+                };
+                if !ptr.is_null() {
+                    meminfo::slab_alloc(index);
+                }
+                ptr
+            }
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    /// Give the block at `ptr` back to its free list (or the fallback allocator)
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `alloc()` with the same `layout`.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // Verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+                meminfo::slab_free(index);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    /// Allocates using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// See `FixedSizeBlockAllocator::init()`
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size);
+    }
+}
+
+// Interrupts disabled around the lock, see `Locked`
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = without_interrupts(|| self.lock().alloc(layout));
+        if !ptr.is_null() {
+            meminfo::heap_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.lock().dealloc(ptr, layout));
+        meminfo::heap_free(layout.size());
+    }
+}
@@ -0,0 +1,544 @@
+use super::fixed_size_block::FixedSizeBlockAllocator;
+use super::{heap_start, Locked, HEAP_SIZE};
+use crate::kernel::meminfo;
+use crate::serial_println;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, ptr};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// KASAN-style heap checking (enabled with the `kasan` cargo feature).
+//
+// We don't have compiler instrumentation for every load and store, so instead every
+// heap allocation is checked at its boundaries:
+//
+// * Each object is surrounded by red zones filled with a known pattern. The left red
+//   zone also starts with a header describing the allocation. Red zones are verified when
+//   the object is freed, catching writes past either end of the buffer.
+// * Freed objects are filled with a poison pattern and parked in a quarantine instead
+//   of being handed back to the allocator straight away. When an object leaves the
+//   quarantine its poison is verified, catching writes through dangling pointers.
+// * Shadow memory holds one byte per 8 byte heap granule describing whether it is
+//   addressable, part of a red zone or freed. It is consulted on every alloc/free
+//   (double free, invalid free, allocator handing out live memory) and can be
+//   queried explicitly via `check_access()`.
+//
+// Reports include the allocation (and free) site as a short list of return addresses,
+// they can be resolved with `addr2line -e <kernel elf>`. Walking the stack relies on
+// frame pointers, see `.cargo/config.toml`.
+
+/// Heap bytes covered by one shadow byte
+const GRANULE: usize = 8;
+/// Minimum size of the red zones on either side of an object
+const REDZONE: usize = 32;
+/// Number of return addresses recorded per allocation/free site
+const SITE_DEPTH: usize = 4;
+/// Max number of freed objects kept in quarantine
+const QUARANTINE_LEN: usize = 128;
+/// Max number of heap bytes kept in quarantine, bigger objects skip it
+pub const QUARANTINE_BYTES: usize = HEAP_SIZE / 16;
+
+const HEADER_MAGIC: u64 = 0x4b41_5341_4e48_4452; // "KASANHDR"
+
+// Fill patterns
+const LEFT_REDZONE_BYTE: u8 = 0xfa;
+const RIGHT_REDZONE_BYTE: u8 = 0xfb;
+const FREED_BYTE: u8 = 0xfd;
+
+// Shadow byte values. 0 means the whole granule is addressable, 1..=7 means only
+// the first N bytes are.
+const SHADOW_ADDRESSABLE: u8 = 0x00;
+const SHADOW_LEFT_REDZONE: u8 = 0xfa;
+const SHADOW_RIGHT_REDZONE: u8 = 0xfb;
+const SHADOW_UNALLOCATED: u8 = 0xfc;
+const SHADOW_FREED: u8 = 0xfd;
+
+/// Kind of memory bug detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BugKind {
+    /// Write past the end of an object
+    HeapBufferOverflow,
+    /// Write before the start of an object
+    HeapBufferUnderflow,
+    /// Write (or checked access) through a pointer to a freed object
+    UseAfterFree,
+    /// Object freed while already in quarantine
+    DoubleFree,
+    /// Pointer passed to `dealloc` that was never returned by `alloc`
+    InvalidFree,
+    /// The allocator returned memory that is still part of a live object
+    AllocatorCorruption,
+    /// Checked access to a red zone or to memory that isn't allocated
+    OutOfBounds,
+}
+
+/// Return addresses of the code that allocated/freed an object, innermost first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Site(pub [usize; SITE_DEPTH]);
+
+/// Description of a detected memory bug
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub kind: BugKind,
+    /// First bad byte (or the freed pointer)
+    pub addr: usize,
+    /// Start and size of the object the bad access belongs to, if known
+    pub object: Option<(usize, usize)>,
+    pub alloc_site: Option<Site>,
+    pub free_site: Option<Site>,
+}
+
+// Header stored at the start of the left red zone
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AllocHeader {
+    magic: u64,
+    /// Requested object size
+    size: usize,
+    freed: bool,
+    alloc_site: Site,
+    free_site: Site,
+}
+
+struct Quarantined {
+    object: *mut u8,
+    layout: Layout,
+}
+
+struct KasanState {
+    shadow: [u8; HEAP_SIZE / GRANULE],
+    quarantine: [Option<Quarantined>; QUARANTINE_LEN],
+    // Oldest entry in the quarantine ring
+    q_head: usize,
+    q_len: usize,
+    q_bytes: usize,
+    last_report: Option<Report>,
+}
+
+// The raw pointers in the quarantine only ever point into the heap
+unsafe impl Send for KasanState {}
+
+static PANIC_ON_REPORT: AtomicBool = AtomicBool::new(true);
+
+/// Heap allocator wrapper adding red zones, quarantine and shadow memory checks
+pub struct Kasan {
+    inner: Locked<FixedSizeBlockAllocator>,
+    state: Mutex<KasanState>,
+}
+
+impl Kasan {
+    pub const fn new(inner: FixedSizeBlockAllocator) -> Self {
+        const EMPTY: Option<Quarantined> = None;
+        Kasan {
+            inner: Locked::new(inner),
+            state: Mutex::new(KasanState {
+                shadow: [SHADOW_ADDRESSABLE; HEAP_SIZE / GRANULE],
+                quarantine: [EMPTY; QUARANTINE_LEN],
+                q_head: 0,
+                q_len: 0,
+                q_bytes: 0,
+                last_report: None,
+            }),
+        }
+    }
+
+    /// Initialize the wrapped allocator with the given heap bounds.
+    ///
+    /// # Safety
+    ///
+    /// Same as `FixedSizeBlockAllocator::init()`, the heap must be `HEAP_SIZE`
+    /// bytes as that is what the shadow memory covers.
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        // The shadow starts out as "nothing allocated"
+        self.state.lock().shadow.fill(SHADOW_UNALLOCATED);
+        self.inner.lock().init(heap_start, heap_size);
+    }
+
+    /// Layout of the block holding an object of `layout` plus its red zones.
+    /// Returns the block layout and the offset of the object within the block.
+    fn block_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(GRANULE);
+        // The header goes first, followed by the actual red zone. The whole left
+        // part must keep the object aligned.
+        let left = round_up(mem::size_of::<AllocHeader>() + REDZONE, align);
+        let size = left + round_up(layout.size(), GRANULE) + REDZONE;
+        (Layout::from_size_align(size, align).unwrap(), left)
+    }
+
+    fn flush(&self, state: &mut KasanState) {
+        while state.q_len > 0 {
+            self.evict(state);
+        }
+    }
+
+    /// Release the oldest quarantined object back to the allocator
+    fn evict(&self, state: &mut KasanState) {
+        let Quarantined { object, layout } = state.quarantine[state.q_head]
+            .take()
+            .expect("kasan: quarantine out of sync");
+        state.q_head = (state.q_head + 1) % QUARANTINE_LEN;
+        state.q_len -= 1;
+        state.q_bytes -= layout.size();
+
+        let (_, left) = Self::block_layout(layout);
+        let block = unsafe { object.sub(left) };
+        let header = unsafe { *(block as *const AllocHeader) };
+        let poisoned = unsafe { find_not(object, header.size, FREED_BYTE) };
+        if let Some(addr) = poisoned {
+            report(
+                state,
+                Report {
+                    kind: BugKind::UseAfterFree,
+                    addr,
+                    object: Some((object as usize, header.size)),
+                    alloc_site: Some(header.alloc_site),
+                    free_site: Some(header.free_site),
+                },
+            );
+        }
+        self.release(state, object, layout);
+    }
+
+    /// Give the freed `object` back to the allocator
+    fn release(&self, state: &mut KasanState, object: *mut u8, layout: Layout) {
+        let (block_layout, left) = Self::block_layout(layout);
+        let block = unsafe { object.sub(left) };
+        poison_shadow(
+            state,
+            block as usize,
+            block_layout.size(),
+            SHADOW_UNALLOCATED,
+        );
+        unsafe { self.inner.lock().dealloc(block, block_layout) };
+    }
+}
+
+impl Kasan {
+    unsafe fn checked_alloc(&self, layout: Layout, site: Site) -> *mut u8 {
+        let (block_layout, left) = Self::block_layout(layout);
+        let mut state = self.state.lock();
+
+        let mut block = self.inner.lock().alloc(block_layout);
+        if block.is_null() && state.q_len > 0 {
+            // Out of memory, give back everything in quarantine and retry
+            self.flush(&mut state);
+            block = self.inner.lock().alloc(block_layout);
+        }
+        if block.is_null() {
+            return block;
+        }
+
+        let object = block.add(left);
+        if let Some(addr) = first_live_granule(&state, block as usize, block_layout.size()) {
+            report(
+                &mut state,
+                Report {
+                    kind: BugKind::AllocatorCorruption,
+                    addr,
+                    object: None,
+                    alloc_site: Some(site),
+                    free_site: None,
+                },
+            );
+        }
+
+        // Red zones, header and shadow
+        ptr::write_bytes(block, LEFT_REDZONE_BYTE, left);
+        let right = block_layout.size() - left - layout.size();
+        ptr::write_bytes(object.add(layout.size()), RIGHT_REDZONE_BYTE, right);
+        (block as *mut AllocHeader).write(AllocHeader {
+            magic: HEADER_MAGIC,
+            size: layout.size(),
+            freed: false,
+            alloc_site: site,
+            free_site: Site::default(),
+        });
+        poison_shadow(&mut state, block as usize, left, SHADOW_LEFT_REDZONE);
+        unpoison_shadow(&mut state, object as usize, layout.size());
+        let object_end = object as usize + round_up(layout.size(), GRANULE);
+        poison_shadow(
+            &mut state,
+            object_end,
+            block as usize + block_layout.size() - object_end,
+            SHADOW_RIGHT_REDZONE,
+        );
+
+        meminfo::heap_alloc(layout.size());
+        object
+    }
+
+    unsafe fn checked_dealloc(&self, object: *mut u8, layout: Layout, site: Site) {
+        let mut state = self.state.lock();
+        let addr = object as usize;
+
+        let (block_layout, left) = Self::block_layout(layout);
+        let in_heap = (heap_start() + left..heap_start() + HEAP_SIZE).contains(&addr);
+        let block = object.wrapping_sub(left);
+        let header = block as *mut AllocHeader;
+        // Anything that doesn't start right after a left red zone with an intact
+        // header was never handed out by us (or has already left the quarantine)
+        if !in_heap
+            || shadow_at(&state, addr - GRANULE) != SHADOW_LEFT_REDZONE
+            || (*header).magic != HEADER_MAGIC
+            || (*header).size != layout.size()
+        {
+            report(
+                &mut state,
+                Report {
+                    kind: BugKind::InvalidFree,
+                    addr,
+                    object: None,
+                    alloc_site: None,
+                    free_site: Some(site),
+                },
+            );
+            return;
+        }
+        // A quarantined object keeps its header, so double frees are easy to spot
+        if (*header).freed {
+            report(
+                &mut state,
+                Report {
+                    kind: BugKind::DoubleFree,
+                    addr,
+                    object: Some((addr, (*header).size)),
+                    alloc_site: Some((*header).alloc_site),
+                    free_site: Some((*header).free_site),
+                },
+            );
+            return;
+        }
+
+        // Check both red zones for out of bounds writes
+        let size = layout.size();
+        let header_end = block.add(mem::size_of::<AllocHeader>());
+        let underflow = find_not(
+            header_end,
+            left - mem::size_of::<AllocHeader>(),
+            LEFT_REDZONE_BYTE,
+        );
+        let right = block_layout.size() - left - size;
+        let overflow = find_not(object.add(size), right, RIGHT_REDZONE_BYTE);
+        for (kind, addr) in [
+            (BugKind::HeapBufferUnderflow, underflow),
+            (BugKind::HeapBufferOverflow, overflow),
+        ] {
+            if let Some(addr) = addr {
+                report(
+                    &mut state,
+                    Report {
+                        kind,
+                        addr,
+                        object: Some((object as usize, size)),
+                        alloc_site: Some((*header).alloc_site),
+                        free_site: None,
+                    },
+                );
+            }
+        }
+
+        // Poison and quarantine the object
+        (*header).freed = true;
+        (*header).free_site = site;
+        ptr::write_bytes(object, FREED_BYTE, size);
+        poison_shadow(&mut state, addr, round_up(size, GRANULE), SHADOW_FREED);
+        meminfo::heap_free(size);
+
+        if layout.size() > QUARANTINE_BYTES {
+            // It alone would push the quarantine past its cap
+            self.release(&mut state, object, layout);
+            return;
+        }
+        while state.q_len == QUARANTINE_LEN || state.q_bytes + layout.size() > QUARANTINE_BYTES {
+            self.evict(&mut state);
+        }
+        let tail = (state.q_head + state.q_len) % QUARANTINE_LEN;
+        state.quarantine[tail] = Some(Quarantined { object, layout });
+        state.q_len += 1;
+        state.q_bytes += layout.size();
+    }
+}
+
+// Interrupts disabled around both locks, for the reason given on `Locked`
+unsafe impl GlobalAlloc for Kasan {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Before anything else, so the site starts at our caller
+        let site = Site::capture();
+        without_interrupts(|| self.checked_alloc(layout, site))
+    }
+
+    unsafe fn dealloc(&self, object: *mut u8, layout: Layout) {
+        let site = Site::capture();
+        without_interrupts(|| self.checked_dealloc(object, layout, site))
+    }
+}
+
+impl Site {
+    /// Record the return addresses of the callers of the allocator
+    #[inline(always)]
+    fn capture() -> Self {
+        let mut site = [0; SITE_DEPTH];
+        let mut rbp: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        for ret in site.iter_mut() {
+            if rbp == 0 || !rbp.is_multiple_of(mem::align_of::<usize>()) {
+                break;
+            }
+            // Frame layout: [rbp] = caller's rbp, [rbp + 8] = return address
+            let (next, ret_addr) =
+                unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+            *ret = ret_addr;
+            // The stack grows down, so each caller's frame must be above ours. This
+            // stops the walk at the outermost frame, whose saved rbp isn't ours to trust.
+            if next <= rbp || next - rbp > 1024 * 1024 {
+                break;
+            }
+            rbp = next;
+        }
+        Site(site)
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for addr in self.0.iter().take_while(|&&a| a != 0) {
+            write!(f, " {:#x}", addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KASAN: {:?} at {:#x}", self.kind, self.addr)?;
+        if let Some((start, size)) = self.object {
+            write!(f, "\n  object {:#x} of size {}", start, size)?;
+        }
+        if let Some(site) = self.alloc_site {
+            write!(f, "\n  allocated at:{}", site)?;
+        }
+        if let Some(site) = self.free_site {
+            write!(f, "\n  freed at:{}", site)?;
+        }
+        Ok(())
+    }
+}
+
+fn round_up(size: usize, align: usize) -> usize {
+    (size + align - 1) & !(align - 1)
+}
+
+/// Address of the first of `len` bytes at `start` that isn't `pattern`
+unsafe fn find_not(start: *const u8, len: usize, pattern: u8) -> Option<usize> {
+    (0..len)
+        .find(|&i| start.add(i).read_volatile() != pattern)
+        .map(|i| start as usize + i)
+}
+
+fn shadow_index(addr: usize) -> usize {
+    (addr - heap_start()) / GRANULE
+}
+
+fn shadow_at(state: &KasanState, addr: usize) -> u8 {
+    state.shadow[shadow_index(addr)]
+}
+
+fn poison_shadow(state: &mut KasanState, start: usize, len: usize, value: u8) {
+    let first = shadow_index(start);
+    state.shadow[first..first + len / GRANULE].fill(value);
+}
+
+fn unpoison_shadow(state: &mut KasanState, start: usize, len: usize) {
+    let first = shadow_index(start);
+    state.shadow[first..first + len / GRANULE].fill(SHADOW_ADDRESSABLE);
+    if !len.is_multiple_of(GRANULE) {
+        state.shadow[first + len / GRANULE] = (len % GRANULE) as u8;
+    }
+}
+
+/// First granule in `[start, start + len)` whose shadow says it belongs to a live object
+fn first_live_granule(state: &KasanState, start: usize, len: usize) -> Option<usize> {
+    (start..start + len)
+        .step_by(GRANULE)
+        .find(|&addr| shadow_at(state, addr) < GRANULE as u8)
+}
+
+fn report(state: &mut KasanState, report: Report) {
+    serial_println!("{}", report);
+    state.last_report = Some(report);
+    if PANIC_ON_REPORT.load(Ordering::Relaxed) {
+        panic!("KASAN: {:?}", report.kind);
+    }
+}
+
+fn global() -> &'static Kasan {
+    &super::ALLOCATOR
+}
+
+/// Lock the checker state, with interrupts disabled like the allocator does
+fn with_state<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut KasanState) -> R,
+{
+    without_interrupts(|| f(&mut global().state.lock()))
+}
+
+/// Check that `len` bytes at `addr` are part of a live heap object
+///
+/// Addresses outside the heap are not tracked and always pass.
+pub fn check_access(addr: usize, len: usize) -> Result<(), Report> {
+    if len == 0 || addr < heap_start() || addr + len > heap_start() + HEAP_SIZE {
+        return Ok(());
+    }
+    with_state(|state| {
+        for a in addr..addr + len {
+            let shadow = shadow_at(state, a);
+            let ok = match shadow {
+                SHADOW_ADDRESSABLE => true,
+                partial if partial < GRANULE as u8 => a % GRANULE < partial as usize,
+                _ => false,
+            };
+            if !ok {
+                let kind = match shadow {
+                    SHADOW_FREED => BugKind::UseAfterFree,
+                    _ => BugKind::OutOfBounds,
+                };
+                let r = Report {
+                    kind,
+                    addr: a,
+                    object: None,
+                    alloc_site: None,
+                    free_site: None,
+                };
+                report(state, r);
+                return Err(r);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Choose whether a report panics the kernel (the default) or is only logged
+///
+/// Tests turn this off to trigger bugs on purpose and inspect `take_report()`.
+pub fn set_panic_on_report(panic: bool) {
+    PANIC_ON_REPORT.store(panic, Ordering::Relaxed);
+}
+
+/// Returns (and clears) the most recent report
+pub fn take_report() -> Option<Report> {
+    with_state(|state| state.last_report.take())
+}
+
+/// Heap bytes of the objects in quarantine
+pub fn quarantine_bytes() -> usize {
+    with_state(|state| state.q_bytes)
+}
+
+/// Release every quarantined object, checking each for writes after free
+pub fn flush_quarantine() {
+    with_state(|state| global().flush(state));
+}
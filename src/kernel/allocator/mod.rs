@@ -0,0 +1,69 @@
+use crate::kernel::{meminfo, memory};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use fixed_size_block::FixedSizeBlockAllocator;
+use x86_64::structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+pub mod fixed_size_block;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+
+// Start of the kernel heap. Any address works as long as it's not already in use,
+// it's picked at random every boot by `kernel::aslr`.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(not(feature = "kasan"))]
+#[global_allocator]
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+// With the `kasan` feature every heap allocation goes through the checking wrapper
+#[cfg(feature = "kasan")]
+#[global_allocator]
+static ALLOCATOR: kasan::Kasan = kasan::Kasan::new(FixedSizeBlockAllocator::new());
+
+/// Start address of the kernel heap
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// Map the heap region at `heap_start` and hand it to the global allocator
+pub fn init_heap(heap_start: VirtAddr) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let region = meminfo::register_region("heap", heap_start, HEAP_SIZE);
+    memory::map_range(heap_start, HEAP_SIZE, flags)?;
+    meminfo::region_map(region, HEAP_SIZE);
+    meminfo::set_heap_size(HEAP_SIZE);
+    HEAP_START.store(heap_start.as_u64() as usize, Ordering::Relaxed);
+
+    unsafe {
+        ALLOCATOR.init(heap_start.as_u64() as usize, HEAP_SIZE);
+    }
+    Ok(())
+}
+
+/// A wrapper around spin::Mutex to permit trait implementations.
+///
+/// `GlobalAlloc` methods only get `&self`, so the allocator state has to live
+/// behind a lock. We can't implement a foreign trait on `spin::Mutex<A>` directly.
+///
+/// Allocators take it with interrupts disabled: the heap is used from interrupt
+/// handlers and the scheduler, which would spin forever on a lock held by the
+/// code they interrupted.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
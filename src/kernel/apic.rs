@@ -0,0 +1,148 @@
+use crate::kernel::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+// The local APIC, each CPU's interrupt controller. We use it to start and
+// signal the other CPUs (see `ipi`); device interrupts still come from the 8259 PICs,
+// which the boot CPU's local APIC passes through on LINT0 ("virtual wire").
+//
+// Registers are memory mapped at the same physical address on every CPU, each
+// CPU reaching its own. We go through the physical memory map.
+
+/// Vector of the spurious interrupts the local APIC raises
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+/// `IA32_APIC_BASE` bit enabling the local APIC
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Register offsets
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xb0;
+const REG_SPURIOUS: usize = 0xf0;
+const REG_ERROR_STATUS: usize = 0x280;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_LINT0: usize = 0x350;
+const REG_LVT_LINT1: usize = 0x360;
+
+/// `REG_SPURIOUS` bit enabling the local APIC
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+// Local vector table and interrupt command register fields
+const LVT_MASKED: u32 = 1 << 16;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_EXTINT: u32 = 0b111 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+// Virtual address of the registers, 0 until `init()`
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Find and enable the boot CPU's local APIC
+///
+/// Keeps the PICs' interrupts coming in through LINT0.
+pub fn init() {
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    unsafe { base_msr.write(base | APIC_GLOBAL_ENABLE) };
+    let phys = PhysAddr::new(base & 0x000f_ffff_ffff_f000);
+    BASE.store(memory::phys_to_virt(phys).as_u64(), Ordering::Release);
+
+    unsafe {
+        write(REG_LVT_LINT0, DELIVERY_EXTINT);
+        write(REG_LVT_LINT1, DELIVERY_NMI);
+    }
+    enable();
+}
+
+/// Enable an application processor's local APIC, after `init()` on the boot CPU
+pub fn init_ap() {
+    unsafe {
+        // Only the boot CPU takes the PICs' interrupts
+        write(REG_LVT_LINT0, LVT_MASKED);
+        write(REG_LVT_LINT1, DELIVERY_NMI);
+    }
+    enable();
+}
+
+fn enable() {
+    unsafe {
+        write(REG_SPURIOUS, SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+        // Writing clears the errors from before we took over
+        write(REG_ERROR_STATUS, 0);
+    }
+}
+
+/// Local APIC ID of the CPU we're running on, `None` before `init()`
+pub fn id() -> Option<u8> {
+    if BASE.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    Some((unsafe { read(REG_ID) } >> 24) as u8)
+}
+
+/// Acknowledge the interrupt being handled, for interrupts the local APIC raised
+pub fn end_of_interrupt() {
+    unsafe { write(REG_EOI, 0) };
+}
+
+/// Send an INIT IPI, resetting the CPU with local APIC ID `apic_id`
+///
+/// # Safety
+///
+/// The CPU must not be running the kernel.
+pub unsafe fn send_init(apic_id: u8) {
+    send_ipi(apic_id, DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Send a startup IPI, which starts a CPU waiting after INIT in real mode at
+/// physical address `page << 12`
+///
+/// # Safety
+///
+/// The CPU must be waiting after `send_init()` and there must be code for it at `page`.
+pub unsafe fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, DELIVERY_STARTUP | page as u32);
+}
+
+/// Raise interrupt `vector` on the CPU with local APIC ID `apic_id`
+pub fn send_fixed(apic_id: u8, vector: u8) {
+    unsafe { send_ipi(apic_id, vector as u32) };
+}
+
+/// Raise interrupt `vector` on every CPU, this one included
+pub fn send_fixed_all(vector: u8) {
+    unsafe { send_ipi(0, ICR_ALL_INCLUDING_SELF | vector as u32) };
+}
+
+/// Raise interrupt `vector` on every CPU but this one
+pub fn send_fixed_others(vector: u8) {
+    unsafe { send_ipi(0, ICR_ALL_EXCLUDING_SELF | vector as u32) };
+}
+
+unsafe fn send_ipi(apic_id: u8, command: u32) {
+    // An interrupt handler sending an IPI in between would change the destination
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        write(REG_ICR_HIGH, (apic_id as u32) << 24);
+        // Writing the low half sends it
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+unsafe fn read(reg: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed) as usize;
+    core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed) as usize;
+    core::ptr::write_volatile((base + reg) as *mut u32, value);
+}
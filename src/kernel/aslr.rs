@@ -0,0 +1,152 @@
+use crate::kernel::allocator::HEAP_SIZE;
+use crate::kernel::random::{self, EntropySource, Rng};
+use crate::kernel::{boot, memory};
+use crate::serial_println;
+use core::fmt;
+use spin::Once;
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+// Kernel address space layout randomization.
+//
+// Every boot picks a seed (RDSEED/RDRAND or TSC jitter) and derives the placement
+// of the regions the kernel maps itself from it. The layout, including the seed,
+// is printed over serial at boot. To reproduce a failure with the same layout,
+// rebuild with the seed fixed:
+//
+//     FOX_ASLR_SEED=0x1234abcd cargo test
+//
+// The kernel image, the physical memory map and the boot stack are placed by the
+// bootloader, which randomizes them too unless a seed is fixed (see
+// `BOOTLOADER_CONFIG`). Those are reported here but not chosen by us.
+
+/// Window the heap is placed in, aligned to `REGION_ALIGN`
+const HEAP_WINDOW: (u64, u64) = (0x_ffff_c000_0000_0000, 0x_ffff_d000_0000_0000);
+/// Window kernel stacks (threads, exception stacks) are placed in
+const STACK_WINDOW: (u64, u64) = (0x_ffff_e000_0000_0000, 0x_ffff_f000_0000_0000);
+/// Randomized regions start on a 2 MiB boundary
+const REGION_ALIGN: u64 = 2 * 1024 * 1024;
+/// Size reserved for kernel stacks
+pub const STACK_REGION_SIZE: u64 = 1024 * 1024 * 1024;
+
+static LAYOUT: Once<KernelLayout> = Once::new();
+
+/// Where everything ended up this boot
+#[derive(Debug, Clone, Copy)]
+pub struct KernelLayout {
+    pub seed: u64,
+    pub source: EntropySource,
+    pub kernel_base: VirtAddr,
+    pub phys_mem_offset: VirtAddr,
+    pub boot_stack: VirtAddr,
+    pub heap_start: VirtAddr,
+    pub stack_region: VirtAddr,
+    /// Kernel, physmap and boot stack were randomized by the bootloader
+    pub bootloader_aslr: bool,
+}
+
+/// Choose the kernel layout for this boot and print it
+///
+/// Must run after `memory::init()` and `boot::init()` (so we can check the
+/// chosen ranges are unused) and before `allocator::init_heap()`.
+pub fn init() -> &'static KernelLayout {
+    LAYOUT.call_once(|| {
+        let (seed, source) = match fixed_seed() {
+            Some(seed) => (seed, EntropySource::Fixed),
+            None => random::hardware_seed(),
+        };
+        let mut rng = Rng::new(seed);
+
+        let rsp: u64;
+        unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+
+        let boot_info = boot::info();
+        let layout = KernelLayout {
+            seed,
+            source,
+            kernel_base: boot_info.kernel_image_offset,
+            phys_mem_offset: boot_info.phys_mem_offset,
+            boot_stack: VirtAddr::new(rsp),
+            heap_start: pick_region(&mut rng, HEAP_WINDOW, HEAP_SIZE as u64),
+            stack_region: pick_region(&mut rng, STACK_WINDOW, STACK_REGION_SIZE),
+            bootloader_aslr: boot_info.bootloader_aslr,
+        };
+        serial_println!("{}", layout);
+        layout
+    })
+}
+
+/// The layout chosen by `init()`
+pub fn layout() -> &'static KernelLayout {
+    LAYOUT.r#try().expect("aslr: layout not initialized")
+}
+
+/// Seed fixed at build time through the `FOX_ASLR_SEED` environment variable
+fn fixed_seed() -> Option<u64> {
+    let seed = option_env!("FOX_ASLR_SEED")?;
+    let parsed = match seed.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => seed.parse(),
+    };
+    Some(parsed.expect("aslr: FOX_ASLR_SEED is not a number"))
+}
+
+/// Pick a random, currently unmapped, `size` byte range within `window`
+fn pick_region(rng: &mut Rng, window: (u64, u64), size: u64) -> VirtAddr {
+    let slots = (window.1 - window.0 - size) / REGION_ALIGN;
+    loop {
+        let start = VirtAddr::new(window.0 + rng.below(slots) * REGION_ALIGN);
+        let end = start + (size - 1);
+        let unused = memory::with_memory(|mm| {
+            mm.mapper.translate_addr(start).is_none() && mm.mapper.translate_addr(end).is_none()
+        });
+        if unused {
+            return start;
+        }
+    }
+}
+
+impl fmt::Display for KernelLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let placed = match self.bootloader_aslr {
+            true => "(bootloader)",
+            false => "(fixed)",
+        };
+        writeln!(f, "----- kernel layout -----")?;
+        writeln!(f, "seed:       {:#018x} ({:?})", self.seed, self.source)?;
+        writeln!(
+            f,
+            "kernel:     {:#018x} {}",
+            self.kernel_base.as_u64(),
+            placed
+        )?;
+        writeln!(
+            f,
+            "physmap:    {:#018x} {}",
+            self.phys_mem_offset.as_u64(),
+            placed
+        )?;
+        writeln!(
+            f,
+            "boot stack: {:#018x} {}",
+            self.boot_stack.as_u64(),
+            placed
+        )?;
+        writeln!(f, "heap:       {:#018x}", self.heap_start.as_u64())?;
+        write!(f, "stacks:     {:#018x}", self.stack_region.as_u64())?;
+        if self.source != EntropySource::Fixed {
+            write!(f, "\nreproduce with FOX_ASLR_SEED={:#x}", self.seed)?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_layout_in_windows() {
+    let layout = layout();
+    let heap = layout.heap_start.as_u64();
+    assert!(heap >= HEAP_WINDOW.0 && heap + HEAP_SIZE as u64 <= HEAP_WINDOW.1);
+    assert!(heap.is_multiple_of(REGION_ALIGN));
+    let stacks = layout.stack_region.as_u64();
+    assert!(stacks >= STACK_WINDOW.0 && stacks + STACK_REGION_SIZE <= STACK_WINDOW.1);
+}
@@ -0,0 +1,296 @@
+use crate::drivers::display::framebuffer::{FrameBufferInfo, PixelFormat};
+use bootloader_api::info::{self, MemoryRegionKind, Optional};
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+#[cfg(feature = "multiboot2")]
+pub mod multiboot2;
+
+// The kernel can be started by two kinds of loaders: `bootloader` (BIOS/UEFI disk
+// images, see `boot/`) and, with the `multiboot2` feature, a Multiboot2 loader such
+// as GRUB. Each entry point translates what its loader hands over into the
+// `BootInfo` below, so nothing after `init()` needs to care how we were booted.
+//
+// `init()` keeps a copy around for subsystems that need it later (ACPI tables, the
+// initial ramdisk...).
+static INFO: Once<BootInfo> = Once::new();
+
+/// Maximum number of physical memory regions we keep track of
+pub const MAX_MEMORY_REGIONS: usize = 256;
+/// Maximum number of boot modules
+pub const MAX_MODULES: usize = 8;
+
+/// Who loaded us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loader {
+    /// `bootloader` 0.11, BIOS or UEFI
+    Bootloader,
+    Multiboot2,
+}
+
+/// Loader independent boot information, consumed by `project_fox::init()`
+#[derive(Debug)]
+pub struct BootInfo {
+    pub loader: Loader,
+    pub memory_map: MemoryMap,
+    /// All of physical memory is mapped at this offset
+    pub phys_mem_offset: VirtAddr,
+    /// Taken by `init()` for the console
+    pub display: Option<Display>,
+    /// Physical address and size of the kernel ELF file (or loaded image)
+    pub kernel_addr: PhysAddr,
+    pub kernel_len: u64,
+    /// Virtual address the kernel image was loaded at
+    pub kernel_image_offset: VirtAddr,
+    /// ACPI root system description pointer, if the firmware provided one
+    pub rsdp_addr: Option<PhysAddr>,
+    pub cmdline: Option<&'static str>,
+    pub modules: [Option<Module>; MAX_MODULES],
+    pub ramdisk: Option<Ramdisk>,
+    /// Whether the loader randomized the kernel, physmap and boot stack placement
+    pub bootloader_aslr: bool,
+}
+
+/// What the console can draw into
+#[derive(Debug)]
+pub enum Display {
+    FrameBuffer {
+        buffer: &'static mut [u8],
+        info: FrameBufferInfo,
+    },
+    /// A VGA text mode buffer
+    Text(VirtAddr),
+}
+
+/// A physical memory region, `[start, end)` in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub end: u64,
+    pub kind: MemoryKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free for the kernel to use
+    Usable,
+    /// Firmware, MMIO, or in use by the kernel image, the loader or boot modules
+    Reserved,
+}
+
+/// The physical memory map
+#[derive(Debug, Clone)]
+pub struct MemoryMap {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    len: usize,
+}
+
+/// A file loaded by the boot loader alongside the kernel
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    pub start: VirtAddr,
+    pub len: u64,
+    /// The module's command line (usually its name)
+    pub name: &'static str,
+}
+
+/// The initial ramdisk as loaded by the bootloader
+#[derive(Debug, Clone, Copy)]
+pub struct Ramdisk {
+    pub start: VirtAddr,
+    pub len: u64,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        const EMPTY: MemoryRegion = MemoryRegion {
+            start: 0,
+            end: 0,
+            kind: MemoryKind::Reserved,
+        };
+        MemoryMap {
+            regions: [EMPTY; MAX_MEMORY_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Append a region, merging it into the previous one if they are adjacent and of the same kind
+    ///
+    /// Panics if the map is full.
+    pub fn push(&mut self, region: MemoryRegion) {
+        if region.start >= region.end {
+            return;
+        }
+        if let Some(last) = self.regions[..self.len].last_mut() {
+            if last.end == region.start && last.kind == region.kind {
+                last.end = region.end;
+                return;
+            }
+        }
+        assert!(
+            self.len < MAX_MEMORY_REGIONS,
+            "boot: too many memory regions"
+        );
+        self.regions[self.len] = region;
+        self.len += 1;
+    }
+
+    /// Take `[start, end)` out of the usable memory
+    ///
+    /// Loaders that only describe the firmware's view of memory (Multiboot2) still
+    /// report the memory holding the kernel, the boot information and modules as usable.
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        let mut i = 0;
+        while i < self.len {
+            let region = self.regions[i];
+            if region.kind != MemoryKind::Usable || region.end <= start || end <= region.start {
+                i += 1;
+                continue;
+            }
+            // Replace the region by what is left of it on either side of the hole
+            self.len -= 1;
+            self.regions[i] = self.regions[self.len];
+            self.push(MemoryRegion {
+                end: start.max(region.start),
+                ..region
+            });
+            self.push(MemoryRegion {
+                start: end.min(region.end),
+                ..region
+            });
+        }
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<&'static mut bootloader_api::BootInfo> for BootInfo {
+    fn from(boot_info: &'static mut bootloader_api::BootInfo) -> Self {
+        let mut memory_map = MemoryMap::new();
+        for region in boot_info.memory_regions.iter() {
+            let kind = match region.kind {
+                MemoryRegionKind::Usable => MemoryKind::Usable,
+                _ => MemoryKind::Reserved,
+            };
+            memory_map.push(MemoryRegion {
+                start: region.start,
+                end: region.end,
+                kind,
+            });
+        }
+
+        let framebuffer = core::mem::replace(&mut boot_info.framebuffer, Optional::None);
+        let display = framebuffer.into_option().and_then(|fb| {
+            let info = framebuffer_info(fb.info())?;
+            Some(Display::FrameBuffer {
+                buffer: fb.into_buffer(),
+                info,
+            })
+        });
+
+        BootInfo {
+            loader: Loader::Bootloader,
+            memory_map,
+            phys_mem_offset: VirtAddr::new(
+                boot_info
+                    .physical_memory_offset
+                    .into_option()
+                    .expect("boot: physical memory is not mapped"),
+            ),
+            display,
+            kernel_addr: PhysAddr::new(boot_info.kernel_addr),
+            kernel_len: boot_info.kernel_len,
+            kernel_image_offset: VirtAddr::new(boot_info.kernel_image_offset),
+            rsdp_addr: boot_info.rsdp_addr.into_option().map(PhysAddr::new),
+            cmdline: None,
+            modules: [None; MAX_MODULES],
+            ramdisk: boot_info.ramdisk_addr.into_option().map(|addr| Ramdisk {
+                start: VirtAddr::new(addr),
+                len: boot_info.ramdisk_len,
+            }),
+            bootloader_aslr: crate::BOOTLOADER_CONFIG.mappings.aslr,
+        }
+    }
+}
+
+/// Translate the bootloader's framebuffer description, `None` if we can't draw into it
+fn framebuffer_info(info: info::FrameBufferInfo) -> Option<FrameBufferInfo> {
+    let pixel_format = match info.pixel_format {
+        info::PixelFormat::Rgb => PixelFormat::Rgb,
+        info::PixelFormat::Bgr => PixelFormat::Bgr,
+        info::PixelFormat::U8 => PixelFormat::U8,
+        _ => return None,
+    };
+    Some(FrameBufferInfo {
+        width: info.width,
+        height: info.height,
+        stride: info.stride,
+        bytes_per_pixel: info.bytes_per_pixel,
+        pixel_format,
+    })
+}
+
+/// Keep the boot information around after boot
+///
+/// The display has been handed to the console by now and is not kept.
+pub fn init(mut boot_info: BootInfo) -> &'static BootInfo {
+    boot_info.display = None;
+    INFO.call_once(|| boot_info)
+}
+
+/// The boot information saved by `init()`
+pub fn info() -> &'static BootInfo {
+    INFO.r#try().expect("boot: not initialized")
+}
+
+/// Contents of the initial ramdisk, if one was loaded
+pub fn ramdisk() -> Option<&'static [u8]> {
+    let ramdisk = info().ramdisk?;
+    // The loader maps the ramdisk for us and never reuses that memory
+    Some(unsafe { core::slice::from_raw_parts(ramdisk.start.as_ptr(), ramdisk.len as usize) })
+}
+
+#[test_case]
+fn test_memory_map_reserve() {
+    let mut map = MemoryMap::new();
+    let usable = |start, end| MemoryRegion {
+        start,
+        end,
+        kind: MemoryKind::Usable,
+    };
+    map.push(usable(0x1000, 0x9000));
+    map.push(MemoryRegion {
+        start: 0x9000,
+        end: 0xa000,
+        kind: MemoryKind::Reserved,
+    });
+    map.push(usable(0x10_0000, 0x20_0000));
+
+    // Splits the first region, trims the start of the last one
+    map.reserve(0x2000, 0x3000);
+    map.reserve(0xf_0000, 0x10_8000);
+
+    let mut usable_ranges = [(0, 0); 4];
+    let mut n = 0;
+    for r in map
+        .regions()
+        .iter()
+        .filter(|r| r.kind == MemoryKind::Usable)
+    {
+        usable_ranges[n] = (r.start, r.end);
+        n += 1;
+    }
+    usable_ranges[..n].sort_unstable();
+    assert_eq!(
+        usable_ranges[..n],
+        [(0x1000, 0x2000), (0x3000, 0x9000), (0x10_8000, 0x20_0000)]
+    );
+}
@@ -0,0 +1,304 @@
+use crate::drivers::display::framebuffer::{FrameBufferInfo, PixelFormat};
+use crate::kernel::boot::{
+    BootInfo, Display, Loader, MemoryKind, MemoryMap, MemoryRegion, Module, Ramdisk, MAX_MODULES,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Multiboot2 boot support, see https://www.gnu.org/software/grub/manual/multiboot2/
+//
+// A Multiboot2 loader (e.g. GRUB's `multiboot2` command) loads our ELF segments at
+// their physical link addresses (see `multiboot2.ld`, used with the `multiboot2`
+// feature) and jumps to the entry address from our header in 32-bit protected mode,
+// without paging. The entry code below identity maps the first 4 GiB with 2 MiB
+// pages, switches to long mode and calls `multiboot2_main` (defined by
+// `entry_point!`), which turns the Multiboot2 information structure into a `BootInfo`.
+//
+// With physical memory identity mapped the physmap offset is 0. Memory above 4 GiB
+// is not mapped and left unused.
+//
+// The header itself is emitted by `entry_point!` into the kernel binary. It refers
+// to `_start_multiboot2`, which makes the linker pull in the code below.
+
+/// Value in `eax` when a Multiboot2 loader jumps to us
+const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+/// Physical memory identity mapped by the entry code
+const IDENTITY_MAP_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+/// Boot stack, same size as the one `BOOTLOADER_CONFIG` asks for
+const BOOT_STACK_SIZE: usize = 128 * 1024;
+/// BIOS data, VGA memory and option ROMs live below 1 MiB
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+// Information structure tag types
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+const TAG_ACPI_OLD: u32 = 14;
+const TAG_ACPI_NEW: u32 = 15;
+
+/// Memory map entry type for available RAM
+const MEMORY_AVAILABLE: u32 = 1;
+
+// Framebuffer types
+const FRAMEBUFFER_RGB: u8 = 1;
+const FRAMEBUFFER_EGA_TEXT: u8 = 2;
+
+core::arch::global_asm!(
+    r#"
+.section .text.multiboot2, "ax"
+.code32
+.global _start_multiboot2
+_start_multiboot2:
+    cli
+    cld
+    mov esp, offset multiboot2_stack_top
+    // Keep the loader's magic value and information structure address for multiboot2_main
+    mov edi, eax
+    mov esi, ebx
+
+    // PML4[0] -> PDPT
+    mov eax, offset multiboot2_pdpt
+    or eax, 0x3
+    mov dword ptr [multiboot2_pml4], eax
+    // PDPT[0..4] -> PD[0..4]
+    xor ecx, ecx
+2:
+    mov eax, ecx
+    shl eax, 12
+    add eax, offset multiboot2_pd
+    or eax, 0x3
+    mov dword ptr [multiboot2_pdpt + ecx * 8], eax
+    inc ecx
+    cmp ecx, 4
+    jne 2b
+    // PD[0..2048] -> 2 MiB pages, identity mapping the first 4 GiB
+    xor ecx, ecx
+3:
+    mov eax, ecx
+    shl eax, 21
+    or eax, 0x83
+    mov dword ptr [multiboot2_pd + ecx * 8], eax
+    inc ecx
+    cmp ecx, 2048
+    jne 3b
+
+    // CR4.PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, offset multiboot2_pml4
+    mov cr3, eax
+    // EFER.LME and EFER.NXE
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    // CR0.PG and CR0.WP
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16)
+    mov cr0, eax
+
+    // Far return into the 64-bit code segment
+    lgdt [multiboot2_gdt_ptr]
+    push 0x08
+    push offset multiboot2_long_mode
+    retf
+
+.code64
+multiboot2_long_mode:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+    // The upper halves are undefined after the mode switch
+    mov edi, edi
+    mov esi, esi
+    call multiboot2_main
+4:
+    hlt
+    jmp 4b
+
+.section .rodata.multiboot2, "a"
+.balign 8
+multiboot2_gdt:
+    .quad 0
+    // 64-bit ring 0 code segment
+    .quad 0x00af9a000000ffff
+multiboot2_gdt_ptr:
+    .short multiboot2_gdt_ptr - multiboot2_gdt - 1
+    .long multiboot2_gdt
+
+.section .bss.multiboot2, "aw", @nobits
+.balign 4096
+multiboot2_pml4:
+    .skip 4096
+multiboot2_pdpt:
+    .skip 4096
+multiboot2_pd:
+    .skip 4096 * 4
+multiboot2_stack:
+    .skip {stack_size}
+multiboot2_stack_top:
+"#,
+    stack_size = const BOOT_STACK_SIZE,
+);
+
+extern "C" {
+    // Defined by `multiboot2.ld`
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+/// Translate the Multiboot2 information structure at physical address `info_addr`
+///
+/// # Safety
+///
+/// Must only be called from the Multiboot2 entry, with the values the loader
+/// passed in `eax` and `ebx`.
+pub unsafe fn boot_info(magic: u32, info_addr: u32) -> BootInfo {
+    assert_eq!(magic, BOOTLOADER_MAGIC, "multiboot2: bad magic value");
+
+    let info_start = info_addr as u64;
+    let total_size = *(info_start as *const u32) as u64;
+    let kernel_start = core::ptr::addr_of!(__kernel_start) as u64;
+    let kernel_end = core::ptr::addr_of!(__kernel_end) as u64;
+
+    let mut boot_info = BootInfo {
+        loader: Loader::Multiboot2,
+        memory_map: MemoryMap::new(),
+        phys_mem_offset: VirtAddr::new(0),
+        display: None,
+        kernel_addr: PhysAddr::new(kernel_start),
+        kernel_len: kernel_end - kernel_start,
+        kernel_image_offset: VirtAddr::new(kernel_start),
+        rsdp_addr: None,
+        cmdline: None,
+        modules: [None; MAX_MODULES],
+        ramdisk: None,
+        bootloader_aslr: false,
+    };
+
+    // Tags follow the 8 byte fixed part, each starts on an 8 byte boundary
+    let mut tag = info_start + 8;
+    while tag < info_start + total_size {
+        let tag_type = *(tag as *const u32);
+        let tag_size = *((tag + 4) as *const u32) as u64;
+        match tag_type {
+            TAG_END => break,
+            TAG_CMDLINE => boot_info.cmdline = c_str(tag + 8),
+            TAG_MODULE => add_module(&mut boot_info, tag),
+            TAG_MEMORY_MAP => add_memory_map(&mut boot_info.memory_map, tag, tag_size),
+            TAG_FRAMEBUFFER => boot_info.display = display(tag),
+            // The tags hold a copy of the RSDP, prefer the ACPI 2.0+ one
+            TAG_ACPI_OLD if boot_info.rsdp_addr.is_none() => {
+                boot_info.rsdp_addr = Some(PhysAddr::new(tag + 8))
+            }
+            TAG_ACPI_NEW => boot_info.rsdp_addr = Some(PhysAddr::new(tag + 8)),
+            _ => {}
+        }
+        tag = x86_64::align_up(tag + tag_size, 8);
+    }
+
+    // The loader's memory map still lists all of this as available
+    let map = &mut boot_info.memory_map;
+    map.reserve(0, LOW_MEMORY_END);
+    map.reserve(kernel_start, kernel_end);
+    map.reserve(info_start, info_start + total_size);
+    for module in boot_info.modules.iter().flatten() {
+        map.reserve(module.start.as_u64(), module.start.as_u64() + module.len);
+    }
+    boot_info.ramdisk = boot_info.modules[0].map(|module| Ramdisk {
+        start: module.start,
+        len: module.len,
+    });
+    boot_info
+}
+
+unsafe fn add_module(boot_info: &mut BootInfo, tag: u64) {
+    let start = *((tag + 8) as *const u32) as u64;
+    let end = *((tag + 12) as *const u32) as u64;
+    let slot = boot_info.modules.iter().position(|m| m.is_none());
+    let Some(slot) = slot else {
+        crate::serial_println!(
+            "multiboot2: more than {} modules, ignoring the rest",
+            MAX_MODULES
+        );
+        return;
+    };
+    boot_info.modules[slot] = Some(Module {
+        start: VirtAddr::new(start),
+        len: end - start,
+        name: c_str(tag + 16).unwrap_or(""),
+    });
+}
+
+unsafe fn add_memory_map(map: &mut MemoryMap, tag: u64, tag_size: u64) {
+    let entry_size = *((tag + 8) as *const u32) as u64;
+    let mut entry = tag + 16;
+    while entry + entry_size <= tag + tag_size {
+        let base = *(entry as *const u64);
+        let len = *((entry + 8) as *const u64);
+        let kind = match *((entry + 16) as *const u32) {
+            MEMORY_AVAILABLE => MemoryKind::Usable,
+            _ => MemoryKind::Reserved,
+        };
+        // We can only reach memory that the entry code mapped
+        map.push(MemoryRegion {
+            start: base,
+            end: (base + len).min(IDENTITY_MAP_SIZE),
+            kind,
+        });
+        entry += entry_size;
+    }
+}
+
+unsafe fn display(tag: u64) -> Option<Display> {
+    let addr = *((tag + 8) as *const u64);
+    let pitch = *((tag + 16) as *const u32) as usize;
+    let width = *((tag + 20) as *const u32) as usize;
+    let height = *((tag + 24) as *const u32) as usize;
+    let bpp = *((tag + 28) as *const u8) as usize;
+    let fb_type = *((tag + 29) as *const u8);
+
+    match fb_type {
+        FRAMEBUFFER_EGA_TEXT => Some(Display::Text(VirtAddr::new(addr))),
+        FRAMEBUFFER_RGB if bpp == 24 || bpp == 32 => {
+            // Colour info: red position, red mask size, green.., blue..
+            let red_position = *((tag + 32) as *const u8);
+            let pixel_format = match red_position {
+                0 => PixelFormat::Rgb,
+                16 => PixelFormat::Bgr,
+                _ => return None,
+            };
+            let bytes_per_pixel = bpp / 8;
+            let byte_len = pitch * height;
+            if addr + byte_len as u64 > IDENTITY_MAP_SIZE {
+                return None;
+            }
+            Some(Display::FrameBuffer {
+                buffer: core::slice::from_raw_parts_mut(addr as *mut u8, byte_len),
+                info: FrameBufferInfo {
+                    width,
+                    height,
+                    stride: pitch / bytes_per_pixel,
+                    bytes_per_pixel,
+                    pixel_format,
+                },
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The NUL terminated string at physical address `addr`
+unsafe fn c_str(addr: u64) -> Option<&'static str> {
+    let start = addr as *const u8;
+    let mut len = 0;
+    while *start.add(len) != 0 {
+        len += 1;
+    }
+    core::str::from_utf8(core::slice::from_raw_parts(start, len)).ok()
+}
@@ -0,0 +1,45 @@
+use crate::kernel::{apic, percpu};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+// The CPUs we run on, numbered from 0 (the boot CPU) in the order `smp::init()`
+// started them. Each CPU finds its own index in its per-CPU block (`percpu`).
+// The hardware knows them by their local APIC ID instead, which need not be
+// contiguous.
+
+/// Maximum number of CPUs the kernel keeps per-CPU state for
+pub const MAX_CPUS: usize = 16;
+/// `APIC_IDS` entry of a CPU that isn't online
+const NO_APIC_ID: u32 = u32::MAX;
+
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(NO_APIC_ID) }; MAX_CPUS];
+// The boot CPU is always running
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Index of the CPU we're running on, below `MAX_CPUS`
+#[inline]
+pub fn id() -> usize {
+    percpu::cpu_id()
+}
+
+/// Number of CPUs running the kernel
+pub fn count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Local APIC ID of CPU `cpu`, `None` if it isn't online
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    match APIC_IDS.get(cpu)?.load(Ordering::Acquire) {
+        NO_APIC_ID => None,
+        apic_id => Some(apic_id),
+    }
+}
+
+/// Report the running CPU as online, with the APIC ID it reads itself
+pub(crate) fn set_online() {
+    let cpu = id();
+    let apic_id = apic::id().expect("cpu: local APIC not enabled");
+    APIC_IDS[cpu].store(apic_id as u32, Ordering::Release);
+    if cpu != 0 {
+        ONLINE.fetch_add(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::kernel::interrupts::{self, InterruptIndex, IrqHandler, IrqHandlerId};
+use crate::kernel::ipc::{Endpoint, Message};
+use crate::kernel::process::fd::File;
+use crate::kernel::sync::IrqSafeSpinLock;
+use crate::kernel::workqueue::{self, Work};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const LINES_COUNT: usize = 16;
+
+struct Line {
+    /// Set by the line's interrupts, cleared by `FORWARD` sending them on
+    pending: AtomicBool,
+    state: IrqSafeSpinLock<LineState>,
+}
+
+struct LineState {
+    /// Whether an `IrqLine` holds it
+    claimed: bool,
+    /// Where its interrupts go, see `IrqLine::bind()`
+    endpoint: Option<Arc<Endpoint>>,
+    /// `HANDLERS`' for the line, registered while it's bound
+    forwarding: Option<IrqHandlerId>,
+    /// The kernel's handlers, set aside while it's claimed
+    kernel_handlers: Vec<IrqHandler>,
+}
+
+static LINES: [Line; LINES_COUNT] = [const {
+    Line {
+        pending: AtomicBool::new(false),
+        state: IrqSafeSpinLock::new(
+            "irq line",
+            LineState {
+                claimed: false,
+                endpoint: None,
+                forwarding: None,
+                kernel_handlers: Vec::new(),
+            },
+        ),
+    }
+}; LINES_COUNT];
+
+// Sends the pending interrupts on, channels can't be used from interrupt handlers
+static FORWARD: Work = Work::new("irq forward", forward);
+
+/// The interrupt handler of bound line `IRQ`
+fn interrupt<const IRQ: u8>() {
+    LINES[IRQ as usize].pending.store(true, Ordering::Release);
+    workqueue::schedule_work(&FORWARD);
+}
+
+const HANDLERS: [IrqHandler; LINES_COUNT] = [
+    interrupt::<0>,
+    interrupt::<1>,
+    interrupt::<2>,
+    interrupt::<3>,
+    interrupt::<4>,
+    interrupt::<5>,
+    interrupt::<6>,
+    interrupt::<7>,
+    interrupt::<8>,
+    interrupt::<9>,
+    interrupt::<10>,
+    interrupt::<11>,
+    interrupt::<12>,
+    interrupt::<13>,
+    interrupt::<14>,
+    interrupt::<15>,
+];
+
+fn forward() {
+    for (irq, line) in LINES.iter().enumerate() {
+        if !line.pending.swap(false, Ordering::Acquire) {
+            continue;
+        }
+        let endpoint = line.state.lock().endpoint.clone();
+        if let Some(endpoint) = endpoint {
+            // A full queue means the driver hasn't got to the last ones yet,
+            // this one comes with them
+            endpoint
+                .try_send(Message {
+                    data: alloc::vec![irq as u8],
+                    handles: Vec::new(),
+                })
+                .ok();
+        }
+    }
+}
+
+/// An interrupt line a driver holds, see `device`
+pub struct IrqLine {
+    irq: u8,
+}
+
+impl IrqLine {
+    /// Take IRQ line `irq` from the kernel
+    ///
+    /// `None` if something else holds it already, or it's the timer's, the
+    /// cascade (2) or not a line.
+    pub fn claim(irq: u8) -> Option<Self> {
+        if irq as usize >= LINES_COUNT || irq == 2 || irq == InterruptIndex::Timer.irq() {
+            return None;
+        }
+        let mut state = LINES[irq as usize].state.lock();
+        if state.claimed {
+            return None;
+        }
+        state.claimed = true;
+        state.kernel_handlers = interrupts::take_irq_handlers(irq);
+        Some(IrqLine { irq })
+    }
+
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// Send the line's interrupts through `endpoint` from now on, instead of
+    /// where they went before
+    pub fn bind(&self, endpoint: Arc<Endpoint>) {
+        let previous = {
+            let mut state = LINES[self.irq as usize].state.lock();
+            if state.forwarding.is_none() {
+                state.forwarding = Some(interrupts::register_irq_handler(
+                    self.irq,
+                    HANDLERS[self.irq as usize],
+                ));
+            }
+            state.endpoint.replace(endpoint)
+        };
+        // Outside the lock, it may be the last reference to the endpoint
+        drop(previous);
+    }
+}
+
+impl Drop for IrqLine {
+    fn drop(&mut self) {
+        let endpoint = {
+            let mut state = LINES[self.irq as usize].state.lock();
+            if let Some(id) = state.forwarding.take() {
+                interrupts::unregister_irq_handler(id);
+            }
+            for handler in core::mem::take(&mut state.kernel_handlers) {
+                interrupts::register_irq_handler(self.irq, handler);
+            }
+            state.claimed = false;
+            state.endpoint.take()
+        };
+        drop(endpoint);
+    }
+}
+
+/// Neither read nor written, only bound to a channel
+impl File for IrqLine {}
+
+#[test_case]
+fn test_irq_line() {
+    assert!(IrqLine::claim(2).is_none());
+    assert!(IrqLine::claim(InterruptIndex::Timer.irq()).is_none());
+    assert!(IrqLine::claim(16).is_none());
+
+    // No device raises IRQ 5 in QEMU, the test raises it by hand
+    let line = IrqLine::claim(5).expect("claiming IRQ 5 failed");
+    assert!(IrqLine::claim(5).is_none());
+    let (driver, kernel) = Endpoint::pair();
+    line.bind(Arc::new(kernel));
+    HANDLERS[5]();
+    workqueue::flush_system();
+    let message = driver.recv(1, 0).unwrap();
+    assert_eq!(message.data, [5]);
+
+    drop(line);
+    assert!(IrqLine::claim(5).is_some());
+}
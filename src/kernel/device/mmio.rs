@@ -0,0 +1,83 @@
+use crate::kernel::address_space::AddressSpace;
+use crate::kernel::process::fd::File;
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Device memory, see `device`
+///
+/// Its frames never came from the frame allocator and never go to it: the
+/// mappings count as their owners besides an owner nobody releases (see
+/// `MemoryManager::share_frame()`).
+pub struct MmioRegion {
+    frames: Vec<PhysFrame>,
+}
+
+impl MmioRegion {
+    /// The `size` bytes of device memory at `start`, rounded up to whole pages
+    ///
+    /// # Safety
+    ///
+    /// `start` must be page aligned, and the pages device memory that the
+    /// kernel doesn't use, or only as it would anyway with a driver using
+    /// them too.
+    pub unsafe fn new(start: PhysAddr, size: usize) -> Self {
+        assert!(
+            start.is_aligned(PAGE_SIZE as u64) && size > 0,
+            "device: MMIO regions are whole pages"
+        );
+        let first = PhysFrame::containing_address(start);
+        MmioRegion {
+            frames: (0..size.div_ceil(PAGE_SIZE))
+                .map(|i| first + i as u64)
+                .collect(),
+        }
+    }
+
+    /// Size in bytes, a whole number of pages
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    /// Map the whole region at `start` in `address_space`, uncached, with `flags`
+    ///
+    /// Nothing stays mapped if it fails.
+    pub fn map(
+        &self,
+        address_space: &AddressSpace,
+        start: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        address_space.map_shared_frames(start, &self.frames, flags)
+    }
+}
+
+/// Can't be read or written, only mapped
+impl File for MmioRegion {}
+
+#[test_case]
+fn test_mmio_region() {
+    use crate::kernel::memory;
+
+    // The HPET's registers in QEMU, only mapped here, never touched
+    let region = unsafe { MmioRegion::new(PhysAddr::new(0xfed0_0000), PAGE_SIZE + 1) };
+    assert_eq!(region.size(), 2 * PAGE_SIZE);
+
+    let address_space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(memory::USER_START + 0x10_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    region.map(&address_space, start, flags).unwrap();
+    let (addr, flags) = address_space.translate(start + 0x1000u64).unwrap();
+    assert_eq!(addr, PhysAddr::new(0xfed0_1000));
+    assert!(flags.contains(PageTableFlags::NO_CACHE));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+    // Unmapping leaves the frame alone, it was never the allocator's
+    let frame = region.frames[1];
+    drop(address_space);
+    assert_eq!(memory::with_memory(|mm| mm.frame_owners(frame)), 1);
+}
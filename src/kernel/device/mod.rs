@@ -0,0 +1,27 @@
+// Device access for drivers in user space.
+//
+// A driver is a process holding handles (see `process::fd`) to the hardware
+// it drives, usually given to it by the kernel when it starts it
+// (`process::spawn_with_handles()`). It can pass them on like any other.
+//
+// - `IoPorts` is a range of I/O ports, which `ioport_enable` lets the
+//   process's code use with `in` and `out`. Each CPU's TSS has an I/O
+//   permission bitmap (see `gdt`) that denies user code every port. The
+//   scheduler resets it on every switch, and an `in` or `out` that faults has
+//   `load_io_ports()` allow the ports of the running process, then runs again.
+//   Only the first one after a switch pays for it.
+// - `MmioRegion` is device memory, which `mmio_map` maps uncached.
+// - `IrqLine` is an interrupt line. The kernel's own handlers for it are set
+//   aside while a driver holds it, and `irq_bind` has its interrupts sent as
+//   messages through a channel (see `ipc`), one byte each: the line's
+//   number. The PICs' lines are edge triggered, so nothing needs masking
+//   until the driver has dealt with the device. Interrupts closer together
+//   than the driver takes the messages may come as one.
+
+mod irq;
+mod mmio;
+mod ports;
+
+pub use irq::IrqLine;
+pub use mmio::MmioRegion;
+pub use ports::{load_io_ports, IoPorts};
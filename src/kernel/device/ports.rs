@@ -0,0 +1,67 @@
+use crate::kernel::gdt;
+use crate::kernel::process::fd::File;
+use crate::kernel::process::{self, Process};
+use crate::percpu;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Bumped whenever a process is allowed more ports
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+percpu! {
+    // `GENERATION` when this CPU's I/O bitmap was last loaded
+    static LOADED: AtomicU64 = AtomicU64::new(0);
+}
+
+/// A range of I/O ports, see `device`
+pub struct IoPorts {
+    ports: RangeInclusive<u16>,
+}
+
+impl IoPorts {
+    /// The `count` ports from `first`, `None` if that's none or goes past the
+    /// last port
+    pub fn new(first: u16, count: u16) -> Option<Self> {
+        let last = first.checked_add(count.checked_sub(1)?)?;
+        Some(IoPorts {
+            ports: first..=last,
+        })
+    }
+
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.ports.clone()
+    }
+
+    /// Let `process`'s code use the ports
+    ///
+    /// Its threads running on other CPUs get them on their next `in` or `out`.
+    pub fn enable(&self, process: &Process) {
+        process.allow_io_ports(self.ports());
+        GENERATION.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Neither read nor written, `ioport_enable` needs both rights
+impl File for IoPorts {}
+
+/// Allow user code on this CPU the I/O ports its process may use, for the
+/// general protection fault handler
+///
+/// Returns false if that changes nothing, the fault is the code's own.
+pub fn load_io_ports() -> bool {
+    let generation = GENERATION.load(Ordering::Acquire);
+    let loaded = LOADED.get();
+    if gdt::io_ports_allowed() && loaded.load(Ordering::Relaxed) == generation {
+        return false;
+    }
+    let Some(process) = process::current() else {
+        return false;
+    };
+    loaded.store(generation, Ordering::Relaxed);
+    process.with_io_ports(|ports| {
+        for ports in ports {
+            gdt::allow_io_ports(ports.clone());
+        }
+        !ports.is_empty()
+    })
+}
@@ -0,0 +1,445 @@
+use crate::kernel::address_space::AddressSpace;
+use crate::kernel::memory::{USER_END, USER_START};
+use crate::kernel::usermode::{self, UserExit};
+use crate::kernel::{random, thread};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// Loading ELF64 executables into user address spaces.
+//
+// Only statically linked x86-64 executables (`ET_EXEC`) are supported, there
+// is no dynamic linker to hand a `PT_INTERP` to. Every `PT_LOAD` segment is
+// mapped into a new `AddressSpace` with the permissions its flags ask for, the
+// file's bytes are copied in and the rest of the segment, its `.bss`, is left
+// zeroed. A `PT_TLS` segment gets the initial thread a TLS block laid out as
+// x86-64's TLS ABI (variant II) wants: the block ends where FS points, and
+// FS:0 holds that address itself.
+//
+// The initial stack is what the System V ABI says `_start` finds at `rsp`:
+//
+//     argc, argv[0..argc], NULL, envp[..], NULL, auxv pairs, AT_NULL
+//
+// with the strings and the bytes `AT_RANDOM` points to above it.
+
+/// Top of the initial stack, a guard page below the end of user space
+pub const STACK_TOP: u64 = USER_END - PAGE_SIZE;
+pub const STACK_SIZE: usize = 64 * 1024;
+/// At most this much of the stack goes to the arguments and environment
+pub const ARGS_MAX: usize = 16 * 1024;
+/// Where the initial thread's TLS block goes
+const TLS_START: u64 = 0x_7f00_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+const PT_TLS: u32 = 7;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// Auxiliary vector entries we pass
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+/// Why an executable couldn't be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// Not a 64-bit, little endian ELF file
+    NotElf,
+    /// An ELF file, but not an x86-64 executable (a shared object, another
+    /// architecture...)
+    NotExecutable,
+    /// Dynamically linked, it names a program interpreter
+    Interpreter,
+    /// Headers or segments outside the file, or segments outside user space
+    Malformed,
+    /// The arguments and environment don't fit on the stack
+    ArgumentsTooLong,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl ProgramHeader {
+    fn parse(data: &[u8]) -> Self {
+        ProgramHeader {
+            kind: u32_at(data, 0),
+            flags: u32_at(data, 4),
+            offset: u64_at(data, 8),
+            vaddr: u64_at(data, 16),
+            filesz: u64_at(data, 32),
+            memsz: u64_at(data, 40),
+            align: u64_at(data, 48),
+        }
+    }
+
+    /// The segment's bytes in `image`
+    fn file_bytes<'a>(&self, image: &'a [u8]) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(self.offset).map_err(|_| ElfError::Malformed)?;
+        let len = usize::try_from(self.filesz).map_err(|_| ElfError::Malformed)?;
+        image
+            .get(start..start.checked_add(len).ok_or(ElfError::Malformed)?)
+            .ok_or(ElfError::Malformed)
+    }
+
+    /// The page flags the segment's permissions ask for
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// A parsed ELF header and its program headers
+struct Elf {
+    entry: u64,
+    phoff: u64,
+    headers: Vec<ProgramHeader>,
+}
+
+impl Elf {
+    fn parse(image: &[u8]) -> Result<Self, ElfError> {
+        let ident = image.get(..EHDR_SIZE).ok_or(ElfError::NotElf)?;
+        if !ident.starts_with(ELF_MAGIC) || ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB {
+            return Err(ElfError::NotElf);
+        }
+        if u16_at(image, 16) != ET_EXEC || u16_at(image, 18) != EM_X86_64 {
+            return Err(ElfError::NotExecutable);
+        }
+        let entry = u64_at(image, 24);
+        let phoff = u64_at(image, 32);
+        let phentsize = u16_at(image, 54) as usize;
+        let phnum = u16_at(image, 56) as usize;
+        if phentsize != PHDR_SIZE {
+            return Err(ElfError::Malformed);
+        }
+        let start = usize::try_from(phoff).map_err(|_| ElfError::Malformed)?;
+        let table = image
+            .get(start..start.saturating_add(phnum * PHDR_SIZE))
+            .ok_or(ElfError::Malformed)?;
+        let headers = table
+            .as_chunks::<PHDR_SIZE>()
+            .0
+            .iter()
+            .map(|header| ProgramHeader::parse(header))
+            .collect();
+        Ok(Elf {
+            entry,
+            phoff,
+            headers,
+        })
+    }
+
+    /// Where the program headers end up in memory, if a segment loads them
+    fn phdr_addr(&self) -> Option<u64> {
+        if let Some(phdr) = self.headers.iter().find(|h| h.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.headers
+            .iter()
+            .find(|h| {
+                h.kind == PT_LOAD && h.offset <= self.phoff && self.phoff - h.offset < h.filesz
+            })
+            .map(|h| h.vaddr + (self.phoff - h.offset))
+    }
+}
+
+/// Whether `[start, start + size)` is within user space
+fn in_user_space(start: u64, size: u64) -> bool {
+    start >= USER_START && start.checked_add(size).is_some_and(|end| end <= USER_END)
+}
+
+/// `a | b`, executable if either is
+fn merge_flags(a: PageTableFlags, b: PageTableFlags) -> PageTableFlags {
+    let executable =
+        !a.contains(PageTableFlags::NO_EXECUTE) || !b.contains(PageTableFlags::NO_EXECUTE);
+    let mut flags = a | b;
+    if executable {
+        flags.remove(PageTableFlags::NO_EXECUTE);
+    }
+    flags
+}
+
+/// An executable loaded into its own address space, ready to run
+pub struct Program {
+    address_space: Arc<AddressSpace>,
+    entry: VirtAddr,
+    stack_pointer: VirtAddr,
+    /// Where FS points for TLS, 0 without a `PT_TLS` segment
+    fs_base: VirtAddr,
+}
+
+impl Program {
+    /// Load the executable `image` into a new address space, with `argv` and
+    /// `envp` on its stack
+    pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Self, ElfError> {
+        let elf = Elf::parse(image)?;
+        if elf.headers.iter().any(|h| h.kind == PT_INTERP) {
+            return Err(ElfError::Interpreter);
+        }
+        if !in_user_space(elf.entry, 1) {
+            return Err(ElfError::Malformed);
+        }
+        let space = AddressSpace::new().map_err(|_| ElfError::OutOfMemory)?;
+
+        for header in elf
+            .headers
+            .iter()
+            .filter(|h| h.kind == PT_LOAD && h.memsz > 0)
+        {
+            load_segment(&space, image, header)?;
+        }
+        let fs_base = match elf.headers.iter().find(|h| h.kind == PT_TLS) {
+            Some(tls) => setup_tls(&space, image, tls)?,
+            None => VirtAddr::zero(),
+        };
+
+        let stack_bottom = VirtAddr::new(STACK_TOP - STACK_SIZE as u64);
+        let flags = PageTableFlags::PRESENT
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::NO_EXECUTE;
+        space
+            .map(stack_bottom, STACK_SIZE, flags)
+            .map_err(|_| ElfError::OutOfMemory)?;
+        let stack_pointer = setup_stack(&space, &elf, argv, envp)?;
+
+        Ok(Program {
+            address_space: Arc::new(space),
+            entry: VirtAddr::new(elf.entry),
+            stack_pointer,
+            fs_base,
+        })
+    }
+
+    pub fn address_space(&self) -> &Arc<AddressSpace> {
+        &self.address_space
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        self.entry
+    }
+
+    /// The initial stack pointer, pointing to `argc`
+    pub fn stack_pointer(&self) -> VirtAddr {
+        self.stack_pointer
+    }
+
+    pub fn fs_base(&self) -> VirtAddr {
+        self.fs_base
+    }
+
+    /// Run the program on the calling thread until it exits or faults
+    ///
+    /// The thread switches to the program's address space for the time being.
+    pub fn run(self) -> UserExit {
+        let previous = thread::set_address_space(Some(self.address_space.clone()));
+        thread::set_fs_base(self.fs_base);
+        let exit = unsafe { usermode::enter(self.entry, self.stack_pointer) };
+        thread::set_fs_base(VirtAddr::zero());
+        thread::set_address_space(previous);
+        exit
+    }
+}
+
+/// Map a `PT_LOAD` segment and copy its bytes in, the rest stays zeroed
+fn load_segment(
+    space: &AddressSpace,
+    image: &[u8],
+    header: &ProgramHeader,
+) -> Result<(), ElfError> {
+    let bytes = header.file_bytes(image)?;
+    if header.filesz > header.memsz || !in_user_space(header.vaddr, header.memsz) {
+        return Err(ElfError::Malformed);
+    }
+    let flags = header.page_flags();
+    let start = VirtAddr::new(header.vaddr).align_down(PAGE_SIZE);
+    let end = VirtAddr::new(header.vaddr + header.memsz).align_up(PAGE_SIZE);
+    // Segments may share a page at their ends, which gets both's permissions
+    for page in (start.as_u64()..end.as_u64()).step_by(PAGE_SIZE as usize) {
+        let page = VirtAddr::new(page);
+        match space.translate(page) {
+            Some((_, existing)) => space
+                .protect(page, PAGE_SIZE as usize, merge_flags(existing, flags))
+                .map_err(|_| ElfError::Malformed)?,
+            None => space
+                .map(page, PAGE_SIZE as usize, flags)
+                .map_err(|_| ElfError::OutOfMemory)?,
+        }
+    }
+    space.write(VirtAddr::new(header.vaddr), bytes);
+    Ok(())
+}
+
+/// Set up the initial thread's TLS block from the `PT_TLS` template, returns
+/// the FS base
+fn setup_tls(
+    space: &AddressSpace,
+    image: &[u8],
+    tls: &ProgramHeader,
+) -> Result<VirtAddr, ElfError> {
+    let template = tls.file_bytes(image)?;
+    let align = tls.align.max(8);
+    if tls.filesz > tls.memsz || !align.is_power_of_two() || align > PAGE_SIZE {
+        return Err(ElfError::Malformed);
+    }
+    // The block ends at the thread pointer, which is followed by the
+    // pointer to itself
+    let size = tls
+        .memsz
+        .checked_next_multiple_of(align)
+        .ok_or(ElfError::Malformed)?;
+    let thread_pointer = TLS_START.checked_add(size).ok_or(ElfError::Malformed)?;
+    if !in_user_space(TLS_START, size + 8) {
+        return Err(ElfError::Malformed);
+    }
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE;
+    space
+        .map(VirtAddr::new(TLS_START), size as usize + 8, flags)
+        .map_err(|_| ElfError::OutOfMemory)?;
+    space.write(VirtAddr::new(TLS_START), template);
+    space.write(VirtAddr::new(thread_pointer), &thread_pointer.to_le_bytes());
+    Ok(VirtAddr::new(thread_pointer))
+}
+
+/// Write `argc`, `argv`, `envp` and the auxiliary vector to the top of the
+/// stack, returns the stack pointer
+fn setup_stack(
+    space: &AddressSpace,
+    elf: &Elf,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    // The strings and random bytes go at the very top
+    let mut strings = Vec::new();
+    let mut offsets = Vec::new();
+    for s in argv.iter().chain(envp) {
+        offsets.push(strings.len());
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let random_offset = strings.len();
+    let (a, _) = random::hardware_seed();
+    let (b, _) = random::hardware_seed();
+    strings.extend_from_slice(&a.to_le_bytes());
+    strings.extend_from_slice(&b.to_le_bytes());
+    if strings.len() > ARGS_MAX / 2 {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    let strings_start = (STACK_TOP - strings.len() as u64) & !15;
+    let string_addr = |offset: usize| strings_start + offset as u64;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(offsets[..argv.len()].iter().map(|&o| string_addr(o)));
+    words.push(0);
+    words.extend(offsets[argv.len()..].iter().map(|&o| string_addr(o)));
+    words.push(0);
+    if let Some(phdr) = elf.phdr_addr() {
+        words.extend([AT_PHDR, phdr]);
+    }
+    words.extend([
+        AT_PHENT,
+        PHDR_SIZE as u64,
+        AT_PHNUM,
+        elf.headers.len() as u64,
+        AT_PAGESZ,
+        PAGE_SIZE,
+        AT_ENTRY,
+        elf.entry,
+        AT_RANDOM,
+        string_addr(random_offset),
+        AT_NULL,
+        0,
+    ]);
+    if words.len() * 8 > ARGS_MAX / 2 {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+    // `_start` is entered with a 16 byte aligned stack pointer
+    let stack_pointer = (strings_start - words.len() as u64 * 8) & !15;
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    space.write(VirtAddr::new(strings_start), &strings);
+    space.write(VirtAddr::new(stack_pointer), &bytes);
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+#[test_case]
+fn test_reject_bad_images() {
+    // An ELF header with one program header right after it
+    let image = |kind: u16, phdr_type: u32| {
+        let mut image = [0u8; EHDR_SIZE + PHDR_SIZE];
+        image[..4].copy_from_slice(ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[16..18].copy_from_slice(&kind.to_le_bytes());
+        image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        image[24..32].copy_from_slice(&(USER_START + 0x1000).to_le_bytes());
+        image[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        image[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&1u16.to_le_bytes());
+        image[EHDR_SIZE..EHDR_SIZE + 4].copy_from_slice(&phdr_type.to_le_bytes());
+        image
+    };
+    let load = |image: &[u8]| Program::load(image, &[], &[]).err();
+
+    assert_eq!(load(b"#!/bin/sh\n"), Some(ElfError::NotElf));
+    assert_eq!(load(&image(3, PT_LOAD)), Some(ElfError::NotExecutable));
+    assert_eq!(
+        load(&image(ET_EXEC, PT_INTERP)),
+        Some(ElfError::Interpreter)
+    );
+    let mut truncated = image(ET_EXEC, PT_LOAD);
+    truncated[56..58].copy_from_slice(&2u16.to_le_bytes());
+    assert_eq!(load(&truncated), Some(ElfError::Malformed));
+    // A segment with more bytes in the file than the file has
+    let mut segment = image(ET_EXEC, PT_LOAD);
+    segment[EHDR_SIZE + 16..EHDR_SIZE + 24].copy_from_slice(&USER_START.to_le_bytes());
+    segment[EHDR_SIZE + 32..EHDR_SIZE + 40].copy_from_slice(&0x1000u64.to_le_bytes());
+    segment[EHDR_SIZE + 40..EHDR_SIZE + 48].copy_from_slice(&0x1000u64.to_le_bytes());
+    assert_eq!(load(&segment), Some(ElfError::Malformed));
+}
@@ -1,6 +1,14 @@
-use lazy_static::lazy_static;
+use crate::kernel::percpu;
+use crate::kernel::thread::stack::KernelStack;
+use crate::percpu;
+use core::cell::UnsafeCell;
+use core::ops::RangeInclusive;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::PrivilegeLevel;
 /// The Global Descriptor Table (GDT) is an old mechanism that was used for memory segmentation
 /// prior to paging becoming the de facto standard. However, we still need it in 64-bit mode for
 /// things such as, kernel/user mode cfg or Task State Segment (TSS) loading.
@@ -18,42 +26,90 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-// Use lazy_static as Rusts const evaluator cannot perform this init at compile time...yet
-lazy_static! {
-    static ref TSS: TaskStateSegment  = {
-        let mut tss = TaskStateSegment::new();
-        // Define 0th IST as the double fault stack (note any other IST index can work too)
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // Without memory management, there's no way to properly allocate a new stack,
-            // so, use a `static mut` array as stack storage for now. If this was a `static` only
-            // the bootloader would map this to a read-only page.
-            // This stack also does not have a guard page to protect against stack overflows, meaning
-            // that we need to be careful in our double fault handler not to overflow the stack. An overflow, may
-            // silently fail corrupting any memory under the stack.
-            // TODO: Implement proper stack allocation + guard page.
-            //
-            // x86_64 ABI requires 16 byte alignment
-            // See: https://github.com/phil-opp/blog_os/issues/449#issuecomment-555811809
-            #[repr(align(16))]
-            struct Stack([u8; STACK_SIZE]);
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
-
-            // The `unsafe` is required here, as the compiler cannot guarantee race freedom
-            // given that we are using a mutable static.
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // Write the top address of a double fault stack to the 0th entry
-            // Note: x86 stack grows downwards, from high addresses to low addresses
-            stack_end
-        };
-        tss
-    };
+// Every CPU's GDT has the same layout. User data comes before user code, the
+// order SYSRET expects them in.
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// Bytes of an I/O permission bitmap covering every port, a bit each
+const IO_BITMAP_SIZE: usize = 65536 / 8;
+
+/// A TSS and its I/O permission bitmap, which must follow it
+#[repr(C)]
+struct TssArea {
+    tss: TaskStateSegment,
+    /// A set bit denies user code the port. The CPU may read a byte past the
+    /// last port's, which must be all ones.
+    io_bitmap: [u8; IO_BITMAP_SIZE + 1],
+}
+
+/// A TSS the CPU reads while we change its RSP0 and I/O bitmap
+struct Tss(UnsafeCell<TssArea>);
+
+// Only the CPU it belongs to writes it, with interrupts disabled
+unsafe impl Sync for Tss {}
+
+percpu! {
+    // Each CPU's TSS and GDT, set up once by `gdt_init()` or `init_ap()`. A TSS
+    // holds the stacks the CPU switches to on interrupts, which can't be shared
+    // between CPUs. Its RSP0, the stack interrupts from user mode land on,
+    // changes with the running thread.
+    //
+    // The I/O bitmap denies user code every port, but those `allow_io_ports()`
+    // grants the thread running until the next switch.
+    static TSS: Tss = Tss(UnsafeCell::new(TssArea {
+        // Its `iomap_base` points right after it
+        tss: TaskStateSegment::new(),
+        io_bitmap: [u8::MAX; IO_BITMAP_SIZE + 1],
+    }));
+    static GDT: Once<GlobalDescriptorTable> = Once::new();
+    // Bytes at the start of the I/O bitmap that may allow ports, 0 if none do
+    static IO_BITMAP_USED: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// This CPU's TSS
+fn tss() -> *mut TaskStateSegment {
+    unsafe { addr_of_mut!((*TSS.get().0.get()).tss) }
 }
 
-// Create a new GDT with a code segment and a TSS segment
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+/// The boot CPU's double fault stack, we can't allocate one this early
+fn boot_double_fault_stack() -> VirtAddr {
+    const STACK_SIZE: usize = 4096 * 5;
+    // Without memory management, there's no way to properly allocate a new stack,
+    // so, use a `static mut` array as stack storage for now. If this was a `static` only
+    // the bootloader would map this to a read-only page.
+    // This stack also does not have a guard page to protect against stack overflows, meaning
+    // that we need to be careful in our double fault handler not to overflow the stack. An overflow, may
+    // silently fail corrupting any memory under the stack.
+    // TODO: Implement proper stack allocation + guard page.
+    //
+    // x86_64 ABI requires 16 byte alignment
+    // See: https://github.com/phil-opp/blog_os/issues/449#issuecomment-555811809
+    #[repr(align(16))]
+    struct Stack([u8; STACK_SIZE]);
+    static mut STACK: Stack = Stack([0; STACK_SIZE]);
+
+    // The `unsafe` is required here, as the compiler cannot guarantee race freedom
+    // given that we are using a mutable static.
+    let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    // Note: x86 stack grows downwards, from high addresses to low addresses
+    stack_start + STACK_SIZE
+}
+
+/// Set up this CPU's TSS and GDT (with kernel and user segments and a TSS
+/// segment) and load them
+fn init_cpu(double_fault_stack: VirtAddr) {
+    let tss = tss();
+    unsafe {
+        // Define 0th IST as the double fault stack (note any other IST index can work too)
+        let mut stacks = (*tss).interrupt_stack_table;
+        stacks[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+        (*tss).interrupt_stack_table = stacks;
+    }
+    let gdt = GDT.get().call_once(|| {
         let mut gdt = GlobalDescriptorTable::new();
         // A segment is a designated area of the address space.
         // It consists of a start address and a length which defines the area
@@ -84,23 +140,119 @@ lazy_static! {
         // has selector 0x10 (16).
         //
         // More at: https://web.archive.org/web/20190217233448/https://www.flingos.co.uk/docs/reference/Global-Descriptor-Table/
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt, Selectors{code_selector, tss_selector})
-    };
+        add_entries(&mut gdt, tss);
+        gdt
+    });
+    load(gdt);
+}
+
+fn add_entries(gdt: &mut GlobalDescriptorTable, tss: *const TaskStateSegment) {
+    let selectors = [
+        gdt.add_entry(Descriptor::kernel_code_segment()),
+        gdt.add_entry(Descriptor::kernel_data_segment()),
+        gdt.add_entry(Descriptor::user_data_segment()),
+        gdt.add_entry(Descriptor::user_code_segment()),
+        // The TSS is a per-CPU static, it never goes away
+        gdt.add_entry(tss_descriptor(tss)),
+    ];
+    assert_eq!(
+        selectors.map(|s| s.0),
+        [
+            KERNEL_CODE_SELECTOR.0,
+            KERNEL_DATA_SELECTOR.0,
+            USER_DATA_SELECTOR.0,
+            USER_CODE_SELECTOR.0,
+            TSS_SELECTOR.0
+        ],
+        "gdt: unexpected layout"
+    );
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// A descriptor for `tss` whose limit takes in the I/O bitmap after it
+fn tss_descriptor(tss: *const TaskStateSegment) -> Descriptor {
+    const LIMIT: usize = core::mem::size_of::<TssArea>() - 1;
+    const _: () = assert!(LIMIT <= 0xffff);
+    match unsafe { Descriptor::tss_segment_unchecked(tss) } {
+        Descriptor::SystemSegment(low, high) => {
+            Descriptor::SystemSegment(low & !0xffff | LIMIT as u64, high)
+        }
+        Descriptor::UserSegment(_) => unreachable!(),
+    }
 }
 
+/// Make interrupts and system calls from user mode on this CPU switch to the
+/// kernel stack at `top`
+///
+/// The scheduler sets it for every thread it switches to that is running user
+/// code, see `usermode`.
+pub fn set_kernel_stack(top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let tss = tss();
+        let mut stacks = (*tss).privilege_stack_table;
+        stacks[0] = top;
+        (*tss).privilege_stack_table = stacks;
+        percpu::set_kernel_stack(top.as_u64());
+    });
+}
+
+/// Address of this CPU's RSP0, for code that sets it without calling back into Rust
+pub(crate) fn kernel_stack_slot() -> *mut u64 {
+    unsafe { addr_of_mut!((*tss()).privilege_stack_table) as *mut u64 }
+}
+
+/// Let user code on this CPU use the I/O ports `ports`, until `deny_io_ports()`
+pub fn allow_io_ports(ports: RangeInclusive<u16>) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let bitmap = unsafe { &mut (*TSS.get().0.get()).io_bitmap };
+        for port in ports.clone() {
+            bitmap[usize::from(port / 8)] &= !(1 << (port % 8));
+        }
+        let used = IO_BITMAP_USED.get();
+        let end = usize::from(*ports.end() / 8) + 1;
+        used.store(used.load(Ordering::Relaxed).max(end), Ordering::Relaxed);
+    });
+}
+
+/// Deny user code on this CPU every I/O port again
+///
+/// The scheduler calls it on every switch.
+pub fn deny_io_ports() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let used = IO_BITMAP_USED.get().swap(0, Ordering::Relaxed);
+        if used > 0 {
+            let bitmap = unsafe { &mut (*TSS.get().0.get()).io_bitmap };
+            bitmap[..used].fill(u8::MAX);
+        }
+    });
+}
+
+/// Whether user code on this CPU may use some I/O ports
+pub fn io_ports_allowed() -> bool {
+    IO_BITMAP_USED.get().load(Ordering::Relaxed) > 0
+}
+
+/// Set up the boot CPU's GDT and TSS, after `percpu::init()`
 #[allow(dead_code)]
 pub fn gdt_init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    init_cpu(boot_double_fault_stack());
+}
+
+/// Set up an application processor's GDT and TSS, and load them
+///
+/// The double fault stack is never freed, CPUs don't go away. Needs the heap
+/// and kernel stacks.
+pub fn init_ap() {
+    let stack = KernelStack::new().expect("gdt: failed to allocate a double fault stack");
+    let top = stack.top();
+    core::mem::forget(stack);
+    init_cpu(top);
+}
+
+fn load(gdt: &'static GlobalDescriptorTable) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.load();
     unsafe {
         // Although the GDT is loaded, GDT segments are not yet
         // active because the segment and TSS registers still contain
@@ -109,8 +261,11 @@ pub fn gdt_init() {
         // might point to some arbitrary descriptor in the new GDT), and load teh TSS.
         // We loaded a GDT that contains a TSS selector, but we still need to tell the
         // CPU that it should use the specified TSS.
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(KERNEL_CODE_SELECTOR);
+        SS::set_reg(KERNEL_DATA_SELECTOR);
+        DS::set_reg(KERNEL_DATA_SELECTOR);
+        ES::set_reg(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
     }
 }
 
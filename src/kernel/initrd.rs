@@ -0,0 +1,108 @@
+use crate::kernel::boot;
+
+// The initial ramdisk: the files user programs are loaded from until there
+// are disks.
+//
+// It is a `newc` cpio archive, the format of Linux's initramfs. `build.rs`
+// builds the programs in `user/` and packs them into one that is embedded in
+// the kernel, so every kernel image (tests included) carries them. A ramdisk
+// the boot loader loaded (`FOX_RAMDISK`, see `boot/`) is searched first and
+// can add or replace files.
+
+static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.cpio"));
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+/// File type bits of the mode
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_REGULAR: u32 = 0o100000;
+
+/// A file in the initial ramdisk
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    /// Path without the leading `/`, e.g. `bin/hello`
+    pub name: &'static str,
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+impl File {
+    pub fn is_regular(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_REGULAR
+    }
+}
+
+/// Iterator over the entries of a cpio archive, stops at the trailer or at
+/// the first entry that doesn't parse
+pub struct Files {
+    archive: &'static [u8],
+}
+
+impl Files {
+    pub fn new(archive: &'static [u8]) -> Self {
+        Files { archive }
+    }
+}
+
+/// The 8 hex digit header field `index`
+fn field(header: &[u8], index: usize) -> Option<u32> {
+    let start = MAGIC.len() + index * 8;
+    let digits = core::str::from_utf8(header.get(start..start + 8)?).ok()?;
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn align4(n: usize) -> usize {
+    n.next_multiple_of(4)
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        let archive = self.archive;
+        let header = archive.get(..HEADER_LEN)?;
+        if !header.starts_with(MAGIC) {
+            return None;
+        }
+        let mode = field(header, 1)?;
+        let size = field(header, 6)? as usize;
+        let name_len = field(header, 11)? as usize;
+        // The name is NUL terminated, header and name are padded to 4 bytes
+        let name = archive.get(HEADER_LEN..HEADER_LEN + name_len.checked_sub(1)?)?;
+        let name = core::str::from_utf8(name).ok()?;
+        let data_start = align4(HEADER_LEN + name_len);
+        let data = archive.get(data_start..data_start.checked_add(size)?)?;
+        if name == TRAILER {
+            return None;
+        }
+        self.archive = archive.get(align4(data_start + size)..).unwrap_or(&[]);
+        Some(File { name, mode, data })
+    }
+}
+
+/// The archives files are looked up in, in order
+fn archives() -> impl Iterator<Item = &'static [u8]> {
+    boot::ramdisk().into_iter().chain(Some(EMBEDDED))
+}
+
+/// Every file, those in the boot loader's ramdisk first
+pub fn files() -> impl Iterator<Item = File> {
+    archives().flat_map(Files::new)
+}
+
+/// The file at `path`, with or without a leading `/`
+pub fn find(path: &str) -> Option<File> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    files().find(|file| file.name == path)
+}
+
+#[test_case]
+fn test_find_embedded() {
+    let hello = find("/bin/hello").expect("no /bin/hello in the initrd");
+    assert!(hello.is_regular());
+    assert!(hello.data.starts_with(b"\x7fELF"));
+    assert!(find("bin/hello").is_some());
+    assert!(find("bin/nonexistent").is_none());
+    assert!(Files::new(EMBEDDED).all(|file| file.name != TRAILER));
+}
@@ -1,7 +1,85 @@
-use crate::kernel::gdt;
-use crate::println;
+use crate::drivers::keyboard;
+use crate::kernel::percpu::{self, InterruptGuard};
+use crate::kernel::sync::rcu::{self, rcu_read_lock, RcuCell};
+use crate::kernel::thread::scheduler;
+use crate::kernel::usermode::{self, user_entry, FaultKind, KernelGs, UserFault};
+use crate::kernel::{address_space, apic, device, gdt, ipi, process, syscall};
+use crate::{dbg_serial, println};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{
+    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+// The legacy 8259 PICs deliver IRQs 0-15 on vectors 0-15 by default, which
+// overlap with CPU exceptions. Remap them to the first free vectors after the
+// 32 exceptions.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Timer interrupts per second
+pub const TIMER_HZ: u32 = 100;
+/// Input clock of the programmable interval timer
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+// Timer interrupts since `pic_init()`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// Extra `fn()` for the timer interrupt to call, 0 for none, see `set_timer_hook()`
+static TIMER_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Handles a device interrupt, see `register_irq_handler()`
+pub type IrqHandler = fn();
+
+/// A registered `IrqHandler`, for `unregister_irq_handler()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandlerId(u64);
+
+#[derive(Clone, Copy)]
+struct IrqAction {
+    id: IrqHandlerId,
+    irq: u8,
+    handler: IrqHandler,
+}
+
+// The registered device interrupt handlers. Every device interrupt reads
+// them, without taking a lock (see `sync::rcu`).
+lazy_static! {
+    static ref IRQ_HANDLERS: RcuCell<Vec<IrqAction>> = RcuCell::new(Vec::new());
+}
+
+/// Hardware interrupt vectors, as remapped by `pic_init()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    /// COM1 (IRQ 4)
+    Serial = PIC_1_OFFSET + 4,
+}
+
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+
+    /// The IRQ line, also its bit in the PIC interrupt masks
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
 
 // Instead of using a static mut for the IDT which is far from idiomatic,
 // as `static muts` are prone to data races and we have to use `unsafe`. Let's use
@@ -11,17 +89,75 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        // Specify breakpoint handler
-        idt.breakpoint.set_handler_fn(bp_handler);
+        // What user code reaches goes through the `user_entry!` stubs
+        unsafe {
+            // Specify breakpoint handler, user code may use `int3` too
+            idt.breakpoint.set_handler_addr(entry_addr(bp_entry)).set_privilege_level(PrivilegeLevel::Ring3);
+            // The other exceptions user code can cause stop it, see `usermode`
+            idt.divide_error.set_handler_addr(entry_addr(divide_error_entry));
+            idt.invalid_opcode.set_handler_addr(entry_addr(invalid_opcode_entry));
+            idt.segment_not_present.set_handler_addr(entry_addr(segment_not_present_entry));
+            idt.stack_segment_fault.set_handler_addr(entry_addr(stack_segment_entry));
+            idt.general_protection_fault.set_handler_addr(entry_addr(general_protection_entry));
+            idt.alignment_check.set_handler_addr(entry_addr(alignment_check_entry));
+            idt.x87_floating_point.set_handler_addr(entry_addr(floating_point_entry));
+            idt.simd_floating_point.set_handler_addr(entry_addr(floating_point_entry));
+            idt.page_fault.set_handler_addr(entry_addr(page_fault_entry));
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(entry_addr(timer_entry));
+        }
         unsafe {
             // We MUST ensure that this index is valid and not used elsewhere.
             idt.double_fault.set_handler_fn(df_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+        // The other IRQs (but the cascade, IRQ 2) run the handlers registered for them
+        let irqs: [(u8, HandlerFunc); 14] = [
+            (1, irq_handler::<1>),
+            (3, irq_handler::<3>),
+            (4, irq_handler::<4>),
+            (5, irq_handler::<5>),
+            (6, irq_handler::<6>),
+            (7, irq_handler::<7>),
+            (8, irq_handler::<8>),
+            (9, irq_handler::<9>),
+            (10, irq_handler::<10>),
+            (11, irq_handler::<11>),
+            (12, irq_handler::<12>),
+            (13, irq_handler::<13>),
+            (14, irq_handler::<14>),
+            (15, irq_handler::<15>),
+        ];
+        for (irq, handler) in irqs {
+            idt[(PIC_1_OFFSET + irq) as usize].set_handler_fn(handler);
+        }
+        idt[ipi::CALL_VECTOR as usize].set_handler_fn(call_handler);
+        idt[ipi::RESCHEDULE_VECTOR as usize].set_handler_fn(reschedule_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_handler);
+        // System calls for debugging, user code may raise it
+        unsafe {
+            idt[syscall::INT80_VECTOR as usize]
+                .set_handler_addr(syscall::int80_entry_addr())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
 
-/// Initialize the interrupt descriptor table
+user_entry!(bp_entry => bp_handler);
+user_entry!(divide_error_entry => divide_error_handler);
+user_entry!(invalid_opcode_entry => invalid_opcode_handler);
+user_entry!(segment_not_present_entry => segment_not_present_handler, error_code);
+user_entry!(stack_segment_entry => stack_segment_handler, error_code);
+user_entry!(general_protection_entry => general_protection_handler, error_code);
+user_entry!(alignment_check_entry => alignment_check_handler, error_code);
+user_entry!(floating_point_entry => floating_point_handler);
+user_entry!(page_fault_entry => page_fault_handler, error_code);
+user_entry!(timer_entry => timer_handler);
+
+fn entry_addr(entry: extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
+}
+
+/// Initialize the interrupt descriptor table and load it, every CPU shares it
 #[allow(dead_code)]
 pub fn idt_init() {
     // For the CPU to use this IDT, we need to load it using the
@@ -33,6 +169,124 @@ pub fn idt_init() {
     IDT.load();
 }
 
+/// Remap the PICs, unmask the timer and register the keyboard and serial handlers
+///
+/// Interrupts stay disabled on the CPU until the caller enables them.
+pub fn pic_init() {
+    // Don't trust the masks the firmware left behind, UEFI masks everything.
+    // IRQ 2 cascades the second PIC. The other lines are unmasked as handlers
+    // are registered for them.
+    let mask = !(1u8 << 2) & !(1 << InterruptIndex::Timer.irq());
+    {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.initialize();
+            pics.write_masks(mask, u8::MAX);
+        }
+    }
+    pit_init();
+    register_irq_handler(InterruptIndex::Keyboard.irq(), keyboard_irq);
+    register_irq_handler(InterruptIndex::Serial.irq(), dbg_serial::receive_interrupt);
+}
+
+/// Make the PIT fire `TIMER_HZ` times a second, instead of the default ~18.2
+fn pit_init() {
+    let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel0: Port<u8> = Port::new(0x40);
+    unsafe {
+        // Channel 0, low byte then high byte, mode 2 (rate generator)
+        command.write(0x34);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Number of timer interrupts since `pic_init()`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Have the timer interrupt call `hook` on every tick, until replaced
+///
+/// Meant for tests that need to run code in interrupt context.
+pub fn set_timer_hook(hook: Option<fn()>) {
+    TIMER_HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::Relaxed);
+}
+
+/// Have `handler` called on every interrupt of IRQ line `irq`, and unmask the line
+///
+/// Any line but the timer's and the cascade (2) can have handlers, several
+/// can share one. Handlers run with interrupts disabled, the PIC is
+/// acknowledged once they all ran. Thread context only.
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> IrqHandlerId {
+    assert!(
+        irq < 16 && irq != 2 && irq != InterruptIndex::Timer.irq(),
+        "interrupts: IRQ {} can't have handlers",
+        irq
+    );
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = IrqHandlerId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    IRQ_HANDLERS.update(|actions| {
+        let mut actions = actions.clone();
+        actions.push(IrqAction { id, irq, handler });
+        set_masked(irq, false);
+        actions
+    });
+    id
+}
+
+/// Remove a handler `register_irq_handler()` added, masking its line if it was the last one
+pub fn unregister_irq_handler(id: IrqHandlerId) {
+    IRQ_HANDLERS.update(|actions| {
+        let removed: Vec<IrqAction> = actions.iter().filter(|a| a.id != id).copied().collect();
+        for action in actions.iter().filter(|a| a.id == id) {
+            if !removed.iter().any(|a| a.irq == action.irq) {
+                set_masked(action.irq, true);
+            }
+        }
+        removed
+    });
+}
+
+/// Remove every handler of IRQ line `irq`, masking it, and return them
+///
+/// For drivers in user space taking a line over from the kernel's, see
+/// `device`; registering the handlers again gives it back.
+pub fn take_irq_handlers(irq: u8) -> Vec<IrqHandler> {
+    let mut taken = Vec::new();
+    IRQ_HANDLERS.update(|actions| {
+        let (line, others): (Vec<IrqAction>, Vec<IrqAction>) =
+            actions.iter().partition(|a| a.irq == irq);
+        if !line.is_empty() {
+            set_masked(irq, true);
+        }
+        taken = line.iter().map(|a| a.handler).collect();
+        others
+    });
+    taken
+}
+
+/// Mask or unmask IRQ line `irq` in the PICs
+fn set_masked(irq: u8, masked: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let (mask, bit) = (&mut masks[irq as usize / 8], irq % 8);
+        if masked {
+            *mask |= 1 << bit;
+        } else {
+            *mask &= !(1 << bit);
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+/// Acknowledge the hardware interrupt `irq`, so the PIC delivers the next one
+fn end_of_interrupt(irq: InterruptIndex) {
+    unsafe { PICS.lock().notify_end_of_interrupt(irq.as_u8()) };
+}
+
 /// Breakpoint handler
 /// Note the `extern` here define foreign calling convention.
 /// Here, it is `x86-interrupt` calling convention
@@ -40,6 +294,9 @@ pub fn idt_init() {
 /// Note: In rust x86-interrupt calling convention is still unstable
 /// To use it anyways, we explicitly enable it with `#![feature(abi_x86_interrupt)]`
 extern "x86-interrupt" fn bp_handler(sf: InterruptStackFrame) {
+    if usermode::from_user(&sf) {
+        exception(FaultKind::Breakpoint, &sf, 0, None);
+    }
     println!("### CPU EXCEPTION: BREAKPOINT ###\n {:#?}", sf);
 }
 
@@ -54,6 +311,166 @@ extern "x86-interrupt" fn df_handler(sf: InterruptStackFrame, err_code: u64) ->
     );
 }
 
+extern "x86-interrupt" fn page_fault_handler(
+    mut sf: InterruptStackFrame,
+    err_code: PageFaultErrorCode,
+) {
+    if usermode::from_user(&sf) {
+        // A write to a page shared since a `fork()`
+        let resolved = err_code.contains(
+            PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+        ) && {
+            let _gs = KernelGs::enter(&sf);
+            address_space::copy_on_write(Cr2::read())
+        };
+        if resolved {
+            return;
+        }
+        exception(
+            FaultKind::PageFault,
+            &sf,
+            err_code.bits(),
+            Some(Cr2::read()),
+        );
+    }
+    // An access that was expected to fault, see `memory::probe_read()`
+    if let Some(fixup) = percpu::take_fault_fixup() {
+        unsafe {
+            sf.as_mut()
+                .update(|sf| sf.instruction_pointer = VirtAddr::new(fixup))
+        };
+        return;
+    }
+    panic!(
+        "### CPU EXCEPTION: PAGE FAULT | EC: {:?} | ADDR: {:?} ###\n {:#?}",
+        err_code,
+        Cr2::read(),
+        sf
+    );
+}
+
+/// Stop the user code that raised exception `kind`, in the kernel it is a bug
+fn exception(
+    kind: FaultKind,
+    sf: &InterruptStackFrame,
+    error_code: u64,
+    address: Option<VirtAddr>,
+) -> ! {
+    if usermode::from_user(sf) {
+        // We never go back to the user code, the kernel's GS base stays
+        core::mem::forget(KernelGs::enter(sf));
+        usermode::fault(UserFault {
+            kind,
+            error_code,
+            instruction_pointer: sf.instruction_pointer,
+            stack_pointer: sf.stack_pointer,
+            address,
+        });
+    }
+    panic!(
+        "### CPU EXCEPTION: {:?} | EC: {:#x} ###\n {:#?}",
+        kind, error_code, sf
+    );
+}
+
+extern "x86-interrupt" fn divide_error_handler(sf: InterruptStackFrame) {
+    exception(FaultKind::DivideError, &sf, 0, None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(sf: InterruptStackFrame) {
+    exception(FaultKind::InvalidOpcode, &sf, 0, None);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(sf: InterruptStackFrame, err_code: u64) {
+    exception(FaultKind::SegmentNotPresent, &sf, err_code, None);
+}
+
+extern "x86-interrupt" fn stack_segment_handler(sf: InterruptStackFrame, err_code: u64) {
+    exception(FaultKind::StackSegment, &sf, err_code, None);
+}
+
+extern "x86-interrupt" fn general_protection_handler(sf: InterruptStackFrame, err_code: u64) {
+    // Maybe an `in` or `out` on a port the process may use that this CPU's
+    // I/O bitmap doesn't allow yet, run it again once it does
+    if usermode::from_user(&sf) && err_code == 0 && {
+        let _gs = KernelGs::enter(&sf);
+        device::load_io_ports()
+    } {
+        return;
+    }
+    exception(FaultKind::GeneralProtection, &sf, err_code, None);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(sf: InterruptStackFrame, err_code: u64) {
+    exception(FaultKind::AlignmentCheck, &sf, err_code, None);
+}
+
+extern "x86-interrupt" fn floating_point_handler(sf: InterruptStackFrame) {
+    exception(FaultKind::FloatingPoint, &sf, 0, None);
+}
+
+extern "x86-interrupt" fn timer_handler(sf: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&sf);
+    {
+        let _irq = InterruptGuard::enter();
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        rcu::note_quiescent();
+        let hook = TIMER_HOOK.load(Ordering::Relaxed);
+        if hook != 0 {
+            let hook: fn() = unsafe { core::mem::transmute(hook) };
+            hook();
+        }
+        // Before we possibly switch to another thread, which may run for a while
+        end_of_interrupt(InterruptIndex::Timer);
+    }
+    scheduler::timer_tick();
+    if usermode::from_user(&sf) {
+        // Killed or signalled while it ran, we may not go back to it directly
+        // (nor give it its GS base)
+        process::returning_to_user(unsafe { usermode::user_context() });
+    }
+}
+
+/// Runs the handlers registered for IRQ line `IRQ`
+extern "x86-interrupt" fn irq_handler<const IRQ: u8>(sf: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&sf);
+    let _irq = InterruptGuard::enter();
+    {
+        let rcu = rcu_read_lock();
+        for action in IRQ_HANDLERS.read(&rcu).iter().filter(|a| a.irq == IRQ) {
+            (action.handler)();
+        }
+    }
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + IRQ) };
+}
+
+/// Hands the scancode to the keyboard driver, decoding happens in a work item
+fn keyboard_irq() {
+    // The controller won't raise another interrupt until we read the scancode
+    let mut port: Port<u8> = Port::new(0x60);
+    let scancode = unsafe { port.read() };
+    keyboard::add_scancode(scancode);
+}
+
+/// Runs the function another CPU posted with `ipi::call()`
+extern "x86-interrupt" fn call_handler(sf: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&sf);
+    let _irq = InterruptGuard::enter();
+    ipi::run_pending();
+    apic::end_of_interrupt();
+}
+
+/// Another CPU queued a thread on this one
+extern "x86-interrupt" fn reschedule_handler(sf: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&sf);
+    apic::end_of_interrupt();
+    scheduler::preempt();
+}
+
+/// Raised by a local APIC for an interrupt that went away before it was
+/// delivered, it must not be acknowledged
+extern "x86-interrupt" fn spurious_handler(_sf: InterruptStackFrame) {}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
@@ -61,3 +478,20 @@ fn test_breakpoint_exception() {
     // when execution resumes normally.
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_irq_handler_registry() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn handler() {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // IRQ 5 is unused in QEMU, raise it in software
+    let id = register_irq_handler(5, handler);
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 5) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    unregister_irq_handler(id);
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 5) };
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    crate::kernel::sync::rcu_barrier();
+}
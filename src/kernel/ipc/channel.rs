@@ -0,0 +1,205 @@
+use crate::kernel::process;
+use crate::kernel::process::fd::{File, Handle};
+use crate::kernel::sync::{IrqSafeSpinLock, WaitQueue};
+use crate::kernel::syscall::Errno;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Messages waiting in each direction before senders block
+pub const CHANNEL_CAPACITY: usize = 16;
+/// Longest message, in bytes
+pub const MAX_MESSAGE: usize = 4096;
+/// Most descriptors a message carries
+pub const MAX_HANDLES: usize = 8;
+
+/// What goes through a channel
+pub struct Message {
+    pub data: Vec<u8>,
+    /// The receiver gets descriptors for them, with the same rights
+    pub handles: Vec<Handle>,
+}
+
+struct Queues {
+    /// Messages to each endpoint
+    queues: [VecDeque<Message>; 2],
+    /// Whether each endpoint is still there
+    open: [bool; 2],
+}
+
+struct Channel {
+    queues: IrqSafeSpinLock<Queues>,
+    /// Woken when a message is sent or taken, or an endpoint goes away
+    changed: WaitQueue,
+}
+
+/// One end of a channel, see `ipc`
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+impl Endpoint {
+    /// A new channel's two endpoints
+    pub fn pair() -> (Endpoint, Endpoint) {
+        let channel = Arc::new(Channel {
+            queues: IrqSafeSpinLock::new(
+                "channel",
+                Queues {
+                    queues: [VecDeque::new(), VecDeque::new()],
+                    open: [true, true],
+                },
+            ),
+            changed: WaitQueue::new(),
+        });
+        (
+            Endpoint {
+                channel: channel.clone(),
+                side: 0,
+            },
+            Endpoint { channel, side: 1 },
+        )
+    }
+
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    /// Whether `other` is an end of the same channel
+    pub fn same_channel(&self, other: &Endpoint) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+
+    /// Send `message` to the other end, blocking while its queue is full
+    ///
+    /// `EMSGSIZE` if the message is too long or carries too many handles,
+    /// `EPIPE` once the other end is gone, `EINTR` if the caller is killed or
+    /// sent a signal while it blocks.
+    pub fn send(&self, message: Message) -> Result<(), Errno> {
+        self.deliver(message, true)
+    }
+
+    /// `send()` without blocking, `EAGAIN` if the other end's queue is full
+    pub fn try_send(&self, message: Message) -> Result<(), Errno> {
+        self.deliver(message, false)
+    }
+
+    fn deliver(&self, message: Message, block: bool) -> Result<(), Errno> {
+        if message.data.len() > MAX_MESSAGE || message.handles.len() > MAX_HANDLES {
+            return Err(Errno::EMSGSIZE);
+        }
+        let peer = self.peer();
+        let mut message = Some(message);
+        let mut result = Ok(());
+        let waited = process::wait_interruptible(&self.channel.changed, || {
+            let mut queues = self.channel.queues.lock();
+            if !queues.open[peer] {
+                result = Err(Errno::EPIPE);
+            } else if queues.queues[peer].len() < CHANNEL_CAPACITY {
+                queues.queues[peer].extend(message.take());
+            } else if block {
+                return false;
+            } else {
+                result = Err(Errno::EAGAIN);
+            }
+            true
+        });
+        self.channel.changed.wake_all();
+        // Dropped outside the locks, closing a handle may take some of its own
+        drop(message);
+        waited.and(result)
+    }
+
+    /// Take the next message, blocking until there is one
+    ///
+    /// `EMSGSIZE`, leaving it queued, if it is longer than `max_len` or
+    /// carries more than `max_handles` handles. `EPIPE` once the other end is
+    /// gone and every message it sent is taken, `EINTR` if the caller is
+    /// killed or sent a signal first.
+    pub fn recv(&self, max_len: usize, max_handles: usize) -> Result<Message, Errno> {
+        let mut result = Err(Errno::EPIPE);
+        process::wait_interruptible(&self.channel.changed, || {
+            let mut queues = self.channel.queues.lock();
+            let open = queues.open[self.peer()];
+            let queue = &mut queues.queues[self.side];
+            match queue.front() {
+                Some(next) if next.data.len() > max_len || next.handles.len() > max_handles => {
+                    result = Err(Errno::EMSGSIZE);
+                }
+                Some(_) => result = Ok(queue.pop_front().unwrap()),
+                None => return !open,
+            }
+            true
+        })?;
+        self.channel.changed.wake_all();
+        result
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        // Messages nobody will take, dropped outside the lock
+        let unread = {
+            let mut queues = self.channel.queues.lock();
+            queues.open[self.side] = false;
+            core::mem::take(&mut queues.queues[self.side])
+        };
+        self.channel.changed.wake_all();
+        drop(unread);
+    }
+}
+
+/// `read()` and `write()` take and send messages without handles, `read()`
+/// returns 0 once the other end is gone
+impl File for Endpoint {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let message = match self.recv(buf.len(), 0) {
+            Err(Errno::EPIPE) => return Ok(0),
+            result => result?,
+        };
+        buf[..message.data.len()].copy_from_slice(&message.data);
+        Ok(message.data.len())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        self.send(Message {
+            data: buf.to_vec(),
+            handles: Vec::new(),
+        })?;
+        Ok(buf.len())
+    }
+}
+
+#[test_case]
+fn test_channel() {
+    use super::pipe;
+    use crate::kernel::process::fd::Rights;
+
+    let (a, b) = Endpoint::pair();
+    let (reader, _writer) = pipe();
+    let handle = Handle::new(Arc::new(reader), Rights::READ);
+    a.send(Message {
+        data: b"ping".to_vec(),
+        handles: alloc::vec![handle.clone()],
+    })
+    .unwrap();
+    // Too small for it, it stays queued
+    assert!(matches!(b.recv(4, 0), Err(Errno::EMSGSIZE)));
+    let message = b.recv(4, 1).unwrap();
+    assert_eq!(message.data, b"ping");
+    assert!(Arc::ptr_eq(&message.handles[0].file, &handle.file));
+    assert_eq!(message.handles[0].rights, Rights::READ);
+
+    assert_eq!(b.write(b"pong"), Ok(4));
+    let mut buf = [0; 8];
+    assert_eq!(a.read(&mut buf), Ok(4));
+    assert_eq!(&buf[..4], b"pong");
+
+    // Sent before the sender went away, still there
+    b.write(b"bye").unwrap();
+    drop(b);
+    assert_eq!(a.read(&mut buf), Ok(3));
+    assert_eq!(a.read(&mut buf), Ok(0));
+    assert!(matches!(a.recv(8, 0), Err(Errno::EPIPE)));
+    assert_eq!(a.write(b"?"), Err(Errno::EPIPE));
+}
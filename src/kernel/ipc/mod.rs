@@ -0,0 +1,28 @@
+// Communication between processes.
+//
+// Three kinds of objects, each an open file (see `process::fd`), so
+// processes reach them through descriptors, share them across `fork()` and
+// close them like any other file:
+//
+// - A `Channel` is a pair of connected endpoints carrying messages both ways:
+//   bytes plus descriptors, which the receiver gets as new descriptors of its
+//   own. That's how one process hands another something it couldn't open
+//   itself. Each direction holds a bounded number of messages, senders block
+//   when it is full.
+// - A pipe is a one way byte stream with a read end and a write end, like
+//   Unix's.
+// - A `SharedMemory` region is a set of frames that every process mapping it
+//   sees, counted as an owner of each (see `MemoryManager::share_frame()`).
+//   The region and each mapping keep the frames alive, the last one to go
+//   frees them.
+//
+// Blocking calls stay blocked when their process is killed, until the other
+// side does something or goes away, like other system calls.
+
+mod channel;
+mod pipe;
+mod shm;
+
+pub use channel::{Endpoint, Message, CHANNEL_CAPACITY, MAX_HANDLES, MAX_MESSAGE};
+pub use pipe::{pipe, PipeReader, PipeWriter, PIPE_SIZE};
+pub use shm::SharedMemory;
@@ -0,0 +1,151 @@
+use crate::kernel::process::fd::File;
+use crate::kernel::process::{self, signal::Signal};
+use crate::kernel::sync::{IrqSafeSpinLock, WaitQueue};
+use crate::kernel::syscall::Errno;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+/// Bytes a pipe holds before writers block
+pub const PIPE_SIZE: usize = 4096;
+
+struct Buffer {
+    data: VecDeque<u8>,
+    reader: bool,
+    writer: bool,
+}
+
+struct Pipe {
+    buffer: IrqSafeSpinLock<Buffer>,
+    /// Woken when bytes are written or read, or an end goes away
+    changed: WaitQueue,
+}
+
+/// The read end of a pipe
+pub struct PipeReader(Arc<Pipe>);
+
+/// The write end of a pipe
+pub struct PipeWriter(Arc<Pipe>);
+
+/// A new pipe's two ends
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: IrqSafeSpinLock::new(
+            "pipe",
+            Buffer {
+                data: VecDeque::new(),
+                reader: true,
+                writer: true,
+            },
+        ),
+        changed: WaitQueue::new(),
+    });
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+/// Blocks until there is something to read, returns 0 once the write end is
+/// gone and everything written has been read, `EINTR` if the reader is
+/// killed or sent a signal first
+impl File for PipeReader {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut read = 0;
+        process::wait_interruptible(&self.0.changed, || {
+            let mut buffer = self.0.buffer.lock();
+            if buffer.data.is_empty() {
+                return !buffer.writer;
+            }
+            read = buf.len().min(buffer.data.len());
+            for (slot, byte) in buf.iter_mut().zip(buffer.data.drain(..read)) {
+                *slot = byte;
+            }
+            true
+        })?;
+        self.0.changed.wake_all();
+        Ok(read)
+    }
+}
+
+/// Blocks until every byte is written, `EPIPE` and `SIGPIPE` for the writer
+/// once the read end is gone; a kill or signal stops it short, with `EINTR`
+/// if nothing was written
+impl File for PipeWriter {
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let mut written = 0;
+        let mut broken = false;
+        while written < buf.len() && !broken {
+            let waited = process::wait_interruptible(&self.0.changed, || {
+                let mut buffer = self.0.buffer.lock();
+                if !buffer.reader {
+                    broken = true;
+                    return true;
+                }
+                let room = PIPE_SIZE - buffer.data.len();
+                let chunk = &buf[written..buf.len().min(written + room)];
+                buffer.data.extend(chunk);
+                written += chunk.len();
+                !chunk.is_empty()
+            });
+            self.0.changed.wake_all();
+            if let Err(errno) = waited {
+                return if written == 0 {
+                    Err(errno)
+                } else {
+                    Ok(written)
+                };
+            }
+        }
+        if broken && written == 0 {
+            if let Some(process) = process::current() {
+                process.send_signal(Signal::SIGPIPE);
+            }
+            return Err(Errno::EPIPE);
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let unread = {
+            let mut buffer = self.0.buffer.lock();
+            buffer.reader = false;
+            core::mem::take(&mut buffer.data)
+        };
+        self.0.changed.wake_all();
+        drop(unread);
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.buffer.lock().writer = false;
+        self.0.changed.wake_all();
+    }
+}
+
+#[test_case]
+fn test_pipe() {
+    let (reader, writer) = pipe();
+    // Bigger than the pipe, a reader makes room
+    let big = alloc::vec![7; PIPE_SIZE + 100];
+    let writing = crate::kernel::thread::spawn(move || {
+        assert_eq!(writer.write(&big), Ok(big.len()));
+    });
+    let mut buf = [0; 1000];
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => {
+                assert!(buf[..read].iter().all(|&byte| byte == 7));
+                total += read;
+            }
+            Err(errno) => panic!("read failed: {:?}", errno),
+        }
+    }
+    assert_eq!(total, PIPE_SIZE + 100);
+    writing.join();
+
+    let (reader, writer) = pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"x"), Err(Errno::EPIPE));
+}
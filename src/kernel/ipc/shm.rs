@@ -0,0 +1,99 @@
+use crate::kernel::address_space::AddressSpace;
+use crate::kernel::memory;
+use crate::kernel::process::fd::File;
+use crate::kernel::syscall::Errno;
+use alloc::vec::Vec;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Memory processes share, see `ipc`
+pub struct SharedMemory {
+    frames: Vec<PhysFrame>,
+}
+
+impl SharedMemory {
+    /// A zeroed region of `size` bytes, rounded up to whole pages
+    ///
+    /// `EINVAL` for 0 bytes, `ENOMEM` if there aren't enough free frames.
+    pub fn new(size: usize) -> Result<Self, Errno> {
+        if size == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let pages = size.div_ceil(PAGE_SIZE);
+        let mut region = SharedMemory {
+            frames: Vec::with_capacity(pages),
+        };
+        for _ in 0..pages {
+            // Dropping the region frees those allocated so far
+            let frame = memory::with_memory(|mm| mm.frame_allocator.allocate_frame())
+                .ok_or(Errno::ENOMEM)?;
+            unsafe {
+                memory::phys_to_virt(frame.start_address())
+                    .as_mut_ptr::<u8>()
+                    .write_bytes(0, PAGE_SIZE);
+            }
+            region.frames.push(frame);
+        }
+        Ok(region)
+    }
+
+    /// Size in bytes, a whole number of pages
+    pub fn size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    /// Map the whole region at `start` in `address_space`, with `flags`
+    ///
+    /// Nothing stays mapped if it fails.
+    pub fn map(
+        &self,
+        address_space: &AddressSpace,
+        start: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        address_space.map_shared_frames(start, &self.frames, flags)
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        memory::with_memory(|mm| {
+            for &frame in &self.frames {
+                // Mappings left keep theirs
+                unsafe { mm.release_frame(frame) };
+            }
+        });
+    }
+}
+
+/// Can't be read or written, only mapped
+impl File for SharedMemory {}
+
+#[test_case]
+fn test_shared_memory() {
+    let region = SharedMemory::new(PAGE_SIZE + 1).expect("allocating the region failed");
+    assert_eq!(region.size(), 2 * PAGE_SIZE);
+    assert!(matches!(SharedMemory::new(0), Err(Errno::EINVAL)));
+
+    let first = AddressSpace::new().unwrap();
+    let second = AddressSpace::new().unwrap();
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let start = VirtAddr::new(memory::USER_START + 0x10_0000);
+    region.map(&first, start, flags).unwrap();
+    region.map(&second, start + 0x1000u64, flags).unwrap();
+    let frame = region.frames[1];
+    drop(region);
+
+    // What one writes the other sees, the frames live on in the mappings
+    assert!(first.write(start + 0x1000u64, b"shared"));
+    let mut buf = [0; 6];
+    assert!(second.read(start + 0x2000u64, &mut buf));
+    assert_eq!(&buf, b"shared");
+    assert_eq!(memory::with_memory(|mm| mm.frame_owners(frame)), 2);
+    drop(first);
+    assert_eq!(memory::with_memory(|mm| mm.frame_owners(frame)), 1);
+}
@@ -0,0 +1,156 @@
+use crate::kernel::apic;
+use crate::kernel::cpu::{self, MAX_CPUS};
+use crate::percpu;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+// Inter-processor interrupts (IPIs), raised on other CPUs through the local
+// APICs. Besides sending plain vectors, the kernel uses them for:
+//
+// * Calls: run a function on other CPUs and wait until they are done with it.
+//   TLB shootdowns reach the other CPUs this way (see `memory`). Every CPU has
+//   a slot for one call, callers wait for it to be free. While a CPU waits, for
+//   a slot or for its call to finish, it runs the calls posted to it itself, so
+//   CPUs calling each other at the same time can't deadlock, even with
+//   interrupts disabled.
+// * Reschedule IPIs: make a CPU pick the next thread again, after another CPU
+//   queued one on it.
+//
+// Only online CPUs are sent anything. Before the local APIC is set up the boot
+// CPU is the only one running and nothing is sent.
+
+/// Vector of the IPI that runs a call
+pub const CALL_VECTOR: u8 = 0xf0;
+/// Vector of the IPI that makes a CPU reschedule
+pub const RESCHEDULE_VECTOR: u8 = 0xf1;
+
+/// The CPUs an IPI goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Cpu(usize),
+    All,
+    AllButSelf,
+}
+
+impl Target {
+    fn includes(self, cpu: usize) -> bool {
+        match self {
+            Target::Cpu(target) => target == cpu,
+            Target::All => true,
+            Target::AllButSelf => cpu != cpu::id(),
+        }
+    }
+}
+
+/// A function posted to other CPUs by `call()`, lives on the caller's stack
+struct Call<'a> {
+    func: &'a (dyn Fn() + Sync),
+    // CPUs that haven't finished running `func`
+    pending: AtomicUsize,
+}
+
+percpu! {
+    // The call posted to each CPU, null while the slot is free
+    static CALL_SLOT: AtomicPtr<Call<'static>> = AtomicPtr::new(ptr::null_mut());
+}
+
+/// Raise interrupt `vector` on the online CPUs in `target`
+pub fn send(target: Target, vector: u8) {
+    if apic::id().is_none() {
+        return;
+    }
+    match target {
+        Target::Cpu(cpu) => {
+            if let Some(apic_id) = cpu::apic_id(cpu) {
+                apic::send_fixed(apic_id as u8, vector);
+            }
+        }
+        Target::All => apic::send_fixed_all(vector),
+        Target::AllButSelf => apic::send_fixed_others(vector),
+    }
+}
+
+/// Run `f` on the online CPUs in `target` and wait until all of them are done
+///
+/// The other CPUs run `f` in an interrupt handler, it must not block or make
+/// calls itself. This CPU runs it directly if it is in `target`.
+pub fn call<F>(target: Target, f: F)
+where
+    F: Fn() + Sync,
+{
+    let call = Call {
+        func: &f,
+        pending: AtomicUsize::new(0),
+    };
+    // The other CPUs are done with it before we return
+    let posted = &call as *const Call as *mut Call<'static>;
+    interrupts::without_interrupts(|| {
+        let this_cpu = cpu::id();
+        for cpu in (0..MAX_CPUS).filter(|&cpu| cpu != this_cpu && target.includes(cpu)) {
+            let Some(apic_id) = cpu::apic_id(cpu) else {
+                continue;
+            };
+            call.pending.fetch_add(1, Ordering::Relaxed);
+            let slot = CALL_SLOT.for_cpu(cpu);
+            while slot
+                .compare_exchange(ptr::null_mut(), posted, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+            {
+                run_pending();
+                core::hint::spin_loop();
+            }
+            apic::send_fixed(apic_id as u8, CALL_VECTOR);
+        }
+        if target.includes(this_cpu) {
+            f();
+        }
+        while call.pending.load(Ordering::Acquire) != 0 {
+            run_pending();
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Run the call posted to this CPU, if there is one
+///
+/// Called by the `CALL_VECTOR` handler, and by code that spins with
+/// interrupts disabled on something another CPU may hold while it waits for
+/// a call to finish.
+pub fn run_pending() {
+    let call = CALL_SLOT.get().swap(ptr::null_mut(), Ordering::AcqRel);
+    if call.is_null() {
+        return;
+    }
+    let call = unsafe { &*call };
+    (call.func)();
+    // Our last use of `call`, the caller may return once it drops to 0
+    call.pending.fetch_sub(1, Ordering::Release);
+}
+
+/// Make CPU `cpu` pick the next thread again, after queueing one on it
+pub fn reschedule(cpu: usize) {
+    send(Target::Cpu(cpu), RESCHEDULE_VECTOR);
+}
+
+#[test_case]
+fn test_call_all_cpus() {
+    let ran = AtomicUsize::new(0);
+    call(Target::All, || {
+        ran.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(ran.load(Ordering::Relaxed), cpu::count());
+
+    let ran_on = AtomicUsize::new(usize::MAX);
+    let last = cpu::count() - 1;
+    call(Target::Cpu(last), || {
+        ran_on.store(cpu::id(), Ordering::Relaxed)
+    });
+    assert_eq!(ran_on.load(Ordering::Relaxed), last);
+
+    ran.store(0, Ordering::Relaxed);
+    call(Target::AllButSelf, || {
+        ran.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(ran.load(Ordering::Relaxed), cpu::count() - 1);
+}
@@ -0,0 +1,255 @@
+use crate::kernel::allocator::fixed_size_block::{BLOCK_SIZES, SLAB_CLASSES};
+use crate::serial_println;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+// Kernel memory accounting.
+//
+// The frame allocator, page table mapper and heap allocator bump these counters
+// as they hand out and take back memory. They are plain atomics so that they can
+// be updated from the allocator (which may run with its own lock held, or from an
+// interrupt handler) without taking any further locks.
+static FRAMES_TOTAL: AtomicUsize = AtomicUsize::new(0);
+static FRAMES_USED: AtomicUsize = AtomicUsize::new(0);
+static PAGE_TABLE_PAGES: AtomicUsize = AtomicUsize::new(0);
+static HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+// Number of in-use blocks per slab class (see `fixed_size_block::BLOCK_SIZES`)
+static SLAB_USED: [AtomicUsize; SLAB_CLASSES] = [const { AtomicUsize::new(0) }; SLAB_CLASSES];
+
+/// Maximum number of tracked virtual memory areas
+pub const MAX_REGIONS: usize = 8;
+
+static REGIONS: Mutex<[Option<VmaUsage>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Usage of a kernel virtual memory area (heap, physical memory map...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmaUsage {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// Size of the reserved virtual range in bytes
+    pub size: usize,
+    /// Bytes of the range that are currently backed by mapped pages
+    pub mapped: usize,
+}
+
+/// Handle to a registered region, see `register_region()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionId(usize);
+
+pub fn set_frames_total(frames: usize) {
+    FRAMES_TOTAL.store(frames, Ordering::Relaxed);
+}
+
+pub fn frame_alloc() {
+    FRAMES_USED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn frame_free() {
+    FRAMES_USED.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn page_table_alloc() {
+    PAGE_TABLE_PAGES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn page_table_free() {
+    PAGE_TABLE_PAGES.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn set_heap_size(size: usize) {
+    HEAP_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn heap_alloc(size: usize) {
+    HEAP_USED.fetch_add(size, Ordering::Relaxed);
+}
+
+pub fn heap_free(size: usize) {
+    HEAP_USED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn slab_alloc(class: usize) {
+    SLAB_USED[class].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn slab_free(class: usize) {
+    SLAB_USED[class].fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Start tracking the virtual range `[start, start + size)` under `name`
+///
+/// Panics if more than `MAX_REGIONS` regions are registered.
+pub fn register_region(name: &'static str, start: VirtAddr, size: usize) -> RegionId {
+    let mut regions = REGIONS.lock();
+    let slot = regions
+        .iter()
+        .position(|r| r.is_none())
+        .expect("meminfo: too many regions");
+    regions[slot] = Some(VmaUsage {
+        name,
+        start,
+        size,
+        mapped: 0,
+    });
+    RegionId(slot)
+}
+
+/// Account for `bytes` newly mapped in `region`
+pub fn region_map(region: RegionId, bytes: usize) {
+    if let Some(r) = REGIONS.lock()[region.0].as_mut() {
+        r.mapped += bytes;
+    }
+}
+
+/// Account for `bytes` unmapped from `region`
+pub fn region_unmap(region: RegionId, bytes: usize) {
+    if let Some(r) = REGIONS.lock()[region.0].as_mut() {
+        r.mapped -= bytes;
+    }
+}
+
+/// A point in time copy of all memory counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    pub frames_total: usize,
+    pub frames_used: usize,
+    pub page_table_pages: usize,
+    pub heap_size: usize,
+    pub heap_used: usize,
+    /// In-use blocks per slab class
+    pub slab_used: [usize; SLAB_CLASSES],
+    pub regions: [Option<VmaUsage>; MAX_REGIONS],
+}
+
+impl MemInfo {
+    pub fn frames_free(&self) -> usize {
+        self.frames_total.saturating_sub(self.frames_used)
+    }
+
+    /// Bytes held by in-use slab blocks (including rounding up to the block size)
+    pub fn slab_bytes(&self) -> usize {
+        self.slab_used
+            .iter()
+            .zip(BLOCK_SIZES)
+            .map(|(used, size)| used * size)
+            .sum()
+    }
+
+    /// Difference between this snapshot and an `earlier` one
+    pub fn delta_since(&self, earlier: &MemInfo) -> MemDelta {
+        let diff = |now: usize, then: usize| now as isize - then as isize;
+        MemDelta {
+            heap: diff(self.heap_used, earlier.heap_used),
+            slab: diff(self.slab_bytes(), earlier.slab_bytes()),
+            frames: diff(self.frames_used, earlier.frames_used),
+            page_tables: diff(self.page_table_pages, earlier.page_table_pages),
+        }
+    }
+}
+
+/// Take a snapshot of the current memory counters
+pub fn snapshot() -> MemInfo {
+    let mut slab_used = [0; SLAB_CLASSES];
+    for (used, counter) in slab_used.iter_mut().zip(&SLAB_USED) {
+        *used = counter.load(Ordering::Relaxed);
+    }
+
+    MemInfo {
+        frames_total: FRAMES_TOTAL.load(Ordering::Relaxed),
+        frames_used: FRAMES_USED.load(Ordering::Relaxed),
+        page_table_pages: PAGE_TABLE_PAGES.load(Ordering::Relaxed),
+        heap_size: HEAP_SIZE.load(Ordering::Relaxed),
+        heap_used: HEAP_USED.load(Ordering::Relaxed),
+        slab_used,
+        regions: *REGIONS.lock(),
+    }
+}
+
+/// Print a meminfo report to the serial port
+pub fn print_report() {
+    serial_println!("{}", snapshot());
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const KIB: usize = 1024;
+        writeln!(f, "----- meminfo -----")?;
+        writeln!(f, "FramesTotal:    {:>10}", self.frames_total)?;
+        writeln!(f, "FramesFree:     {:>10}", self.frames_free())?;
+        writeln!(f, "FramesUsed:     {:>10}", self.frames_used)?;
+        writeln!(f, "PageTables:     {:>10} kB", self.page_table_pages * 4)?;
+        writeln!(f, "HeapTotal:      {:>10} kB", self.heap_size / KIB)?;
+        writeln!(f, "HeapUsed:       {:>10} B", self.heap_used)?;
+        writeln!(f, "Slab:           {:>10} B", self.slab_bytes())?;
+        for (size, used) in BLOCK_SIZES.iter().zip(&self.slab_used) {
+            writeln!(f, "  slab-{:<5}    {:>10} blocks", size, used)?;
+        }
+        write!(f, "VmallocRegions:")?;
+        for r in self.regions.iter().flatten() {
+            write!(
+                f,
+                "\n  {:<12} {:#018x} {:>10} kB / {} kB mapped",
+                r.name,
+                r.start.as_u64(),
+                r.size / KIB,
+                r.mapped / KIB
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Change in memory usage between two `MemInfo` snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemDelta {
+    pub heap: isize,
+    pub slab: isize,
+    pub frames: isize,
+    pub page_tables: isize,
+}
+
+impl MemDelta {
+    pub fn is_zero(&self) -> bool {
+        *self
+            == MemDelta {
+                heap: 0,
+                slab: 0,
+                frames: 0,
+                page_tables: 0,
+            }
+    }
+}
+
+impl fmt::Display for MemDelta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap {:+} B, slab {:+} B, frames {:+}, page tables {:+}",
+            self.heap, self.slab, self.frames, self.page_tables
+        )
+    }
+}
+
+#[test_case]
+fn test_heap_accounting() {
+    use alloc::boxed::Box;
+
+    let before = snapshot();
+    let boxed = Box::new([0u8; 100]);
+    let during = snapshot().delta_since(&before);
+    assert_eq!(during.heap, 100);
+    // 100 bytes are served from the 128 byte slab class. With KASAN red zones
+    // make the block bigger and freed blocks sit in quarantine for a while.
+    #[cfg(not(feature = "kasan"))]
+    assert_eq!(during.slab, 128);
+
+    drop(boxed);
+    // Freed blocks sit in KASAN's quarantine, only the heap's books balance
+    #[cfg(not(feature = "kasan"))]
+    assert!(snapshot().delta_since(&before).is_zero());
+    #[cfg(feature = "kasan")]
+    assert_eq!(snapshot().delta_since(&before).heap, 0);
+}
@@ -0,0 +1,640 @@
+use crate::kernel::boot::{MemoryKind, MemoryRegion};
+use crate::kernel::ipi::{self, Target};
+use crate::kernel::{cpu, meminfo, percpu};
+use alloc::collections::BTreeMap;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::mapper::{FlagUpdateError, MapToError, UnmapError};
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// The bootloader maps the complete physical memory at some virtual offset
+// (`physical_memory` mapping in `BOOTLOADER_CONFIG`), which lets us reach any frame, including
+// the page tables themselves, through `PHYS_MEM_OFFSET + phys_addr`.
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Set up once in `init()`, the page table mapper and the frame allocator are
+// shared by everything that needs to create mappings (heap, stacks...).
+static MEMORY: Mutex<Option<MemoryManager>> = Mutex::new(None);
+
+// Every address space (see `address_space`) has its own level 4 table, but
+// they all share the kernel's part of it: the first entry, where Multiboot2
+// loaders and the AP trampoline identity map low memory, and the upper half,
+// where everything else the kernel maps lives. `init()` gives each of those
+// entries a level 3 table up front, so that a kernel mapping made through any
+// address space shows up in all of them. The entries in between belong to
+// user space.
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Start of the part of the address space user code can be given, the second
+/// level 4 entry
+pub const USER_START: u64 = 0x_0000_0080_0000_0000;
+/// End of the lower half of the address space, and of user space
+pub const USER_END: u64 = 0x_0000_8000_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+
+// Pages unmapped at once before their TLB entries are shot down on the other
+// CPUs, their frames are only freed after that
+const SHOOTDOWN_BATCH: usize = 32;
+
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+    // Owners beyond the first of the frames mapped in several places, see
+    // `share_frame()`
+    frame_refs: BTreeMap<PhysFrame, usize>,
+}
+
+/// Initialize the kernel page table mapper and the physical frame allocator
+///
+/// # Safety
+///
+/// The caller must guarantee that the complete physical memory is
+/// mapped at `phys_mem_offset`, that the usable `memory_regions` are really
+/// unused and that this is only called once.
+pub unsafe fn init(phys_mem_offset: VirtAddr, memory_regions: &'static [MemoryRegion]) {
+    PHYS_MEM_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
+
+    let (level_4_frame, _) = Cr3::read();
+    KERNEL_PAGE_TABLE.store(level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+    let mapper = page_table(level_4_frame);
+    let mut frame_allocator = BootInfoFrameAllocator::init(memory_regions);
+    share_kernel_entries(level_4_frame, &mut frame_allocator);
+
+    let phys_mem_size = memory_regions.iter().map(|r| r.end).max().unwrap_or(0) as usize;
+    let physmap = meminfo::register_region("physmap", phys_mem_offset, phys_mem_size);
+    meminfo::region_map(physmap, phys_mem_size);
+
+    *MEMORY.lock() = Some(MemoryManager {
+        mapper,
+        frame_allocator,
+        frame_refs: BTreeMap::new(),
+    });
+}
+
+/// A mapper for the page tables under the level 4 table in `level_4_frame`
+///
+/// Only ever used with `MEMORY` locked, so there is one user of the tables at
+/// a time even though every call creates a new `&mut`.
+fn page_table(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed));
+    let table = phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>();
+    unsafe { OffsetPageTable::new(&mut *table, phys_mem_offset) }
+}
+
+/// Whether the level 4 entry `index` maps part of the kernel, see `KERNEL_PAGE_TABLE`
+pub fn is_kernel_entry(index: usize) -> bool {
+    index == 0 || index >= 256
+}
+
+/// Give every kernel entry of the level 4 table a level 3 table
+fn share_kernel_entries(level_4_frame: PhysFrame, frame_allocator: &mut BootInfoFrameAllocator) {
+    let table =
+        unsafe { &mut *phys_to_virt(level_4_frame.start_address()).as_mut_ptr::<PageTable>() };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for (index, entry) in table.iter_mut().enumerate() {
+        if !is_kernel_entry(index) || !entry.is_unused() {
+            continue;
+        }
+        let frame = PageTableAllocator(&mut *frame_allocator)
+            .allocate_frame()
+            .expect("memory: no frames for the kernel page tables");
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<PageTable>()
+                .write(PageTable::new())
+        };
+        entry.set_frame(frame, flags);
+    }
+}
+
+/// The level 4 table the kernel booted with, kernel threads run on it
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Translate a physical address into its virtual alias in the physical memory map
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Run `f` with exclusive access to the memory manager, its mapper on the
+/// active page table
+///
+/// Interrupts are disabled for the duration of `f` so that an interrupt handler
+/// cannot end up spinning on a lock held by the code it interrupted.
+pub fn with_memory<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    lock_memory(None, f)
+}
+
+/// Run `f` with exclusive access to the memory manager, its mapper on the
+/// page table under `level_4_frame`, active or not
+pub fn with_page_table<F, R>(level_4_frame: PhysFrame, f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    lock_memory(Some(level_4_frame), f)
+}
+
+fn lock_memory<F, R>(level_4_frame: Option<PhysFrame>, f: F) -> R
+where
+    F: FnOnce(&mut MemoryManager) -> R,
+{
+    let mut f = Some(f);
+    loop {
+        let done = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut memory = MEMORY.try_lock()?;
+            let mm = memory.as_mut().expect("memory: not initialized");
+            mm.mapper = page_table(level_4_frame.unwrap_or_else(|| Cr3::read().0));
+            let f = f.take().expect("memory: closure already run");
+            Some(f(mm))
+        });
+        if let Some(result) = done {
+            return result;
+        }
+        // The holder may be waiting for us to flush our TLB
+        ipi::run_pending();
+        core::hint::spin_loop();
+    }
+}
+
+/// Map `size` bytes starting at `start` to freshly allocated frames
+///
+/// Nothing stays mapped if it fails.
+pub fn map_range(
+    start: VirtAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::<Size4KiB>::containing_address(start + size - 1u64);
+
+    with_memory(|mm| {
+        for page in Page::range_inclusive(start_page, end_page) {
+            if let Err(err) = mm.map_page(page, flags) {
+                if page > start_page {
+                    mm.unmap_pages(Page::range_inclusive(start_page, page - 1))
+                        .expect("memory: mapped pages vanished");
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmap `size` bytes starting at `start`, returning the backing frames to the allocator
+pub fn unmap_range(start: VirtAddr, size: usize) -> Result<(), UnmapError> {
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::<Size4KiB>::containing_address(start + size - 1u64);
+    with_memory(|mm| mm.unmap_pages(Page::range_inclusive(start_page, end_page)))
+}
+
+/// Change the flags of the `size` mapped bytes starting at `start`
+pub fn protect_range(
+    start: VirtAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::<Size4KiB>::containing_address(start + size - 1u64);
+    with_memory(|mm| mm.protect_pages(Page::range_inclusive(start_page, end_page), flags))
+}
+
+/// Read the `u64` at `addr`, `None` if that faults
+///
+/// For checking whether an address is mapped on the CPU we run on, its TLB
+/// included.
+pub fn probe_read(addr: VirtAddr) -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let value: u64;
+        let faulted: u32;
+        // The page fault handler resumes at 2 if the load faults
+        unsafe {
+            asm!(
+                "lea {fixup}, [rip + 2f]",
+                "mov gs:[{offset}], {fixup}",
+                "xor {faulted:e}, {faulted:e}",
+                "mov {value}, [{addr}]",
+                "jmp 3f",
+                "2:",
+                "mov {faulted:e}, 1",
+                "3:",
+                "mov qword ptr gs:[{offset}], 0",
+                addr = in(reg) addr.as_u64(),
+                value = out(reg) value,
+                faulted = out(reg) faulted,
+                fixup = out(reg) _,
+                offset = const percpu::FAULT_FIXUP_OFFSET,
+                options(nostack)
+            );
+        }
+        (faulted == 0).then_some(value)
+    })
+}
+
+/// Whether the `size` bytes at `start` are mapped for user code in the active
+/// address space, writable too if `write`
+///
+/// The kernel checks this before touching memory a user program pointed it to.
+pub fn user_accessible(start: VirtAddr, size: usize, write: bool) -> bool {
+    if size == 0 {
+        return true;
+    }
+    let Some(end) = start.as_u64().checked_add(size as u64 - 1) else {
+        return false;
+    };
+    if start.as_u64() < USER_START || end >= USER_END {
+        return false;
+    }
+    let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        needed |= PageTableFlags::WRITABLE;
+    }
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::<Size4KiB>::containing_address(VirtAddr::new(end));
+    with_memory(|mm| {
+        Page::range_inclusive(start_page, end_page).all(|page| {
+            match mm.mapper.translate(page.start_address()) {
+                TranslateResult::Mapped { flags, .. } => flags.contains(needed),
+                _ => false,
+            }
+        })
+    })
+}
+
+/// Flush `pages` from the TLBs of the other CPUs, ours is flushed already
+///
+/// Runs with the memory manager locked, so nothing can reuse what the pages
+/// were mapped to before every CPU has forgotten the old mapping.
+fn shootdown(pages: PageRangeInclusive) {
+    if cpu::count() == 1 {
+        return;
+    }
+    ipi::call(Target::AllButSelf, move || {
+        for page in pages {
+            tlb::flush(page.start_address());
+        }
+    });
+}
+
+/// `shootdown()` for pages that aren't contiguous
+fn shootdown_each(pages: &[Option<Page>]) {
+    if cpu::count() == 1 || pages.is_empty() {
+        return;
+    }
+    ipi::call(Target::AllButSelf, || {
+        for page in pages.iter().flatten() {
+            tlb::flush(page.start_address());
+        }
+    });
+}
+
+impl MemoryManager {
+    /// Map `page` to a newly allocated frame
+    pub fn map_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, MapToError<Size4KiB>> {
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        if let Err(e) = unsafe { self.map_frame(page, frame, flags) } {
+            unsafe { self.frame_allocator.deallocate_frame(frame) };
+            return Err(e);
+        }
+        Ok(frame)
+    }
+
+    /// Map `page` to `frame`, which stays owned by the caller
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may be using `frame`, unless it is meant to be shared.
+    pub unsafe fn map_frame(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        // Any frame the mapper pulls from here ends up as an intermediate page table
+        let mut table_allocator = PageTableAllocator(&mut self.frame_allocator);
+        self.mapper
+            .map_to(page, frame, flags, &mut table_allocator)?
+            .flush();
+        Ok(())
+    }
+
+    /// The lowest `size` bytes from `start` to `end` with nothing mapped
+    ///
+    /// Skips whole unused tables at a time, so the cost grows with what is
+    /// mapped rather than with the size of the range.
+    pub fn find_unmapped(&mut self, start: VirtAddr, end: VirtAddr, size: u64) -> Option<VirtAddr> {
+        let (mut free, mut addr) = (start.as_u64(), start.as_u64());
+        while addr - free < size && addr < end.as_u64() {
+            match self.unmapped_until(VirtAddr::new(addr)) {
+                Some(next) => addr = next.min(end.as_u64()),
+                None => {
+                    addr += PAGE_SIZE;
+                    free = addr;
+                }
+            }
+        }
+        (addr - free >= size).then(|| VirtAddr::new(free))
+    }
+
+    /// Where the unmapped stretch at the page aligned `addr` ends, `None` if
+    /// `addr` is mapped
+    fn unmapped_until(&mut self, addr: VirtAddr) -> Option<u64> {
+        let indexes = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+        let mut table: &PageTable = self.mapper.level_4_table();
+        for (level, index) in indexes.into_iter().enumerate() {
+            let entry = &table[index];
+            if entry.is_unused() {
+                // What the entry covers, from 512 GiB down to a page
+                let span = PAGE_SIZE << (9 * (3 - level));
+                return Some(x86_64::align_down(addr.as_u64(), span) + span);
+            }
+            if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = unsafe { &*phys_to_virt(entry.addr()).as_ptr::<PageTable>() };
+        }
+        None
+    }
+
+    /// Unmap `page`, see `unmap_pages()`
+    pub fn unmap_page(&mut self, page: Page) -> Result<(), UnmapError> {
+        self.unmap_pages(Page::range_inclusive(page, page))
+    }
+
+    /// Unmap `pages` and give their frames back to the frame allocator, those
+    /// that aren't mapped elsewhere too
+    ///
+    /// Stops at the first page that can't be unmapped, the ones before it stay
+    /// unmapped.
+    pub fn unmap_pages(&mut self, pages: PageRangeInclusive) -> Result<(), UnmapError> {
+        let mut frames = [None; SHOOTDOWN_BATCH];
+        let mut pages = pages.peekable();
+        while let Some(&first) = pages.peek() {
+            let mut last = first;
+            let mut result = Ok(());
+            for (slot, page) in frames.iter_mut().zip(pages.by_ref()) {
+                match self.mapper.unmap(page) {
+                    Ok((frame, flush)) => {
+                        flush.flush();
+                        *slot = Some(frame);
+                        last = page;
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            if frames[0].is_some() {
+                shootdown(Page::range_inclusive(first, last));
+            }
+            for frame in frames.iter_mut().filter_map(Option::take) {
+                unsafe { self.release_frame(frame) };
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Count another owner of `frame`, it then takes one more
+    /// `release_frame()` to free it
+    ///
+    /// For frames mapped in several address spaces, every mapping owns it.
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        *self.frame_refs.entry(frame).or_insert(0) += 1;
+    }
+
+    /// Number of owners of the allocated `frame`
+    pub fn frame_owners(&self, frame: PhysFrame) -> usize {
+        1 + self.frame_refs.get(&frame).copied().unwrap_or(0)
+    }
+
+    /// Drop an owner of `frame`, the last one gives it back to the frame allocator
+    ///
+    /// # Safety
+    ///
+    /// The caller must not use `frame` anymore.
+    pub unsafe fn release_frame(&mut self, frame: PhysFrame) {
+        match self.frame_refs.get_mut(&frame) {
+            Some(1) => {
+                self.frame_refs.remove(&frame);
+            }
+            Some(refs) => *refs -= 1,
+            None => self.frame_allocator.deallocate_frame(frame),
+        }
+    }
+
+    /// Change the flags of the mapped `pages`
+    pub fn protect_pages(
+        &mut self,
+        pages: PageRangeInclusive,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        let mut result = Ok(());
+        let mut last = None;
+        for page in pages {
+            match unsafe { self.mapper.update_flags(page, flags) } {
+                Ok(flush) => {
+                    flush.flush();
+                    last = Some(page);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        if let Some(last) = last {
+            shootdown(Page::range_inclusive(pages.start, last));
+        }
+        result
+    }
+
+    /// Change the flags of each page in `pages` to those paired with it, like
+    /// `protect_pages()` for pages that aren't contiguous
+    ///
+    /// The other CPUs flush them `SHOOTDOWN_BATCH` pages at a time.
+    pub fn protect_each(
+        &mut self,
+        pages: impl IntoIterator<Item = (Page, PageTableFlags)>,
+    ) -> Result<(), FlagUpdateError> {
+        let mut batch = [None; SHOOTDOWN_BATCH];
+        let mut pages = pages.into_iter().peekable();
+        while pages.peek().is_some() {
+            let mut len = 0;
+            let mut result = Ok(());
+            for (slot, (page, flags)) in batch.iter_mut().zip(pages.by_ref()) {
+                match unsafe { self.mapper.update_flags(page, flags) } {
+                    Ok(flush) => {
+                        flush.flush();
+                        *slot = Some(page);
+                        len += 1;
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+            }
+            shootdown_each(&batch[..len]);
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// Wraps the frame allocator handed to the mapper so that the frames it takes
+/// for intermediate page tables show up in the meminfo counters.
+struct PageTableAllocator<'a, A>(&'a mut A);
+
+unsafe impl<A: FrameAllocator<Size4KiB>> FrameAllocator<Size4KiB> for PageTableAllocator<'_, A> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.0.allocate_frame();
+        if frame.is_some() {
+            meminfo::page_table_alloc();
+        }
+        frame
+    }
+}
+
+/// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Frames are handed out linearly from the usable regions; frames that are given
+/// back are kept on an intrusive free list (the link to the next free frame is
+/// stored in the first 8 bytes of the frame itself) and reused first.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static [MemoryRegion],
+    // Index of the memory map region to allocate from next
+    region: usize,
+    // Next unallocated frame address within `region`
+    next_addr: u64,
+    free_list: Option<PhysFrame>,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the passed memory map is valid.
+    /// i.e. all frames that are marked as `Usable` in it are really unused.
+    pub unsafe fn init(memory_map: &'static [MemoryRegion]) -> Self {
+        let usable_frames: u64 = memory_map
+            .iter()
+            .filter(|r| r.kind == MemoryKind::Usable)
+            .map(usable_range)
+            .map(|(start, end)| (end - start) / 4096)
+            .sum();
+        meminfo::set_frames_total(usable_frames as usize);
+
+        BootInfoFrameAllocator {
+            memory_map,
+            region: 0,
+            next_addr: 0,
+            free_list: None,
+        }
+    }
+
+    fn next_unused_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.kind == MemoryKind::Usable {
+                let (start, end) = usable_range(region);
+                let addr = self.next_addr.max(start);
+                if addr < end {
+                    self.next_addr = addr + 4096;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+}
+
+/// The whole frames within `region`
+///
+/// Regions are given in bytes, nothing guarantees that they start or end on a
+/// page boundary.
+fn usable_range(region: &MemoryRegion) -> (u64, u64) {
+    let start = x86_64::align_up(region.start, 4096);
+    let end = x86_64::align_down(region.end, 4096);
+    (start, end.max(start))
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = match self.free_list {
+            Some(frame) => {
+                let next = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+                self.free_list = match next {
+                    0 => None,
+                    addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+                };
+                Some(frame)
+            }
+            None => self.next_unused_frame(),
+        };
+        if frame.is_some() {
+            meminfo::frame_alloc();
+        }
+        frame
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // Frame zero is never usable, so `0` doubles as the end of list marker
+        let next = self.free_list.map_or(0, |f| f.start_address().as_u64());
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = next;
+        self.free_list = Some(frame);
+        meminfo::frame_free();
+    }
+}
+
+#[test_case]
+fn test_map_unmap_range() {
+    let addr = VirtAddr::new(0x_5555_0000_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let before = meminfo::snapshot();
+
+    map_range(addr, 2 * 4096, flags).expect("map_range failed");
+    unsafe {
+        let ptr: *mut u64 = addr.as_mut_ptr();
+        ptr.write_volatile(0xDEAD_BEEF);
+        assert_eq!(ptr.read_volatile(), 0xDEAD_BEEF);
+    }
+
+    unmap_range(addr, 2 * 4096).expect("unmap_range failed");
+    let after = meminfo::snapshot();
+    // Intermediate tables are not reclaimed, data frames are
+    assert_eq!(
+        after.frames_used,
+        before.frames_used + after.page_table_pages - before.page_table_pages
+    );
+}
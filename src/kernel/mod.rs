@@ -1,3 +1,28 @@
+pub mod acpi;
+pub mod address_space;
+pub mod allocator;
+pub mod apic;
+pub mod aslr;
+pub mod boot;
+pub mod cpu;
 pub mod delay;
+pub mod device;
+pub mod elf;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
+pub mod ipc;
+pub mod ipi;
+pub mod meminfo;
+pub mod memory;
+pub mod percpu;
+pub mod process;
+pub mod random;
+pub mod smp;
+pub mod softirq;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
+pub mod usermode;
+pub mod workqueue;
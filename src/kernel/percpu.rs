@@ -0,0 +1,234 @@
+use crate::kernel::cpu::MAX_CPUS;
+use crate::kernel::softirq;
+use crate::kernel::thread::Thread;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+// Per-CPU data.
+//
+// Each CPU's GS base (IA32_GS_BASE) points to its `PerCpu` block, so the
+// running CPU's index, thread and interrupt nesting are a single GS relative
+// load away, without knowing which CPU we're on. IA32_KERNEL_GS_BASE holds the
+// GS base of user code (always 0), `swapgs` exchanges the two on every entry
+// from and exit to user mode (see `usermode`): in the kernel GS always points
+// to the block.
+//
+// Bigger per-CPU state is declared with `percpu!`: one value per CPU in a
+// static, indexed by the CPU index from the block.
+
+/// The part of a CPU's state that is reached through GS
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this block, GS relative loads only give us its fields
+    this: AtomicPtr<PerCpu>,
+    cpu: AtomicUsize,
+    /// The running thread, the run queue holds the reference
+    current: AtomicPtr<Thread>,
+    /// Interrupt handlers we're in, see `InterruptGuard`
+    irq_depth: AtomicUsize,
+    /// Where the page fault handler resumes a faulting access, 0 for none
+    fault_fixup: AtomicU64,
+    /// Stack system calls from user mode switch to, the TSS's RSP0
+    kernel_stack: AtomicU64,
+    /// User stack pointer while the system call entry switches stacks
+    user_rsp: AtomicU64,
+}
+
+static BLOCKS: [PerCpu; MAX_CPUS] = [const {
+    PerCpu {
+        this: AtomicPtr::new(ptr::null_mut()),
+        cpu: AtomicUsize::new(0),
+        current: AtomicPtr::new(ptr::null_mut()),
+        irq_depth: AtomicUsize::new(0),
+        fault_fixup: AtomicU64::new(0),
+        kernel_stack: AtomicU64::new(0),
+        user_rsp: AtomicU64::new(0),
+    }
+}; MAX_CPUS];
+
+/// Point this CPU's GS base at the block of CPU `cpu`
+///
+/// Must be the first thing a CPU does, everything that asks for the CPU index
+/// (locks among them) reads it from there. `entry_point!` does it for the boot
+/// CPU, `smp` for the others.
+pub fn init(cpu: usize) {
+    let block = &BLOCKS[cpu];
+    block
+        .this
+        .store(block as *const _ as *mut _, Ordering::Relaxed);
+    block.cpu.store(cpu, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(block));
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Read the `usize` at `offset` into this CPU's block
+#[inline(always)]
+fn read_gs<const OFFSET: usize>() -> usize {
+    let value: usize;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{offset}]",
+            out(reg) value,
+            offset = const OFFSET,
+            options(nostack, preserves_flags, readonly)
+        );
+    }
+    value
+}
+
+/// This CPU's block
+pub fn this() -> &'static PerCpu {
+    unsafe { &*(read_gs::<{ offset_of!(PerCpu, this) }>() as *const PerCpu) }
+}
+
+/// Index of the CPU we're running on, see `cpu::id()`
+#[inline(always)]
+pub fn cpu_id() -> usize {
+    read_gs::<{ offset_of!(PerCpu, cpu) }>()
+}
+
+/// The thread running on this CPU, null before the scheduler is set up
+pub(crate) fn current_thread() -> *const Thread {
+    read_gs::<{ offset_of!(PerCpu, current) }>() as *const Thread
+}
+
+/// Record the thread this CPU runs, called by the scheduler as it switches
+pub(crate) fn set_current_thread(thread: *const Thread) {
+    this().current.store(thread as *mut _, Ordering::Relaxed);
+}
+
+/// Whether this CPU is running an interrupt handler
+pub fn in_interrupt() -> bool {
+    read_gs::<{ offset_of!(PerCpu, irq_depth) }>() != 0
+}
+
+/// Offset of the fault fixup address in the block, set with a GS relative store
+pub(crate) const FAULT_FIXUP_OFFSET: usize = offset_of!(PerCpu, fault_fixup);
+
+/// Offsets of the fields the system call entry uses before it has a stack
+pub(crate) const KERNEL_STACK_OFFSET: usize = offset_of!(PerCpu, kernel_stack);
+pub(crate) const USER_RSP_OFFSET: usize = offset_of!(PerCpu, user_rsp);
+
+/// Set the stack system calls from user mode start on, see `gdt::set_kernel_stack()`
+pub(crate) fn set_kernel_stack(top: u64) {
+    this().kernel_stack.store(top, Ordering::Relaxed);
+}
+
+/// Take the address this CPU resumes at after an expected page fault
+pub(crate) fn take_fault_fixup() -> Option<u64> {
+    match this().fault_fixup.swap(0, Ordering::Relaxed) {
+        0 => None,
+        fixup => Some(fixup),
+    }
+}
+
+/// Counts the interrupt handler it is created in as running, until dropped
+///
+/// Handlers drop it before they might switch to another thread, the count
+/// belongs to the CPU and not to the interrupted thread. Dropping the guard of
+/// the outermost handler runs the tasklets it scheduled, see `softirq`.
+pub struct InterruptGuard(());
+
+impl InterruptGuard {
+    pub fn enter() -> Self {
+        this().irq_depth.fetch_add(1, Ordering::Relaxed);
+        InterruptGuard(())
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        // Still counted while they run: they can't sleep, and interrupts
+        // nested in them leave their tasklets to us
+        if this().irq_depth.load(Ordering::Relaxed) == 1 {
+            softirq::run_pending();
+        }
+        this().irq_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// One `T` for each CPU, declared with `percpu!`
+pub struct PerCpuVar<T> {
+    values: [T; MAX_CPUS],
+}
+
+impl<T> PerCpuVar<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpuVar { values }
+    }
+
+    /// The running CPU's value
+    ///
+    /// A thread that moves to another CPU keeps the value of the old one,
+    /// disable interrupts for as long as it must be this CPU's.
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+
+    /// CPU `cpu`'s value
+    pub fn for_cpu(&self, cpu: usize) -> &T {
+        &self.values[cpu]
+    }
+
+    /// Every CPU's value, in CPU order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.values.iter()
+    }
+}
+
+/// Declare statics with one value per CPU
+///
+/// ```ignore
+/// percpu! {
+///     static COUNTER: AtomicUsize = AtomicUsize::new(0);
+/// }
+/// COUNTER.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+///
+/// The initializer must be a constant, it is evaluated once for every CPU.
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::kernel::percpu::PerCpuVar<$ty> =
+                $crate::kernel::percpu::PerCpuVar::new(
+                    [const { $init }; $crate::kernel::cpu::MAX_CPUS],
+                );
+        )*
+    };
+}
+
+#[test_case]
+fn test_percpu() {
+    use crate::kernel::{cpu, thread};
+
+    percpu! {
+        static VALUE: AtomicUsize = AtomicUsize::new(0);
+    }
+    VALUE.get().store(42, Ordering::Relaxed);
+    assert_eq!(VALUE.for_cpu(cpu::id()).load(Ordering::Relaxed), 42);
+    assert_eq!(
+        VALUE
+            .iter()
+            .map(|v| v.load(Ordering::Relaxed))
+            .sum::<usize>(),
+        42
+    );
+
+    assert_eq!(this() as *const PerCpu, &BLOCKS[cpu::id()] as *const PerCpu);
+    assert_eq!(
+        current_thread(),
+        alloc::sync::Arc::as_ptr(&thread::current())
+    );
+    assert!(!in_interrupt());
+    let guard = InterruptGuard::enter();
+    assert!(in_interrupt());
+    drop(guard);
+    assert!(!in_interrupt());
+}
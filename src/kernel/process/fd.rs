@@ -0,0 +1,213 @@
+use crate::kernel::syscall::Errno;
+use crate::print;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::ops::BitOr;
+
+// File descriptors: how a process refers to the kernel objects it uses.
+//
+// A descriptor is an index into the process's table, and the table entry is
+// a handle: a reference to the object (a `File`) and the rights the process
+// has over it. Objects are files in the broad sense, the console, pipes and
+// channels but also shared memory and other processes. System calls that
+// only make sense on one kind of object get it back with `downcast()`.
+//
+// Handles are capabilities. A process only reaches objects it has a handle
+// to, and only does with them what the handle's rights allow: `READ` and
+// `WRITE` for what each kind of object counts as reading or writing,
+// `DUPLICATE` to make another handle to it, with the same or fewer rights,
+// and `TRANSFER` to send it through a channel. Rights never grow, so what a
+// process can do follows from the handles it was given. Several handles, in
+// one process or in several, can refer to the same object.
+
+/// Highest number of descriptors a process can have open
+pub const MAX_FILES: usize = 256;
+
+/// What a handle lets its process do with the object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rights(u32);
+
+impl Rights {
+    pub const NONE: Rights = Rights(0);
+    pub const READ: Rights = Rights(1);
+    pub const WRITE: Rights = Rights(2);
+    pub const DUPLICATE: Rights = Rights(4);
+    pub const TRANSFER: Rights = Rights(8);
+    pub const ALL: Rights = Rights(15);
+
+    /// `None` if `bits` has unknown rights
+    pub fn from_bits(bits: u32) -> Option<Rights> {
+        (bits & !Rights::ALL.0 == 0).then_some(Rights(bits))
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+
+    fn bitor(self, other: Rights) -> Rights {
+        Rights(self.0 | other.0)
+    }
+}
+
+/// Something a file descriptor refers to
+///
+/// Both calls take what they can and return how much that was, a file that
+/// can't be read or written returns `EBADF`.
+pub trait File: Any + Send + Sync {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+}
+
+/// `file` as the file type `T`, if that's what it is
+pub fn downcast<T: File>(file: Arc<dyn File>) -> Option<Arc<T>> {
+    let file: Arc<dyn Any + Send + Sync> = file;
+    file.downcast().ok()
+}
+
+/// A reference to an object with rights over it, a descriptor table entry
+#[derive(Clone)]
+pub struct Handle {
+    pub file: Arc<dyn File>,
+    pub rights: Rights,
+}
+
+impl Handle {
+    pub fn new(file: Arc<dyn File>, rights: Rights) -> Self {
+        Handle { file, rights }
+    }
+
+    /// The object, `EACCES` unless the handle has all of `rights`
+    pub fn check(self, rights: Rights) -> Result<Arc<dyn File>, Errno> {
+        if !self.rights.contains(rights) {
+            return Err(Errno::EACCES);
+        }
+        Ok(self.file)
+    }
+}
+
+/// The screen, standard output and error of every process
+pub struct Console;
+
+impl File for Console {
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// A process's handles, by descriptor
+#[derive(Clone, Default)]
+pub struct FdTable {
+    handles: Vec<Option<Handle>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable::default()
+    }
+
+    /// A table with 0, 1 and 2 open on the console
+    pub fn with_console() -> Self {
+        let mut table = FdTable::new();
+        let console: Arc<dyn File> = Arc::new(Console);
+        for _ in 0..3 {
+            table.open(console.clone()).unwrap();
+        }
+        table
+    }
+
+    /// Give `file` the lowest free descriptor, with every right, `EMFILE`
+    /// if there is none
+    pub fn open(&mut self, file: Arc<dyn File>) -> Result<i32, Errno> {
+        self.insert(Handle::new(file, Rights::ALL))
+    }
+
+    /// Give `handle` the lowest free descriptor, `EMFILE` if there is none
+    pub fn insert(&mut self, handle: Handle) -> Result<i32, Errno> {
+        let fd = match self.handles.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.handles.len() < MAX_FILES => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        self.handles[fd] = Some(handle);
+        Ok(fd as i32)
+    }
+
+    /// The handle at `fd`
+    pub fn get(&self, fd: i32) -> Result<Handle, Errno> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.handles.get(fd)?.clone())
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Another descriptor for the object `fd` refers to, with `rights`
+    ///
+    /// `EACCES` unless `fd` has `DUPLICATE` and every one of `rights`.
+    pub fn duplicate(&mut self, fd: i32, rights: Rights) -> Result<i32, Errno> {
+        let handle = self.get(fd)?;
+        if !handle.rights.contains(rights | Rights::DUPLICATE) {
+            return Err(Errno::EACCES);
+        }
+        self.insert(Handle::new(handle.file, rights))
+    }
+
+    /// Free `fd`, returns the handle it held
+    pub fn close(&mut self, fd: i32) -> Result<Handle, Errno> {
+        usize::try_from(fd)
+            .ok()
+            .and_then(|fd| self.handles.get_mut(fd)?.take())
+            .ok_or(Errno::EBADF)
+    }
+
+    /// Number of open descriptors
+    pub fn len(&self) -> usize {
+        self.handles.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test_case]
+fn test_rights() {
+    let mut table = FdTable::with_console();
+    let fd = table
+        .insert(Handle::new(
+            Arc::new(Console),
+            Rights::WRITE | Rights::DUPLICATE,
+        ))
+        .unwrap();
+    assert_eq!(fd, 3);
+    assert!(table.get(fd).unwrap().check(Rights::WRITE).is_ok());
+    assert_eq!(
+        table.get(fd).unwrap().check(Rights::READ).err(),
+        Some(Errno::EACCES)
+    );
+
+    // Fewer rights, never more
+    let copy = table.duplicate(fd, Rights::WRITE).unwrap();
+    assert_eq!(table.get(copy).unwrap().rights, Rights::WRITE);
+    assert_eq!(table.duplicate(fd, Rights::ALL), Err(Errno::EACCES));
+    assert_eq!(table.duplicate(copy, Rights::WRITE), Err(Errno::EACCES));
+    assert_eq!(Rights::from_bits(16), None);
+}
@@ -0,0 +1,720 @@
+use crate::kernel::address_space::AddressSpace;
+use crate::kernel::elf::{ElfError, Program};
+use crate::kernel::initrd;
+use crate::kernel::sync::{IrqSafeSpinLock, WaitQueue};
+use crate::kernel::syscall::Errno;
+use crate::kernel::thread::{self, Thread};
+use crate::kernel::usermode::{self, UserContext, UserExit, UserFault};
+use crate::serial_println;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::VirtAddr;
+
+pub mod fd;
+pub mod signal;
+
+use fd::{FdTable, File, Handle};
+use signal::{Signal, Signals};
+
+// Processes: programs running in address spaces of their own.
+//
+// A process owns its address space, its open files and its threads. Each of
+// its threads is a kernel thread that switches to the address space and runs
+// the process's code in user mode (see `usermode`), holding on to the process
+// while it does.
+//
+// The process ends when one of its threads exits or faults, or when it is
+// killed. Its other threads are stopped the next time they are on their way
+// back to user mode: when a system call returns or the timer interrupts them.
+// A thread blocked in a system call stops once the call returns; the calls
+// that wait on other processes or on I/O wait with `wait_interruptible()`,
+// which gives up with `EINTR` when the process is killed. A fault the
+// process has no signal handler for (see `signal`) is logged and only ends the
+// process that caused it.
+//
+// Once its last thread is gone the process is a zombie: its memory and files
+// are freed and only its exit status is left, until it is waited for. A
+// process started by another one is its child, and waiting reaps it. The
+// children of a process that ends go to the init process (see `set_init()`),
+// which is expected to reap them.
+//
+// Processes also refer to each other through handles (see `fd`), which
+// carry rights and can be passed on through channels, unlike pids.
+//
+// Unix-style, a process makes another with `fork()`: a copy of itself, sharing
+// its memory copy-on-write (see `address_space`), whose thread returns from
+// the same system call. `exec()` then replaces the program it runs; the
+// process's other threads stop like when it exits.
+
+/// Unique id of a process, the first one is 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy)]
+pub enum ExitStatus {
+    /// A thread called `exit` with this status
+    Exited(i32),
+    /// A thread faulted
+    Faulted(UserFault),
+    /// Someone called `kill()`, or sent it `SIGKILL`
+    Killed,
+    /// A signal it didn't handle ended it
+    Signaled(Signal),
+}
+
+impl ExitStatus {
+    /// The status `wait` gives user code, Linux's encoding: the exit status in
+    /// bits 8 to 15, or the number of the signal that ended it
+    pub fn wait_status(&self) -> i32 {
+        match *self {
+            ExitStatus::Exited(status) => (status & 0xff) << 8,
+            ExitStatus::Faulted(fault) => Signal::for_fault(fault.kind).number() as i32,
+            ExitStatus::Killed => Signal::SIGKILL.number() as i32,
+            ExitStatus::Signaled(signal) => signal.number() as i32,
+        }
+    }
+}
+
+/// Why `spawn()` or `load()` failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// No regular file at that path in the initial ramdisk
+    NotFound,
+    Elf(ElfError),
+}
+
+pub struct Process {
+    pid: Pid,
+    // Set once it starts exiting, checked on every way back to user mode
+    exiting: AtomicBool,
+    // Id + 1 of the thread that ran `exec()`, the others stop like when it
+    // exits; 0 otherwise
+    exec_thread: AtomicU64,
+    inner: IrqSafeSpinLock<ProcessInner>,
+    /// Woken when it becomes a zombie
+    exited: WaitQueue,
+    /// Woken when one of its children becomes a zombie
+    child_exited: WaitQueue,
+    /// Woken when it is continued after a stop signal, or starts exiting
+    continued: WaitQueue,
+    /// The queues its threads are blocked on in `wait_interruptible()`, woken
+    /// when it starts exiting or is sent a signal
+    blocked_on: IrqSafeSpinLock<Vec<BlockedOn>>,
+}
+
+/// A queue a thread is blocked on in `wait_interruptible()`
+struct BlockedOn(*const WaitQueue);
+
+// Only used while the thread that put it there is still waiting on the queue
+unsafe impl Send for BlockedOn {}
+
+struct ProcessInner {
+    /// The program's file name
+    name: String,
+    parent: Weak<Process>,
+    /// Children not waited for yet
+    children: Vec<Arc<Process>>,
+    /// Threads running its code
+    threads: Vec<Arc<Thread>>,
+    /// Threads started and not gone yet, including those that haven't run yet
+    live_threads: usize,
+    /// `None` once it is a zombie
+    address_space: Option<Arc<AddressSpace>>,
+    files: FdTable,
+    signals: Signals,
+    /// I/O ports its code may use, see `device`
+    io_ports: Vec<RangeInclusive<u16>>,
+    /// Why it exits, set once it starts to
+    status: Option<ExitStatus>,
+    zombie: bool,
+}
+
+// Every process by pid, for `find()`
+static PROCESSES: IrqSafeSpinLock<BTreeMap<u64, Weak<Process>>> =
+    IrqSafeSpinLock::new("processes", BTreeMap::new());
+
+// Where orphans go, see `set_init()`
+static INIT: IrqSafeSpinLock<Weak<Process>> = IrqSafeSpinLock::new("init", Weak::new());
+
+impl Process {
+    fn new(
+        name: &str,
+        address_space: Arc<AddressSpace>,
+        files: FdTable,
+        signals: Signals,
+        parent: Option<&Arc<Process>>,
+    ) -> Arc<Self> {
+        let process = Arc::new(Process {
+            pid: Pid::new(),
+            exiting: AtomicBool::new(false),
+            exec_thread: AtomicU64::new(0),
+            inner: IrqSafeSpinLock::new(
+                "process",
+                ProcessInner {
+                    name: name.to_string(),
+                    parent: parent.map_or_else(Weak::new, Arc::downgrade),
+                    children: Vec::new(),
+                    threads: Vec::new(),
+                    live_threads: 0,
+                    address_space: Some(address_space),
+                    files,
+                    signals,
+                    io_ports: Vec::new(),
+                    status: None,
+                    zombie: false,
+                },
+            ),
+            exited: WaitQueue::new(),
+            child_exited: WaitQueue::new(),
+            continued: WaitQueue::new(),
+            blocked_on: IrqSafeSpinLock::new("blocked on", Vec::new()),
+        });
+        PROCESSES
+            .lock()
+            .insert(process.pid.0, Arc::downgrade(&process));
+        if let Some(parent) = parent {
+            parent.inner.lock().children.push(process.clone());
+        }
+        process
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// File name of the program it runs
+    pub fn name(&self) -> String {
+        self.inner.lock().name.clone()
+    }
+
+    /// The process that started this one, unless it is gone
+    pub fn parent(&self) -> Option<Arc<Process>> {
+        self.inner.lock().parent.upgrade()
+    }
+
+    /// Children that haven't been waited for
+    pub fn children(&self) -> Vec<Arc<Process>> {
+        self.inner.lock().children.clone()
+    }
+
+    /// Threads running the process's code
+    pub fn threads(&self) -> Vec<Arc<Thread>> {
+        self.inner.lock().threads.clone()
+    }
+
+    /// `None` once the process is a zombie
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.inner.lock().address_space.clone()
+    }
+
+    /// Run `f` on the open files, it must not block
+    pub fn with_files<R>(&self, f: impl FnOnce(&mut FdTable) -> R) -> R {
+        f(&mut self.inner.lock().files)
+    }
+
+    /// Let the process's code use the I/O ports `ports`, see `device`
+    ///
+    /// `exec()` keeps them, `fork()` doesn't pass them on.
+    pub fn allow_io_ports(&self, ports: RangeInclusive<u16>) {
+        self.inner.lock().io_ports.push(ports);
+    }
+
+    /// Run `f` on the I/O ports the process's code may use, it must not block
+    pub fn with_io_ports<R>(&self, f: impl FnOnce(&[RangeInclusive<u16>]) -> R) -> R {
+        f(&self.inner.lock().io_ports)
+    }
+
+    /// Whether the process has started to exit
+    pub fn is_exiting(&self) -> bool {
+        self.exiting.load(Ordering::Relaxed)
+    }
+
+    /// How it ended, once it is a zombie
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        let inner = self.inner.lock();
+        inner.status.filter(|_| inner.zombie)
+    }
+
+    /// Start a thread running the process's code from `entry`, with
+    /// `stack_pointer` and the FS base `fs_base`
+    ///
+    /// Returns false, and starts nothing, once the process is exiting.
+    pub fn spawn_thread(
+        self: &Arc<Self>,
+        entry: VirtAddr,
+        stack_pointer: VirtAddr,
+        fs_base: VirtAddr,
+    ) -> bool {
+        self.start_thread(UserContext::new(entry, stack_pointer), fs_base)
+    }
+
+    /// `spawn_thread()` from every register in `context`
+    fn start_thread(self: &Arc<Self>, context: UserContext, fs_base: VirtAddr) -> bool {
+        let name = {
+            let mut inner = self.inner.lock();
+            if self.is_exiting() {
+                return false;
+            }
+            inner.live_threads += 1;
+            inner.name.clone()
+        };
+        let process = self.clone();
+        thread::spawn_named(&name, move || run_thread(process, context, fs_base));
+        true
+    }
+
+    /// A copy of the process as a child of it, whose thread goes on from
+    /// `context` with the FS base `fs_base`
+    ///
+    /// The child gets a copy-on-write copy of the address space, the same
+    /// open files and signal actions, and no pending signals or I/O ports.
+    pub fn fork(
+        self: &Arc<Self>,
+        context: UserContext,
+        fs_base: VirtAddr,
+    ) -> Result<Arc<Process>, MapToError<Size4KiB>> {
+        let (name, address_space, files, signals) = {
+            let inner = self.inner.lock();
+            (
+                inner.name.clone(),
+                inner.address_space.clone(),
+                inner.files.clone(),
+                inner.signals.forked(),
+            )
+        };
+        // A zombie has nothing left to copy, nor a thread to ask
+        let address_space = address_space.ok_or(MapToError::FrameAllocationFailed)?;
+        let child = Process::new(
+            &name,
+            Arc::new(address_space.fork()?),
+            files,
+            signals,
+            Some(self),
+        );
+        child.start_thread(context, fs_base);
+        Ok(child)
+    }
+
+    /// Make the running thread, one of the process's, run `program` instead,
+    /// under the name `name`
+    ///
+    /// The process's other threads stop, and its signal handlers are reset.
+    /// The caller goes on with `usermode::leave(UserExit::Resume(..))` from
+    /// the program's entry point.
+    pub fn exec(&self, name: &str, program: &Program) {
+        let current = thread::current();
+        self.exec_thread
+            .store(current.id().as_u64() + 1, Ordering::Relaxed);
+        let old = {
+            let mut inner = self.inner.lock();
+            inner.name = name.to_string();
+            inner.signals.reset_handlers();
+            inner.address_space.replace(program.address_space().clone())
+        };
+        drop(thread::set_address_space(Some(
+            program.address_space().clone(),
+        )));
+        thread::set_fs_base(program.fs_base());
+        // Freed once the threads that are stopping are done with it
+        drop(old);
+    }
+
+    /// End the process, see the top of the module for when its threads stop
+    ///
+    /// Does nothing if it is exiting already.
+    pub fn kill(&self) {
+        self.exit(ExitStatus::Killed);
+    }
+
+    /// Block until the process is a zombie, returns how it ended
+    ///
+    /// This reaps it, it is no longer its parent's child. Unlike
+    /// `wait_ended()`, kills and signals don't end the wait.
+    pub fn wait(self: &Arc<Self>) -> ExitStatus {
+        self.exited.wait_until(|| self.inner.lock().zombie);
+        let status = self
+            .exit_status()
+            .expect("process: zombie without an exit status");
+        if let Some(parent) = self.parent() {
+            parent
+                .inner
+                .lock()
+                .children
+                .retain(|child| !Arc::ptr_eq(child, self));
+        }
+        status
+    }
+
+    /// Block until the process is a zombie, returns how it ended without
+    /// reaping it
+    ///
+    /// `EINTR` if the caller's process is killed or sent a signal first.
+    pub fn wait_ended(&self) -> Result<ExitStatus, Errno> {
+        wait_interruptible(&self.exited, || self.inner.lock().zombie)?;
+        Ok(self
+            .exit_status()
+            .expect("process: zombie without an exit status"))
+    }
+
+    /// Block until a child is a zombie and reap it: the child with pid `pid`,
+    /// or any with `None`; returns its pid and how it ended
+    ///
+    /// With `block` false returns `None` instead of blocking. `ECHILD` if
+    /// there is no such child, `EINTR` if the process is killed or sent a
+    /// signal while it waits.
+    pub fn wait_child(
+        &self,
+        pid: Option<u64>,
+        block: bool,
+    ) -> Result<Option<(Pid, ExitStatus)>, Errno> {
+        let mut found = None;
+        wait_interruptible(&self.child_exited, || {
+            let inner = self.inner.lock();
+            let mut children = inner
+                .children
+                .iter()
+                .filter(|child| pid.is_none_or(|pid| child.pid.0 == pid))
+                .peekable();
+            if children.peek().is_none() {
+                found = Some(Err(Errno::ECHILD));
+                return true;
+            }
+            if let Some(child) = children.find(|child| child.inner.lock().zombie) {
+                found = Some(Ok(child.clone()));
+                return true;
+            }
+            !block
+        })?;
+        match found {
+            Some(Ok(child)) => Ok(Some((child.pid, child.wait()))),
+            Some(Err(errno)) => Err(errno),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the thread `thread` of the process must stop running its code
+    fn must_stop(&self, thread: &Thread) -> bool {
+        let exec_thread = self.exec_thread.load(Ordering::Relaxed);
+        self.is_exiting() || (exec_thread != 0 && exec_thread != thread.id().as_u64() + 1)
+    }
+
+    /// Start exiting with `status`, unless already exiting
+    fn exit(&self, status: ExitStatus) {
+        let mut inner = self.inner.lock();
+        inner.status.get_or_insert(status);
+        self.exiting.store(true, Ordering::Relaxed);
+        drop(inner);
+        // Stopped and blocked threads have to go and stop for good
+        self.continued.wake_all();
+        self.interrupt();
+    }
+
+    /// Whether a thread of the process must give up waiting in
+    /// `wait_interruptible()`: the process is exiting or has signals to act on
+    fn interrupted(&self) -> bool {
+        self.is_exiting() || self.has_signals()
+    }
+
+    /// Wake the threads of the process blocked in `wait_interruptible()`, to
+    /// see whether they are `interrupted()`
+    ///
+    /// Must not be called with the process locked, their conditions lock it.
+    fn interrupt(&self) {
+        for queue in self.blocked_on.lock().iter() {
+            // The waiting thread takes it out before it stops borrowing it
+            unsafe { &*queue.0 }.wake_all();
+        }
+    }
+
+    /// `thread` stopped running the process's code, the last one to do so
+    /// makes it a zombie
+    fn thread_exited(&self, thread: &Arc<Thread>) {
+        let (address_space, files, children, parent) = {
+            let mut inner = self.inner.lock();
+            inner.threads.retain(|t| !Arc::ptr_eq(t, thread));
+            inner.live_threads -= 1;
+            if inner.live_threads == 1 {
+                // The threads `exec()` stopped are gone
+                self.exec_thread.store(0, Ordering::Relaxed);
+            }
+            if inner.live_threads > 0 {
+                return;
+            }
+            inner.zombie = true;
+            (
+                inner.address_space.take(),
+                core::mem::take(&mut inner.files),
+                core::mem::take(&mut inner.children),
+                inner.parent.upgrade(),
+            )
+        };
+        // Outside the lock, unmapping takes locks of its own
+        drop(address_space);
+        drop(files);
+        let init =
+            init_process().filter(|init| !core::ptr::eq(&**init, self) && !init.is_exiting());
+        for child in children {
+            let zombie = {
+                let mut inner = child.inner.lock();
+                inner.parent = init.as_ref().map_or_else(Weak::new, Arc::downgrade);
+                inner.zombie
+            };
+            if let Some(init) = &init {
+                init.inner.lock().children.push(child);
+                if zombie {
+                    init.child_exited.wake_all();
+                }
+            }
+        }
+        self.exited.wake_all();
+        if let Some(parent) = parent {
+            parent.child_exited.wake_all();
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        PROCESSES.lock().remove(&self.pid.0);
+    }
+}
+
+/// Handles to processes are neither read nor written, `WRITE` lets their
+/// holder signal the process and `READ` wait for it to end
+impl File for Process {}
+
+/// Body of the threads of processes
+fn run_thread(process: Arc<Process>, context: UserContext, fs_base: VirtAddr) {
+    let current = thread::current();
+    let address_space = {
+        let mut inner = process.inner.lock();
+        inner.threads.push(current.clone());
+        inner.address_space.clone()
+    };
+    thread::set_process(Some(process.clone()));
+    let kernel = thread::set_address_space(address_space);
+    thread::set_fs_base(fs_base);
+    let mut context = context;
+    let exit = loop {
+        // Killed before it got to run, or by a signal
+        if process.must_stop(&current) || !process.handle_signals(&mut context) {
+            break UserExit::Killed;
+        }
+        match unsafe { usermode::enter_context(&context) } {
+            UserExit::Resume(resume) => context = resume,
+            UserExit::Faulted(fault, faulted) if process.catch_fault(&fault) => context = faulted,
+            exit => break exit,
+        }
+    };
+    thread::set_fs_base(VirtAddr::zero());
+    drop(thread::set_address_space(kernel));
+    thread::set_process(None);
+
+    match exit {
+        UserExit::Exited(status) => process.exit(ExitStatus::Exited(status)),
+        UserExit::Faulted(fault, _) => {
+            serial_println!(
+                "process: {} ({}) killed, {:?} at {:#x}, error code {:#x}, address {:?}",
+                process.pid.0,
+                process.name(),
+                fault.kind,
+                fault.instruction_pointer.as_u64(),
+                fault.error_code,
+                fault.address.map(VirtAddr::as_u64)
+            );
+            process.exit(ExitStatus::Faulted(fault));
+        }
+        // Someone else set the status, or `exec()` stopped it
+        UserExit::Killed | UserExit::Resume(_) => {}
+    }
+    process.thread_exited(&current);
+}
+
+/// Load the program at `path` in the initial ramdisk into a new address
+/// space, for `spawn()` or `Process::exec()`
+///
+/// The signal trampoline is mapped in it too.
+pub fn load(path: &str, argv: &[&str], envp: &[&str]) -> Result<Program, SpawnError> {
+    let file = initrd::find(path)
+        .filter(|file| file.is_regular())
+        .ok_or(SpawnError::NotFound)?;
+    let program = Program::load(file.data, argv, envp).map_err(SpawnError::Elf)?;
+    signal::map_trampoline(program.address_space())
+        .map_err(|_| SpawnError::Elf(ElfError::OutOfMemory))?;
+    Ok(program)
+}
+
+/// File name of the program at `path`, what processes running it are called
+pub fn program_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Start the program at `path` in the initial ramdisk in a new process
+///
+/// The process is a child of the running thread's, if that belongs to one.
+pub fn spawn(path: &str, argv: &[&str], envp: &[&str]) -> Result<Arc<Process>, SpawnError> {
+    spawn_with_handles(path, argv, envp, [])
+}
+
+/// `spawn()` with `handles` open on descriptors 3 and up
+///
+/// How the kernel hands a program objects it can't open itself, like the
+/// devices a driver in user space runs (see `device`).
+pub fn spawn_with_handles(
+    path: &str,
+    argv: &[&str],
+    envp: &[&str],
+    handles: impl IntoIterator<Item = Handle>,
+) -> Result<Arc<Process>, SpawnError> {
+    let program = load(path, argv, envp)?;
+    let mut files = FdTable::with_console();
+    for handle in handles {
+        files
+            .insert(handle)
+            .expect("process: too many handles to spawn with");
+    }
+    let process = Process::new(
+        program_name(path),
+        program.address_space().clone(),
+        files,
+        Signals::new(),
+        current().as_ref(),
+    );
+    process.spawn_thread(program.entry(), program.stack_pointer(), program.fs_base());
+    Ok(process)
+}
+
+/// Make `process` the init process, which gets the children of processes
+/// that end
+///
+/// Without one, or once it ends, those children have no parent.
+pub fn set_init(process: &Arc<Process>) {
+    *INIT.lock() = Arc::downgrade(process);
+}
+
+/// Where the init process's program is, in the initial ramdisk
+pub const INIT_PATH: &str = "/bin/init";
+
+/// Start `INIT_PATH` and make it the init process, the first process the
+/// kernel starts
+pub fn start_init() -> Result<Arc<Process>, SpawnError> {
+    let init = spawn(INIT_PATH, &["init"], &[])?;
+    set_init(&init);
+    Ok(init)
+}
+
+/// The init process, see `set_init()`
+pub fn init_process() -> Option<Arc<Process>> {
+    INIT.lock().upgrade()
+}
+
+/// The process the running thread belongs to
+pub fn current() -> Option<Arc<Process>> {
+    thread::current().process()
+}
+
+/// Block the running thread on `queue` until `condition` returns true, like
+/// `WaitQueue::wait_until()`, but give up with `EINTR` once its process is
+/// exiting or has signals to act on
+///
+/// Killing the process or sending it a signal wakes the queue. Threads
+/// outside processes wait like `wait_until()`.
+pub fn wait_interruptible(
+    queue: &WaitQueue,
+    mut condition: impl FnMut() -> bool,
+) -> Result<(), Errno> {
+    let Some(process) = current() else {
+        queue.wait_until(condition);
+        return Ok(());
+    };
+    let queue_ptr: *const WaitQueue = queue;
+    process.blocked_on.lock().push(BlockedOn(queue_ptr));
+    let mut interrupted = false;
+    queue.wait_until(|| {
+        if condition() {
+            return true;
+        }
+        interrupted = process.interrupted();
+        interrupted
+    });
+    let mut blocked_on = process.blocked_on.lock();
+    let index = blocked_on
+        .iter()
+        .position(|blocked| core::ptr::eq(blocked.0, queue_ptr))
+        .expect("process: blocked queue vanished");
+    blocked_on.swap_remove(index);
+    if interrupted {
+        Err(Errno::EINTR)
+    } else {
+        Ok(())
+    }
+}
+
+/// The process with pid `pid`, zombies included, unless it is gone
+pub fn find(pid: u64) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).and_then(Weak::upgrade)
+}
+
+/// Stop the running thread's user code, about to go on from `context`, if its
+/// process is exiting or has `exec()`ed on another thread, or if it has
+/// signals to act on
+///
+/// Called on the way back to user mode, by system calls and the timer interrupt.
+pub fn returning_to_user(context: &UserContext) {
+    if !usermode::in_user_thread() {
+        return;
+    }
+    let current = thread::current();
+    let exit = current.process().and_then(|process| {
+        if process.must_stop(&current) {
+            Some(UserExit::Killed)
+        } else if process.has_signals() {
+            // `run_thread()` acts on them and goes back
+            Some(UserExit::Resume(*context))
+        } else {
+            None
+        }
+    });
+    drop(current);
+    if let Some(exit) = exit {
+        usermode::leave(exit);
+    }
+}
+
+#[test_case]
+fn test_spawn_wait() {
+    let process = spawn("/bin/hello", &["hello"], &[]).expect("spawn failed");
+    let pid = process.pid().as_u64();
+    assert!(process.parent().is_none());
+    assert!(Arc::ptr_eq(&find(pid).unwrap(), &process));
+
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+    assert!(process.address_space().is_none());
+    assert!(process.threads().is_empty());
+    assert!(!process.spawn_thread(VirtAddr::zero(), VirtAddr::zero(), VirtAddr::zero()));
+    drop(process);
+    assert!(find(pid).is_none());
+    assert!(matches!(
+        spawn("/bin/nonexistent", &[], &[]),
+        Err(SpawnError::NotFound)
+    ));
+}
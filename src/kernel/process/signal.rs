@@ -0,0 +1,548 @@
+use super::{ExitStatus, Process};
+use crate::kernel::address_space::AddressSpace;
+use crate::kernel::memory;
+use crate::kernel::sync::IrqSafeSpinLock;
+use crate::kernel::syscall::{self, Errno, SyscallArg, UserPtr};
+use crate::kernel::usermode::{FaultKind, UserContext, UserFault};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+// Signals, POSIX-like, with Linux's numbers.
+//
+// A process has a set of pending signals, a set of blocked ones and an action
+// for each: the default, ignoring it, or running a handler. Signals are acted
+// on by a thread of the process on its way back to user mode, when a system
+// call returns or the timer interrupts its user code: it leaves with
+// `UserExit::Resume` and `handle_signals()` runs before it goes back. A
+// thread blocked in a system call gets to them once the call returns, which
+// a signal makes waits in `wait_interruptible()` do early with `EINTR`.
+//
+// To run a handler the kernel pushes a `SignalFrame` with the interrupted
+// registers on the user stack, and the address of the trampoline as the
+// return address: `ret` from the handler lands in it, and its `sigreturn`
+// puts the registers back. The trampoline is a page of code the kernel maps
+// at the same address in every process.
+//
+// An exception in user code raises the signal for it (`SIGSEGV` for a page
+// fault and so on), which ends the process unless it has a handler for it.
+// `SIGKILL` and `SIGSTOP` can't be caught, blocked or ignored.
+
+/// A signal number, 1 to 31
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const SIGHUP: Signal = Signal(1);
+    pub const SIGINT: Signal = Signal(2);
+    pub const SIGQUIT: Signal = Signal(3);
+    pub const SIGILL: Signal = Signal(4);
+    pub const SIGTRAP: Signal = Signal(5);
+    pub const SIGABRT: Signal = Signal(6);
+    pub const SIGBUS: Signal = Signal(7);
+    pub const SIGFPE: Signal = Signal(8);
+    pub const SIGKILL: Signal = Signal(9);
+    pub const SIGUSR1: Signal = Signal(10);
+    pub const SIGSEGV: Signal = Signal(11);
+    pub const SIGUSR2: Signal = Signal(12);
+    pub const SIGPIPE: Signal = Signal(13);
+    pub const SIGALRM: Signal = Signal(14);
+    pub const SIGTERM: Signal = Signal(15);
+    pub const SIGCHLD: Signal = Signal(17);
+    pub const SIGCONT: Signal = Signal(18);
+    pub const SIGSTOP: Signal = Signal(19);
+    pub const SIGTSTP: Signal = Signal(20);
+    pub const SIGTTIN: Signal = Signal(21);
+    pub const SIGTTOU: Signal = Signal(22);
+    pub const SIGURG: Signal = Signal(23);
+    pub const SIGWINCH: Signal = Signal(28);
+
+    /// The signal numbered `number`, if there is one
+    pub fn new(number: u32) -> Option<Signal> {
+        (1..=31).contains(&number).then_some(Signal(number as u8))
+    }
+
+    pub fn number(self) -> u32 {
+        u32::from(self.0)
+    }
+
+    /// The signal an exception in user code raises
+    pub fn for_fault(kind: FaultKind) -> Signal {
+        match kind {
+            FaultKind::DivideError | FaultKind::FloatingPoint => Signal::SIGFPE,
+            FaultKind::Breakpoint => Signal::SIGTRAP,
+            FaultKind::InvalidOpcode => Signal::SIGILL,
+            FaultKind::AlignmentCheck => Signal::SIGBUS,
+            FaultKind::SegmentNotPresent
+            | FaultKind::StackSegment
+            | FaultKind::GeneralProtection
+            | FaultKind::PageFault => Signal::SIGSEGV,
+        }
+    }
+
+    /// What happens to a process that neither handles nor ignores it
+    pub fn default_action(self) -> DefaultAction {
+        match self {
+            Signal::SIGCHLD | Signal::SIGURG | Signal::SIGWINCH => DefaultAction::Ignore,
+            Signal::SIGCONT => DefaultAction::Continue,
+            Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU => {
+                DefaultAction::Stop
+            }
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    /// `SIGKILL` and `SIGSTOP` always get their default action
+    pub fn is_catchable(self) -> bool {
+        self != Signal::SIGKILL && self != Signal::SIGSTOP
+    }
+
+    fn index(self) -> usize {
+        usize::from(self.0 - 1)
+    }
+}
+
+/// See `Signal::default_action()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    /// Resume the process if it is stopped
+    Continue,
+}
+
+/// A set of signals, signal n in bit n - 1 like Linux's `sigset_t`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    pub const fn empty() -> Self {
+        SigSet(0)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        SigSet(bits & 0x7fff_ffff)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, signal: Signal) -> bool {
+        self.0 & 1 << signal.index() != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= 1 << signal.index();
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !(1 << signal.index());
+    }
+
+    /// The set without the signals that can't be blocked
+    fn blockable(mut self) -> Self {
+        self.remove(Signal::SIGKILL);
+        self.remove(Signal::SIGSTOP);
+        self
+    }
+
+    /// The lowest numbered signal in the set
+    fn first(self) -> Option<Signal> {
+        (self.0 != 0).then(|| Signal(self.0.trailing_zeros() as u8 + 1))
+    }
+}
+
+/// `SigAction::handler` for the default action
+pub const SIG_DFL: u64 = 0;
+/// `SigAction::handler` to ignore the signal
+pub const SIG_IGN: u64 = 1;
+
+/// `SigAction::flags`: return from the handler to `restorer` instead of the
+/// kernel's trampoline
+pub const SA_RESTORER: u64 = 0x0400_0000;
+/// `SigAction::flags`: don't block the signal while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+/// `SigAction::flags`: back to the default action once the handler runs
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// What a process does with a signal, laid out like Linux's kernel `sigaction`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    /// `SIG_DFL`, `SIG_IGN` or the address of the handler, called with the
+    /// signal number and the address of the saved `UserContext`
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    /// Signals blocked while the handler runs, besides the signal itself
+    pub mask: u64,
+}
+
+impl SigAction {
+    /// Whether the signal is thrown away, with this action
+    fn ignores(&self, signal: Signal) -> bool {
+        self.handler == SIG_IGN
+            || (self.handler == SIG_DFL && signal.default_action() == DefaultAction::Ignore)
+    }
+}
+
+/// What the kernel pushes on the user stack, above the handler's return
+/// address, to run a handler; `sigreturn` takes it back
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFrame {
+    /// The registers the handler interrupted
+    pub context: UserContext,
+    /// The blocked signals before the handler ran
+    pub blocked: u64,
+    pub signal: u64,
+}
+
+/// `sigprocmask` `how`: block the signals in the set too
+pub const SIG_BLOCK: u32 = 0;
+/// `sigprocmask` `how`: unblock the signals in the set
+pub const SIG_UNBLOCK: u32 = 1;
+/// `sigprocmask` `how`: block the signals in the set only
+pub const SIG_SETMASK: u32 = 2;
+
+/// A process's signals, in its lock
+#[derive(Clone, Copy)]
+pub(super) struct Signals {
+    pending: SigSet,
+    blocked: SigSet,
+    actions: [SigAction; 31],
+    stopped: bool,
+}
+
+impl Signals {
+    pub(super) fn new() -> Self {
+        Signals {
+            pending: SigSet::empty(),
+            blocked: SigSet::empty(),
+            actions: [SigAction::default(); 31],
+            stopped: false,
+        }
+    }
+
+    /// What a child forked with these gets: the same actions and blocked
+    /// signals, nothing pending
+    pub(super) fn forked(&self) -> Self {
+        Signals {
+            pending: SigSet::empty(),
+            stopped: false,
+            ..*self
+        }
+    }
+
+    /// For a new program: handlers are gone, ignored signals stay ignored
+    pub(super) fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::default();
+            }
+        }
+    }
+
+    fn deliverable(&self) -> SigSet {
+        SigSet(self.pending.0 & !self.blocked.0)
+    }
+}
+
+/// Where the trampoline that calls `sigreturn` is in every process, the last
+/// page of user space
+pub const TRAMPOLINE: u64 = memory::USER_END - 4096;
+
+// `mov eax, SYS_SIGRETURN; syscall; ud2`
+const TRAMPOLINE_CODE: [u8; 9] = [
+    0xb8,
+    syscall::SYS_SIGRETURN as u8,
+    0,
+    0,
+    0,
+    0x0f,
+    0x05,
+    0x0f,
+    0x0b,
+];
+
+// The frame holding the trampoline, allocated on first use and never freed
+static TRAMPOLINE_FRAME: IrqSafeSpinLock<Option<PhysFrame>> =
+    IrqSafeSpinLock::new("trampoline", None);
+
+/// Map the trampoline into `address_space`, read-only
+pub(super) fn map_trampoline(address_space: &AddressSpace) -> Result<(), MapToError<Size4KiB>> {
+    let frame = {
+        let mut frame = TRAMPOLINE_FRAME.lock();
+        match *frame {
+            Some(frame) => frame,
+            None => {
+                let new = memory::with_memory(|mm| mm.frame_allocator.allocate_frame())
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    let page = memory::phys_to_virt(new.start_address()).as_mut_ptr::<u8>();
+                    page.write_bytes(0, 4096);
+                    page.copy_from_nonoverlapping(TRAMPOLINE_CODE.as_ptr(), TRAMPOLINE_CODE.len());
+                }
+                *frame.insert(new)
+            }
+        }
+    };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    address_space.map_shared(VirtAddr::new(TRAMPOLINE), frame, flags)
+}
+
+impl Process {
+    /// Send `signal` to the process
+    ///
+    /// `SIGKILL` kills it right away, `SIGCONT` resumes it if it is stopped.
+    /// Signals it ignores are thrown away.
+    pub fn send_signal(&self, signal: Signal) {
+        if signal == Signal::SIGKILL {
+            return self.kill();
+        }
+        let mut inner = self.inner.lock();
+        let signals = &mut inner.signals;
+        match signal.default_action() {
+            DefaultAction::Continue => {
+                signals.stopped = false;
+                for stop in [
+                    Signal::SIGSTOP,
+                    Signal::SIGTSTP,
+                    Signal::SIGTTIN,
+                    Signal::SIGTTOU,
+                ] {
+                    signals.pending.remove(stop);
+                }
+            }
+            DefaultAction::Stop => signals.pending.remove(Signal::SIGCONT),
+            _ => {}
+        }
+        if !signals.actions[signal.index()].ignores(signal) {
+            signals.pending.insert(signal);
+        }
+        drop(inner);
+        // Unlocked, the threads waiting check their conditions with it locked
+        if signal.default_action() == DefaultAction::Continue {
+            self.continued.wake_all();
+        }
+        self.interrupt();
+    }
+
+    /// Set the action for `signal` to `action` unless it is `None`, returns the
+    /// previous one
+    ///
+    /// `EINVAL` for `SIGKILL` and `SIGSTOP`, for unknown flags, and for a
+    /// handler or restorer outside user space.
+    pub fn sigaction(&self, signal: Signal, action: Option<SigAction>) -> Result<SigAction, Errno> {
+        if let Some(action) = action {
+            let handler_ok = matches!(action.handler, SIG_DFL | SIG_IGN) || in_user(action.handler);
+            let restorer_ok = action.flags & SA_RESTORER == 0 || in_user(action.restorer);
+            if !signal.is_catchable()
+                || action.flags & !(SA_RESTORER | SA_NODEFER | SA_RESETHAND) != 0
+                || !handler_ok
+                || !restorer_ok
+            {
+                return Err(Errno::EINVAL);
+            }
+        }
+        let mut inner = self.inner.lock();
+        let signals = &mut inner.signals;
+        let old = signals.actions[signal.index()];
+        if let Some(action) = action {
+            signals.actions[signal.index()] = action;
+            if action.ignores(signal) {
+                signals.pending.remove(signal);
+            }
+        }
+        Ok(old)
+    }
+
+    /// Change the blocked signals with `set` as `how` says (`SIG_BLOCK`,
+    /// `SIG_UNBLOCK` or `SIG_SETMASK`), returns the previous ones
+    ///
+    /// `SIGKILL` and `SIGSTOP` are left out of `set`.
+    pub fn sigprocmask(&self, how: u32, set: SigSet) -> Result<SigSet, Errno> {
+        let mut inner = self.inner.lock();
+        let blocked = &mut inner.signals.blocked;
+        let old = *blocked;
+        let set = set.blockable();
+        *blocked = match how {
+            SIG_BLOCK => SigSet(old.0 | set.0),
+            SIG_UNBLOCK => SigSet(old.0 & !set.0),
+            SIG_SETMASK => set,
+            _ => return Err(Errno::EINVAL),
+        };
+        Ok(old)
+    }
+
+    /// End the process as `signal`'s default action would, whatever its
+    /// action for it
+    pub fn terminate(&self, signal: Signal) {
+        self.exit(ExitStatus::Signaled(signal));
+    }
+
+    /// Signals sent and not acted on yet
+    pub fn pending_signals(&self) -> SigSet {
+        self.inner.lock().signals.pending
+    }
+
+    /// Whether a stop signal stopped the process, until a `SIGCONT`
+    pub fn is_stopped(&self) -> bool {
+        self.inner.lock().signals.stopped
+    }
+
+    /// Whether a thread of the process on its way back to user mode has
+    /// signals to act on
+    pub(super) fn has_signals(&self) -> bool {
+        let inner = self.inner.lock();
+        inner.signals.stopped || inner.signals.deliverable() != SigSet::empty()
+    }
+
+    /// Make the signal for `fault` pending if the process has a handler for
+    /// it that can run, returns whether it had
+    ///
+    /// Otherwise the fault ends the process.
+    pub(super) fn catch_fault(&self, fault: &UserFault) -> bool {
+        let signal = Signal::for_fault(fault.kind);
+        let mut inner = self.inner.lock();
+        let signals = &mut inner.signals;
+        let handler = signals.actions[signal.index()].handler;
+        if handler == SIG_DFL || handler == SIG_IGN || signals.blocked.contains(signal) {
+            return false;
+        }
+        signals.pending.insert(signal);
+        true
+    }
+
+    /// Act on the signals pending for a thread about to go back to user code
+    /// with `context`: run their default actions, or set `context` up to run
+    /// a handler
+    ///
+    /// Blocks while the process is stopped. Returns false if the process is
+    /// exiting.
+    pub(super) fn handle_signals(&self, context: &mut UserContext) -> bool {
+        loop {
+            let mut inner = self.inner.lock();
+            if self.is_exiting() {
+                return false;
+            }
+            if inner.signals.stopped {
+                drop(inner);
+                self.continued
+                    .wait_until(|| !self.inner.lock().signals.stopped || self.is_exiting());
+                continue;
+            }
+            let signals = &mut inner.signals;
+            let Some(signal) = signals.deliverable().first() else {
+                return true;
+            };
+            signals.pending.remove(signal);
+            let action = signals.actions[signal.index()];
+            match action.handler {
+                SIG_IGN => {}
+                SIG_DFL => match signal.default_action() {
+                    DefaultAction::Ignore | DefaultAction::Continue => {}
+                    DefaultAction::Stop => signals.stopped = true,
+                    DefaultAction::Terminate => {
+                        drop(inner);
+                        self.exit(ExitStatus::Signaled(signal));
+                        return false;
+                    }
+                },
+                handler => {
+                    let blocked = signals.blocked;
+                    let mut mask = SigSet::from_bits(action.mask);
+                    if action.flags & SA_NODEFER == 0 {
+                        mask.insert(signal);
+                    }
+                    signals.blocked = SigSet(blocked.0 | mask.blockable().0);
+                    if action.flags & SA_RESETHAND != 0 {
+                        signals.actions[signal.index()] = SigAction::default();
+                    }
+                    drop(inner);
+                    let restorer = if action.flags & SA_RESTORER != 0 {
+                        action.restorer
+                    } else {
+                        TRAMPOLINE
+                    };
+                    if push_frame(context, signal, handler, restorer, blocked).is_err() {
+                        // No room on its stack or nowhere to go, there is
+                        // nothing to go back to
+                        self.exit(ExitStatus::Signaled(Signal::SIGSEGV));
+                        return false;
+                    }
+                    // The others once it has returned
+                    return true;
+                }
+            }
+        }
+    }
+}
+
+/// Whether `addr` is in user space, where handlers and restorers must be
+fn in_user(addr: u64) -> bool {
+    (memory::USER_START..memory::USER_END).contains(&addr)
+}
+
+/// Push the frame for running `handler` for `signal` on the stack of
+/// `context`, and point `context` at the handler
+///
+/// `EFAULT` if `handler` or `restorer` is outside user space, `sigaction()`
+/// keeps them out but returning to one would fault in the kernel.
+fn push_frame(
+    context: &mut UserContext,
+    signal: Signal,
+    handler: u64,
+    restorer: u64,
+    blocked: SigSet,
+) -> Result<(), Errno> {
+    if !in_user(handler) || !in_user(restorer) {
+        return Err(Errno::EFAULT);
+    }
+    // Below the red zone, the 128 bytes the interrupted code may use below
+    // its stack pointer
+    let frame_addr = context
+        .rsp
+        .checked_sub(128 + size_of::<SignalFrame>() as u64)
+        .ok_or(Errno::EFAULT)?
+        & !15;
+    let frame = SignalFrame {
+        context: *context,
+        blocked: blocked.bits(),
+        signal: u64::from(signal.number()),
+    };
+    UserPtr::<SignalFrame>::decode(frame_addr)?.write(frame)?;
+    // The handler starts as if called, the stack aligned for that
+    let return_addr = frame_addr - 8;
+    UserPtr::<u64>::decode(return_addr)?.write(restorer)?;
+
+    context.rip = handler;
+    context.rsp = return_addr;
+    context.rdi = u64::from(signal.number());
+    context.rsi = frame_addr;
+    // The ABI wants the direction flag clear on function entry
+    context.rflags &= !0x400;
+    Ok(())
+}
+
+#[test_case]
+fn test_signal_numbers() {
+    assert_eq!(Signal::new(0), None);
+    assert_eq!(Signal::new(32), None);
+    assert_eq!(Signal::new(15), Some(Signal::SIGTERM));
+    assert_eq!(Signal::for_fault(FaultKind::PageFault), Signal::SIGSEGV);
+    assert_eq!(Signal::SIGTSTP.default_action(), DefaultAction::Stop);
+    assert_eq!(Signal::SIGCHLD.default_action(), DefaultAction::Ignore);
+
+    let mut set = SigSet::from_bits(u64::MAX).blockable();
+    assert!(!set.contains(Signal::SIGKILL) && !set.contains(Signal::SIGSTOP));
+    assert_eq!(set.first(), Some(Signal::SIGHUP));
+    set = SigSet::empty();
+    set.insert(Signal::SIGUSR2);
+    set.insert(Signal::SIGUSR1);
+    assert_eq!(set.bits(), 0b11 << 9);
+    assert_eq!(set.first(), Some(Signal::SIGUSR1));
+    assert_eq!(size_of::<SignalFrame>(), size_of::<UserContext>() + 16);
+}
@@ -0,0 +1,94 @@
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid_count, _rdtsc};
+use x86_64::instructions::random::RdRand;
+
+/// Where a seed came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    /// Fixed at build time, see `kernel::aslr`
+    Fixed,
+    RdSeed,
+    RdRand,
+    /// Timing jitter of the time stamp counter, the fallback for CPUs (like QEMU's
+    /// default `qemu64` model) without RDSEED/RDRAND
+    TscJitter,
+}
+
+/// Get a 64-bit seed from the best entropy source the CPU offers
+pub fn hardware_seed() -> (u64, EntropySource) {
+    if let Some(seed) = rdseed() {
+        return (seed, EntropySource::RdSeed);
+    }
+    if let Some(seed) = RdRand::new().and_then(|r| r.get_u64()) {
+        return (seed, EntropySource::RdRand);
+    }
+    (tsc_jitter(), EntropySource::TscJitter)
+}
+
+fn rdseed() -> Option<u64> {
+    // CPUID.(EAX=07H, ECX=0H):EBX.RDSEED[bit 18]
+    let has_rdseed = __cpuid_count(7, 0).ebx & (1 << 18) != 0;
+    if !has_rdseed {
+        return None;
+    }
+    // RDSEED may transiently fail (CF=0) when the entropy pool is drained, retry a bit
+    for _ in 0..64 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}; setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        }
+        if ok == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Gather entropy from the jitter between consecutive TSC reads
+///
+/// The number of cycles a small busy loop takes varies with cache, TLB and
+/// interrupt state, the low bits of each measurement are folded into the seed.
+fn tsc_jitter() -> u64 {
+    let mut seed = unsafe { _rdtsc() };
+    for _ in 0..64 {
+        let start = unsafe { _rdtsc() };
+        for i in 0..(start & 0xff) {
+            core::hint::black_box(i);
+        }
+        let delta = unsafe { _rdtsc() } - start;
+        seed = (seed.rotate_left(7) ^ delta).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+    seed
+}
+
+/// Small non-cryptographic PRNG (splitmix64), good enough to spread layout choices
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in `0..bound`
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+#[test_case]
+fn test_rng_is_deterministic() {
+    let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+    for _ in 0..16 {
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+    assert!(Rng::new(1).below(10) < 10);
+}
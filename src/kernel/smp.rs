@@ -0,0 +1,316 @@
+use crate::kernel::boot::{MemoryKind, MemoryMap};
+use crate::kernel::thread::stack::KernelStack;
+use crate::kernel::thread::{self, scheduler};
+use crate::kernel::{acpi, apic, cpu, gdt, interrupts, memory, percpu, syscall};
+use crate::serial_println;
+use alloc::boxed::Box;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Starting the application processors (APs), the CPUs other than the one we
+// booted on.
+//
+// The CPUs are listed in the ACPI MADT. An AP waits for an INIT and a startup
+// IPI from the boot CPU, then starts in real mode at the 4 KiB page below 1 MiB
+// the startup IPI names. The trampoline below is copied there: it switches
+// straight to long mode on the kernel page tables (the page is identity mapped
+// for it) and calls `ap_main()` on a kernel stack of its own.
+//
+// APs are started one at a time, they share the trampoline. Each gets its own
+// per-CPU block, GDT, TSS and double fault stack, loads the shared IDT and
+// ends up in the idle loop of its own run queue.
+
+/// Timer ticks to wait after INIT before sending the startup IPI (10 ms)
+const INIT_DELAY_TICKS: u64 = 2;
+/// Timer ticks to wait for an AP to run after a startup IPI, before sending another
+const STARTUP_TIMEOUT_TICKS: u64 = 2;
+/// Timer ticks to wait for an AP to finish setting up
+const ONLINE_TIMEOUT_TICKS: u64 = 100;
+
+// Physical address of the page reserved for the trampoline, 0 if there is none
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+// Set by the AP being started, once in `ap_main()` and once it is online
+static AP_RUNNING: AtomicBool = AtomicBool::new(false);
+static AP_ONLINE: AtomicBool = AtomicBool::new(false);
+
+core::arch::global_asm!(
+    r#"
+.section .rodata.ap_trampoline, "a"
+.balign 16
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    // We start at offset 0 of our page, with CS set to the page's segment
+    mov ax, cs
+    mov ds, ax
+    // lgdt [ap_gdt_ptr], with a 32 bit base: operand size prefix, 0f 01 /2, disp16
+    .byte 0x66, 0x0f, 0x01, 0x16
+    .short ap_gdt_ptr - ap_trampoline_start
+    // CR4.PAE
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    // mov eax, [ap_cr3]: operand size prefix, a1, disp16
+    .byte 0x66, 0xa1
+    .short ap_cr3 - ap_trampoline_start
+    mov cr3, eax
+    // EFER.LME and EFER.NXE
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    // CR0.PE, CR0.WP and CR0.PG at once, straight to long mode
+    mov eax, cr0
+    or eax, (1 << 31) | (1 << 16) | 1
+    mov cr0, eax
+    // jmp far [ap_long_jump], with a 32 bit offset: operand size prefix, ff /5, disp16
+    .byte 0x66, 0xff, 0x2e
+    .short ap_long_jump - ap_trampoline_start
+
+.code64
+.global ap_long_mode
+ap_long_mode:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, [rip + ap_stack]
+    mov rdi, [rip + ap_arg]
+    mov rax, [rip + ap_entry]
+    call rax
+2:
+    hlt
+    jmp 2b
+
+// Filled in by `start_ap()`, see `TrampolineParams`
+.global ap_trampoline_params
+ap_trampoline_params:
+ap_gdt:
+    .quad 0
+    // 64-bit ring 0 code segment
+    .quad 0x00af9a000000ffff
+ap_gdt_ptr:
+    .short 15
+    .long 0
+ap_long_jump:
+    .long 0
+    .short 0x08
+ap_cr3:
+    .quad 0
+ap_stack:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_arg:
+    .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_long_mode: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Layout of `ap_trampoline_params`
+#[repr(C, packed)]
+struct TrampolineParams {
+    gdt: [u64; 2],
+    gdt_limit: u16,
+    /// Physical address of `gdt`
+    gdt_base: u32,
+    /// Physical address of `ap_long_mode`
+    long_mode: u32,
+    code_selector: u16,
+    /// Kernel page tables, must be below 4 GiB
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    arg: u64,
+}
+
+/// What `ap_main()` gets from the boot CPU
+struct ApStart {
+    cpu: usize,
+    stack: KernelStack,
+}
+
+/// Offset of `symbol` within the trampoline
+fn trampoline_offset(symbol: &u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &ap_trampoline_start as *const u8 as usize }
+}
+
+/// Take a page below 1 MiB out of `memory_map` for the trampoline
+///
+/// Must run before the frame allocator is set up, or it might hand the page out.
+pub fn reserve_trampoline(memory_map: &mut MemoryMap) {
+    let page = memory_map
+        .regions()
+        .iter()
+        .filter(|r| r.kind == MemoryKind::Usable)
+        .filter_map(|r| {
+            // The highest whole page of the region that is below 1 MiB
+            let end = x86_64::align_down(r.end.min(0x10_0000), 4096);
+            let start = x86_64::align_up(r.start.max(0x1000), 4096);
+            (end > start).then_some(end - 4096)
+        })
+        .max();
+    if let Some(page) = page {
+        memory_map.reserve(page, page + 4096);
+        TRAMPOLINE.store(page, Ordering::Relaxed);
+    }
+}
+
+/// Start all the other CPUs the firmware lists
+///
+/// Must run on the boot CPU once threads are set up and the timer interrupt is
+/// enabled. CPUs that fail to start are reported and left alone.
+pub fn init() {
+    apic::init();
+    let boot_apic_id = apic::id().expect("smp: local APIC not enabled");
+    cpu::set_online();
+
+    let processors = acpi::processors();
+    let aps = processors
+        .iter()
+        .filter(|p| p.apic_id != boot_apic_id as u32);
+    if processors.len() <= 1 {
+        return;
+    }
+    let Some(trampoline) = trampoline_page() else {
+        serial_println!("smp: no memory for the AP trampoline, only running on the boot CPU");
+        return;
+    };
+
+    let mut next_cpu = 1;
+    for ap in aps {
+        if next_cpu == cpu::MAX_CPUS || ap.apic_id > u8::MAX as u32 {
+            serial_println!("smp: not starting CPU with APIC ID {}", ap.apic_id);
+            continue;
+        }
+        if start_ap(next_cpu, ap.apic_id as u8, trampoline) {
+            next_cpu += 1;
+        } else {
+            serial_println!("smp: CPU with APIC ID {} did not start", ap.apic_id);
+        }
+    }
+}
+
+/// Copy the trampoline to its page and identity map it, `None` if it can't be
+fn trampoline_page() -> Option<PhysFrame> {
+    let addr = TRAMPOLINE.load(Ordering::Relaxed);
+    if addr == 0 {
+        return None;
+    }
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(addr));
+    // The APs load CR3 in 32 bit mode
+    let (cr3, _) = Cr3::read();
+    if cr3.start_address().as_u64() >= 1 << 32 {
+        return None;
+    }
+
+    let page = Page::containing_address(VirtAddr::new(addr));
+    let mapped = memory::with_memory(|mm| {
+        match mm.mapper.translate_addr(page.start_address()) {
+            Some(phys) => phys == frame.start_address(),
+            // Writable for the parameters, executable for the code
+            None => unsafe {
+                mm.map_frame(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                )
+                .is_ok()
+            },
+        }
+    });
+    if !mapped {
+        return None;
+    }
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = trampoline_offset(&ap_trampoline_end);
+        assert!(len <= 4096, "smp: trampoline larger than a page");
+        ptr::copy_nonoverlapping(start, page.start_address().as_mut_ptr::<u8>(), len);
+    }
+    Some(frame)
+}
+
+/// Start the AP with local APIC ID `apic_id` as CPU `cpu`, returns whether it came online
+fn start_ap(cpu: usize, apic_id: u8, trampoline: PhysFrame) -> bool {
+    let Some(stack) = KernelStack::new() else {
+        return false;
+    };
+    let base = trampoline.start_address().as_u64();
+    let params = TrampolineParams {
+        gdt: [0, 0x00af_9a00_0000_ffff],
+        gdt_limit: 15,
+        gdt_base: (base + trampoline_offset(unsafe { &ap_trampoline_params }) as u64) as u32,
+        long_mode: (base + trampoline_offset(unsafe { &ap_long_mode }) as u64) as u32,
+        code_selector: 0x08,
+        cr3: Cr3::read().0.start_address().as_u64(),
+        stack_top: stack.top().as_u64(),
+        entry: ap_main as *const () as u64,
+        arg: Box::into_raw(Box::new(ApStart { cpu, stack })) as u64,
+    };
+    unsafe {
+        let offset = trampoline_offset(&ap_trampoline_params);
+        let dest = (base + offset as u64) as *mut TrampolineParams;
+        // Identity mapped by `trampoline_page()`
+        ptr::write_unaligned(dest, params);
+    }
+
+    AP_RUNNING.store(false, Ordering::Relaxed);
+    AP_ONLINE.store(false, Ordering::Relaxed);
+    let page = (base >> 12) as u8;
+    unsafe {
+        apic::send_init(apic_id);
+        thread::sleep_ticks(INIT_DELAY_TICKS);
+        apic::send_startup(apic_id, page);
+        // A second startup IPI if the first one got lost, never once it's running
+        if !wait_for(&AP_RUNNING, STARTUP_TIMEOUT_TICKS) {
+            apic::send_startup(apic_id, page);
+        }
+    }
+    // On timeout the AP, its stack and `ApStart` are given up on for good
+    wait_for(&AP_ONLINE, ONLINE_TIMEOUT_TICKS)
+}
+
+/// Sleep until `flag` is set, for at most `timeout` ticks
+fn wait_for(flag: &AtomicBool, timeout: u64) -> bool {
+    let deadline = interrupts::ticks() + timeout;
+    while !flag.load(Ordering::Acquire) {
+        if interrupts::ticks() >= deadline {
+            return false;
+        }
+        thread::sleep_ticks(1);
+    }
+    true
+}
+
+/// First Rust code an AP runs, called by the trampoline
+extern "C" fn ap_main(start: *mut ApStart) -> ! {
+    AP_RUNNING.store(true, Ordering::Release);
+    let ApStart { cpu, stack } = *unsafe { Box::from_raw(start) };
+    percpu::init(cpu);
+    gdt::init_ap();
+    interrupts::idt_init();
+    syscall::init_cpu();
+    apic::init_ap();
+
+    thread::init_ap(stack);
+    cpu::set_online();
+    AP_ONLINE.store(true, Ordering::Release);
+    scheduler::idle();
+}
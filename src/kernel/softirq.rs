@@ -0,0 +1,146 @@
+use crate::kernel::percpu::{self, InterruptGuard};
+use crate::kernel::sync::IrqSafeSpinLock;
+use crate::percpu;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use x86_64::instructions::interrupts;
+
+// Tasklets: the deferred half of an interrupt handler ("bottom half").
+//
+// A handler does what can't wait (reading the device, acknowledging it) and
+// schedules a tasklet for the rest. Tasklets run on the CPU that scheduled
+// them, when the outermost interrupt handler is about to return, with
+// interrupts enabled again: a slow bottom half doesn't hold up other
+// interrupts. They are still in interrupt context and must not block, work
+// that has to sleep goes to a `workqueue`.
+
+/// A bottom half, usually a `static` next to the handler that schedules it
+pub struct Tasklet {
+    name: &'static str,
+    func: fn(),
+    // Queued and not run yet, a tasklet is queued at most once
+    scheduled: AtomicBool,
+    // Next in the CPU's pending list
+    next: AtomicPtr<Tasklet>,
+}
+
+// Tasklets scheduled on each CPU, in order
+struct Pending {
+    head: Option<&'static Tasklet>,
+    tail: Option<&'static Tasklet>,
+}
+
+percpu! {
+    static PENDING: IrqSafeSpinLock<Pending> = IrqSafeSpinLock::new(
+        "tasklets",
+        Pending {
+            head: None,
+            tail: None,
+        },
+    );
+}
+
+impl Tasklet {
+    pub const fn new(name: &'static str, func: fn()) -> Self {
+        Tasklet {
+            name,
+            func,
+            scheduled: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Run the tasklet once the interrupt handler we're in returns
+    ///
+    /// Returns false if it was scheduled already and hasn't run yet. Outside
+    /// an interrupt handler it runs right away.
+    pub fn schedule(&'static self) -> bool {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        let mut pending = PENDING.get().lock();
+        self.next.store(ptr::null_mut(), Ordering::Relaxed);
+        match pending.tail {
+            Some(tail) => tail
+                .next
+                .store(self as *const _ as *mut _, Ordering::Relaxed),
+            None => pending.head = Some(self),
+        }
+        pending.tail = Some(self);
+        drop(pending);
+
+        if !percpu::in_interrupt() {
+            // No handler is going to return and run it
+            interrupts::without_interrupts(|| drop(InterruptGuard::enter()));
+        }
+        true
+    }
+}
+
+/// Take the next tasklet scheduled on this CPU
+fn pop() -> Option<&'static Tasklet> {
+    let mut pending = PENDING.get().lock();
+    let tasklet = pending.head?;
+    let next = tasklet.next.load(Ordering::Relaxed);
+    pending.head = unsafe { next.as_ref() };
+    if pending.head.is_none() {
+        pending.tail = None;
+    }
+    Some(tasklet)
+}
+
+/// Run the tasklets scheduled on this CPU
+///
+/// Called as the outermost interrupt handler returns. Interrupts are enabled
+/// while the tasklets run, those nested in between leave theirs to us.
+pub(crate) fn run_pending() {
+    if PENDING.get().lock().head.is_none() {
+        return;
+    }
+    let enabled = interrupts::are_enabled();
+    interrupts::enable();
+    while let Some(tasklet) = interrupts::without_interrupts(pop) {
+        // Scheduling it again from here runs it again
+        tasklet.scheduled.store(false, Ordering::Release);
+        (tasklet.func)();
+    }
+    if !enabled {
+        interrupts::disable();
+    }
+}
+
+#[test_case]
+fn test_tasklet() {
+    use crate::kernel::interrupts::{register_irq_handler, unregister_irq_handler, PIC_1_OFFSET};
+    use core::sync::atomic::AtomicUsize;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static IN_INTERRUPT: AtomicBool = AtomicBool::new(false);
+    static INTERRUPTS_ENABLED: AtomicBool = AtomicBool::new(false);
+    static TASKLET: Tasklet = Tasklet::new("test", || {
+        RUNS.fetch_add(1, Ordering::Relaxed);
+        IN_INTERRUPT.store(percpu::in_interrupt(), Ordering::Relaxed);
+        INTERRUPTS_ENABLED.store(interrupts::are_enabled(), Ordering::Relaxed);
+    });
+
+    // From thread context it runs at once
+    assert!(TASKLET.schedule());
+    assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+
+    // Scheduled twice by a handler, it runs once as the handler returns
+    let id = register_irq_handler(5, || {
+        TASKLET.schedule();
+        TASKLET.schedule();
+        assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+    });
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 5) };
+    unregister_irq_handler(id);
+    assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+    assert!(IN_INTERRUPT.load(Ordering::Relaxed));
+    assert!(INTERRUPTS_ENABLED.load(Ordering::Relaxed));
+    crate::kernel::sync::rcu_barrier();
+}
@@ -0,0 +1,117 @@
+use crate::kernel::sync::{MutexGuard, WaitQueue};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Condition variable: threads holding a `Mutex` sleep until notified
+///
+/// As usual, waiters must check what they are waiting for in a loop (or use
+/// `wait_while()`): a wakeup only means it may have changed.
+pub struct Condvar {
+    // Bumped by every notification, waiters sleep until it moves
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            generation: AtomicU64::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock `guard`'s mutex, sleep until notified, and lock it again
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // Read before unlocking, a notification right after is not missed
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Like `wait()`, but gives up after `timeout` timer ticks
+    ///
+    /// Also returns whether it was notified rather than timed out.
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: u64,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex();
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        let notified = self.waiters.wait_until_timeout(timeout, || {
+            self.generation.load(Ordering::Acquire) != generation
+        });
+        (mutex.lock(), notified)
+    }
+
+    /// Wait for as long as `condition` holds for the data
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake one waiting thread
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wake all waiting threads
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_condvar_handoff() {
+    use crate::kernel::sync::Mutex;
+    use crate::kernel::thread;
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+
+    let queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+    let consumer = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            let (items, ready) = &*queue;
+            let mut sum = 0;
+            for _ in 0..10 {
+                let mut items = ready.wait_while(items.lock(), |items| items.is_empty());
+                sum += items.pop_front().unwrap();
+            }
+            assert_eq!(sum, 45);
+        })
+    };
+    let (items, ready) = &*queue;
+    for n in 0..10 {
+        items.lock().push_back(n);
+        ready.notify_one();
+        if n % 3 == 0 {
+            thread::yield_now();
+        }
+    }
+    consumer.join();
+
+    let (guard, notified) = ready.wait_timeout(items.lock(), 1);
+    assert!(!notified);
+    assert!(guard.is_empty());
+}
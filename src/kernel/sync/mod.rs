@@ -0,0 +1,22 @@
+// Synchronization primitives.
+//
+// `IrqSafeSpinLock` spins and is safe to use from interrupt handlers. The rest
+// put the waiting thread to sleep (see `WaitQueue`) and are only for thread
+// context; timeouts are in timer ticks. Data that is read far more often than
+// it changes can go without locks on the read side with `rcu`.
+
+mod condvar;
+mod mutex;
+pub mod rcu;
+mod rwlock;
+mod semaphore;
+mod spinlock;
+mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rcu::{call_rcu, rcu_barrier, rcu_read_lock, synchronize_rcu, RcuCell, RcuReadGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{rank, IrqSafeSpinLock, IrqSafeSpinLockGuard};
+pub use wait_queue::{WaitQueue, WaitUntil};
@@ -0,0 +1,292 @@
+use crate::kernel::sync::{IrqSafeSpinLock, WaitQueue};
+use crate::kernel::thread::scheduler::{self, NICE_MAX};
+use crate::kernel::thread::{self, Thread, ThreadId};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+
+// A mutex that puts threads to sleep while it is held by someone else.
+//
+// Priority inheritance: a thread that has to wait lends its niceness to the
+// owner if that is more important than the owner's own, so a less important
+// owner can't be kept off the CPU by threads in between while the important
+// one waits. Each mutex keeps what its waiters lend, and each thread the best
+// loan of every mutex it holds: it runs at the best of those until it unlocks
+// them, a waiter that gives up takes its loan back, and whoever gets the
+// mutex next inherits from those still waiting. Lending is not passed on if
+// the owner itself waits for another mutex.
+//
+// Only for thread context, interrupt handlers can't sleep: use an
+// `IrqSafeSpinLock` for anything they touch.
+
+pub struct Mutex<T: ?Sized> {
+    state: IrqSafeSpinLock<State>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+struct State {
+    owner: Option<Arc<Thread>>,
+    /// The niceness each waiting thread lends the owner
+    loans: Vec<(ThreadId, i8)>,
+}
+
+impl State {
+    fn best_loan(&self) -> Option<i8> {
+        self.loans.iter().map(|&(_, nice)| nice).min()
+    }
+}
+
+/// Access to the data of a locked `Mutex`, unlocks when dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // Owned by the thread that locked it
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: IrqSafeSpinLock::new(
+                "mutex owner",
+                State {
+                    owner: None,
+                    loans: Vec::new(),
+                },
+            ),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Sleep until the mutex is ours
+    ///
+    /// Panics if the running thread holds it already.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let current = thread::current();
+        self.waiters.wait_until(|| self.acquire(&current));
+        self.guard()
+    }
+
+    /// Like `lock()`, but gives up after `timeout` timer ticks
+    pub fn lock_timeout(&self, timeout: u64) -> Option<MutexGuard<'_, T>> {
+        let current = thread::current();
+        if self
+            .waiters
+            .wait_until_timeout(timeout, || self.acquire(&current))
+        {
+            return Some(self.guard());
+        }
+        // Take back what we lent the owner
+        let mut state = self.state.lock();
+        state.loans.retain(|&(id, _)| id != current.id());
+        if let Some(owner) = &state.owner {
+            self.lend(owner, state.best_loan());
+        }
+        None
+    }
+
+    /// Take the mutex if nobody holds it
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let current = thread::current();
+        let mut state = self.state.lock();
+        if state.owner.is_some() {
+            return None;
+        }
+        state.owner = Some(current);
+        Some(self.guard())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().owner.is_some()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Make `current` the owner if the mutex is free, otherwise lend the owner
+    /// our niceness
+    fn acquire(&self, current: &Arc<Thread>) -> bool {
+        let mut state = self.state.lock();
+        let State { owner, loans } = &mut *state;
+        match owner {
+            None => {
+                loans.retain(|&(id, _)| id != current.id());
+                *owner = Some(current.clone());
+                // Those still waiting lend to us now
+                if !loans.is_empty() {
+                    self.lend(current, state.best_loan());
+                }
+                true
+            }
+            Some(owner) => {
+                assert!(
+                    !Arc::ptr_eq(owner, current),
+                    "mutex: locked again by its owner {}",
+                    current.name()
+                );
+                let nice = current.effective_nice();
+                match loans.iter_mut().find(|(id, _)| *id == current.id()) {
+                    Some(loan) => loan.1 = nice,
+                    None => loans.push((current.id(), nice)),
+                }
+                let owner = owner.clone();
+                self.lend(&owner, state.best_loan());
+                false
+            }
+        }
+    }
+
+    /// Have the owner `owner` borrow `loan` through this mutex, see `Thread::lend_nice()`
+    fn lend(&self, owner: &Arc<Thread>, loan: Option<i8>) {
+        let key = self as *const Self as *const () as usize;
+        let best = owner.lend_nice(key, loan);
+        scheduler::set_inherited_nice(owner, best.unwrap_or(NICE_MAX));
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(owner) = self.mutex.state.lock().owner.take() {
+            // Whoever lent us their niceness through this mutex is about to
+            // get it, loans through the others we hold stay
+            self.mutex.lend(&owner, None);
+        }
+        self.mutex.waiters.wake_one();
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> MutexGuard<'a, T> {
+    /// The mutex this guard locks, for `Condvar`
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+#[test_case]
+fn test_mutex_priority_inheritance() {
+    use crate::kernel::sync::Semaphore;
+    use core::sync::atomic::{AtomicI8, Ordering};
+
+    let mutex = Arc::new(Mutex::new(0));
+    let locked = Arc::new(Semaphore::new(0));
+    let lent = Arc::new(AtomicI8::new(NICE_MAX));
+    let low = {
+        let (mutex, locked, lent) = (mutex.clone(), locked.clone(), lent.clone());
+        thread::spawn(move || {
+            let mut guard = mutex.lock();
+            locked.release();
+            while mutex.waiters.is_empty() {
+                thread::sleep_ticks(1);
+            }
+            lent.store(thread::current().effective_nice(), Ordering::Relaxed);
+            *guard += 1;
+        })
+    };
+    low.thread().set_nice(10);
+
+    // Wait for the mutex as a more important thread
+    let current = thread::current();
+    current.set_nice(-5);
+    locked.acquire();
+    *mutex.lock() += 1;
+    current.set_nice(0);
+
+    let low_thread = low.thread().clone();
+    low.join();
+    assert_eq!(lent.load(Ordering::Relaxed), -5);
+    assert_eq!(low_thread.effective_nice(), 10);
+    assert_eq!(*mutex.lock(), 2);
+}
+
+#[test_case]
+fn test_mutex_inheritance_per_mutex() {
+    use crate::kernel::sync::Semaphore;
+    use core::sync::atomic::{AtomicI8, Ordering};
+
+    let a = Arc::new(Mutex::new(()));
+    let b = Arc::new(Mutex::new(()));
+    let locked = Arc::new(Semaphore::new(0));
+    let step = Arc::new(Semaphore::new(0));
+    let seen = Arc::new([const { AtomicI8::new(0) }; 3]);
+    let low = {
+        let (a, b, locked, step, seen) = (
+            a.clone(),
+            b.clone(),
+            locked.clone(),
+            step.clone(),
+            seen.clone(),
+        );
+        thread::spawn(move || {
+            let current = thread::current();
+            current.set_nice(10);
+            let guard_a = a.lock();
+            let guard_b = b.lock();
+            locked.release();
+            step.acquire();
+            seen[0].store(current.effective_nice(), Ordering::Relaxed);
+            drop(guard_b);
+            seen[1].store(current.effective_nice(), Ordering::Relaxed);
+            drop(guard_a);
+            seen[2].store(current.effective_nice(), Ordering::Relaxed);
+        })
+    };
+    locked.acquire();
+    let waiter = {
+        let a = a.clone();
+        thread::spawn(move || {
+            thread::current().set_nice(-2);
+            drop(a.lock());
+        })
+    };
+    while a.waiters.is_empty() {
+        thread::sleep_ticks(1);
+    }
+
+    // Lend more through `b` and give up
+    let current = thread::current();
+    current.set_nice(-5);
+    assert!(b.lock_timeout(2).is_none());
+    current.set_nice(0);
+    step.release();
+    low.join();
+    waiter.join();
+
+    // Back to the loan through `a` once the timed out one is taken back, kept
+    // while `a` is held, gone with it
+    let seen = seen.each_ref().map(|nice| nice.load(Ordering::Relaxed));
+    assert_eq!(seen, [-2, -2, 10]);
+}
@@ -0,0 +1,230 @@
+use crate::kernel::cpu::{self, MAX_CPUS};
+use crate::kernel::ipi::{self, Target};
+use crate::kernel::sync::{IrqSafeSpinLock, WaitQueue};
+use crate::kernel::thread;
+use crate::percpu;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+// Read-copy-update: readers go without locks, writers publish a new copy of
+// the data and free the old one once no reader can still see it.
+//
+// Readers mark their critical section with `rcu_read_lock()`. Inside it the
+// thread isn't preempted and must not block, so a CPU that switches threads,
+// is idle, or takes an interrupt outside a read-side section can't be reading
+// anything from before; it passed a quiescent state. Once every CPU has done
+// so after a writer unpublished something (a grace period), nobody holds on to
+// it any more. Each CPU counts its quiescent states, `synchronize_rcu()` waits
+// for every other CPU's count to move and sends an IPI to those that are slow
+// about it, which idle CPUs are.
+//
+// `call_rcu()` defers a callback (usually freeing) to after a grace period
+// instead of waiting for one, the `rcu` thread runs them in batches.
+
+percpu! {
+    // Read-side critical sections this CPU is in
+    static NESTING: AtomicUsize = AtomicUsize::new(0);
+    // Quiescent states this CPU went through
+    static QUIESCENT: AtomicU64 = AtomicU64::new(0);
+}
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+static CALLBACKS: IrqSafeSpinLock<Vec<Callback>> =
+    IrqSafeSpinLock::new("rcu callbacks", Vec::new());
+// Wakes the `rcu` thread when callbacks are queued
+static QUEUED: WaitQueue = WaitQueue::new();
+// Callbacks queued and run so far, see `rcu_barrier()`
+static QUEUED_COUNT: AtomicU64 = AtomicU64::new(0);
+static DONE_COUNT: AtomicU64 = AtomicU64::new(0);
+static DONE: WaitQueue = WaitQueue::new();
+
+/// A read-side critical section, ends when dropped
+pub struct RcuReadGuard {
+    // Must end on the CPU it started on
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Start a read-side critical section
+///
+/// Until the guard is dropped, data read from an `RcuCell` stays valid. The
+/// thread isn't preempted meanwhile and must not block. Sections nest and can
+/// be used in interrupt handlers.
+pub fn rcu_read_lock() -> RcuReadGuard {
+    NESTING.get().fetch_add(1, Ordering::Relaxed);
+    RcuReadGuard {
+        _not_send: PhantomData,
+    }
+}
+
+impl Drop for RcuReadGuard {
+    fn drop(&mut self) {
+        NESTING.get().fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Whether this CPU is in a read-side critical section
+pub fn in_read_section() -> bool {
+    NESTING.get().load(Ordering::Relaxed) != 0
+}
+
+/// Report a quiescent state for this CPU, if it isn't in a read-side section
+///
+/// Called by the scheduler when it switches threads or idles and by the
+/// timer interrupt.
+pub fn note_quiescent() {
+    if !in_read_section() {
+        QUIESCENT.get().fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Wait until every read-side critical section that might have started
+/// before has ended
+///
+/// Thread context only, outside a read-side section.
+pub fn synchronize_rcu() {
+    assert!(
+        !in_read_section(),
+        "rcu: synchronize_rcu() in a read-side section"
+    );
+    // No other thread on this CPU can be in a read-side section, it would
+    // still be running
+    let this_cpu = cpu::id();
+    let start: [u64; MAX_CPUS] =
+        core::array::from_fn(|cpu| QUIESCENT.for_cpu(cpu).load(Ordering::Acquire));
+    let lagging = |cpu: &usize| {
+        *cpu != this_cpu
+            && cpu::apic_id(*cpu).is_some()
+            && QUIESCENT.for_cpu(*cpu).load(Ordering::Acquire) == start[*cpu]
+    };
+    while (0..MAX_CPUS).any(|cpu| lagging(&cpu)) {
+        for cpu in (0..MAX_CPUS).filter(lagging) {
+            // Interrupting it is a quiescent state, unless it's reading
+            ipi::call(Target::Cpu(cpu), note_quiescent);
+        }
+        if (0..MAX_CPUS).any(|cpu| lagging(&cpu)) {
+            thread::sleep_ticks(1);
+        }
+    }
+}
+
+/// Run `callback` after a grace period, on the `rcu` thread
+///
+/// Doesn't wait, but allocates: thread context only.
+pub fn call_rcu<F>(callback: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    CALLBACKS.lock().push(Box::new(callback));
+    QUEUED_COUNT.fetch_add(1, Ordering::Relaxed);
+    QUEUED.wake_one();
+}
+
+/// Wait until the callbacks queued with `call_rcu()` so far have run
+pub fn rcu_barrier() {
+    let queued = QUEUED_COUNT.load(Ordering::Relaxed);
+    DONE.wait_until(|| DONE_COUNT.load(Ordering::Acquire) >= queued);
+}
+
+/// Start the `rcu` thread that runs `call_rcu()` callbacks
+pub fn init() {
+    thread::spawn_named("rcu", || loop {
+        QUEUED.wait_until(|| !CALLBACKS.lock().is_empty());
+        let batch = core::mem::take(&mut *CALLBACKS.lock());
+        synchronize_rcu();
+        let count = batch.len() as u64;
+        for callback in batch {
+            callback();
+        }
+        DONE_COUNT.fetch_add(count, Ordering::Release);
+        DONE.wake_all();
+    });
+}
+
+/// A pointer to a `T` that readers follow in a read-side critical section
+/// while writers replace it
+///
+/// Writers take turns and run in thread context, the old value is dropped
+/// after a grace period.
+pub struct RcuCell<T> {
+    ptr: AtomicPtr<T>,
+    writer: IrqSafeSpinLock<()>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+unsafe impl<T: Send + Sync> Send for RcuCell<T> {}
+
+impl<T: Send + Sync + 'static> RcuCell<T> {
+    pub fn new(value: T) -> Self {
+        RcuCell {
+            ptr: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: IrqSafeSpinLock::new("rcu writer", ()),
+        }
+    }
+
+    /// The current value, valid for as long as the read-side section lasts
+    pub fn read<'a>(&'a self, _guard: &'a RcuReadGuard) -> &'a T {
+        unsafe { &*self.ptr.load(Ordering::Acquire) }
+    }
+
+    /// Publish `value`, the old one is dropped after a grace period
+    pub fn replace(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.publish(value);
+    }
+
+    /// Publish what `f` makes of a copy of the current value
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let _writer = self.writer.lock();
+        // Nobody else replaces it while we hold the writer lock
+        let value = f(unsafe { &*self.ptr.load(Ordering::Acquire) });
+        self.publish(value);
+    }
+
+    fn publish(&self, value: T) {
+        let new = Box::into_raw(Box::new(value));
+        let old = unsafe { Box::from_raw(self.ptr.swap(new, Ordering::AcqRel)) };
+        call_rcu(move || drop(old));
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        let ptr = core::mem::replace(self.ptr.get_mut(), ptr::null_mut());
+        drop(unsafe { Box::from_raw(ptr) });
+    }
+}
+
+#[test_case]
+fn test_rcu_cell() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    struct Value(u64, Arc<AtomicBool>);
+    impl Drop for Value {
+        fn drop(&mut self) {
+            self.1.store(true, Ordering::Relaxed);
+        }
+    }
+
+    let dropped = Arc::new(AtomicBool::new(false));
+    let cell = RcuCell::new(Value(1, dropped.clone()));
+    {
+        let guard = rcu_read_lock();
+        let old = cell.read(&guard);
+        cell.update(|value| Value(value.0 + 1, Arc::new(AtomicBool::new(false))));
+        // Readers keep what they saw, new readers see the new value
+        assert_eq!(old.0, 1);
+        assert_eq!(cell.read(&rcu_read_lock()).0, 2);
+        assert!(!dropped.load(Ordering::Relaxed));
+    }
+    rcu_barrier();
+    assert!(dropped.load(Ordering::Relaxed));
+    synchronize_rcu();
+}
@@ -0,0 +1,198 @@
+use crate::kernel::sync::WaitQueue;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// A reader-writer lock that puts threads to sleep while they can't have it.
+//
+// Any number of readers or a single writer. A writer waiting keeps new readers
+// out, so a steady stream of readers can't starve it. Unlike `Mutex` there is
+// no priority inheritance, readers aren't tracked.
+
+/// `state` bit of a writer holding the lock
+const WRITER: usize = 1 << (usize::BITS - 1);
+/// `state` bit of writers waiting for it
+const WRITER_WAITING: usize = 1 << (usize::BITS - 2);
+/// `state` bits counting the readers holding the lock
+const READERS: usize = WRITER_WAITING - 1;
+
+pub struct RwLock<T: ?Sized> {
+    state: AtomicUsize,
+    // Writers waiting, the `WRITER_WAITING` bit is set while there are any
+    writers_waiting: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+
+/// Shared access to the data of an `RwLock`
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// Exclusive access to the data of an `RwLock`
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Sleep until there is no writer, then read
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.waiters.wait_until(|| self.try_acquire_read());
+        RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Sleep until nobody else holds the lock, then write
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if !self.try_acquire_write() {
+            if self.writers_waiting.fetch_add(1, Ordering::Relaxed) == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            self.waiters.wait_until(|| self.try_acquire_write());
+            if self.writers_waiting.fetch_sub(1, Ordering::Relaxed) == 1 {
+                // Readers held back for us get in once we unlock
+                self.state.fetch_and(!WRITER_WAITING, Ordering::Relaxed);
+            }
+        }
+        RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.try_acquire_read().then_some(RwLockReadGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.try_acquire_write().then_some(RwLockWriteGuard {
+            lock: self,
+            _not_send: PhantomData,
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    fn try_acquire_read(&self) -> bool {
+        self.state
+            .try_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                if state & (WRITER | WRITER_WAITING) != 0 {
+                    return None;
+                }
+                assert!(state & READERS != READERS, "rwlock: too many readers");
+                Some(state + 1)
+            })
+            .is_ok()
+    }
+
+    fn try_acquire_write(&self) -> bool {
+        self.state
+            .try_update(Ordering::Acquire, Ordering::Relaxed, |state| {
+                (state & (WRITER | READERS) == 0).then_some(state | WRITER)
+            })
+            .is_ok()
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // The last reader out lets a writer in
+        if self.lock.state.fetch_sub(1, Ordering::Release) & READERS == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+#[test_case]
+fn test_rwlock_readers_and_writers() {
+    use crate::kernel::thread;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let lock = Arc::new(RwLock::new(0u64));
+    {
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(*first + *second, 0);
+        assert!(lock.try_write().is_none());
+    }
+    let threads: Vec<_> = (0..4)
+        .map(|n| {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    if n % 2 == 0 {
+                        *lock.write() += 1;
+                    } else {
+                        // Writers never leave it half updated
+                        let value = *lock.read();
+                        assert!(value <= 100);
+                    }
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join();
+    }
+    assert_eq!(*lock.read(), 100);
+    assert!(lock.try_write().is_some());
+}
@@ -0,0 +1,89 @@
+use crate::kernel::sync::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counting semaphore: `acquire()` takes a permit, sleeping until one is
+/// available, `release()` hands one back
+///
+/// Permits aren't tied to a thread, any thread or interrupt handler may
+/// release. Async tasks can wait with `acquire_async()`.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Sleep until a permit is available and take it
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Like `acquire()`, but gives up after `timeout` timer ticks
+    ///
+    /// Returns whether a permit was taken.
+    pub fn acquire_timeout(&self, timeout: u64) -> bool {
+        self.waiters
+            .wait_until_timeout(timeout, || self.try_acquire())
+    }
+
+    /// `acquire()` for async tasks
+    pub async fn acquire_async(&self) {
+        self.waiters.wait_until_async(|| self.try_acquire()).await
+    }
+
+    /// Take a permit if one is available
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .try_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Hand a permit back, waking a waiter
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Permits currently available
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+#[test_case]
+fn test_semaphore_limits_holders() {
+    use crate::kernel::thread;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let semaphore = Arc::new(Semaphore::new(2));
+    let holders = Arc::new(AtomicUsize::new(0));
+    let most = Arc::new(AtomicUsize::new(0));
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let (semaphore, holders, most) = (semaphore.clone(), holders.clone(), most.clone());
+            thread::spawn(move || {
+                semaphore.acquire();
+                let now = holders.fetch_add(1, Ordering::Relaxed) + 1;
+                most.fetch_max(now, Ordering::Relaxed);
+                thread::sleep_ticks(2);
+                holders.fetch_sub(1, Ordering::Relaxed);
+                semaphore.release();
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join();
+    }
+    assert_eq!(most.load(Ordering::Relaxed), 2);
+    assert_eq!(semaphore.available(), 2);
+    assert!(!Semaphore::new(0).acquire_timeout(1));
+}
@@ -0,0 +1,319 @@
+use crate::kernel::cpu;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+// A spinlock that keeps interrupts disabled while it is held.
+//
+// With a plain `spin::Mutex`, an interrupt handler that takes a lock the code it
+// interrupted is holding spins forever: the holder can't run again until the
+// handler returns. Disabling interrupts for as long as the lock is held rules
+// that out, and also keeps the timer from preempting the holder (see
+// `thread::scheduler`), so other threads never spin on a lock held by a thread
+// that isn't running.
+//
+// Since the holder can't be interrupted, a CPU finding a lock held by itself
+// is always a deadlock, and is reported as one along with where the lock was
+// taken. A lock held by another CPU for longer than `SPIN_LIMIT` iterations is
+// reported as a likely deadlock too.
+//
+// In debug builds, locks created with a rank (`with_rank()`) are also checked
+// for ordering: a CPU may only take a ranked lock while all the ranked locks it
+// holds have a lower rank. Two paths taking the same locks in opposite orders
+// can deadlock even if they never happen to do so in testing.
+
+/// Spins before a held lock is reported as deadlocked
+const SPIN_LIMIT: usize = 1 << 28;
+/// Ranked locks a CPU can hold at once
+const MAX_HELD: usize = 8;
+/// `owner_cpu` of a free lock
+const NO_OWNER: usize = usize::MAX;
+
+/// Ranks of the ordered locks, locks are taken in increasing rank
+///
+/// Anything may print while holding another lock, so the console locks come last.
+pub mod rank {
+    pub const VGA: u8 = 200;
+    pub const SERIAL: u8 = 210;
+}
+
+// Ranks of the locks each CPU holds, in the order they were taken. Only ever
+// touched by the CPU itself, with interrupts disabled.
+struct HeldLocks {
+    depth: AtomicUsize,
+    ranks: [AtomicU8; MAX_HELD],
+}
+
+crate::percpu! {
+    static HELD: HeldLocks = HeldLocks {
+        depth: AtomicUsize::new(0),
+        ranks: [const { AtomicU8::new(0) }; MAX_HELD],
+    };
+}
+
+pub struct IrqSafeSpinLock<T: ?Sized> {
+    name: &'static str,
+    rank: Option<u8>,
+    locked: AtomicBool,
+    owner_cpu: AtomicUsize,
+    // Where the current owner took the lock
+    owner_site: AtomicPtr<Location<'static>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for IrqSafeSpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for IrqSafeSpinLock<T> {}
+
+/// Access to the data of a locked `IrqSafeSpinLock`
+///
+/// Unlocks and restores the interrupt flag when dropped.
+pub struct IrqSafeSpinLockGuard<'a, T: ?Sized> {
+    lock: &'a IrqSafeSpinLock<T>,
+    irq_enabled: bool,
+    // Must be dropped on the CPU that took the lock
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> IrqSafeSpinLock<T> {
+    /// A lock without a rank, it is not checked for ordering
+    pub const fn new(name: &'static str, value: T) -> Self {
+        IrqSafeSpinLock {
+            name,
+            rank: None,
+            locked: AtomicBool::new(false),
+            owner_cpu: AtomicUsize::new(NO_OWNER),
+            owner_site: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// A lock that may only be taken while holding ranked locks of lower `rank`
+    pub const fn with_rank(name: &'static str, rank: u8, value: T) -> Self {
+        let mut lock = Self::new(name, value);
+        lock.rank = Some(rank);
+        lock
+    }
+}
+
+impl<T: ?Sized> IrqSafeSpinLock<T> {
+    /// Disable interrupts and spin until we get the lock
+    ///
+    /// Panics on a deadlock or, in debug builds, on a lock ordering violation.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeSpinLockGuard<'_, T> {
+        let irq_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let cpu = cpu::id();
+        if self.owner_cpu.load(Ordering::Relaxed) == cpu {
+            self.deadlock("taken again by its owner");
+        }
+        #[cfg(debug_assertions)]
+        self.check_order(cpu);
+
+        let mut spins = 0;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+                spins += 1;
+                if spins == SPIN_LIMIT {
+                    self.deadlock("still held after spinning");
+                }
+            }
+        }
+        self.acquired(cpu, irq_enabled)
+    }
+
+    /// Take the lock if it is free
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeSpinLockGuard<'_, T>> {
+        let irq_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        let cpu = cpu::id();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if irq_enabled {
+                interrupts::enable();
+            }
+            return None;
+        }
+        #[cfg(debug_assertions)]
+        self.check_order(cpu);
+        Some(self.acquired(cpu, irq_enabled))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Release the lock whoever holds it
+    ///
+    /// # Safety
+    ///
+    /// Only for panic handlers that need the console no matter what: the
+    /// holder is assumed to never run again, its guard must not be used.
+    pub unsafe fn force_unlock(&self) {
+        if self.owner_cpu.load(Ordering::Relaxed) == cpu::id() {
+            self.pop_rank(cpu::id());
+        }
+        self.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+
+    #[track_caller]
+    fn acquired(&self, cpu: usize, irq_enabled: bool) -> IrqSafeSpinLockGuard<'_, T> {
+        self.owner_cpu.store(cpu, Ordering::Relaxed);
+        let site = Location::caller() as *const Location<'static>;
+        self.owner_site.store(site as *mut _, Ordering::Relaxed);
+        if let Some(rank) = self.rank {
+            let held = HELD.for_cpu(cpu);
+            let depth = held.depth.load(Ordering::Relaxed);
+            if depth < MAX_HELD {
+                held.ranks[depth].store(rank, Ordering::Relaxed);
+            }
+            held.depth.store(depth + 1, Ordering::Relaxed);
+        }
+        IrqSafeSpinLockGuard {
+            lock: self,
+            irq_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Forget about holding this lock, guards may be dropped in any order
+    fn pop_rank(&self, cpu: usize) {
+        let Some(rank) = self.rank else {
+            return;
+        };
+        let held = HELD.for_cpu(cpu);
+        let depth = held.depth.load(Ordering::Relaxed);
+        if depth == 0 {
+            return;
+        }
+        let tracked = depth.min(MAX_HELD);
+        let ranks = &held.ranks[..tracked];
+        if let Some(i) = ranks
+            .iter()
+            .rposition(|r| r.load(Ordering::Relaxed) == rank)
+        {
+            for j in i..tracked - 1 {
+                ranks[j].store(ranks[j + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+        }
+        held.depth.store(depth - 1, Ordering::Relaxed);
+    }
+
+    /// Panic if taking this lock now violates the lock order
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn check_order(&self, cpu: usize) {
+        let Some(rank) = self.rank else {
+            return;
+        };
+        let held = HELD.for_cpu(cpu);
+        let depth = held.depth.load(Ordering::Relaxed).min(MAX_HELD);
+        for held_rank in held.ranks[..depth].iter() {
+            let held_rank = held_rank.load(Ordering::Relaxed);
+            if held_rank >= rank {
+                panic!(
+                    "lock order violation: {} (rank {}) taken at {} while holding a lock of rank {}",
+                    self.name,
+                    rank,
+                    Location::caller(),
+                    held_rank
+                );
+            }
+        }
+    }
+
+    #[track_caller]
+    fn deadlock(&self, what: &str) -> ! {
+        let site = self.owner_site.load(Ordering::Relaxed);
+        let owner_cpu = self.owner_cpu.load(Ordering::Relaxed);
+        // The owner may be the console, take it over so the panic can be printed
+        if owner_cpu == cpu::id() {
+            unsafe { self.force_unlock() };
+        }
+        match unsafe { site.as_ref() } {
+            Some(site) => panic!(
+                "deadlock: {} {}, wanted at {}, held by cpu {} since {}",
+                self.name,
+                what,
+                Location::caller(),
+                owner_cpu,
+                site
+            ),
+            None => panic!("deadlock: {} {}", self.name, what),
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let cpu = cpu::id();
+        self.lock.pop_rank(cpu);
+        self.lock.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+        if self.irq_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSafeSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+#[test_case]
+fn test_irq_disabled_while_held() {
+    let lock = IrqSafeSpinLock::new("test", 0);
+    let enabled = interrupts::are_enabled();
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+    }
+    assert_eq!(interrupts::are_enabled(), enabled);
+    assert_eq!(*lock.lock(), 1);
+
+    // Nested guards restore the flag the outer one found
+    interrupts::without_interrupts(|| {
+        let _guard = lock.lock();
+    });
+    assert_eq!(interrupts::are_enabled(), enabled);
+}
+
+#[test_case]
+fn test_ranked_locks_in_order() {
+    let outer = IrqSafeSpinLock::with_rank("outer", 1, ());
+    let inner = IrqSafeSpinLock::with_rank("inner", 2, ());
+    let before = HELD.get().depth.load(Ordering::Relaxed);
+    {
+        let _outer = outer.lock();
+        let _inner = inner.lock();
+        assert_eq!(HELD.get().depth.load(Ordering::Relaxed), before + 2);
+    }
+    assert_eq!(HELD.get().depth.load(Ordering::Relaxed), before);
+}
@@ -0,0 +1,241 @@
+use crate::kernel::interrupts::ticks;
+use crate::kernel::sync::IrqSafeSpinLock;
+use crate::kernel::thread::{self, scheduler, Thread};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
+
+// Threads and async tasks waiting for something to happen.
+//
+// Waiters pass a condition that is checked with the queue locked, both before
+// going to sleep and every time they are woken. Whoever makes the condition
+// true wakes the queue afterwards, which takes the same lock, so a waiter can't
+// check, miss the change and then sleep through the wakeup.
+//
+// Wakeups only say that the condition may have changed: a woken waiter that
+// finds it still false (someone else got there first) goes back to sleep.
+
+enum Waiter {
+    Thread(Arc<Thread>),
+    Task(Waker),
+}
+
+impl Waiter {
+    fn wake(self) {
+        match self {
+            Waiter::Thread(thread) => scheduler::wake(thread),
+            Waiter::Task(waker) => waker.wake(),
+        }
+    }
+}
+
+pub struct WaitQueue {
+    waiters: IrqSafeSpinLock<VecDeque<Waiter>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: IrqSafeSpinLock::new("wait queue", VecDeque::new()),
+        }
+    }
+
+    /// Block the running thread until `condition` returns true
+    ///
+    /// `condition` runs with the queue locked and interrupts disabled, it must
+    /// not block.
+    pub fn wait_until<F>(&self, condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait(None, condition);
+    }
+
+    /// Like `wait_until()`, but gives up after `timeout` timer ticks
+    ///
+    /// Returns whether `condition` became true.
+    pub fn wait_until_timeout<F>(&self, timeout: u64, condition: F) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        self.wait(Some(ticks() + timeout), condition)
+    }
+
+    fn wait<F>(&self, deadline: Option<u64>, mut condition: F) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        let current = thread::current();
+        // The timer must not preempt us between queueing and switching away
+        interrupts::without_interrupts(|| loop {
+            let mut waiters = self.waiters.lock();
+            // Checked before the deadline, a wakeup that raced with it is not lost
+            if condition() {
+                return true;
+            }
+            if deadline.is_some_and(|tick| ticks() >= tick) {
+                return false;
+            }
+            waiters.push_back(Waiter::Thread(current.clone()));
+            scheduler::block_current(deadline);
+            drop(waiters);
+            scheduler::schedule();
+
+            scheduler::cancel_deadline();
+            // Still queued if the deadline woke us rather than the queue
+            self.waiters.lock().retain(|waiter| match waiter {
+                Waiter::Thread(thread) => !Arc::ptr_eq(thread, &current),
+                Waiter::Task(_) => true,
+            });
+        })
+    }
+
+    /// A future that completes once `condition` returns true, for async tasks
+    ///
+    /// `condition` runs with the queue locked, see `wait_until()`.
+    pub fn wait_until_async<F>(&self, condition: F) -> WaitUntil<'_, F>
+    where
+        F: FnMut() -> bool + Unpin,
+    {
+        WaitUntil {
+            queue: self,
+            condition,
+            waker: None,
+        }
+    }
+
+    /// Wake the longest waiting thread or task, returns whether there was one
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        match waiters.pop_front() {
+            Some(waiter) => {
+                waiter.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake everyone waiting, returns how many there were
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        let woken = waiters.len();
+        for waiter in waiters.drain(..) {
+            waiter.wake();
+        }
+        woken
+    }
+
+    /// Number of threads and tasks waiting
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `WaitQueue::wait_until_async()`
+pub struct WaitUntil<'a, F> {
+    queue: &'a WaitQueue,
+    condition: F,
+    // The waker we queued, taken out again if we're dropped before being woken
+    waker: Option<Waker>,
+}
+
+impl<F> WaitUntil<'_, F> {
+    fn dequeue(waiters: &mut VecDeque<Waiter>, waker: &Waker) {
+        waiters.retain(|waiter| match waiter {
+            Waiter::Task(queued) => !queued.will_wake(waker),
+            Waiter::Thread(_) => true,
+        });
+    }
+}
+
+impl<F: FnMut() -> bool + Unpin> Future for WaitUntil<'_, F> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        let mut waiters = this.queue.waiters.lock();
+        if let Some(waker) = this.waker.take() {
+            Self::dequeue(&mut waiters, &waker);
+        }
+        if (this.condition)() {
+            return Poll::Ready(());
+        }
+        waiters.push_back(Waiter::Task(cx.waker().clone()));
+        this.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<F> Drop for WaitUntil<'_, F> {
+    fn drop(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            Self::dequeue(&mut self.queue.waiters.lock(), &waker);
+        }
+    }
+}
+
+#[test_case]
+fn test_wait_queue_threads() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let queue = Arc::new(WaitQueue::new());
+    let ready = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let (queue, ready) = (queue.clone(), ready.clone());
+        thread::spawn(move || queue.wait_until(|| ready.load(Ordering::Relaxed)))
+    };
+    while queue.is_empty() {
+        thread::yield_now();
+    }
+    ready.store(true, Ordering::Relaxed);
+    assert!(queue.wake_one());
+    waiter.join();
+
+    // Nobody makes it true, so only the deadline ends the wait
+    let start = ticks();
+    assert!(!queue.wait_until_timeout(2, || false));
+    assert!(ticks() >= start + 2);
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_wait_queue_tasks() {
+    use crate::kernel::task::executor::Executor;
+    use crate::kernel::task::Task;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    let queue = Arc::new(WaitQueue::new());
+    let ready = Arc::new(AtomicBool::new(false));
+    let mut executor = Executor::new();
+    {
+        let (queue, ready) = (queue.clone(), ready.clone());
+        executor.spawn(Task::new(async move {
+            queue
+                .wait_until_async(|| ready.load(Ordering::Relaxed))
+                .await;
+        }));
+    }
+    executor.run_until_stalled();
+    assert_eq!(executor.task_count(), 1);
+    assert_eq!(queue.len(), 1);
+
+    ready.store(true, Ordering::Relaxed);
+    queue.wake_all();
+    executor.run_until_stalled();
+    assert_eq!(executor.task_count(), 0);
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,260 @@
+use crate::kernel::address_space;
+use crate::kernel::memory::{self, USER_END};
+use crate::kernel::process::fd::Rights;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+
+// System call arguments arrive as raw registers. Each handler declares the
+// types it takes, and `SyscallArg` turns the registers into them before the
+// handler runs, rejecting what doesn't fit: a pointer into the kernel half, a
+// negative count, unknown flags.
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Why a system call failed, returned to user code as `-errno`
+///
+/// The numbers are Linux's, so C code knows them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EINVAL = 22,
+    EMFILE = 24,
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    EMSGSIZE = 90,
+}
+
+/// What a system call handler returns, the value or why it failed
+pub type SysResult = Result<u64, Errno>;
+
+/// The value user code sees in `rax`
+pub fn encode(result: SysResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+/// A type a system call argument is decoded into
+pub trait SyscallArg: Sized {
+    fn decode(raw: u64) -> Result<Self, Errno>;
+}
+
+impl SyscallArg for u64 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        Ok(raw)
+    }
+}
+
+impl SyscallArg for usize {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        usize::try_from(raw).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl SyscallArg for u32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        u32::try_from(raw).map_err(|_| Errno::EINVAL)
+    }
+}
+
+impl SyscallArg for i32 {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        // C passes an `int` in the low half, whatever is above it
+        Ok(raw as u32 as i32)
+    }
+}
+
+/// A pointer user code handed us, to memory it says holds `T`s
+///
+/// Decoding only checks that it points below the kernel half, the memory is
+/// checked when it is accessed.
+#[derive(Debug)]
+pub struct UserPtr<T> {
+    addr: VirtAddr,
+    _marker: PhantomData<*const T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    pub fn addr(self) -> VirtAddr {
+        self.addr
+    }
+
+    pub fn is_null(self) -> bool {
+        self.addr.is_null()
+    }
+
+    /// The `len` `T`s it points to, if user code may read them
+    ///
+    /// # Safety
+    ///
+    /// The memory must stay mapped while the slice is used.
+    pub unsafe fn slice(self, len: usize) -> Result<&'static [T], Errno> {
+        let size = len
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(Errno::EFAULT)?;
+        if !self.addr.is_aligned(core::mem::align_of::<T>() as u64)
+            || !memory::user_accessible(self.addr, size, false)
+        {
+            return Err(Errno::EFAULT);
+        }
+        Ok(core::slice::from_raw_parts(self.addr.as_ptr(), len))
+    }
+
+    /// The pointer `count` `T`s further on
+    pub fn offset(self, count: usize) -> Result<Self, Errno> {
+        let offset = count
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(Errno::EFAULT)?;
+        let addr = self
+            .addr
+            .as_u64()
+            .checked_add(offset as u64)
+            .ok_or(Errno::EFAULT)?;
+        Self::decode(addr)
+    }
+
+    /// The `T` it points to, if user code may read it
+    pub fn read(self) -> Result<T, Errno>
+    where
+        T: Copy + 'static,
+    {
+        Ok(unsafe { self.slice(1)? }[0])
+    }
+
+    /// Store `value` where it points, if user code may write there
+    pub fn write(self, value: T) -> Result<(), Errno> {
+        self.writable(1)?;
+        unsafe { self.addr.as_mut_ptr::<T>().write(value) };
+        Ok(())
+    }
+
+    /// Store `values` where it points, if user code may write there
+    pub fn write_slice(self, values: &[T]) -> Result<(), Errno>
+    where
+        T: Copy,
+    {
+        self.writable(values.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(values.as_ptr(), self.addr.as_mut_ptr(), values.len())
+        };
+        Ok(())
+    }
+
+    /// Check that user code may write `len` `T`s where it points
+    fn writable(self, len: usize) -> Result<(), Errno> {
+        let size = len
+            .checked_mul(core::mem::size_of::<T>())
+            .ok_or(Errno::EFAULT)?
+            .max(1);
+        if !self.addr.is_aligned(core::mem::align_of::<T>() as u64)
+            || !memory::user_accessible(self.addr, size, false)
+        {
+            return Err(Errno::EFAULT);
+        }
+        // Pages shared since a `fork()` get a copy of their own first
+        let first = Page::<Size4KiB>::containing_address(self.addr);
+        let last = Page::<Size4KiB>::containing_address(self.addr + (size as u64 - 1));
+        for page in Page::range_inclusive(first, last) {
+            address_space::copy_on_write(page.start_address());
+        }
+        if !memory::user_accessible(self.addr, size, true) {
+            return Err(Errno::EFAULT);
+        }
+        Ok(())
+    }
+}
+
+impl UserPtr<u8> {
+    /// The NUL terminated string it points to, `E2BIG` if longer than `max`
+    pub fn c_str(self, max: usize) -> Result<String, Errno> {
+        let mut bytes = Vec::new();
+        let mut addr = self.addr;
+        loop {
+            // A page at a time, the string may end before the next one
+            let len = (PAGE_SIZE - addr.as_u64() % PAGE_SIZE) as usize;
+            if !memory::user_accessible(addr, len, false) {
+                return Err(Errno::EFAULT);
+            }
+            let page = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len) };
+            let end = page.iter().position(|&byte| byte == 0);
+            bytes.extend_from_slice(&page[..end.unwrap_or(len)]);
+            if bytes.len() > max {
+                return Err(Errno::E2BIG);
+            }
+            if end.is_some() {
+                return String::from_utf8(bytes).map_err(|_| Errno::EINVAL);
+            }
+            addr += len as u64;
+        }
+    }
+}
+
+impl<T> SyscallArg for UserPtr<T> {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        if raw >= USER_END {
+            return Err(Errno::EFAULT);
+        }
+        Ok(UserPtr {
+            addr: VirtAddr::new(raw),
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// Access a `mmap` mapping gives, `PROT_*` bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prot(u32);
+
+impl Prot {
+    pub const READ: Prot = Prot(1);
+    pub const WRITE: Prot = Prot(2);
+    pub const EXEC: Prot = Prot(4);
+
+    pub fn contains(self, other: Prot) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl SyscallArg for Prot {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        let all = Prot::READ.0 | Prot::WRITE.0 | Prot::EXEC.0;
+        match u32::try_from(raw) {
+            Ok(bits) if bits & !all == 0 => Ok(Prot(bits)),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+impl SyscallArg for Rights {
+    fn decode(raw: u64) -> Result<Self, Errno> {
+        u32::try_from(raw)
+            .ok()
+            .and_then(Rights::from_bits)
+            .ok_or(Errno::EINVAL)
+    }
+}
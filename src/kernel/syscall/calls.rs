@@ -0,0 +1,638 @@
+use super::args::{Errno, Prot, SysResult, SyscallArg, UserPtr};
+use crate::kernel::device::{IoPorts, IrqLine, MmioRegion};
+use crate::kernel::elf::{self, ElfError};
+use crate::kernel::ipc::{self, Endpoint, Message, SharedMemory};
+use crate::kernel::process::fd::{self, Console, File, Handle, Rights};
+use crate::kernel::process::signal::{self, SigAction, SigSet, Signal, SignalFrame};
+use crate::kernel::process::{self, Process, SpawnError};
+use crate::kernel::usermode::{self, UserContext, UserExit};
+use crate::kernel::{memory, sync, thread};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// The system call handlers, see the table in `syscall` for their numbers.
+
+/// Where `mmap` puts mappings user code doesn't choose an address for
+const MMAP_START: u64 = 0x_7c00_0000_0000;
+const MMAP_END: u64 = 0x_7f00_0000_0000;
+/// Held from finding room for a mapping until it is mapped, so two threads
+/// don't pick the same place
+static MMAP_LOCK: sync::Mutex<()> = sync::Mutex::new(());
+
+const PAGE_SIZE: u64 = 4096;
+/// Longest path `spawn` and `execve` take, with the NUL
+const PATH_MAX: usize = 4096;
+/// `waitpid` option to return instead of waiting
+const WNOHANG: u32 = 1;
+/// Most bytes one `read` takes, it returns how many it did
+///
+/// They go through a buffer on the kernel stack rather than the small heap.
+const READ_MAX: usize = PAGE_SIZE as usize;
+
+/// The handle at `fd`
+///
+/// Threads outside processes only have the console, on 1 and 2.
+fn handle(fd: i32) -> Result<Handle, Errno> {
+    match process::current() {
+        Some(process) => process.with_files(|files| files.get(fd)),
+        None if fd == 1 || fd == 2 => Ok(Handle::new(Arc::new(Console), Rights::ALL)),
+        None => Err(Errno::EBADF),
+    }
+}
+
+/// The object `fd` refers to, `EACCES` unless its handle has `rights`
+fn file(fd: i32, rights: Rights) -> Result<Arc<dyn File>, Errno> {
+    handle(fd)?.check(rights)
+}
+
+/// Write `len` bytes from `buf` to `fd`
+pub(super) fn write(fd: i32, buf: UserPtr<u8>, len: usize) -> SysResult {
+    let file = file(fd, Rights::WRITE)?;
+    let bytes = unsafe { buf.slice(len)? };
+    file.write(bytes).map(|written| written as u64)
+}
+
+/// Read up to `len` bytes from `fd` into `buf`
+pub(super) fn read(fd: i32, buf: UserPtr<u8>, len: usize) -> SysResult {
+    let file = file(fd, Rights::READ)?;
+    let mut bytes = [0; READ_MAX];
+    let read = file.read(&mut bytes[..len.min(READ_MAX)])?;
+    buf.write_slice(&bytes[..read])?;
+    Ok(read as u64)
+}
+
+/// End the process with `status`
+///
+/// A kernel thread running user code outside a process gets it back from
+/// `usermode::enter()`.
+pub(super) fn exit(status: i32) -> SysResult {
+    if !usermode::in_user_thread() {
+        return Err(Errno::EINVAL);
+    }
+    usermode::leave(UserExit::Exited(status))
+}
+
+pub(super) fn sched_yield() -> SysResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// Sleep for `ticks` timer ticks
+pub(super) fn sleep(ticks: u64) -> SysResult {
+    thread::sleep_ticks(ticks);
+    Ok(0)
+}
+
+/// Threads outside processes are known by their thread id
+pub(super) fn getpid() -> SysResult {
+    Ok(match process::current() {
+        Some(process) => process.pid().as_u64(),
+        None => thread::current().id().as_u64(),
+    })
+}
+
+/// Map `size` bytes, a multiple of the page size, with `map`: at `addr`, or
+/// in the first unmapped range that fits if it is null, returns the address
+///
+/// `EINVAL` unless the whole area is in user space.
+fn map_area(
+    addr: UserPtr<u8>,
+    size: u64,
+    map: impl FnOnce(VirtAddr) -> Result<(), Errno>,
+) -> Result<VirtAddr, Errno> {
+    if !addr.addr().is_aligned(PAGE_SIZE) {
+        return Err(Errno::EINVAL);
+    }
+    if !addr.is_null() {
+        let start = addr.addr().as_u64();
+        if start < memory::USER_START
+            || start
+                .checked_add(size)
+                .is_none_or(|end| end > memory::USER_END)
+        {
+            return Err(Errno::EINVAL);
+        }
+        map(addr.addr())?;
+        return Ok(addr.addr());
+    }
+    let _placing = MMAP_LOCK.lock();
+    let start = memory::with_memory(|mm| {
+        mm.find_unmapped(VirtAddr::new(MMAP_START), VirtAddr::new(MMAP_END), size)
+    })
+    .ok_or(Errno::ENOMEM)?;
+    map(start)?;
+    Ok(start)
+}
+
+/// The page flags for user memory with access `prot`
+///
+/// Any mapping can be read, x86 pages can't be write or execute only.
+fn page_flags(prot: Prot) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot.contains(Prot::WRITE) {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !prot.contains(Prot::EXEC) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Map `len` bytes of zeroed memory at `addr`, or wherever there is room if
+/// it is null, returns the address
+pub(super) fn mmap(addr: UserPtr<u8>, len: usize, prot: Prot) -> SysResult {
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let size = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(Errno::EINVAL)?;
+    let start = map_area(addr, size, |start| {
+        let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        memory::map_range(start, size as usize, user | PageTableFlags::WRITABLE)
+            .map_err(|_| Errno::ENOMEM)?;
+        // Frames come with whatever the last owner left in them
+        unsafe { core::ptr::write_bytes(start.as_mut_ptr::<u8>(), 0, size as usize) };
+        memory::protect_range(start, size as usize, page_flags(prot)).map_err(|_| {
+            memory::unmap_range(start, size as usize).ok();
+            Errno::ENOMEM
+        })
+    })?;
+    Ok(start.as_u64())
+}
+
+/// Free `fd`
+pub(super) fn close(fd: i32) -> SysResult {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    let handle = process.with_files(|files| files.close(fd))?;
+    // Outside the process's lock, closing may wake whoever is at the other end
+    drop(handle);
+    Ok(0)
+}
+
+/// Another descriptor for what `fd` refers to, with `rights`, which `fd` must
+/// have along with `DUPLICATE`
+pub(super) fn dup(fd: i32, rights: Rights) -> SysResult {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    let fd = process.with_files(|files| files.duplicate(fd, rights))?;
+    Ok(fd as u64)
+}
+
+/// The rights `fd` has, see `fd::Rights`
+pub(super) fn handle_rights(fd: i32) -> SysResult {
+    Ok(u64::from(handle(fd)?.rights.bits()))
+}
+
+/// The strings of the NULL terminated array of string pointers at `array`, a
+/// null `array` is empty
+fn string_array(array: UserPtr<u64>) -> Result<Vec<String>, Errno> {
+    let mut strings = Vec::new();
+    if array.is_null() {
+        return Ok(strings);
+    }
+    let mut size = 0;
+    loop {
+        let ptr = array.offset(strings.len())?.read()?;
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        let string = UserPtr::<u8>::decode(ptr)?.c_str(elf::ARGS_MAX - size)?;
+        size += string.len() + 1;
+        strings.push(string);
+    }
+}
+
+/// What `errno` a program that `process::load()` failed to load gives
+fn load_errno(error: SpawnError) -> Errno {
+    match error {
+        SpawnError::NotFound => Errno::ENOENT,
+        SpawnError::Elf(ElfError::ArgumentsTooLong) => Errno::E2BIG,
+        SpawnError::Elf(ElfError::OutOfMemory) => Errno::ENOMEM,
+        SpawnError::Elf(_) => Errno::ENOEXEC,
+    }
+}
+
+/// The path at `path`
+fn path(path: UserPtr<u8>) -> Result<String, Errno> {
+    path.c_str(PATH_MAX - 1).map_err(|errno| match errno {
+        Errno::E2BIG => Errno::ENAMETOOLONG,
+        errno => errno,
+    })
+}
+
+/// Start the program at `path` in a child process with the arguments `argv`,
+/// returns its pid
+pub(super) fn spawn(path: UserPtr<u8>, argv: UserPtr<u64>) -> SysResult {
+    let path = self::path(path)?;
+    let argv = string_array(argv)?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    process::spawn(&path, &argv, &[])
+        .map(|child| child.pid().as_u64())
+        .map_err(load_errno)
+}
+
+/// Wait for the child `pid`, or any child if it is -1, to end and reap it,
+/// returns its pid
+///
+/// Stores how it ended in `status` unless that is null. With `WNOHANG` in
+/// `options` returns 0 instead of waiting if it hasn't ended yet.
+pub(super) fn waitpid(pid: i32, status: UserPtr<i32>, options: u32) -> SysResult {
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    let pid = match pid {
+        -1 => None,
+        pid => Some(u64::try_from(pid).map_err(|_| Errno::EINVAL)?),
+    };
+    let process = process::current().ok_or(Errno::ECHILD)?;
+    let Some((pid, exit)) = process.wait_child(pid, options & WNOHANG == 0)? else {
+        return Ok(0);
+    };
+    if !status.is_null() {
+        status.write(exit.wait_status())?;
+    }
+    Ok(pid.as_u64())
+}
+
+/// Copy the process, returns the child's pid to the parent and 0 to the child
+pub(super) fn fork() -> SysResult {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let mut context = *unsafe { usermode::user_context() };
+    context.rax = 0;
+    let child = process
+        .fork(context, FsBase::read())
+        .map_err(|_| Errno::ENOMEM)?;
+    Ok(child.pid().as_u64())
+}
+
+/// Replace the process's program with the one at `path`, run with the
+/// arguments `argv` and environment `envp`
+///
+/// Doesn't return on success: the thread goes on from the new program's entry
+/// point, the process's other threads stop. Open files stay open.
+pub(super) fn execve(path: UserPtr<u8>, argv: UserPtr<u64>, envp: UserPtr<u64>) -> SysResult {
+    // Everything but the way back to user mode dropped before `leave()`
+    let exit = exec(path, argv, envp)?;
+    usermode::leave(exit)
+}
+
+fn exec(path: UserPtr<u8>, argv: UserPtr<u64>, envp: UserPtr<u64>) -> Result<UserExit, Errno> {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let path = self::path(path)?;
+    let argv = string_array(argv)?;
+    let envp = string_array(envp)?;
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    let program = process::load(&path, &argv, &envp).map_err(load_errno)?;
+    process.exec(process::program_name(&path), &program);
+    Ok(UserExit::Resume(UserContext::new(
+        program.entry(),
+        program.stack_pointer(),
+    )))
+}
+
+/// The parent's pid, 0 for processes without one and threads outside
+/// processes
+pub(super) fn getppid() -> SysResult {
+    Ok(process::current()
+        .and_then(|process| process.parent())
+        .map_or(0, |parent| parent.pid().as_u64()))
+}
+
+/// The caller if `pid` is its pid, otherwise one of its children
+///
+/// Other processes can only be reached through handles sent to the caller,
+/// `ESRCH`.
+fn caller_or_child(pid: u64) -> Result<Arc<Process>, Errno> {
+    let process = process::current().ok_or(Errno::ESRCH)?;
+    if process.pid().as_u64() == pid {
+        return Ok(process);
+    }
+    process
+        .children()
+        .into_iter()
+        .find(|child| child.pid().as_u64() == pid)
+        .ok_or(Errno::ESRCH)
+}
+
+/// Send the signal numbered `signal` to the process `pid`, the caller or one
+/// of its children, 0 to only check that it is one
+pub(super) fn kill(pid: i32, signal: u32) -> SysResult {
+    let signal = match signal {
+        0 => None,
+        number => Some(self::signal(number)?),
+    };
+    let process = caller_or_child(u64::try_from(pid).map_err(|_| Errno::ESRCH)?)?;
+    if let Some(signal) = signal {
+        process.send_signal(signal);
+    }
+    Ok(0)
+}
+
+/// A descriptor for the process `pid` with every right: the caller if it is
+/// 0, see `caller_or_child()` otherwise
+pub(super) fn process_open(pid: i32) -> SysResult {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let target = match u64::try_from(pid).map_err(|_| Errno::ESRCH)? {
+        0 => process,
+        pid => caller_or_child(pid)?,
+    };
+    let fd = open_files([Handle::new(target, Rights::ALL)])?;
+    Ok(fd[0] as u64)
+}
+
+/// The process `fd` refers to, `EBADF` if it isn't one and `EACCES` unless
+/// its handle has `rights`
+fn process_handle(fd: i32, rights: Rights) -> Result<Arc<Process>, Errno> {
+    fd::downcast(file(fd, rights)?).ok_or(Errno::EBADF)
+}
+
+/// Send the signal numbered `signal` to the process `fd`, which needs `WRITE`
+pub(super) fn process_kill(fd: i32, signal: u32) -> SysResult {
+    let signal = self::signal(signal)?;
+    process_handle(fd, Rights::WRITE)?.send_signal(signal);
+    Ok(0)
+}
+
+/// Wait for the process `fd` to end and store how it did at `status` like
+/// `waitpid`, which needs `READ`
+///
+/// Doesn't reap it, that is still for its parent to do.
+pub(super) fn process_wait(fd: i32, status: UserPtr<i32>) -> SysResult {
+    let process = process_handle(fd, Rights::READ)?;
+    let ended = process.wait_ended()?;
+    status.write(ended.wait_status())?;
+    Ok(0)
+}
+
+/// The signal numbered `number`, `EINVAL` if there is none
+fn signal(number: u32) -> Result<Signal, Errno> {
+    Signal::new(number).ok_or(Errno::EINVAL)
+}
+
+/// Set the action for `signal` to the one at `act` unless it is null, and
+/// store the previous one at `oldact` unless that is null
+pub(super) fn sigaction(
+    signal: u32,
+    act: UserPtr<SigAction>,
+    oldact: UserPtr<SigAction>,
+) -> SysResult {
+    let signal = self::signal(signal)?;
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let act = if act.is_null() {
+        None
+    } else {
+        Some(act.read()?)
+    };
+    let old = process.sigaction(signal, act)?;
+    if !oldact.is_null() {
+        oldact.write(old)?;
+    }
+    Ok(0)
+}
+
+/// Change the blocked signals with the set at `set` unless it is null, as
+/// `how` says, and store the previous ones at `oldset` unless that is null
+pub(super) fn sigprocmask(how: u32, set: UserPtr<u64>, oldset: UserPtr<u64>) -> SysResult {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let old = if set.is_null() {
+        process.sigprocmask(signal::SIG_BLOCK, SigSet::empty())?
+    } else {
+        process.sigprocmask(how, SigSet::from_bits(set.read()?))?
+    };
+    if !oldset.is_null() {
+        oldset.write(old.bits())?;
+    }
+    Ok(0)
+}
+
+/// Return from a signal handler: go back to the registers and blocked signals
+/// in the `SignalFrame` the handler's return popped off the stack
+///
+/// Doesn't return; a frame that isn't there or that would go back to kernel
+/// space ends the process with `SIGSEGV`.
+pub(super) fn sigreturn() -> SysResult {
+    let exit = restore_frame()?;
+    usermode::leave(exit)
+}
+
+fn restore_frame() -> Result<UserExit, Errno> {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let sp = unsafe { usermode::user_context() }.rsp;
+    let frame = UserPtr::<SignalFrame>::decode(sp)
+        .and_then(UserPtr::read)
+        .ok()
+        .filter(|frame| {
+            frame.context.rip < memory::USER_END && frame.context.rsp < memory::USER_END
+        });
+    let Some(frame) = frame else {
+        process.terminate(Signal::SIGSEGV);
+        return Ok(UserExit::Killed);
+    };
+    process.sigprocmask(signal::SIG_SETMASK, SigSet::from_bits(frame.blocked))?;
+    Ok(UserExit::Resume(frame.context))
+}
+
+/// Give `handles` descriptors in the current process, all of them or none
+fn open_files(handles: impl IntoIterator<Item = Handle>) -> Result<Vec<i32>, Errno> {
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let mut fds = Vec::new();
+    for handle in handles {
+        match process.with_files(|table| table.insert(handle)) {
+            Ok(fd) => fds.push(fd),
+            Err(errno) => {
+                close_files(&fds);
+                return Err(errno);
+            }
+        }
+    }
+    Ok(fds)
+}
+
+/// Close `fds`, which `open_files()` opened for a call that failed after all
+fn close_files(fds: &[i32]) {
+    let Some(process) = process::current() else {
+        return;
+    };
+    for &fd in fds {
+        // Outside the process's lock, closing may wake whoever is at the other end
+        let handle = process.with_files(|table| table.close(fd));
+        drop(handle);
+    }
+}
+
+/// Open `a` and `b` and store their descriptors at `fds`
+fn open_pair(fds: UserPtr<[i32; 2]>, a: Handle, b: Handle) -> SysResult {
+    let opened = open_files([a, b])?;
+    if let Err(errno) = fds.write([opened[0], opened[1]]) {
+        close_files(&opened);
+        return Err(errno);
+    }
+    Ok(0)
+}
+
+/// Make a pipe, store the descriptors of its read and write ends at `fds`
+///
+/// Each end only has the right to be used the way it goes.
+pub(super) fn pipe(fds: UserPtr<[i32; 2]>) -> SysResult {
+    let (reader, writer) = ipc::pipe();
+    let pass = Rights::DUPLICATE | Rights::TRANSFER;
+    open_pair(
+        fds,
+        Handle::new(Arc::new(reader), Rights::READ | pass),
+        Handle::new(Arc::new(writer), Rights::WRITE | pass),
+    )
+}
+
+/// Make a channel, store the descriptors of its two endpoints at `fds`
+pub(super) fn channel(fds: UserPtr<[i32; 2]>) -> SysResult {
+    let (a, b) = Endpoint::pair();
+    open_pair(
+        fds,
+        Handle::new(Arc::new(a), Rights::ALL),
+        Handle::new(Arc::new(b), Rights::ALL),
+    )
+}
+
+/// The channel endpoint `fd` refers to, `EBADF` if it isn't one and
+/// `EACCES` unless its handle has `rights`
+fn endpoint(fd: i32, rights: Rights) -> Result<Arc<Endpoint>, Errno> {
+    fd::downcast(file(fd, rights)?).ok_or(Errno::EBADF)
+}
+
+/// Send the `len` bytes at `buf` and the `count` descriptors at `handles`
+/// through the channel endpoint `fd`
+///
+/// The descriptors stay open in the sender, the receiver's get the same
+/// rights. Each needs `TRANSFER`, and `fd` needs `WRITE`. An endpoint can't be
+/// sent through its own channel, `EINVAL`.
+pub(super) fn send(
+    fd: i32,
+    buf: UserPtr<u8>,
+    len: usize,
+    handles: UserPtr<i32>,
+    count: usize,
+) -> SysResult {
+    let endpoint = endpoint(fd, Rights::WRITE)?;
+    if len > ipc::MAX_MESSAGE || count > ipc::MAX_HANDLES {
+        return Err(Errno::EMSGSIZE);
+    }
+    let data = unsafe { buf.slice(len)? }.to_vec();
+    let handles = unsafe { handles.slice(count)? }
+        .iter()
+        .map(|&fd| handle(fd))
+        .collect::<Result<Vec<_>, _>>()?;
+    for handle in &handles {
+        if !handle.rights.contains(Rights::TRANSFER) {
+            return Err(Errno::EACCES);
+        }
+        // It would keep its channel open forever
+        if fd::downcast::<Endpoint>(handle.file.clone())
+            .is_some_and(|sent| sent.same_channel(&endpoint))
+        {
+            return Err(Errno::EINVAL);
+        }
+    }
+    endpoint.send(Message { data, handles })?;
+    Ok(0)
+}
+
+/// Take the next message from the channel endpoint `fd`: its bytes into the
+/// `len` at `buf`, and descriptors for its handles into the `count` at
+/// `handles`, -1 in the slots left; returns its length
+///
+/// `EMSGSIZE`, leaving it queued, if it doesn't fit. `fd` needs `READ`.
+pub(super) fn recv(
+    fd: i32,
+    buf: UserPtr<u8>,
+    len: usize,
+    handles: UserPtr<i32>,
+    count: usize,
+) -> SysResult {
+    let endpoint = endpoint(fd, Rights::READ)?;
+    let message = endpoint.recv(len, count)?;
+    let mut fds = open_files(message.handles)?;
+    let received = fds.len();
+    fds.resize(count, -1);
+    if let Err(errno) = buf
+        .write_slice(&message.data)
+        .and_then(|()| handles.write_slice(&fds))
+    {
+        close_files(&fds[..received]);
+        return Err(errno);
+    }
+    Ok(message.data.len() as u64)
+}
+
+/// Make a shared memory region of `size` bytes, returns its descriptor
+pub(super) fn shm_create(size: usize) -> SysResult {
+    let region = SharedMemory::new(size)?;
+    let fd = open_files([Handle::new(Arc::new(region), Rights::ALL)])?;
+    Ok(fd[0] as u64)
+}
+
+/// The rights mapping a descriptor's object with `prot` needs
+fn map_rights(prot: Prot) -> Rights {
+    if prot.contains(Prot::WRITE) {
+        Rights::READ | Rights::WRITE
+    } else {
+        Rights::READ
+    }
+}
+
+/// Map the shared memory region `fd` at `addr`, or wherever there is room if
+/// it is null, returns the address
+///
+/// `fd` needs `READ`, and `WRITE` for a writable mapping.
+pub(super) fn shm_map(fd: i32, addr: UserPtr<u8>, prot: Prot) -> SysResult {
+    let region: Arc<SharedMemory> =
+        fd::downcast(file(fd, map_rights(prot))?).ok_or(Errno::EBADF)?;
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let address_space = process.address_space().ok_or(Errno::EINVAL)?;
+    let start = map_area(addr, region.size() as u64, |start| {
+        region
+            .map(&address_space, start, page_flags(prot))
+            .map_err(|_| Errno::ENOMEM)
+    })?;
+    Ok(start.as_u64())
+}
+
+/// Let the caller's code use the I/O ports `fd` refers to, which needs `READ`
+/// and `WRITE`
+pub(super) fn ioport_enable(fd: i32) -> SysResult {
+    let ports: Arc<IoPorts> =
+        fd::downcast(file(fd, Rights::READ | Rights::WRITE)?).ok_or(Errno::EBADF)?;
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    ports.enable(&process);
+    Ok(0)
+}
+
+/// Map the device memory `fd` at `addr`, or wherever there is room if it is
+/// null, returns the address
+///
+/// `fd` needs `READ`, and `WRITE` for a writable mapping.
+pub(super) fn mmio_map(fd: i32, addr: UserPtr<u8>, prot: Prot) -> SysResult {
+    let region: Arc<MmioRegion> = fd::downcast(file(fd, map_rights(prot))?).ok_or(Errno::EBADF)?;
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let address_space = process.address_space().ok_or(Errno::EINVAL)?;
+    let start = map_area(addr, region.size() as u64, |start| {
+        region
+            .map(&address_space, start, page_flags(prot))
+            .map_err(|_| Errno::ENOMEM)
+    })?;
+    Ok(start.as_u64())
+}
+
+/// Send the interrupts of the line `fd` through the channel endpoint
+/// `endpoint`, a one byte message each
+///
+/// `fd` needs `READ` and `endpoint` `WRITE`.
+pub(super) fn irq_bind(fd: i32, endpoint: i32) -> SysResult {
+    let line: Arc<IrqLine> = fd::downcast(file(fd, Rights::READ)?).ok_or(Errno::EBADF)?;
+    line.bind(self::endpoint(endpoint, Rights::WRITE)?);
+    Ok(0)
+}
@@ -0,0 +1,320 @@
+use crate::kernel::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR};
+use crate::kernel::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::kernel::process::fd::Rights;
+use crate::kernel::process::signal::SigAction;
+use crate::kernel::usermode::UserContext;
+use crate::kernel::{memory, percpu, process};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+mod args;
+mod calls;
+
+pub use args::{Errno, Prot, SysResult, SyscallArg, UserPtr};
+
+// System calls from user mode.
+//
+// User code puts the number in `rax` and up to six arguments in `rdi`, `rsi`,
+// `rdx`, `r10`, `r8` and `r9` (Linux's convention), runs `syscall` and gets the
+// result back in `rax`, `-errno` on failure. `rcx` and `r11` are clobbered,
+// everything else is preserved.
+//
+// `syscall` leaves us on the user stack with the user's GS base: the entry
+// stub does `swapgs` and switches to the thread's kernel stack (RSP0, kept in
+// the per-CPU block) before anything else. `int 0x80` does the same through
+// an interrupt gate, handy for debugging from a debugger or from the kernel
+// itself. Both save every user register in a `UserContext` at the top of the
+// kernel stack, where `usermode::user_context()` finds them, and call
+// `dispatch()`, which decodes the arguments for the handler in the table at
+// the bottom, with interrupts enabled: system calls may block. They return to
+// user code with the registers in the context, those a handler changed
+// included.
+
+/// Vector of the legacy `int 0x80` entry
+pub const INT80_VECTOR: u8 = 0x80;
+
+core::arch::global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_stack}]
+    // The frame `int 0x80` gets, on the stack aligned like the CPU does it
+    and rsp, -16
+    push {user_ss}
+    push qword ptr gs:[{user_rsp}]
+    push r11
+    push {user_cs}
+    push rcx
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    sti
+    call {dispatch}
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    // A handler may have put rip anywhere. `sysretq` to a non-canonical rip
+    // faults in ring 0 on the user stack, so anything outside user space goes
+    // back through `iretq`, which faults on the kernel stack
+    push rcx
+    mov rcx, {user_end}
+    cmp [rsp + 8], rcx
+    pop rcx
+    jae 3f
+    // Back where the frame says, `sysretq` takes it from rcx and r11
+    mov rcx, [rsp]
+    mov r11, [rsp + 16]
+    mov rsp, [rsp + 24]
+    swapgs
+    sysretq
+3:
+    swapgs
+    iretq
+
+.global int80_entry
+int80_entry:
+    test qword ptr [rsp + 8], 3
+    jz 1f
+    swapgs
+1:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    sti
+    call {dispatch}
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    test qword ptr [rsp + 8], 3
+    jz 2f
+    swapgs
+2:
+    iretq
+"#,
+    user_rsp = const percpu::USER_RSP_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    user_cs = const USER_CODE_SELECTOR.0,
+    user_ss = const USER_DATA_SELECTOR.0,
+    user_end = const memory::USER_END,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+    fn int80_entry();
+}
+
+/// Address of the `int 0x80` entry stub, for the IDT
+pub fn int80_entry_addr() -> VirtAddr {
+    VirtAddr::new(int80_entry as *const () as u64)
+}
+
+/// Point this CPU's `syscall` instruction at our entry stub
+pub fn init_cpu() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    Star::write(
+        USER_CODE_SELECTOR,
+        USER_DATA_SELECTOR,
+        KERNEL_CODE_SELECTOR,
+        KERNEL_DATA_SELECTOR,
+    )
+    .expect("syscall: GDT layout doesn't suit SYSRET");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // The entry stub runs with interrupts disabled until it is on the kernel
+    // stack, and with the flags the kernel expects
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+}
+
+extern "C" fn syscall_dispatch(context: &mut UserContext) {
+    let args = [
+        context.rdi,
+        context.rsi,
+        context.rdx,
+        context.r10,
+        context.r8,
+        context.r9,
+    ];
+    context.rax = args::encode(dispatch(context.rax, &args));
+    process::returning_to_user(context);
+}
+
+// Declares the system call numbers and generates `dispatch()` and `name()`
+macro_rules! syscalls {
+    ($($(#[$attr:meta])* $constant:ident = $number:literal => $handler:ident($($arg:ident: $ty:ty),*);)*) => {
+        $(
+            $(#[$attr])*
+            pub const $constant: u64 = $number;
+        )*
+
+        /// Run system call `number` with the raw `args`
+        fn dispatch(number: u64, args: &[u64; 6]) -> SysResult {
+            match number {
+                $(
+                    $number => {
+                        let mut _raw = args.iter().copied();
+                        $(let $arg = <$ty as SyscallArg>::decode(_raw.next().unwrap_or(0))?;)*
+                        calls::$handler($($arg),*)
+                    }
+                )*
+                _ => Err(Errno::ENOSYS),
+            }
+        }
+
+        /// Name of system call `number`
+        pub fn name(number: u64) -> Option<&'static str> {
+            match number {
+                $($number => Some(stringify!($handler)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+syscalls! {
+    /// `write(fd, buf, len)`: write to an open file, 1 and 2 are the console
+    SYS_WRITE = 0 => write(fd: i32, buf: UserPtr<u8>, len: usize);
+    /// `exit(status)`: end the process
+    SYS_EXIT = 1 => exit(status: i32);
+    /// `yield()`: let other threads run
+    SYS_YIELD = 2 => sched_yield();
+    /// `sleep(ticks)`: block for at least `ticks` timer ticks
+    SYS_SLEEP = 3 => sleep(ticks: u64);
+    /// `getpid()`: the process's pid
+    SYS_GETPID = 4 => getpid();
+    /// `mmap(addr, len, prot)`: map zeroed memory, `addr` 0 to let the kernel choose
+    SYS_MMAP = 5 => mmap(addr: UserPtr<u8>, len: usize, prot: Prot);
+    /// `close(fd)`: free a file descriptor
+    SYS_CLOSE = 6 => close(fd: i32);
+    /// `spawn(path, argv)`: start a program in a child process, returns its pid
+    SYS_SPAWN = 7 => spawn(path: UserPtr<u8>, argv: UserPtr<u64>);
+    /// `waitpid(pid, status, options)`: wait for a child, any with -1, to end, and reap it
+    SYS_WAITPID = 8 => waitpid(pid: i32, status: UserPtr<i32>, options: u32);
+    /// `kill(pid, signal)`: send a signal to the caller or a child, 0 to check that it is one
+    SYS_KILL = 9 => kill(pid: i32, signal: u32);
+    /// `fork()`: copy the process, returns the child's pid, 0 in the child
+    SYS_FORK = 10 => fork();
+    /// `execve(path, argv, envp)`: replace the process's program, doesn't return on success
+    SYS_EXECVE = 11 => execve(path: UserPtr<u8>, argv: UserPtr<u64>, envp: UserPtr<u64>);
+    /// `getppid()`: the parent's pid, 0 without one
+    SYS_GETPPID = 12 => getppid();
+    /// `sigreturn()`: return from a signal handler, see `process::signal`
+    SYS_SIGRETURN = 13 => sigreturn();
+    /// `sigaction(signal, act, oldact)`: set and/or get what a signal does
+    SYS_SIGACTION = 14 => sigaction(signal: u32, act: UserPtr<SigAction>, oldact: UserPtr<SigAction>);
+    /// `sigprocmask(how, set, oldset)`: block and unblock signals and/or get the blocked ones
+    SYS_SIGPROCMASK = 15 => sigprocmask(how: u32, set: UserPtr<u64>, oldset: UserPtr<u64>);
+    /// `read(fd, buf, len)`: read from an open file, returns how many bytes it read
+    SYS_READ = 16 => read(fd: i32, buf: UserPtr<u8>, len: usize);
+    /// `pipe(fds)`: make a pipe, `fds` gets the read and write ends
+    SYS_PIPE = 17 => pipe(fds: UserPtr<[i32; 2]>);
+    /// `channel(fds)`: make a channel, `fds` gets its two endpoints, see `ipc`
+    SYS_CHANNEL = 18 => channel(fds: UserPtr<[i32; 2]>);
+    /// `send(fd, buf, len, handles, count)`: send a message with descriptors through a channel
+    SYS_SEND = 19 => send(fd: i32, buf: UserPtr<u8>, len: usize, handles: UserPtr<i32>, count: usize);
+    /// `recv(fd, buf, len, handles, count)`: take a message from a channel, returns its length
+    SYS_RECV = 20 => recv(fd: i32, buf: UserPtr<u8>, len: usize, handles: UserPtr<i32>, count: usize);
+    /// `shm_create(size)`: make a shared memory region, returns its descriptor
+    SYS_SHM_CREATE = 21 => shm_create(size: usize);
+    /// `shm_map(fd, addr, prot)`: map a shared memory region, `addr` 0 to let the kernel choose
+    SYS_SHM_MAP = 22 => shm_map(fd: i32, addr: UserPtr<u8>, prot: Prot);
+    /// `dup(fd, rights)`: another descriptor for the same object, with fewer rights or the same
+    SYS_DUP = 23 => dup(fd: i32, rights: Rights);
+    /// `handle_rights(fd)`: the rights a descriptor has
+    SYS_HANDLE_RIGHTS = 24 => handle_rights(fd: i32);
+    /// `process_open(pid)`: a descriptor for the caller, with `pid` 0, or one of its children
+    SYS_PROCESS_OPEN = 25 => process_open(pid: i32);
+    /// `process_kill(fd, signal)`: send a signal to a process through its descriptor
+    SYS_PROCESS_KILL = 26 => process_kill(fd: i32, signal: u32);
+    /// `process_wait(fd, &status)`: wait for a process to end without reaping it
+    SYS_PROCESS_WAIT = 27 => process_wait(fd: i32, status: UserPtr<i32>);
+    /// `ioport_enable(fd)`: let the caller use the I/O ports a descriptor refers to
+    SYS_IOPORT_ENABLE = 28 => ioport_enable(fd: i32);
+    /// `mmio_map(fd, addr, prot)`: map device memory uncached, `addr` 0 to let the kernel choose
+    SYS_MMIO_MAP = 29 => mmio_map(fd: i32, addr: UserPtr<u8>, prot: Prot);
+    /// `irq_bind(fd, endpoint)`: send an interrupt line's interrupts through a channel
+    SYS_IRQ_BIND = 30 => irq_bind(fd: i32, endpoint: i32);
+}
+
+#[test_case]
+fn test_int80_from_kernel() {
+    use crate::kernel::thread;
+
+    let call = |number: u64, arg: u64| -> u64 {
+        let result: u64;
+        unsafe {
+            core::arch::asm!(
+                "int 0x80",
+                inout("rax") number => result,
+                in("rdi") arg,
+                out("rcx") _,
+                out("r11") _,
+            );
+        }
+        result
+    };
+    assert_eq!(call(SYS_GETPID, 0), thread::current().id().as_u64());
+    // Not in user mode, nothing to exit from
+    assert_eq!(call(SYS_EXIT, 0), args::encode(Err(Errno::EINVAL)));
+    assert_eq!(call(1234, 0), args::encode(Err(Errno::ENOSYS)));
+    assert_eq!(name(SYS_MMAP), Some("mmap"));
+}
@@ -0,0 +1,164 @@
+use crate::kernel::task::{Task, TaskId};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+/// Maximum number of woken tasks waiting to be polled
+const TASK_QUEUE_SIZE: usize = 128;
+
+/// Runs tasks until they complete, polling each one only after it has been woken
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    // Ids of tasks ready to be polled. Wakers push to it, possibly from interrupt
+    // handlers, so this is a fixed size lock free queue: no allocation, no locks.
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // Wakers are reference counted, keep one per task instead of allocating
+    // a new one on every poll
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// Add a task, it is polled for the first time on the next run
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("executor: task with same ID already spawned");
+        }
+        self.task_queue
+            .push(task_id)
+            .expect("executor: task queue full");
+    }
+
+    /// Number of tasks that have not completed yet
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Run tasks forever, halting the CPU while none of them is ready
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Run tasks until none of them is ready, then return
+    ///
+    /// Tasks that wait for an interrupt are left pending.
+    pub fn run_until_stalled(&mut self) {
+        while !self.task_queue.is_empty() {
+            self.run_ready_tasks();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // Destructure `self` to borrow the fields independently of each other
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // Woken after it completed
+                None => continue,
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::waker(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // An interrupt between checking the queue and `hlt` could wake a task,
+        // and we'd sleep until the next interrupt with that task ready. Check with
+        // interrupts disabled; `sti; hlt` only lets them in once halted.
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wakes a task by queueing its id for the executor
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue
+            .push(self.task_id)
+            .expect("executor: task queue full");
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn test_executor_runs_tasks() {
+    use crate::kernel::task::yield_now;
+    use alloc::vec::Vec;
+    use spin::Mutex;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    for n in 0..2 {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            order.lock().push((n, 0));
+            yield_now().await;
+            order.lock().push((n, 1));
+        }));
+    }
+    executor.run_until_stalled();
+
+    assert_eq!(executor.task_count(), 0);
+    // Yielding lets the other task run in between
+    assert_eq!(*order.lock(), [(0, 0), (1, 0), (0, 1), (1, 1)]);
+}
@@ -0,0 +1,98 @@
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+/// Data from an interrupt handler, consumed by one task as a `Stream`
+///
+/// The handler side (`push`) neither allocates nor takes locks, so it is safe to
+/// call with interrupts disabled and from the handler itself. The queue is
+/// allocated when the stream is created, data arriving before that is dropped.
+/// Data that doesn't fit is dropped too, and reported by the stream.
+pub struct IrqQueue<T: 'static> {
+    name: &'static str,
+    capacity: usize,
+    queue: OnceCell<ArrayQueue<T>>,
+    waker: AtomicWaker,
+    /// Values dropped for lack of room since the stream last reported it
+    dropped: AtomicUsize,
+}
+
+impl<T> IrqQueue<T> {
+    pub const fn new(name: &'static str, capacity: usize) -> Self {
+        IrqQueue {
+            name,
+            capacity,
+            queue: OnceCell::uninit(),
+            waker: AtomicWaker::new(),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queue `value` and wake the stream's task, called from interrupt handlers
+    pub fn push(&'static self, value: T) {
+        let Ok(queue) = self.queue.try_get() else {
+            // Nobody is listening yet
+            return;
+        };
+        if queue.push(value).is_err() {
+            // Printing here could spin on a lock the interrupted code holds,
+            // the stream reports it from task context
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.waker.wake();
+    }
+
+    /// The stream of queued values
+    ///
+    /// Panics if called more than once, there is a single consumer.
+    pub fn stream(&'static self) -> IrqStream<T> {
+        self.queue
+            .try_init_once(|| ArrayQueue::new(self.capacity))
+            .unwrap_or_else(|_| panic!("{}: stream already taken", self.name));
+        IrqStream { queue: self }
+    }
+}
+
+/// Values pushed to an `IrqQueue`, in order
+pub struct IrqStream<T: 'static> {
+    queue: &'static IrqQueue<T>,
+}
+
+impl<T> Stream for IrqStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let queue = self
+            .queue
+            .queue
+            .try_get()
+            .expect("stream: queue not initialized");
+        let dropped = self.queue.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            crate::serial_println!(
+                "WARNING: {} queue full, dropped {} items",
+                self.queue.name,
+                dropped
+            );
+        }
+
+        // Fast path, avoids touching the waker
+        if let Some(value) = queue.pop() {
+            return Poll::Ready(Some(value));
+        }
+        // Register before checking again: a push in between would otherwise
+        // wake nobody and the value would sit in the queue
+        self.queue.waker.register(cx.waker());
+        match queue.pop() {
+            Some(value) => {
+                self.queue.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
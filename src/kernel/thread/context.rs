@@ -0,0 +1,82 @@
+use x86_64::VirtAddr;
+
+// A thread that is not running is described by nothing more than its saved
+// stack pointer: `switch_context` pushes the callee-saved registers on the old
+// stack, stores `rsp`, loads the new thread's `rsp` and pops its registers.
+// Everything else (caller-saved registers, the interrupt frame when switching
+// from an interrupt handler) is already on the stack by the time we get there.
+//
+// New threads get a stack that looks as if `switch_context` had been called
+// from `thread_entry`, which passes `r12` on to the Rust entry function.
+
+core::arch::global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global thread_entry
+thread_entry:
+    mov rdi, r12
+    // The ABI wants rsp 16 byte aligned before a call
+    and rsp, -16
+    call {start}
+    ud2
+"#,
+    start = sym super::thread_start,
+);
+
+extern "C" {
+    /// Save the current context's stack pointer to `*old_rsp` and resume the context at `new_rsp`
+    ///
+    /// Returns when another thread switches back to us.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_entry();
+}
+
+/// Callee-saved registers in the order `switch_context` pops them
+#[repr(C)]
+struct InitialFrame {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    ret: u64,
+}
+
+/// Prepare the stack ending at `stack_top` so that switching to it calls
+/// `thread_start(arg)`, returns the stack pointer to switch to
+///
+/// # Safety
+///
+/// `stack_top` must be the top of a mapped, unused stack.
+pub unsafe fn init_stack(stack_top: VirtAddr, arg: u64) -> u64 {
+    let frame = (stack_top.as_u64() as *mut InitialFrame).sub(1);
+    frame.write(InitialFrame {
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: arg,
+        rbx: 0,
+        // Terminates frame pointer based backtraces
+        rbp: 0,
+        ret: thread_entry as *const () as u64,
+    });
+    frame as u64
+}
@@ -0,0 +1,424 @@
+use crate::kernel::address_space::AddressSpace;
+use crate::kernel::interrupts::{ticks, TIMER_HZ};
+use crate::kernel::process::Process;
+use crate::kernel::thread::scheduler::{SchedEntity, NICE_MAX, NICE_MIN};
+use crate::kernel::thread::stack::KernelStack;
+use crate::kernel::{cpu, memory};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicI8, AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+mod context;
+pub mod scheduler;
+pub mod stack;
+
+// Kernel threads: each has its own stack (with a guard page, see `stack`) and
+// is switched to and from by saving and restoring its stack pointer (see
+// `context`). The code `init()` runs from becomes the "boot" thread, running
+// on the stack the bootloader gave us.
+//
+// Threads switch when they yield, block (`JoinHandle::join`, `sleep_ticks`),
+// exit, or when the timer preempts them (see `scheduler`).
+
+/// Unique id of a thread, the boot thread is 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    /// Waiting in the run queue
+    Ready,
+    Running,
+    /// Waiting for something, out of the run queue until woken
+    Blocked,
+    /// Finished, will never run again
+    Exited,
+}
+
+impl ThreadState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            _ => ThreadState::Exited,
+        }
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    // Read by the timer interrupt, so these are atomics rather than behind `inner`
+    state: AtomicU8,
+    nice: AtomicI8,
+    // Niceness of the most important thread waiting on a mutex we hold, see
+    // `scheduler::set_inherited_nice()`
+    inherited_nice: AtomicI8,
+    // The best niceness lent through each mutex we hold, by the mutex's
+    // address, see `lend_nice()`. Only locked with interrupts disabled.
+    mutex_loans: Mutex<Vec<(usize, i8)>>,
+    /// CPU whose run queue the thread is on
+    cpu: usize,
+    // Saved stack pointer while switched out, only used by the scheduler
+    context: UnsafeCell<u64>,
+    // Kernel stack pointer saved when the thread entered user mode, 0 while
+    // it runs kernel code; the CPU's RSP0 while it runs, see `usermode`
+    kernel_rsp: AtomicU64,
+    // Level 4 table the thread runs on, 0 for the kernel's, switched to by the
+    // scheduler; `inner.address_space` keeps it alive
+    page_table: AtomicU64,
+    // FS base of its user code, user TLS
+    fs_base: AtomicU64,
+    // Only locked with the run queue locked
+    sched: Mutex<SchedEntity>,
+    // Only locked with interrupts disabled, the scheduler takes it too
+    inner: Mutex<ThreadInner>,
+}
+
+struct ThreadInner {
+    /// `None` for the boot thread and once an exited thread has been reaped
+    stack: Option<KernelStack>,
+    /// Thread blocked in `join()` on this one
+    joiner: Option<Arc<Thread>>,
+    /// The user address space it runs on, `None` for kernel threads
+    address_space: Option<Arc<AddressSpace>>,
+    /// The process it belongs to while it runs the process's code
+    process: Option<Arc<Process>>,
+}
+
+// `context` is only accessed by the scheduler, with its lock held or while
+// switching with interrupts disabled.
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(name: &str, rsp: u64, stack: Option<KernelStack>, state: ThreadState) -> Self {
+        Thread {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            state: AtomicU8::new(state as u8),
+            nice: AtomicI8::new(0),
+            inherited_nice: AtomicI8::new(NICE_MAX),
+            mutex_loans: Mutex::new(Vec::new()),
+            cpu: 0,
+            context: UnsafeCell::new(rsp),
+            kernel_rsp: AtomicU64::new(0),
+            page_table: AtomicU64::new(0),
+            fs_base: AtomicU64::new(0),
+            sched: Mutex::new(SchedEntity::default()),
+            inner: Mutex::new(ThreadInner {
+                stack,
+                joiner: None,
+                address_space: None,
+                process: None,
+            }),
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice.load(Ordering::Relaxed)
+    }
+
+    /// Change the niceness, `NICE_MIN` (most CPU time) to `NICE_MAX`
+    ///
+    /// Takes effect the next time the thread is queued.
+    pub fn set_nice(&self, nice: i8) {
+        self.nice
+            .store(nice.clamp(NICE_MIN, NICE_MAX), Ordering::Relaxed);
+    }
+
+    /// Niceness the scheduler goes by, better than `nice()` while a more
+    /// important thread waits for a mutex this one holds
+    pub fn effective_nice(&self) -> i8 {
+        self.nice().min(self.inherited_nice.load(Ordering::Relaxed))
+    }
+
+    /// Record that the threads waiting for the mutex at `key`, which this one
+    /// holds, lend it `loan`, or nothing anymore with `None`; returns the best
+    /// niceness lent through any mutex it holds, see `sync::Mutex`
+    pub(crate) fn lend_nice(&self, key: usize, loan: Option<i8>) -> Option<i8> {
+        interrupts::without_interrupts(|| {
+            let mut loans = self.mutex_loans.lock();
+            loans.retain(|&(held, _)| held != key);
+            loans.extend(loan.map(|nice| (key, nice)));
+            loans.iter().map(|&(_, nice)| nice).min()
+        })
+    }
+
+    /// Timer ticks the thread has been running for
+    pub fn runtime_ticks(&self) -> u64 {
+        interrupts::without_interrupts(|| self.sched.lock().runtime_ticks)
+    }
+
+    /// The process the thread runs the code of, `None` for kernel threads
+    pub fn process(&self) -> Option<Arc<Process>> {
+        interrupts::without_interrupts(|| self.inner.lock().process.clone())
+    }
+
+    /// Where `usermode` saves the kernel stack pointer on its way to user mode
+    pub(crate) fn kernel_rsp(&self) -> &AtomicU64 {
+        &self.kernel_rsp
+    }
+
+    /// The level 4 table the thread runs on
+    fn page_table(&self) -> PhysFrame {
+        match self.page_table.load(Ordering::Relaxed) {
+            0 => memory::kernel_page_table(),
+            addr => PhysFrame::containing_address(PhysAddr::new(addr)),
+        }
+    }
+
+    fn take_stack(&self) -> Option<KernelStack> {
+        interrupts::without_interrupts(|| self.inner.lock().stack.take())
+    }
+
+    fn take_address_space(&self) -> Option<Arc<AddressSpace>> {
+        interrupts::without_interrupts(|| self.inner.lock().address_space.take())
+    }
+}
+
+/// Owned permission to wait for a thread to exit
+///
+/// Dropping it detaches the thread.
+pub struct JoinHandle {
+    thread: Arc<Thread>,
+}
+
+impl JoinHandle {
+    pub fn thread(&self) -> &Arc<Thread> {
+        &self.thread
+    }
+
+    /// Block until the thread has exited
+    pub fn join(self) {
+        interrupts::without_interrupts(|| loop {
+            let current = scheduler::current();
+            assert!(
+                !Arc::ptr_eq(&current, &self.thread),
+                "thread: a thread can't join itself"
+            );
+            if self.thread.state() == ThreadState::Exited {
+                // Free its stack now rather than when the idle thread gets to it
+                scheduler::reap();
+                return;
+            }
+            self.thread.inner.lock().joiner = Some(current);
+            scheduler::block_current(None);
+            scheduler::schedule();
+        })
+    }
+}
+
+/// Turn the running code into the boot thread and start scheduling
+///
+/// Must run after the heap has been set up. Preemption starts once the timer
+/// interrupt is enabled.
+pub fn init() {
+    let boot = Arc::new(Thread::new("boot", 0, None, ThreadState::Running));
+    let idle = create("idle", || scheduler::idle());
+    scheduler::init(boot, idle);
+}
+
+/// Turn an application processor's startup code into its idle thread
+///
+/// `stack` is the stack the CPU is running on. Once set up, the CPU calls
+/// `scheduler::idle()` and runs whatever gets queued on it.
+pub fn init_ap(stack: KernelStack) {
+    let mut idle = Thread::new("idle", 0, Some(stack), ThreadState::Running);
+    idle.cpu = cpu::id();
+    let idle = Arc::new(idle);
+    scheduler::init(idle.clone(), idle);
+}
+
+/// Start a thread running `f`
+///
+/// Panics if no stack can be allocated for it.
+pub fn spawn<F>(f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_named("thread", f)
+}
+
+/// Start a thread named `name` running `f`
+pub fn spawn_named<F>(name: &str, f: F) -> JoinHandle
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = create(name, f);
+    scheduler::add(thread.clone());
+    JoinHandle { thread }
+}
+
+/// A thread that will run `f` once scheduled
+fn create<F>(name: &str, f: F) -> Arc<Thread>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = KernelStack::new().expect("thread: failed to allocate a stack");
+    // A thin pointer fits in a register, `thread_start` takes ownership of it
+    let entry: Box<Box<dyn FnOnce() + Send>> = Box::new(Box::new(f));
+    let rsp = unsafe { context::init_stack(stack.top(), Box::into_raw(entry) as u64) };
+    Arc::new(Thread::new(name, rsp, Some(stack), ThreadState::Ready))
+}
+
+/// The running thread
+pub fn current() -> Arc<Thread> {
+    scheduler::current()
+}
+
+/// Let the other ready threads run first
+pub fn yield_now() {
+    interrupts::without_interrupts(scheduler::schedule);
+}
+
+/// Move the running thread to `space`, `None` for the kernel's page table
+///
+/// Returns the address space it ran on before, which may be dropped once the
+/// thread is off it.
+pub fn set_address_space(space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+    interrupts::without_interrupts(|| {
+        let current = scheduler::current();
+        let addr = space
+            .as_ref()
+            .map_or(0, |space| space.level_4_frame().start_address().as_u64());
+        current.page_table.store(addr, Ordering::Relaxed);
+        scheduler::switch_page_table(current.page_table());
+        let previous = core::mem::replace(&mut current.inner.lock().address_space, space);
+        previous
+    })
+}
+
+/// Make the running thread one of `process`'s, `None` to leave it
+///
+/// The thread holds on to the process until it leaves, see `process`.
+pub(crate) fn set_process(process: Option<Arc<Process>>) -> Option<Arc<Process>> {
+    interrupts::without_interrupts(|| {
+        let current = scheduler::current();
+        let previous = core::mem::replace(&mut current.inner.lock().process, process);
+        previous
+    })
+}
+
+/// Set the FS base the running thread's user code runs with
+pub fn set_fs_base(base: VirtAddr) {
+    interrupts::without_interrupts(|| {
+        scheduler::current()
+            .fs_base
+            .store(base.as_u64(), Ordering::Relaxed);
+        FsBase::write(base);
+    });
+}
+
+/// Block the running thread for at least `n` timer ticks
+pub fn sleep_ticks(n: u64) {
+    scheduler::sleep_until(ticks() + n);
+}
+
+/// Block the running thread for at least `ms` milliseconds
+pub fn sleep_ms(ms: u64) {
+    sleep_ticks((ms * TIMER_HZ as u64).div_ceil(1000));
+}
+
+/// End the running thread
+pub fn exit() -> ! {
+    interrupts::disable();
+    let current = scheduler::current();
+    current.set_state(ThreadState::Exited);
+    let joiner = current.inner.lock().joiner.take();
+    if let Some(joiner) = joiner {
+        scheduler::wake(joiner);
+    }
+    drop(current);
+    scheduler::schedule();
+    unreachable!("thread: exited thread was scheduled");
+}
+
+/// First Rust code a new thread runs, called by `context::thread_entry`
+extern "C" fn thread_start(entry: *mut Box<dyn FnOnce() + Send>) -> ! {
+    // We got here through `schedule()` on some other thread, finish its job
+    scheduler::finish_switch();
+    let entry = unsafe { Box::from_raw(entry) };
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+#[test_case]
+fn test_spawn_join() {
+    use core::sync::atomic::AtomicBool;
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let flag = ran.clone();
+    let handle = spawn(move || flag.store(true, Ordering::Relaxed));
+    let thread = handle.thread().clone();
+    handle.join();
+
+    assert!(ran.load(Ordering::Relaxed));
+    assert_eq!(thread.state(), ThreadState::Exited);
+    // Reaped by `join()`
+    assert!(thread.take_stack().is_none());
+}
+
+#[test_case]
+fn test_yield_interleaves() {
+    use alloc::vec::Vec;
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = ['a', 'b']
+        .into_iter()
+        .map(|name| {
+            let order = order.clone();
+            spawn(move || {
+                order.lock().push((name, 0));
+                yield_now();
+                order.lock().push((name, 1));
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    // The timer may preempt either thread too, but yielding always lets the other one in
+    let order = order.lock();
+    let position = |entry| order.iter().position(|&e| e == entry).unwrap();
+    assert_eq!(order.len(), 4);
+    assert!(position(('b', 0)) < position(('a', 1)));
+    assert!(position(('a', 0)) < position(('b', 1)));
+}
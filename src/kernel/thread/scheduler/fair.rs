@@ -0,0 +1,146 @@
+use super::{nice_index, Policy, SchedEntity, NICE_LEVELS};
+use crate::kernel::interrupts::TIMER_HZ;
+use crate::kernel::thread::Thread;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Weight of a nice 0 thread
+const NICE_0_WEIGHT: u64 = 1024;
+/// Weight per niceness, each step is worth ~10% CPU time (same table as Linux)
+const WEIGHTS: [u64; NICE_LEVELS] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+/// Length of a timer tick in nanoseconds, the unit of virtual time
+const TICK_NS: u64 = 1_000_000_000 / TIMER_HZ as u64;
+/// Requested time slice of every thread
+const SLICE_NS: u64 = 3 * TICK_NS;
+
+fn weight(thread: &Thread) -> u64 {
+    WEIGHTS[nice_index(thread.effective_nice())]
+}
+
+/// Virtual time for `ns` of real time at `weight`
+fn scaled(ns: u64, weight: u64) -> u64 {
+    ns * NICE_0_WEIGHT / weight
+}
+
+/// Weighted fair sharing, after EEVDF
+///
+/// Each thread's virtual runtime advances inversely to its weight. A thread is
+/// eligible when it has not received more than its share (its virtual runtime is
+/// at most the weighted average), and among those the one whose current slice
+/// would end first (earliest virtual deadline) runs.
+///
+/// Unlike real EEVDF we don't carry lag across sleeps: waking threads are
+/// placed at the queue's minimum virtual runtime, so sleeping neither earns nor
+/// costs CPU time.
+pub struct FairShare {
+    queue: Vec<Arc<Thread>>,
+    min_vruntime: u64,
+}
+
+impl FairShare {
+    pub fn new() -> Self {
+        FairShare {
+            queue: Vec::new(),
+            min_vruntime: 0,
+        }
+    }
+}
+
+impl Default for FairShare {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy for FairShare {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        {
+            let weight = weight(&thread);
+            let mut entity = thread.sched.lock();
+            entity.vruntime = entity.vruntime.max(self.min_vruntime);
+            // New slice once the last one has been used up
+            if entity.deadline <= entity.vruntime {
+                entity.deadline = entity.vruntime + scaled(SLICE_NS, weight);
+            }
+        }
+        self.queue.push(thread);
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let entities: Vec<(u64, u64, u64)> = self
+            .queue
+            .iter()
+            .map(|thread| {
+                let entity = thread.sched.lock();
+                (entity.vruntime, entity.deadline, weight(thread))
+            })
+            .collect();
+        let total_weight: u128 = entities.iter().map(|&(_, _, w)| w as u128).sum();
+        if total_weight == 0 {
+            return None;
+        }
+        let average = entities
+            .iter()
+            .map(|&(v, _, w)| v as u128 * w as u128)
+            .sum::<u128>()
+            / total_weight;
+        // The thread with the smallest vruntime is always eligible
+        let (index, _) = entities
+            .iter()
+            .enumerate()
+            .filter(|(_, &(v, _, _))| v as u128 <= average)
+            .min_by_key(|(_, &(_, deadline, _))| deadline)?;
+
+        let min = entities.iter().map(|&(v, _, _)| v).min().unwrap_or(0);
+        self.min_vruntime = self.min_vruntime.max(min);
+        Some(self.queue.swap_remove(index))
+    }
+
+    fn tick(&mut self, current: &Thread, entity: &mut SchedEntity) -> bool {
+        entity.vruntime += scaled(TICK_NS, weight(current));
+        entity.vruntime >= entity.deadline
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn drain(&mut self) -> Vec<Arc<Thread>> {
+        core::mem::take(&mut self.queue)
+    }
+}
+
+#[test_case]
+fn test_fair_share_weights() {
+    let mut fair = FairShare::new();
+    let threads = [super::test_thread(0), super::test_thread(5)];
+    let mut ran = [0u64; 2];
+    for thread in &threads {
+        fair.enqueue(thread.clone());
+    }
+    // Run one tick at a time, always letting the policy pick
+    for _ in 0..300 {
+        let thread = fair.pick_next().unwrap();
+        let index = threads
+            .iter()
+            .position(|t| Arc::ptr_eq(t, &thread))
+            .unwrap();
+        ran[index] += 1;
+        let mut entity = *thread.sched.lock();
+        fair.tick(&thread, &mut entity);
+        *thread.sched.lock() = entity;
+        fair.enqueue(thread);
+    }
+    // Nice 5 weighs 335 against 1024, ~1/4 of the CPU time
+    assert!(ran[0] > 2 * ran[1], "fair: shares {:?}", ran);
+    assert!(ran[1] > 0, "fair: nice 5 thread starved");
+    fair.drain();
+}
@@ -0,0 +1,127 @@
+use super::{Policy, SchedEntity, NICE_MAX};
+use crate::kernel::thread::Thread;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Number of queue levels, 0 is the most important
+const LEVELS: usize = 4;
+/// Every thread goes back to the top level this often, so nothing starves
+const BOOST_TICKS: u64 = 100;
+
+/// Time slice at `level`, longer for the less important levels
+fn slice_ticks(level: usize) -> u64 {
+    2 << level
+}
+
+/// Lowest level a thread of niceness `nice` starts at and is boosted to
+fn base_level(nice: i8) -> usize {
+    nice.max(0) as usize * LEVELS / (NICE_MAX as usize + 1)
+}
+
+/// Multi-level feedback queue
+///
+/// Threads start at the top level. Using up a whole time slice moves a thread
+/// down a level, threads that block before that (interactive ones) stay up.
+/// Higher levels always run first; a periodic boost moves everyone back up.
+pub struct Mlfq {
+    queues: [VecDeque<Arc<Thread>>; LEVELS],
+    len: usize,
+    ticks: u64,
+}
+
+impl Mlfq {
+    pub fn new() -> Self {
+        Mlfq {
+            queues: [const { VecDeque::new() }; LEVELS],
+            len: 0,
+            ticks: 0,
+        }
+    }
+
+    fn boost(&mut self) {
+        let threads = self.drain();
+        for thread in threads {
+            thread.sched.lock().level = base_level(thread.effective_nice());
+            self.enqueue(thread);
+        }
+    }
+}
+
+impl Default for Mlfq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy for Mlfq {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        let level = {
+            let mut entity = thread.sched.lock();
+            entity.level = entity
+                .level
+                .clamp(base_level(thread.effective_nice()), LEVELS - 1);
+            entity.level
+        };
+        self.queues[level].push_back(thread);
+        self.len += 1;
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let thread = self.queues.iter_mut().find_map(|q| q.pop_front())?;
+        self.len -= 1;
+        Some(thread)
+    }
+
+    fn tick(&mut self, current: &Thread, entity: &mut SchedEntity) -> bool {
+        self.ticks += 1;
+        if self.ticks.is_multiple_of(BOOST_TICKS) {
+            self.boost();
+            entity.level = base_level(current.effective_nice());
+        }
+        if entity.slice_ticks >= slice_ticks(entity.level) {
+            entity.level = (entity.level + 1).min(LEVELS - 1);
+            return true;
+        }
+        self.queues[..entity.level].iter().any(|q| !q.is_empty())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn drain(&mut self) -> Vec<Arc<Thread>> {
+        self.len = 0;
+        self.queues.iter_mut().flat_map(|q| q.drain(..)).collect()
+    }
+}
+
+#[test_case]
+fn test_mlfq_demotes_cpu_hogs() {
+    let mut mlfq = Mlfq::new();
+    let hog = super::test_thread(0);
+    let interactive = super::test_thread(0);
+    mlfq.enqueue(hog.clone());
+    let mut entity = SchedEntity::default();
+
+    // Using up the top level slice moves the hog down a level
+    let running = mlfq.pick_next().unwrap();
+    entity.slice_ticks = slice_ticks(0);
+    assert!(mlfq.tick(&running, &mut entity));
+    assert_eq!(entity.level, 1);
+    *running.sched.lock() = entity;
+    mlfq.enqueue(running);
+
+    // A thread at a higher level runs first, and preempts the hog
+    mlfq.enqueue(interactive.clone());
+    assert!(Arc::ptr_eq(&mlfq.pick_next().unwrap(), &interactive));
+    assert!(Arc::ptr_eq(&mlfq.pick_next().unwrap(), &hog));
+    mlfq.enqueue(interactive.clone());
+    entity.slice_ticks = 0;
+    assert!(mlfq.tick(&hog, &mut entity));
+    assert_eq!(mlfq.drain().len(), 1);
+}
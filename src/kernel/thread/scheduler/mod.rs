@@ -0,0 +1,412 @@
+use crate::kernel::sync::rcu;
+use crate::kernel::thread::context::switch_context;
+use crate::kernel::thread::{Thread, ThreadState};
+use crate::kernel::{cpu, gdt, interrupts, ipi, percpu};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::FsBase;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+mod fair;
+mod mlfq;
+mod round_robin;
+
+pub use fair::FairShare;
+pub use mlfq::Mlfq;
+pub use round_robin::RoundRobin;
+
+// Preemptive scheduling with pluggable policies.
+//
+// Each CPU has a run queue: the running thread, an idle thread that halts
+// when nothing else is ready, and a `Policy` that owns the ready threads and
+// decides who runs next. The timer interrupt charges the running thread for
+// the tick and preempts it when the policy says its time is up. A thread
+// queued on another CPU's run queue sends that CPU a reschedule IPI.
+//
+// The policy used at boot is chosen at build time with `FOX_SCHED` (`rr`,
+// `mlfq` or `fair`, round robin by default) and can be swapped at runtime
+// with `set_policy()`.
+//
+// Everything here runs with interrupts disabled: a thread switch must not be
+// interrupted halfway, and the timer interrupt takes the run queue lock too.
+crate::percpu! {
+    static RUN_QUEUE: Mutex<Option<RunQueue>> = Mutex::new(None);
+}
+
+/// Niceness range, lower is more important, as on Unix
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// Decides which ready thread runs next and for how long
+///
+/// Policies only ever see threads that are ready, the running thread is
+/// handed back through `enqueue()` when it is preempted or yields. All
+/// methods are called with the run queue locked and interrupts disabled,
+/// they must not block.
+pub trait Policy: Send {
+    fn name(&self) -> &'static str;
+
+    /// Queue a thread that became ready: new, woken, preempted or yielding
+    fn enqueue(&mut self, thread: Arc<Thread>);
+
+    /// Take the thread to run next out of the queue
+    fn pick_next(&mut self) -> Option<Arc<Thread>>;
+
+    /// A timer tick was charged to `current` (see `SchedEntity`), returns
+    /// whether it should be preempted
+    fn tick(&mut self, current: &Thread, entity: &mut SchedEntity) -> bool;
+
+    /// Number of queued threads
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all queued threads, used when switching policies
+    fn drain(&mut self) -> Vec<Arc<Thread>>;
+}
+
+/// Per thread scheduling state
+///
+/// The scheduler keeps the tick counts, the rest belongs to whichever policy
+/// the thread is queued with.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SchedEntity {
+    /// Ticks since the thread was last picked to run
+    pub slice_ticks: u64,
+    /// Ticks the thread has run in total
+    pub runtime_ticks: u64,
+    /// `Mlfq` queue level
+    pub level: usize,
+    /// `FairShare` virtual runtime and virtual deadline
+    pub vruntime: u64,
+    pub deadline: u64,
+}
+
+struct RunQueue {
+    current: Arc<Thread>,
+    idle: Arc<Thread>,
+    policy: Box<dyn Policy>,
+    // The thread we just switched away from, see `finish_switch()`
+    prev: Option<Arc<Thread>>,
+    // Exited threads whose stacks still need freeing, see `reap()`
+    dead: Vec<Arc<Thread>>,
+    // Blocked with a deadline (`block_current()`), with the tick to wake them at
+    sleepers: Vec<(u64, Arc<Thread>)>,
+}
+
+fn with_run_queue<F, R>(cpu: usize, f: F) -> R
+where
+    F: FnOnce(&mut RunQueue) -> R,
+{
+    cpu_interrupts::without_interrupts(|| {
+        let mut rq = RUN_QUEUE.for_cpu(cpu).lock();
+        f(rq.as_mut().expect("sched: not initialized"))
+    })
+}
+
+/// The policy named by `FOX_SCHED` at build time
+fn boot_policy() -> Box<dyn Policy> {
+    match option_env!("FOX_SCHED") {
+        None | Some("rr") => Box::new(RoundRobin::new()),
+        Some("mlfq") => Box::new(Mlfq::new()),
+        Some("fair") => Box::new(FairShare::new()),
+        Some(other) => panic!("sched: unknown FOX_SCHED policy {:?}", other),
+    }
+}
+
+/// Start scheduling on this CPU, with `boot` as the running thread
+pub(super) fn init(boot: Arc<Thread>, idle: Arc<Thread>) {
+    percpu::set_current_thread(Arc::as_ptr(&boot));
+    *RUN_QUEUE.get().lock() = Some(RunQueue {
+        current: boot,
+        idle,
+        policy: boot_policy(),
+        prev: None,
+        dead: Vec::new(),
+        sleepers: Vec::new(),
+    });
+}
+
+/// Replace this CPU's policy, threads queued with the old one move over
+pub fn set_policy(policy: Box<dyn Policy>) {
+    with_run_queue(cpu::id(), |rq| {
+        let mut old = core::mem::replace(&mut rq.policy, policy);
+        for thread in old.drain() {
+            rq.policy.enqueue(thread);
+        }
+    });
+}
+
+/// Name of this CPU's policy
+pub fn policy_name() -> &'static str {
+    with_run_queue(cpu::id(), |rq| rq.policy.name())
+}
+
+/// The running thread
+pub(crate) fn current() -> Arc<Thread> {
+    let current = percpu::current_thread();
+    assert!(!current.is_null(), "sched: not initialized");
+    // The run queue holds a reference for as long as the thread runs
+    unsafe {
+        Arc::increment_strong_count(current);
+        Arc::from_raw(current)
+    }
+}
+
+/// Queue a new thread
+pub(super) fn add(thread: Arc<Thread>) {
+    let cpu = thread.cpu;
+    with_run_queue(cpu, |rq| {
+        thread.set_state(ThreadState::Ready);
+        rq.policy.enqueue(thread);
+    });
+    kick(cpu);
+}
+
+/// Mark the running thread as blocked, it stops running on the next `schedule()`
+///
+/// With a `deadline` the timer wakes it again at that tick, unless it has been
+/// woken before. Either way whoever blocks must call `cancel_deadline()` once
+/// running again. Interrupt handlers can't block, they would take the thread
+/// they interrupted with them.
+pub(crate) fn block_current(deadline: Option<u64>) {
+    assert!(
+        !percpu::in_interrupt(),
+        "sched: blocking in an interrupt handler"
+    );
+    with_run_queue(cpu::id(), |rq| {
+        rq.current.set_state(ThreadState::Blocked);
+        if let Some(tick) = deadline {
+            let current = rq.current.clone();
+            rq.sleepers.push((tick, current));
+        }
+    });
+}
+
+/// Forget the deadline the running thread blocked with, if it has not passed
+pub(crate) fn cancel_deadline() {
+    with_run_queue(cpu::id(), |rq| {
+        let current = rq.current.clone();
+        rq.sleepers
+            .retain(|(_, thread)| !Arc::ptr_eq(thread, &current));
+    });
+}
+
+/// Make a blocked thread runnable again, does nothing if it isn't blocked
+pub(crate) fn wake(thread: Arc<Thread>) {
+    let cpu = thread.cpu;
+    with_run_queue(cpu, |rq| wake_locked(rq, thread));
+    kick(cpu);
+}
+
+/// Have `cpu` look at its run queue, if it isn't the one we're running on
+fn kick(cpu: usize) {
+    if cpu != cpu::id() {
+        ipi::reschedule(cpu);
+    }
+}
+
+fn wake_locked(rq: &mut RunQueue, thread: Arc<Thread>) {
+    if thread.state() == ThreadState::Blocked {
+        thread.set_state(ThreadState::Ready);
+        rq.policy.enqueue(thread);
+    }
+}
+
+/// Block the running thread until timer tick `tick`
+pub(super) fn sleep_until(tick: u64) {
+    cpu_interrupts::without_interrupts(|| {
+        block_current(Some(tick));
+        schedule();
+    });
+}
+
+/// Have `thread` run at niceness `nice` or better, until set back to `NICE_MAX`
+///
+/// Used for priority inheritance (see `sync::Mutex`). A queued thread is moved
+/// to the queue for its new priority right away.
+pub(crate) fn set_inherited_nice(thread: &Arc<Thread>, nice: i8) {
+    with_run_queue(thread.cpu, |rq| {
+        thread.inherited_nice.store(nice, Ordering::Relaxed);
+        if thread.state() == ThreadState::Ready {
+            // Policies have no way to take out a single thread, requeue them all
+            for thread in rq.policy.drain() {
+                rq.policy.enqueue(thread);
+            }
+        }
+    });
+}
+
+/// Switch to the thread the policy picks next
+///
+/// The running thread is queued again unless it blocked or exited. With
+/// nothing else ready it keeps running, or the idle thread takes over if it
+/// can't. Must be called with interrupts disabled.
+pub(crate) fn schedule() {
+    debug_assert!(!cpu_interrupts::are_enabled());
+    debug_assert!(
+        !rcu::in_read_section(),
+        "sched: switching threads in an RCU read-side section"
+    );
+    rcu::note_quiescent();
+    let (old_rsp, new_rsp) = {
+        let mut guard = RUN_QUEUE.get().lock();
+        let rq = guard.as_mut().expect("sched: not initialized");
+        let prev = rq.current.clone();
+        let is_idle = Arc::ptr_eq(&prev, &rq.idle);
+        if prev.state() == ThreadState::Running && !is_idle {
+            prev.set_state(ThreadState::Ready);
+            rq.policy.enqueue(prev.clone());
+        }
+        let next = rq.policy.pick_next().unwrap_or_else(|| rq.idle.clone());
+        next.set_state(ThreadState::Running);
+        next.sched.lock().slice_ticks = 0;
+        // Nothing else to run, or blocked and woken again before we switched away
+        if Arc::ptr_eq(&next, &prev) {
+            return;
+        }
+        percpu::set_current_thread(Arc::as_ptr(&next));
+        // Interrupts from its user code land below its kernel frames
+        let kernel_rsp = next.kernel_rsp.load(Ordering::Relaxed);
+        if kernel_rsp != 0 {
+            gdt::set_kernel_stack(VirtAddr::new(kernel_rsp));
+        }
+        switch_page_table(next.page_table());
+        // It gets the I/O ports its process may use back on the first `in` or
+        // `out`, see `device`
+        gdt::deny_io_ports();
+        let fs_base = next.fs_base.load(Ordering::Relaxed);
+        if fs_base != 0 || prev.fs_base.load(Ordering::Relaxed) != 0 {
+            FsBase::write(VirtAddr::new(fs_base));
+        }
+        rq.current = next;
+        let switch = (prev.context.get(), unsafe { *rq.current.context.get() });
+        rq.prev = Some(prev);
+        switch
+    };
+    unsafe { switch_context(old_rsp, new_rsp) };
+    finish_switch();
+}
+
+/// Clean up after the thread we switched away from, runs on the new thread
+pub(super) fn finish_switch() {
+    with_run_queue(cpu::id(), |rq| {
+        if let Some(prev) = rq.prev.take() {
+            // We may be in an interrupt handler, leave freeing its stack to `reap()`
+            if prev.state() == ThreadState::Exited {
+                rq.dead.push(prev);
+            }
+        }
+    });
+}
+
+/// Make `level_4_frame` the active page table, unless it is already
+pub(super) fn switch_page_table(level_4_frame: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != level_4_frame {
+        unsafe { Cr3::write(level_4_frame, flags) };
+    }
+}
+
+/// Free the stacks and address spaces of exited threads
+///
+/// Unmapping takes locks that are also used with interrupts enabled, so this
+/// only runs in thread context: on the idle thread and in `join()`.
+pub(super) fn reap() {
+    let dead = with_run_queue(cpu::id(), |rq| core::mem::take(&mut rq.dead));
+    for thread in dead {
+        drop(thread.take_stack());
+        drop(thread.take_address_space());
+    }
+}
+
+/// Called on every timer interrupt, after it has been acknowledged
+///
+/// Charges the tick to the running thread, wakes sleepers that are due and
+/// preempts the running thread if the policy says so.
+pub fn timer_tick() {
+    let Some(mut guard) = RUN_QUEUE.get().try_lock() else {
+        // Interrupted with the lock held can't happen, but don't hang if it does
+        return;
+    };
+    let Some(rq) = guard.as_mut() else {
+        return;
+    };
+
+    let now = interrupts::ticks();
+    let mut i = 0;
+    while i < rq.sleepers.len() {
+        if rq.sleepers[i].0 <= now {
+            let (_, thread) = rq.sleepers.swap_remove(i);
+            wake_locked(rq, thread);
+        } else {
+            i += 1;
+        }
+    }
+
+    let preempt = if Arc::ptr_eq(&rq.current, &rq.idle) {
+        !rq.policy.is_empty()
+    } else {
+        let current = rq.current.clone();
+        let mut entity = current.sched.lock();
+        entity.slice_ticks += 1;
+        entity.runtime_ticks += 1;
+        rq.policy.tick(&current, &mut entity)
+    };
+    drop(guard);
+    if preempt {
+        self::preempt();
+    }
+}
+
+/// Switch to the next thread from an interrupt handler, after acknowledging it
+///
+/// Threads in an RCU read-side section aren't preempted, they keep running
+/// until the next chance. Neither is an interrupt handler running tasklets on
+/// the way out, the interrupt that wants to preempt it is nested in it.
+pub fn preempt() {
+    if !rcu::in_read_section() && !percpu::in_interrupt() {
+        schedule();
+    }
+}
+
+/// Body of the idle threads
+pub(crate) fn idle() -> ! {
+    loop {
+        reap();
+        rcu::note_quiescent();
+        // Same race as in the task executor: check and halt with interrupts off,
+        // `sti; hlt` only lets them in once halted
+        cpu_interrupts::disable();
+        if with_run_queue(cpu::id(), |rq| !rq.policy.is_empty()) {
+            schedule();
+            cpu_interrupts::enable();
+        } else {
+            cpu_interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Index into per-nice tables, 0 for the most important
+fn nice_index(nice: i8) -> usize {
+    (nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize
+}
+
+/// Number of niceness levels
+const NICE_LEVELS: usize = (NICE_MAX - NICE_MIN) as usize + 1;
+
+/// A thread that is never run, for exercising policies directly
+#[cfg(test)]
+fn test_thread(nice: i8) -> Arc<Thread> {
+    let thread = Arc::new(Thread::new("test", 0, None, ThreadState::Ready));
+    thread.set_nice(nice);
+    thread
+}
@@ -0,0 +1,99 @@
+use super::{nice_index, Policy, SchedEntity, NICE_LEVELS};
+use crate::kernel::thread::Thread;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Ticks a thread runs before the next one of the same priority gets a turn
+const SLICE_TICKS: u64 = 5;
+
+/// Strict priorities, round robin within each
+///
+/// Every niceness value is its own priority level. The most important ready
+/// threads always run, less important ones only when those block: a busy
+/// thread starves everything below it.
+pub struct RoundRobin {
+    queues: [VecDeque<Arc<Thread>>; NICE_LEVELS],
+    len: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            queues: [const { VecDeque::new() }; NICE_LEVELS],
+            len: 0,
+        }
+    }
+
+    /// Index of the most important non-empty queue
+    fn highest(&self) -> Option<usize> {
+        self.queues.iter().position(|q| !q.is_empty())
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Policy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "rr"
+    }
+
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        self.queues[nice_index(thread.effective_nice())].push_back(thread);
+        self.len += 1;
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let thread = self.queues[self.highest()?].pop_front()?;
+        self.len -= 1;
+        Some(thread)
+    }
+
+    fn tick(&mut self, current: &Thread, entity: &mut SchedEntity) -> bool {
+        // A more important thread woke up, or our turn is over
+        let more_important = self
+            .highest()
+            .is_some_and(|level| level < nice_index(current.effective_nice()));
+        more_important || entity.slice_ticks >= SLICE_TICKS
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn drain(&mut self) -> Vec<Arc<Thread>> {
+        self.len = 0;
+        self.queues.iter_mut().flat_map(|q| q.drain(..)).collect()
+    }
+}
+
+#[test_case]
+fn test_round_robin_priorities() {
+    let mut rr = RoundRobin::new();
+    let low = super::test_thread(5);
+    let high = [super::test_thread(-5), super::test_thread(-5)];
+    rr.enqueue(low.clone());
+    rr.enqueue(high[0].clone());
+    rr.enqueue(high[1].clone());
+
+    // Most important first, in queue order
+    let first = rr.pick_next().unwrap();
+    assert!(Arc::ptr_eq(&first, &high[0]));
+    // Preempted by the slice running out, not by the less important thread
+    let mut entity = SchedEntity::default();
+    assert!(!rr.tick(&first, &mut entity));
+    entity.slice_ticks = SLICE_TICKS;
+    assert!(rr.tick(&first, &mut entity));
+
+    rr.enqueue(first);
+    assert!(Arc::ptr_eq(&rr.pick_next().unwrap(), &high[1]));
+    assert!(Arc::ptr_eq(&rr.pick_next().unwrap(), &high[0]));
+    // A waiting more important thread preempts right away
+    rr.enqueue(high[0].clone());
+    assert!(rr.tick(&low, &mut SchedEntity::default()));
+    assert_eq!(rr.drain().len(), 2);
+}
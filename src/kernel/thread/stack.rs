@@ -0,0 +1,107 @@
+use crate::kernel::aslr::{self, STACK_REGION_SIZE};
+use crate::kernel::meminfo::{self, RegionId};
+use crate::kernel::memory;
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// Thread stacks are carved out of the randomized kernel stack region (see
+// `kernel::aslr`) in fixed size slots. The lowest page of each slot is never
+// mapped: a thread running off the end of its stack faults on it instead of
+// silently corrupting whatever lies below.
+
+/// Usable size of a thread stack
+pub const STACK_SIZE: usize = 64 * 1024;
+/// Unmapped guard below every stack
+pub const GUARD_SIZE: usize = 4096;
+const SLOT_SIZE: usize = STACK_SIZE + GUARD_SIZE;
+const SLOTS: usize = STACK_REGION_SIZE as usize / SLOT_SIZE;
+
+struct Slots {
+    region: RegionId,
+    // Slots given back by dropped stacks, reused first
+    free: Vec<usize>,
+    // Slots from here on have never been used
+    next: usize,
+}
+
+static SLOTS_STATE: Once<Mutex<Slots>> = Once::new();
+
+/// A mapped kernel stack with a guard page below it, unmapped when dropped
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Map a new stack, `None` if we're out of slots or memory
+    pub fn new() -> Option<KernelStack> {
+        let mut slots = slots().lock();
+        let slot = match slots.free.pop() {
+            Some(slot) => slot,
+            None if slots.next < SLOTS => {
+                slots.next += 1;
+                slots.next - 1
+            }
+            None => return None,
+        };
+        let stack = KernelStack { slot };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if memory::map_range(stack.bottom(), STACK_SIZE, flags).is_err() {
+            slots.free.push(slot);
+            return None;
+        }
+        meminfo::region_map(slots.region, STACK_SIZE);
+        Some(stack)
+    }
+
+    /// Start of the guard page
+    pub fn guard(&self) -> VirtAddr {
+        aslr::layout().stack_region + (self.slot * SLOT_SIZE) as u64
+    }
+
+    /// Lowest usable address
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard() + GUARD_SIZE as u64
+    }
+
+    /// Initial stack pointer, 16 byte aligned
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_SIZE as u64
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        memory::unmap_range(self.bottom(), STACK_SIZE).expect("stack: not mapped");
+        let mut slots = slots().lock();
+        meminfo::region_unmap(slots.region, STACK_SIZE);
+        slots.free.push(self.slot);
+    }
+}
+
+fn slots() -> &'static Mutex<Slots> {
+    SLOTS_STATE.call_once(|| {
+        let start = aslr::layout().stack_region;
+        Mutex::new(Slots {
+            region: meminfo::register_region("stacks", start, STACK_REGION_SIZE as usize),
+            free: Vec::new(),
+            next: 0,
+        })
+    })
+}
+
+#[test_case]
+fn test_stack_guard_unmapped() {
+    use x86_64::structures::paging::Translate;
+
+    let stack = KernelStack::new().expect("stack: allocation failed");
+    let mapped =
+        |addr: VirtAddr| memory::with_memory(|mm| mm.mapper.translate_addr(addr).is_some());
+    assert!(!mapped(stack.guard()));
+    assert!(!mapped(stack.bottom() - 1u64));
+    assert!(mapped(stack.bottom()));
+    assert!(mapped(stack.top() - 1u64));
+    unsafe { (stack.top() - 8u64).as_mut_ptr::<u64>().write(0x5a5a) };
+}
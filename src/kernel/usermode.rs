@@ -0,0 +1,389 @@
+use crate::kernel::gdt::{self, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::kernel::percpu;
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use core::sync::atomic::Ordering;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::segmentation::GS;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+// Running code in ring 3.
+//
+// A kernel thread calls `enter()`, which saves its callee-saved registers and
+// stack pointer and `iretq`s to the user code. The saved stack pointer doubles
+// as the CPU's RSP0: interrupts, exceptions and system calls from user mode
+// land just below the thread's kernel frames, and the scheduler restores it
+// whenever it switches back to the thread. When the user code exits (see
+// `syscall`) or faults, `leave()` unwinds to the saved stack pointer and makes
+// `enter()` return why, so a misbehaving program only ends its own run
+// instead of the kernel.
+//
+// `enter_context()` starts from every register in a `UserContext`. Every way
+// in from user mode leaves one at the top of the kernel stack, the system
+// call entries and those of the exceptions and interrupts wrapped with
+// `user_entry!`; `user_context()` finds it. That's how a forked process goes
+// on from where its parent made the call, and how a thread leaves with
+// `UserExit::Resume` to go back to its user code after a detour, like running
+// a signal handler (see `process::signal`).
+//
+// User code runs with its own GS base, so every way in from user mode starts
+// with `swapgs` to get the kernel's back and every way out ends with one;
+// interrupt handlers do it with `KernelGs`.
+
+/// RFLAGS user code starts with: interrupts enabled
+const USER_RFLAGS: u64 = 0x202;
+/// The RFLAGS bits user code keeps when resumed: the arithmetic flags and
+/// the direction flag
+const USER_RFLAGS_MASK: u64 = 0xcd5;
+
+/// The exceptions user code is stopped for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    DivideError,
+    Breakpoint,
+    InvalidOpcode,
+    SegmentNotPresent,
+    StackSegment,
+    GeneralProtection,
+    PageFault,
+    AlignmentCheck,
+    FloatingPoint,
+}
+
+/// Why user code stopped running
+#[derive(Debug, Clone, Copy)]
+pub enum UserExit {
+    /// It called `exit` with this status
+    Exited(i32),
+    /// It faulted, with these registers
+    Faulted(UserFault, UserContext),
+    /// Its process is being torn down, see `process::Process::kill()`
+    Killed,
+    /// Go back to user mode with these registers, once the thread has done
+    /// what it left for
+    Resume(UserContext),
+}
+
+/// What user code did to be stopped
+#[derive(Debug, Clone, Copy)]
+pub struct UserFault {
+    pub kind: FaultKind,
+    /// The exception's error code, 0 for those without one
+    pub error_code: u64,
+    pub instruction_pointer: VirtAddr,
+    pub stack_pointer: VirtAddr,
+    /// The address accessed, for page faults
+    pub address: Option<VirtAddr>,
+}
+
+/// The registers of user code: the general purpose ones, then what `iretq`
+/// pops, in the order they are popped
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl UserContext {
+    /// Starting at `entry` on the stack `stack`, every other register zeroed
+    pub fn new(entry: VirtAddr, stack: VirtAddr) -> Self {
+        UserContext {
+            rip: entry.as_u64(),
+            rsp: stack.as_u64(),
+            ..UserContext::default()
+        }
+    }
+}
+
+core::arch::global_asm!(
+    r#"
+// rdi: where to save the kernel stack pointer, rsi: the CPU's RSP0,
+// rdx: the `UserContext`, rcx: where `leave()` stores why we're back
+.global user_enter
+user_enter:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    push rcx
+    mov [rdi], rsp
+    mov [rsi], rsp
+    mov gs:[{kernel_stack}], rsp
+    // Every register comes from the context, nothing of the kernel's is left
+    mov ecx, {context_words}
+1:
+    push qword ptr [rdx + rcx * 8 - 8]
+    loop 1b
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    swapgs
+    iretq
+
+// rdi: the kernel stack pointer `user_enter` saved, returns from it
+.global user_leave
+user_leave:
+    mov rsp, rdi
+    add rsp, 8
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    context_words = const size_of::<UserContext>() / 8,
+);
+
+extern "C" {
+    fn user_enter(
+        kernel_rsp: *mut u64,
+        rsp0: *mut u64,
+        context: *const UserContext,
+        exit: *mut c_void,
+    );
+    fn user_leave(kernel_rsp: u64) -> !;
+}
+
+/// Run user code from `entry` on the user stack `stack` until it exits or faults
+///
+/// Both must be mapped `USER_ACCESSIBLE`. The thread can be preempted while
+/// the user code runs, like anywhere else. Thread context only, with
+/// interrupts enabled.
+///
+/// # Safety
+///
+/// The user code can read and write everything mapped `USER_ACCESSIBLE`.
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr) -> UserExit {
+    enter_context(&UserContext::new(entry, stack))
+}
+
+/// Run user code from the registers in `context` until it exits or faults
+///
+/// Like `enter()`, the code and segment registers and the flags but the
+/// arithmetic and direction ones are the user ones whatever `context` says.
+///
+/// # Safety
+///
+/// See `enter()`.
+pub unsafe fn enter_context(context: &UserContext) -> UserExit {
+    assert!(
+        interrupts::are_enabled() && !percpu::in_interrupt(),
+        "usermode: enter() in atomic context"
+    );
+    let context = UserContext {
+        cs: u64::from(USER_CODE_SELECTOR.0),
+        rflags: context.rflags & USER_RFLAGS_MASK | USER_RFLAGS,
+        ss: u64::from(USER_DATA_SELECTOR.0),
+        ..*context
+    };
+    let mut exit = MaybeUninit::<UserExit>::uninit();
+    let thread = percpu::current_thread();
+    assert!(!thread.is_null(), "usermode: no thread to run user code on");
+    // RSP0 belongs to this CPU, we mustn't move before `iretq`
+    interrupts::disable();
+    user_enter(
+        (*thread).kernel_rsp().as_ptr(),
+        gdt::kernel_stack_slot(),
+        &context,
+        exit.as_mut_ptr().cast(),
+    );
+    interrupts::enable();
+    exit.assume_init()
+}
+
+/// Whether the exception in `sf` interrupted user code
+pub fn from_user(sf: &InterruptStackFrame) -> bool {
+    sf.code_segment & 3 == 3
+}
+
+/// Whether the running thread is in `enter()`, running user code
+pub fn in_user_thread() -> bool {
+    let thread = percpu::current_thread();
+    !thread.is_null() && unsafe { (*thread).kernel_rsp().load(Ordering::Relaxed) } != 0
+}
+
+/// The registers of the running thread's user code, saved when it entered the
+/// kernel
+///
+/// Changes take effect when the kernel returns to it, through a system call
+/// return (where `syscall` clobbers `rcx` and `r11`), or through
+/// `UserExit::Resume`.
+///
+/// # Safety
+///
+/// Only from a system call, or an exception or interrupt wrapped with
+/// `user_entry!`, that came from user mode; the context mustn't be borrowed
+/// twice.
+pub unsafe fn user_context<'a>() -> &'a mut UserContext {
+    let thread = percpu::current_thread();
+    assert!(
+        in_user_thread(),
+        "usermode: no user context outside user threads"
+    );
+    // The CPU aligns the stack before pushing its frame, the `syscall` entry
+    // does the same
+    let top = (*thread).kernel_rsp().load(Ordering::Relaxed) & !15;
+    &mut *((top as usize - size_of::<UserContext>()) as *mut UserContext)
+}
+
+/// Defines `$entry`, an entry stub for the interrupt or exception handler
+/// `$handler` that saves a `UserContext` when it comes from user mode, for
+/// `user_context()`
+///
+/// Add `error_code` for exceptions that push one. The handler then runs as
+/// if the IDT pointed at it, on a copy of the CPU's frame below the context.
+macro_rules! user_entry {
+    ($entry:ident => $handler:path) => {
+        #[unsafe(naked)]
+        extern "C" fn $entry() {
+            core::arch::naked_asm!(
+                "test qword ptr [rsp + 8], 3",
+                "jz {handler}",
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                // The CPU's frame, from ss down to rip
+                ".rept 5",
+                "push qword ptr [rsp + 152]",
+                ".endr",
+                "jmp {handler}",
+                handler = sym $handler,
+            )
+        }
+    };
+    ($entry:ident => $handler:path, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $entry() {
+            core::arch::naked_asm!(
+                "test qword ptr [rsp + 16], 3",
+                "jz {handler}",
+                // rax goes where the error code was, right below the CPU's
+                // frame like in a `UserContext`
+                "xchg rax, [rsp]",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push rbp",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                ".rept 5",
+                "push qword ptr [rsp + 152]",
+                ".endr",
+                "push rax",
+                "mov rax, [rsp + 160]",
+                "jmp {handler}",
+                handler = sym $handler,
+            )
+        }
+    };
+}
+
+pub(crate) use user_entry;
+
+/// Stop the user code and return `exit` from `enter()`
+///
+/// Called by exception handlers and system calls, on the thread's kernel stack
+/// below the frames of `enter()`, with the kernel's GS base.
+pub fn leave(exit: UserExit) -> ! {
+    let thread = percpu::current_thread();
+    let kernel_rsp = unsafe { (*thread).kernel_rsp().swap(0, Ordering::Relaxed) };
+    assert!(
+        kernel_rsp != 0,
+        "usermode: leave() from a thread not in user mode"
+    );
+    unsafe {
+        // `user_enter` pushed where the exit goes last
+        let slot = *(kernel_rsp as *const *mut UserExit);
+        slot.write(exit);
+        user_leave(kernel_rsp)
+    }
+}
+
+/// Stop the user code that caused `fault`
+///
+/// From exceptions wrapped with `user_entry!`, see `user_context()`.
+pub fn fault(fault: UserFault) -> ! {
+    let context = unsafe { *user_context() };
+    leave(UserExit::Faulted(fault, context))
+}
+
+/// Gets the kernel's GS base back in an interrupt handler that interrupted user
+/// code, and gives the user's back when dropped
+///
+/// Must be the first thing a handler does, before anything reads per-CPU data.
+pub struct KernelGs(bool);
+
+impl KernelGs {
+    pub fn enter(sf: &InterruptStackFrame) -> Self {
+        let user = from_user(sf);
+        if user {
+            unsafe { GS::swap() };
+        }
+        KernelGs(user)
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { GS::swap() };
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::kernel::sync::{IrqSafeSpinLock, WaitQueue};
+use crate::kernel::thread;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+// Work queues: deferred work that runs in worker threads.
+//
+// Unlike tasklets (see `softirq`), work items run in thread context and may
+// block, allocate and take sleeping locks. Interrupt handlers queue them when
+// what's left to do doesn't fit in interrupt context. Queueing neither
+// allocates nor blocks: items are statics linked into the queue through a
+// pointer of their own, and an item is in at most one queue at a time.
+//
+// `SYSTEM` (the `events` thread) serves everything that doesn't need a queue
+// of its own.
+
+/// A piece of deferred work, usually a `static` next to the code that queues it
+pub struct Work {
+    name: &'static str,
+    func: fn(),
+    // Queued and not started yet
+    pending: AtomicBool,
+    // Next in the queue
+    next: AtomicPtr<Work>,
+}
+
+impl Work {
+    pub const fn new(name: &'static str, func: fn()) -> Self {
+        Work {
+            name,
+            func,
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+// Queued items, oldest first
+struct WorkList {
+    head: Option<&'static Work>,
+    tail: Option<&'static Work>,
+}
+
+impl WorkList {
+    fn push(&mut self, work: &'static Work) {
+        work.next.store(ptr::null_mut(), Ordering::Relaxed);
+        match self.tail {
+            Some(tail) => tail
+                .next
+                .store(work as *const _ as *mut _, Ordering::Relaxed),
+            None => self.head = Some(work),
+        }
+        self.tail = Some(work);
+    }
+
+    fn pop(&mut self) -> Option<&'static Work> {
+        let work = self.head?;
+        self.head = unsafe { work.next.load(Ordering::Relaxed).as_ref() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        Some(work)
+    }
+}
+
+/// Work items and the threads that run them
+pub struct WorkQueue {
+    name: &'static str,
+    list: IrqSafeSpinLock<WorkList>,
+    // Workers waiting for work
+    idle: WaitQueue,
+    // Items queued and run so far, see `flush()`
+    queued: AtomicU64,
+    done: AtomicU64,
+    finished: WaitQueue,
+}
+
+/// The shared queue, run by the `events` thread
+pub static SYSTEM: WorkQueue = WorkQueue::new("events");
+
+impl WorkQueue {
+    /// A queue without workers, `start()` gives it some
+    pub const fn new(name: &'static str) -> Self {
+        WorkQueue {
+            name,
+            list: IrqSafeSpinLock::new(
+                "work queue",
+                WorkList {
+                    head: None,
+                    tail: None,
+                },
+            ),
+            idle: WaitQueue::new(),
+            queued: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            finished: WaitQueue::new(),
+        }
+    }
+
+    /// Start `workers` threads, named after the queue, running its items
+    pub fn start(&'static self, workers: usize) {
+        for _ in 0..workers {
+            thread::spawn_named(self.name, move || self.worker());
+        }
+    }
+
+    /// Run `work` on one of the workers
+    ///
+    /// Returns false if it is queued already and hasn't started yet. Can be
+    /// called from interrupt handlers.
+    pub fn queue(&'static self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        // Counted first, so a `flush()` that sees it in the list waits for it
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.list.lock().push(work);
+        self.idle.wake_one();
+        true
+    }
+
+    /// Wait until the items queued so far have run
+    ///
+    /// Thread context only, and not from an item of this queue.
+    pub fn flush(&self) {
+        let queued = self.queued.load(Ordering::Relaxed);
+        self.finished
+            .wait_until(|| self.done.load(Ordering::Acquire) >= queued);
+    }
+
+    fn worker(&'static self) -> ! {
+        loop {
+            let mut next = None;
+            self.idle.wait_until(|| {
+                next = self.list.lock().pop();
+                next.is_some()
+            });
+            let work = next.unwrap();
+            // Queueing it again from here runs it again
+            work.pending.store(false, Ordering::Release);
+            (work.func)();
+            self.done.fetch_add(1, Ordering::Release);
+            self.finished.wake_all();
+        }
+    }
+}
+
+/// Run `work` on the `events` thread, see `WorkQueue::queue()`
+pub fn schedule_work(work: &'static Work) -> bool {
+    SYSTEM.queue(work)
+}
+
+/// Wait until the work given to the `events` thread so far has run
+pub fn flush_system() {
+    SYSTEM.flush();
+}
+
+/// Start the `events` thread
+pub fn init() {
+    SYSTEM.start(1);
+}
+
+#[test_case]
+fn test_work_queue() {
+    use crate::kernel::interrupts::{register_irq_handler, unregister_irq_handler, PIC_1_OFFSET};
+    use crate::kernel::percpu;
+    use core::sync::atomic::AtomicUsize;
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static IN_INTERRUPT: AtomicBool = AtomicBool::new(true);
+    static WORK: Work = Work::new("test", || {
+        IN_INTERRUPT.store(percpu::in_interrupt(), Ordering::Relaxed);
+        // Work may sleep
+        thread::sleep_ticks(1);
+        RUNS.fetch_add(1, Ordering::Relaxed);
+    });
+
+    assert!(schedule_work(&WORK));
+    flush_system();
+    assert_eq!(RUNS.load(Ordering::Relaxed), 1);
+
+    // Queued from an interrupt handler, it runs in the worker
+    let id = register_irq_handler(5, || {
+        schedule_work(&WORK);
+    });
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 5) };
+    unregister_irq_handler(id);
+    flush_system();
+    assert_eq!(RUNS.load(Ordering::Relaxed), 2);
+    assert!(!IN_INTERRUPT.load(Ordering::Relaxed));
+    crate::kernel::sync::rcu_barrier();
+}
@@ -6,15 +6,45 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
 pub mod dbg_serial;
 pub mod drivers;
 pub mod kernel;
+use crate::drivers::display::framebuffer;
 #[allow(unused_imports)]
 pub use crate::drivers::display::vga;
+use crate::kernel::boot::{BootInfo, Display};
 pub use crate::kernel::gdt;
 pub use crate::kernel::interrupts;
+use crate::kernel::process::fd::Handle;
+use crate::kernel::process::{self, Process};
+use crate::kernel::sync::rcu;
+use crate::kernel::{allocator, aslr, boot, meminfo, memory, smp, syscall, thread, workqueue};
+use alloc::sync::Arc;
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use core::panic::PanicInfo;
 
+// Used by `entry_point!`
+#[doc(hidden)]
+pub use bootloader_api;
+
+/// How the bootloader should set up the kernel, `entry_point!` hands this to the bootloader
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    // `kernel::memory` reaches page tables and frames through a map of all physical memory
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    // Let the bootloader randomize where it puts the kernel, unless the layout
+    // is pinned for reproducibility, see `kernel::aslr`
+    config.mappings.aslr = option_env!("FOX_ASLR_SEED").is_none();
+    // The lower half is for user space, see `kernel::memory`. Our own regions
+    // are above this range, see `kernel::aslr`.
+    config.mappings.dynamic_range_start = Some(0x_ffff_8000_0000_0000);
+    config.mappings.dynamic_range_end = Some(0x_ffff_c000_0000_0000);
+    config.kernel_stack_size = 128 * 1024;
+    config
+};
+
 pub trait Testable {
     fn run(&self) -> ();
 }
@@ -25,8 +55,11 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
+        let before = meminfo::snapshot();
         self();
-        serial_println!("[ok]");
+        // Report what the test left behind, a non-zero delta usually means a leak
+        let delta = meminfo::snapshot().delta_since(&before);
+        serial_println!("[ok] ({})", delta);
     }
 }
 
@@ -45,13 +78,110 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     loop {}
 }
 
+/// Start the program at `path` in the initial ramdisk with `args` after its
+/// name, for tests of user programs
+pub fn test_spawn(path: &str, args: &[&str]) -> Arc<Process> {
+    test_spawn_with_handles(path, args, [])
+}
+
+/// `test_spawn()` with `handles` open on descriptors 3 and up
+pub fn test_spawn_with_handles(
+    path: &str,
+    args: &[&str],
+    handles: impl IntoIterator<Item = Handle>,
+) -> Arc<Process> {
+    let mut argv = alloc::vec![process::program_name(path)];
+    argv.extend_from_slice(args);
+    process::spawn_with_handles(path, &argv, &[], handles)
+        .unwrap_or_else(|err| panic!("spawning {} failed: {:?}", path, err))
+}
+
+/// Define the kernel entry point(s), `$path` must be a `fn(BootInfo) -> !`
+///
+/// Every kernel binary (main, tests) uses this instead of defining `_start`
+/// itself. It sets up the entry for `bootloader` and, with the `multiboot2`
+/// feature, the entry Multiboot2 loaders jump to. Both set up the boot CPU's
+/// per-CPU block and translate what they are given into a
+/// `kernel::boot::BootInfo` before calling `$path`.
+#[macro_export]
+macro_rules! entry_point {
+    ($path:path) => {
+        fn __bootloader_main(boot_info: &'static mut $crate::bootloader_api::BootInfo) -> ! {
+            // Before anything takes a lock, locks ask which CPU they run on
+            $crate::kernel::percpu::init(0);
+            let kernel_main: fn($crate::kernel::boot::BootInfo) -> ! = $path;
+            kernel_main(boot_info.into())
+        }
+        $crate::bootloader_api::entry_point!(
+            __bootloader_main,
+            config = &$crate::BOOTLOADER_CONFIG
+        );
+        $crate::__multiboot2_entry_point!($path);
+    };
+}
+
+#[cfg(feature = "multiboot2")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __multiboot2_entry_point {
+    ($path:path) => {
+        // The Multiboot2 header, it must be within the first 32 KiB of the kernel file,
+        // `multiboot2.ld` puts it first. The entry address tag makes the loader start us
+        // at the 32-bit entry code rather than the ELF entry point (which is `bootloader`'s).
+        core::arch::global_asm!(
+            r#"
+.section .multiboot2_header, "a"
+.balign 8
+multiboot2_header_start:
+    .long 0xe85250d6
+    // Architecture: i386 protected mode
+    .long 0
+    .long multiboot2_header_end - multiboot2_header_start
+    .long 0x100000000 - (0xe85250d6 + (multiboot2_header_end - multiboot2_header_start))
+    // Entry address tag
+    .balign 8
+    .short 3, 0
+    .long 12
+    .long _start_multiboot2
+    // Framebuffer tag, optional: we can also use text mode
+    .balign 8
+    .short 5, 1
+    .long 20
+    .long 1024, 768, 32
+    // End tag
+    .balign 8
+    .short 0, 0
+    .long 8
+multiboot2_header_end:
+"#
+        );
+
+        // Called by the 32-bit entry code in `kernel::boot::multiboot2` once in long mode
+        #[export_name = "multiboot2_main"]
+        extern "C" fn __multiboot2_main(magic: u32, info_addr: u32) -> ! {
+            $crate::kernel::percpu::init(0);
+            let kernel_main: fn($crate::kernel::boot::BootInfo) -> ! = $path;
+            kernel_main(unsafe { $crate::kernel::boot::multiboot2::boot_info(magic, info_addr) })
+        }
+    };
+}
+
+#[cfg(not(feature = "multiboot2"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __multiboot2_entry_point {
+    ($path:path) => {};
+}
+
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// lib.rs is tested independently of `main.rs` so it required an entry point
 /// and a panic_handler for when it is compiled in `test mode.
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: BootInfo) -> ! {
     // Init kernel sub-routines
-    if let Err(()) = init() {
+    if let Err(()) = init(boot_info) {
         panic!("Kernel Init Failed");
     }
     test_main();
@@ -66,9 +196,32 @@ fn panic(info: &PanicInfo) -> ! {
 
 /// Initialize OS, central place for initialization subroutines
 /// that are shared between `_start` functions (main/lib/tests)
-pub fn init() -> Result<(), ()> {
+pub fn init(mut boot_info: BootInfo) -> Result<(), ()> {
     interrupts::idt_init();
     gdt::gdt_init();
+    syscall::init_cpu();
+    match boot_info.display.take() {
+        Some(Display::FrameBuffer { buffer, info }) => framebuffer::init(buffer, info),
+        Some(Display::Text(addr)) => unsafe { vga::use_text_buffer(addr) },
+        None => {}
+    }
+
+    // Before the frame allocator gets to see the memory map
+    smp::reserve_trampoline(&mut boot_info.memory_map);
+    let boot_info = boot::init(boot_info);
+    unsafe {
+        memory::init(boot_info.phys_mem_offset, boot_info.memory_map.regions());
+    }
+    let layout = aslr::init();
+    allocator::init_heap(layout.heap_start).map_err(|_| ())?;
+    thread::init();
+    rcu::init();
+    workqueue::init();
+
+    // Interrupt driven drivers queue their input on the heap, so only now
+    interrupts::pic_init();
+    x86_64::instructions::interrupts::enable();
+    smp::init();
     Ok(())
 }
 
@@ -1,19 +1,24 @@
 #![no_std]
 #![no_main]
-// Allow use of unstable `x86-interrupt` calling convention
-#![feature(abi_x86_interrupt)]
 // Custom test framework
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-mod drivers;
-mod kernel;
-use crate::drivers::display::vga;
-use crate::kernel::delay::nops;
 use core::panic::PanicInfo;
+use futures_util::stream::StreamExt;
+use project_fox::dbg_serial;
+use project_fox::drivers::keyboard;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::meminfo;
+use project_fox::kernel::process;
+use project_fox::kernel::task::executor::Executor;
+use project_fox::kernel::task::Task;
 #[allow(unused_imports)]
 use project_fox::test_runner;
+use project_fox::{print, println};
+
+project_fox::entry_point!(kernel_main);
 
 /// For typical rust binary that links to stdlib, execution start in
 /// the C runtime lib`crt0` ("C runtime zero"). `crt0` initializes the environment
@@ -24,36 +29,48 @@ use project_fox::test_runner;
 /// This runtime then calls `main`.
 ///
 /// Since we are a freestanding executable, we do not have access to the Rust runtime nor `crt0`.
-/// So the below defines our own entry point by overwriting the `crt0` entry point directly.
+/// So we need our own entry point, overwriting the `crt0` entry point directly.
 /// Note that implementing the `start` language item isn't useful, since it still needs `crt0`.
 ///
-/// With `#[no_mangle]` we disable name mangling so that the Rust compiler outputs a function named `_start`
-/// not some cryptic symbol in an effort to give every function a unique name. We need the actual name so we can
-/// tell the linker where to look for entry.
-///
-/// The reason this function is named `_start`, typically the default entry point name
-/// for most systems.
+/// The `entry_point!` macro above defines the actual `_start` symbol (unmangled, so the
+/// bootloader can find it in our ELF) and calls this function from it. It also checks the
+/// signature of this function at compile time and embeds our `BOOTLOADER_CONFIG` in the
+/// kernel image for the bootloader to read. With the `multiboot2` feature it also adds
+/// the Multiboot2 header and entry, so GRUB can start the same kernel.
 ///
-/// This function also does not return `!` as it is not called by any function, but directly
+/// This function does not return `!` as it is not called by any function, but directly
 /// by the `bootloader` or `OS`, so instead of returning, the entry point should e.g. invoke the
 /// exit system call of the operating system. For our case, shutting down/looping is sufficient.
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+///
+/// Whichever loader started us, its boot information (memory regions, physical memory
+/// offset, framebuffer, ACPI RSDP address, ramdisk...) arrives here as a `kernel::boot::BootInfo`.
+fn kernel_main(boot_info: BootInfo) -> ! {
     println!("----- Booting Fox Kernel v0.0.1 -----");
 
     // Init kernel sub-routines
-    if let Err(()) = project_fox::init() {
+    if let Err(()) = project_fox::init(boot_info) {
         panic!("Kernel Init Failed");
     }
+    meminfo::print_report();
 
     #[cfg(test)]
     test_main();
 
-    let mut loop_count: usize = 0;
-    loop {
-        nops(1000000);
-        println!("Kernel Loop Count: {}", loop_count);
-        loop_count = loop_count.wrapping_add(1);
+    process::start_init().expect("starting the init process failed");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(echo_serial()));
+    executor.run();
+}
+
+/// Show what is typed on the serial console
+async fn echo_serial() {
+    let mut bytes = dbg_serial::byte_stream();
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            b'\r' => println!(),
+            byte => print!("{}", byte as char),
+        }
     }
 }
 
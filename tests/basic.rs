@@ -5,10 +5,12 @@
 #![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
 use project_fox::println;
 
-#[no_mangle] // don't mangle the name of this function
-pub extern "C" fn _start() -> ! {
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(_boot_info: BootInfo) -> ! {
     test_main();
 
     loop {}
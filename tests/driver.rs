@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::device::{IoPorts, IrqLine, MmioRegion};
+use project_fox::kernel::ipc;
+use project_fox::kernel::memory;
+use project_fox::kernel::process::fd::{File, Handle, Rights};
+use project_fox::kernel::process::ExitStatus;
+use project_fox::kernel::usermode::FaultKind;
+use project_fox::{test_spawn, test_spawn_with_handles};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// A process faulting on an `in`
+fn assert_general_protection(status: ExitStatus) {
+    let ExitStatus::Faulted(fault) = status else {
+        panic!("expected a fault, got {:?}", status);
+    };
+    assert_eq!(fault.kind, FaultKind::GeneralProtection);
+}
+
+/// The driver in user space gets the keyboard's interrupts and scancodes,
+/// and the kernel its line back once the driver is gone
+#[test_case]
+fn ps2_keyboard_driver() {
+    let ports = IoPorts::new(0x60, 5).unwrap();
+    let line = IrqLine::claim(1).expect("claiming the keyboard's line failed");
+    let (reader, writer) = ipc::pipe();
+    // A press and release of A
+    let driver = test_spawn_with_handles(
+        "/bin/ps2kbd",
+        &["30", "158"],
+        [
+            Handle::new(Arc::new(ports), Rights::ALL),
+            Handle::new(Arc::new(line), Rights::ALL),
+            Handle::new(Arc::new(writer), Rights::WRITE),
+        ],
+    );
+    let mut scancodes = [0; 4];
+    let mut total = 0;
+    while let Ok(read @ 1..) = reader.read(&mut scancodes[total..]) {
+        total += read;
+    }
+    let status = driver.wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+    assert_eq!(&scancodes[..total], [30, 158]);
+    assert!(IrqLine::claim(1).is_some());
+}
+
+/// Only the ports a process was given work, and only once it enabled them
+#[test_case]
+fn io_ports_denied() {
+    assert_general_protection(test_spawn("/bin/devtest", &["deny"]).wait());
+
+    let ports = IoPorts::new(0x60, 5).unwrap();
+    let cmos = IoPorts::new(0x70, 1).unwrap();
+    let status = test_spawn_with_handles(
+        "/bin/devtest",
+        &["outside"],
+        [
+            Handle::new(Arc::new(ports), Rights::ALL),
+            Handle::new(Arc::new(cmos), Rights::READ),
+        ],
+    )
+    .wait();
+    assert_general_protection(status);
+}
+
+/// Device memory maps into a process, which reads the device's registers
+#[test_case]
+fn mmio_mapping() {
+    let base = unsafe { Msr::new(0x1b).read() } & !0xfff;
+    let version = unsafe {
+        memory::phys_to_virt(PhysAddr::new(base + 0x30))
+            .as_ptr::<u32>()
+            .read_volatile()
+    };
+    let region = unsafe { MmioRegion::new(PhysAddr::new(base), 4096) };
+    let (reader, writer) = ipc::pipe();
+    let status = test_spawn_with_handles(
+        "/bin/devtest",
+        &["mmio"],
+        [
+            Handle::new(Arc::new(region), Rights::READ),
+            Handle::new(Arc::new(writer), Rights::WRITE),
+        ],
+    )
+    .wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+    let mut buf = [0; 4];
+    assert_eq!(reader.read(&mut buf), Ok(4));
+    assert_eq!(u32::from_le_bytes(buf), version);
+}
@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use project_fox::kernel::address_space::AddressSpace;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::elf::Program;
+use project_fox::kernel::usermode::UserExit;
+use project_fox::kernel::{initrd, memory};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// `/bin/hello` from the initial ramdisk
+fn load_hello(argv: &[&str], envp: &[&str]) -> Program {
+    let file = initrd::find("/bin/hello").expect("no /bin/hello in the initrd");
+    Program::load(file.data, argv, envp).expect("loading /bin/hello failed")
+}
+
+fn word(space: &AddressSpace, addr: u64) -> u64 {
+    let mut buf = [0; 8];
+    assert!(
+        space.read(VirtAddr::new(addr), &mut buf),
+        "{:#x} not mapped",
+        addr
+    );
+    u64::from_le_bytes(buf)
+}
+
+fn string(space: &AddressSpace, addr: u64) -> String {
+    let mut bytes = Vec::new();
+    let mut byte = [0];
+    for addr in addr.. {
+        assert!(space.read(VirtAddr::new(addr), &mut byte));
+        if byte[0] == 0 {
+            break;
+        }
+        bytes.push(byte[0]);
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+/// The stack holds `argc`, `argv`, `envp` and the auxiliary vector
+#[test_case]
+fn initial_stack() {
+    let program = load_hello(&["hello", "world"], &["FOX=1"]);
+    let space = program.address_space();
+    let sp = program.stack_pointer().as_u64();
+    assert_eq!(sp % 16, 0);
+
+    assert_eq!(word(space, sp), 2);
+    assert_eq!(string(space, word(space, sp + 8)), "hello");
+    assert_eq!(string(space, word(space, sp + 16)), "world");
+    assert_eq!(word(space, sp + 24), 0);
+    assert_eq!(string(space, word(space, sp + 32)), "FOX=1");
+    assert_eq!(word(space, sp + 40), 0);
+
+    let mut auxv = Vec::new();
+    let mut addr = sp + 48;
+    while word(space, addr) != AT_NULL {
+        auxv.push((word(space, addr), word(space, addr + 8)));
+        addr += 16;
+    }
+    let aux = |key| auxv.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v);
+    assert_eq!(aux(AT_ENTRY), Some(program.entry().as_u64()));
+    assert_eq!(aux(AT_PAGESZ), Some(4096));
+    // The first program header, of the `PT_PHDR` segment
+    let phdr = aux(AT_PHDR).expect("no AT_PHDR");
+    assert_eq!(word(space, phdr) as u32, 6);
+}
+
+/// Segments get the permissions their flags ask for, the TLS block points to itself
+#[test_case]
+fn segment_permissions() {
+    let program = load_hello(&["hello"], &[]);
+    let space = program.address_space();
+
+    let (_, code) = space.translate(program.entry()).expect("entry not mapped");
+    assert!(code.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!code.contains(PageTableFlags::WRITABLE));
+    assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+    let (_, stack) = space
+        .translate(program.stack_pointer())
+        .expect("stack not mapped");
+    assert!(stack.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+    let fs_base = program.fs_base().as_u64();
+    assert_ne!(fs_base, 0);
+    assert_eq!(word(space, fs_base), fs_base);
+    assert_eq!(word(space, fs_base - 16), 0x1234_5678);
+
+    // Nothing of it in the kernel's page table
+    let entry = program.entry();
+    assert!(memory::with_memory(|mm| mm.mapper.translate_addr(entry)).is_none());
+}
+
+/// The program runs in its own address space, which goes away with it
+#[test_case]
+fn run_hello() {
+    let program = load_hello(&["hello", "from", "the", "initrd"], &["FOX=1"]);
+    let space = Arc::downgrade(program.address_space());
+
+    let exit = program.run();
+    assert!(matches!(exit, UserExit::Exited(0)), "{:?}", exit);
+    assert_eq!(Cr3::read().0, memory::kernel_page_table());
+    assert_eq!(space.strong_count(), 0);
+}
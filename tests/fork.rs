@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::process::{self, ExitStatus};
+use project_fox::test_spawn;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// Every process of a forked tree reports its subtree's size to its parent,
+/// and each one's writes stay its own
+#[test_case]
+fn fork_tree() {
+    let root = test_spawn("/bin/forktest", &["tree", "3"]);
+    let pid = root.pid().as_u64();
+    let status = root.wait();
+    assert!(matches!(status, ExitStatus::Exited(15)), "{:?}", status);
+    // The 14 descendants are reaped
+    assert!(((pid + 1)..(pid + 15)).all(|pid| process::find(pid).is_none()));
+}
+
+/// A forked child runs another program, `waitpid` finds children by pid or
+/// any of them, with or without waiting
+#[test_case]
+fn exec_and_waitpid() {
+    let process = test_spawn("/bin/forktest", &["exec"]);
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// The child of a process that ends goes to the init process, which reaps it
+#[test_case]
+fn orphans_go_to_init() {
+    let init = test_spawn("/bin/forktest", &["reap"]);
+    process::set_init(&init);
+    let parent = test_spawn("/bin/forktest", &["orphan"]);
+    let status = parent.wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+
+    let status = init.wait();
+    assert!(matches!(status, ExitStatus::Exited(9)), "{:?}", status);
+    assert!(init.children().is_empty());
+}
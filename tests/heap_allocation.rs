@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+use project_fox::kernel::allocator::HEAP_SIZE;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::meminfo;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn many_boxes() {
+    // Allocates far more than the heap in total, so freed memory must be reused
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn no_leak_after_drop() {
+    let before = meminfo::snapshot();
+    let vec: Vec<u64> = (0..4096).collect();
+    assert!(meminfo::snapshot().heap_used > before.heap_used);
+    drop(vec);
+    // Freed blocks sit in KASAN's quarantine, only the heap's books balance
+    #[cfg(not(feature = "kasan"))]
+    assert!(meminfo::snapshot().delta_since(&before).is_zero());
+    #[cfg(feature = "kasan")]
+    assert_eq!(meminfo::snapshot().delta_since(&before).heap, 0);
+}
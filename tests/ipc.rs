@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::process::signal::Signal;
+use project_fox::kernel::process::ExitStatus;
+use project_fox::kernel::thread;
+use project_fox::test_spawn;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// A child's writes to a pipe reach its parent, which then sees the end of
+/// the stream
+#[test_case]
+fn pipe_between_processes() {
+    let status = test_spawn("/bin/ipctest", &["pipe"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// A descriptor sent through a channel opens in the receiver, which maps the
+/// shared memory it refers to
+#[test_case]
+fn channel_carries_descriptors() {
+    let status = test_spawn("/bin/ipctest", &["channel"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// Shared memory stays shared across `fork`
+#[test_case]
+fn shared_memory_across_fork() {
+    let status = test_spawn("/bin/ipctest", &["shm"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// Writing to a pipe nobody reads raises `SIGPIPE`
+#[test_case]
+fn broken_pipe() {
+    let status = test_spawn("/bin/ipctest", &["broken"]).wait();
+    assert!(
+        matches!(status, ExitStatus::Signaled(Signal::SIGPIPE)),
+        "{:?}",
+        status
+    );
+}
+
+/// Descriptors only allow what their rights say, and a handle to a child
+/// signals it and waits for it
+#[test_case]
+fn handle_rights() {
+    let status = test_spawn("/bin/ipctest", &["rights"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// Killing a process blocked reading a pipe ends the read
+#[test_case]
+fn kill_blocked_reader() {
+    let process = test_spawn("/bin/ipctest", &["stuck"]);
+    thread::sleep_ticks(3);
+    assert!(process.exit_status().is_none());
+    process.kill();
+    assert!(matches!(process.wait(), ExitStatus::Killed));
+}
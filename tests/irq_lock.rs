@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::interrupts;
+use project_fox::{print, println, serial_print};
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+static TIMER_PRINTS: AtomicUsize = AtomicUsize::new(0);
+
+fn print_from_timer() {
+    TIMER_PRINTS.fetch_add(1, Ordering::Relaxed);
+    print!("T");
+    // Takes the serial lock without cluttering the test output
+    serial_print!("");
+}
+
+/// Both print paths are taken from the timer interrupt while the test keeps
+/// them busy, with plain spinlocks this deadlocks on the first collision
+#[test_case]
+fn print_from_interrupt_and_thread() {
+    TIMER_PRINTS.store(0, Ordering::Relaxed);
+    interrupts::set_timer_hook(Some(print_from_timer));
+    let end = interrupts::ticks() + 50;
+    let mut lines = 0;
+    while interrupts::ticks() < end {
+        println!("thread line {}", lines);
+        serial_print!("");
+        lines += 1;
+    }
+    interrupts::set_timer_hook(None);
+
+    assert!(TIMER_PRINTS.load(Ordering::Relaxed) >= 50);
+    assert!(lines > 0);
+}
@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::{boxed::Box, vec::Vec};
+use core::panic::PanicInfo;
+use project_fox::kernel::allocator::kasan::{self, BugKind};
+use project_fox::kernel::boot::BootInfo;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    // Trigger bugs on purpose and check the reports instead of panicking
+    kasan::set_panic_on_report(false);
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+#[test_case]
+fn clean_alloc_free() {
+    let v: Vec<u32> = (0..100).collect();
+    assert_eq!(v.iter().sum::<u32>(), 4950);
+    drop(v);
+    kasan::flush_quarantine();
+    assert_eq!(kasan::take_report(), None);
+}
+
+#[test_case]
+fn heap_buffer_overflow() {
+    let mut v: Vec<u8> = Vec::with_capacity(10);
+    let ptr = v.as_mut_ptr();
+    unsafe { ptr.add(12).write_volatile(0x41) };
+    drop(v);
+
+    let report = kasan::take_report().expect("overflow not detected");
+    assert_eq!(report.kind, BugKind::HeapBufferOverflow);
+    assert_eq!(report.addr, ptr as usize + 12);
+    assert!(report.alloc_site.is_some());
+}
+
+#[test_case]
+fn heap_buffer_underflow() {
+    let mut v: Vec<u8> = Vec::with_capacity(10);
+    let ptr = v.as_mut_ptr();
+    unsafe { ptr.sub(1).write_volatile(0x41) };
+    drop(v);
+
+    let report = kasan::take_report().expect("underflow not detected");
+    assert_eq!(report.kind, BugKind::HeapBufferUnderflow);
+}
+
+#[test_case]
+fn use_after_free() {
+    let b = Box::new([0u64; 4]);
+    let ptr = Box::into_raw(b);
+    unsafe {
+        drop(Box::from_raw(ptr));
+        (*ptr)[1] = 0xdead;
+    }
+    // Writes after free are found when the object leaves the quarantine
+    kasan::flush_quarantine();
+
+    let report = kasan::take_report().expect("use after free not detected");
+    assert_eq!(report.kind, BugKind::UseAfterFree);
+    assert_eq!(report.addr, ptr as usize + 8);
+    assert!(report.free_site.is_some());
+}
+
+#[test_case]
+fn use_after_free_checked_access() {
+    let b = Box::new(7u64);
+    let ptr = Box::into_raw(b);
+    assert!(kasan::check_access(ptr as usize, 8).is_ok());
+    unsafe { drop(Box::from_raw(ptr)) };
+
+    let report = kasan::check_access(ptr as usize, 8).unwrap_err();
+    assert_eq!(report.kind, BugKind::UseAfterFree);
+    kasan::take_report();
+}
+
+#[test_case]
+fn out_of_bounds_checked_access() {
+    let b = Box::new(7u32);
+    let ptr = &*b as *const u32 as usize;
+    // The object ends 4 bytes into its granule
+    assert!(kasan::check_access(ptr, 4).is_ok());
+    let report = kasan::check_access(ptr, 5).unwrap_err();
+    assert_eq!(report.kind, BugKind::OutOfBounds);
+    assert_eq!(report.addr, ptr + 4);
+    kasan::take_report();
+}
+
+#[test_case]
+fn double_free() {
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+    let report = kasan::take_report().expect("double free not detected");
+    assert_eq!(report.kind, BugKind::DoubleFree);
+}
+
+#[test_case]
+fn invalid_free() {
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr.add(8), layout);
+        dealloc(ptr, layout);
+    }
+    let report = kasan::take_report().expect("invalid free not detected");
+    assert_eq!(report.kind, BugKind::InvalidFree);
+}
+
+/// A free bigger than the quarantine skips it instead of pushing it past its cap
+#[test_case]
+fn quarantine_stays_capped() {
+    let small: Vec<Box<[u8; 64]>> = (0..16).map(|_| Box::new([0; 64])).collect();
+    drop(small);
+    drop(alloc::vec![0u8; kasan::QUARANTINE_BYTES + 1]);
+    assert!(kasan::quarantine_bytes() <= kasan::QUARANTINE_BYTES);
+    assert!(kasan::quarantine_bytes() > 0);
+    kasan::flush_quarantine();
+    assert_eq!(kasan::take_report(), None);
+}
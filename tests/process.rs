@@ -0,0 +1,143 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::process::{self, ExitStatus};
+use project_fox::kernel::thread;
+use project_fox::kernel::usermode::FaultKind;
+use project_fox::test_spawn;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// The exit status reaches whoever waits, with everything the process had freed
+#[test_case]
+fn exit_status() {
+    let process = test_spawn("/bin/proctest", &["exit", "42"]);
+    let space = Arc::downgrade(&process.address_space().unwrap());
+
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(42)), "{:?}", status);
+    assert_eq!(status.wait_status(), 42 << 8);
+    assert!(matches!(
+        process.exit_status(),
+        Some(ExitStatus::Exited(42))
+    ));
+    assert!(process.threads().is_empty());
+    assert_eq!(space.strong_count(), 0);
+    process.with_files(|files| assert!(files.is_empty()));
+}
+
+/// A page fault ends the process that caused it, and only that one
+#[test_case]
+fn fault_ends_the_process() {
+    let process = test_spawn("/bin/proctest", &["fault"]);
+    let ExitStatus::Faulted(fault) = process.wait() else {
+        panic!("expected a fault");
+    };
+    assert_eq!(fault.kind, FaultKind::PageFault);
+    assert_eq!(fault.address.map(|addr| addr.as_u64()), Some(0x10));
+    assert_eq!(process.exit_status().unwrap().wait_status(), 11);
+
+    let next = test_spawn("/bin/proctest", &["exit", "0"]);
+    assert!(matches!(next.wait(), ExitStatus::Exited(0)));
+}
+
+/// A process that never makes a system call is stopped by the timer
+#[test_case]
+fn kill_spinning() {
+    let process = test_spawn("/bin/proctest", &["spin"]);
+    thread::sleep_ticks(3);
+    assert!(process.exit_status().is_none());
+    assert_eq!(process.threads().len(), 1);
+
+    process.kill();
+    assert!(matches!(process.wait(), ExitStatus::Killed));
+    // Too late to start anything in it
+    assert!(!process.spawn_thread(
+        x86_64::VirtAddr::zero(),
+        x86_64::VirtAddr::zero(),
+        x86_64::VirtAddr::zero()
+    ));
+}
+
+/// `kill` on itself doesn't return to the process
+#[test_case]
+fn kill_itself() {
+    let process = test_spawn("/bin/proctest", &["kill"]);
+    assert!(matches!(process.wait(), ExitStatus::Killed));
+}
+
+/// `kill` doesn't reach processes that aren't the caller's children
+#[test_case]
+fn kill_others_refused() {
+    let spinner = test_spawn("/bin/proctest", &["spin"]);
+    let pid = spinner.pid().as_u64().to_string();
+    let status = test_spawn("/bin/proctest", &["signal", &pid]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+    assert!(spinner.exit_status().is_none());
+    spinner.kill();
+    assert!(matches!(spinner.wait(), ExitStatus::Killed));
+}
+
+/// Closed and unknown descriptors are refused
+#[test_case]
+fn file_descriptors() {
+    let process = test_spawn("/bin/proctest", &["files"]);
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// A process spawns a child and waits for it, which reaps it
+#[test_case]
+fn spawn_child() {
+    let process = test_spawn("/bin/proctest", &["spawn"]);
+    let pid = process.pid();
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(4)), "{:?}", status);
+    assert!(process.children().is_empty());
+    // The child got the pid after its parent's and is gone
+    let child = pid.as_u64() + 1;
+    assert!(process::find(child).is_none());
+}
+
+/// Killing a process blocked in `waitpid` ends the wait, its child goes on
+#[test_case]
+fn kill_waiting() {
+    let process = test_spawn("/bin/proctest", &["wait"]);
+    let child = loop {
+        if let Some(child) = process.children().pop() {
+            break child;
+        }
+        thread::yield_now();
+    };
+    thread::sleep_ticks(3);
+    assert!(process.exit_status().is_none());
+
+    process.kill();
+    assert!(matches!(process.wait(), ExitStatus::Killed));
+    assert!(child.exit_status().is_none());
+    child.kill();
+    assert!(matches!(child.wait(), ExitStatus::Killed));
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::process::{self, ExitStatus};
+use project_fox::kernel::thread;
+use project_fox::test_spawn;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// A program built on the runtime gets its arguments, a heap, formatted
+/// output and the system calls' results and errors
+#[test_case]
+fn runtime_checks() {
+    let status = test_spawn("/bin/rttest", &["check"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// A panic in user code prints it and exits with 101
+#[test_case]
+fn runtime_panic() {
+    let status = test_spawn("/bin/rttest", &["panic"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(101)), "{:?}", status);
+}
+
+/// `/bin/init` starts as the init process and reaps the orphans it gets
+#[test_case]
+fn init_reaps_orphans() {
+    let init = process::start_init().expect("starting /bin/init failed");
+    assert!(process::init_process().is_some_and(|p| Arc::ptr_eq(&p, &init)));
+
+    let parent = test_spawn("/bin/forktest", &["orphan"]);
+    assert!(matches!(parent.wait(), ExitStatus::Exited(0)));
+    // The orphan exits once it sees its new parent
+    for _ in 0..500 {
+        if init.children().is_empty() {
+            break;
+        }
+        thread::sleep_ticks(1);
+    }
+    assert!(init.children().is_empty());
+    assert!(init.exit_status().is_none());
+}
@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::interrupts;
+use project_fox::kernel::thread::scheduler::{self, FairShare, Mlfq, Policy, RoundRobin};
+use project_fox::kernel::thread::{self, JoinHandle};
+use project_fox::serial_println;
+
+// Compares the scheduling policies under the same load: CPU bound threads at
+// two niceness levels, plus the test thread repeatedly sleeping for a tick and
+// measuring how late it gets to run again. Results are printed over serial,
+// the assertions only check that the policies behave as documented.
+
+/// Number of sleeps the latency measurement averages over
+const SLEEPS: u64 = 20;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+struct Results {
+    /// Timer ticks between a sleep ending and the sleeper running, average and worst
+    latency_avg: u64,
+    latency_max: u64,
+    /// Ticks each group of CPU bound threads ran for
+    nice_0_ticks: u64,
+    nice_10_ticks: u64,
+}
+
+fn spawn_hogs(nice: i8, stop: &Arc<AtomicBool>) -> Vec<JoinHandle> {
+    (0..2)
+        .map(|_| {
+            let stop = stop.clone();
+            let handle = thread::spawn_named("hog", move || {
+                while !stop.load(Ordering::Relaxed) {
+                    core::hint::spin_loop();
+                }
+            });
+            handle.thread().set_nice(nice);
+            handle
+        })
+        .collect()
+}
+
+fn run(policy: Box<dyn Policy>) -> Results {
+    scheduler::set_policy(policy);
+    let stop = Arc::new(AtomicBool::new(false));
+    let nice_0 = spawn_hogs(0, &stop);
+    let nice_10 = spawn_hogs(10, &stop);
+
+    let mut total = 0;
+    let mut latency_max = 0;
+    for _ in 0..SLEEPS {
+        let wake_at = interrupts::ticks() + 1;
+        thread::sleep_ticks(1);
+        let late = interrupts::ticks() - wake_at;
+        total += late;
+        latency_max = latency_max.max(late);
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    let runtime = |hogs: Vec<JoinHandle>| {
+        hogs.into_iter()
+            .map(|hog| {
+                let thread = hog.thread().clone();
+                hog.join();
+                thread.runtime_ticks()
+            })
+            .sum()
+    };
+    let results = Results {
+        latency_avg: total / SLEEPS,
+        latency_max,
+        nice_0_ticks: runtime(nice_0),
+        nice_10_ticks: runtime(nice_10),
+    };
+    serial_println!(
+        "\n  {}: wakeup latency avg {} max {} ticks, cpu nice 0: {} ticks, nice 10: {} ticks",
+        scheduler::policy_name(),
+        results.latency_avg,
+        results.latency_max,
+        results.nice_0_ticks,
+        results.nice_10_ticks
+    );
+    scheduler::set_policy(Box::new(RoundRobin::new()));
+    results
+}
+
+#[test_case]
+fn round_robin() {
+    let results = run(Box::new(RoundRobin::new()));
+    // Strict priorities: the nice 0 threads are always ready, the nice 10 ones never get in
+    assert!(results.nice_0_ticks > results.nice_10_ticks);
+}
+
+#[test_case]
+fn mlfq() {
+    let results = run(Box::new(Mlfq::new()));
+    // The sleeper stays at the top level, the CPU bound threads sink below it
+    assert!(results.latency_avg <= 2);
+}
+
+#[test_case]
+fn fair_share() {
+    let results = run(Box::new(FairShare::new()));
+    // Nice 10 is weighted ~10x less, but still gets some time
+    assert!(results.nice_0_ticks > results.nice_10_ticks);
+}
@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::process::signal::Signal;
+use project_fox::kernel::process::{ExitStatus, Process};
+use project_fox::kernel::thread;
+use project_fox::kernel::usermode::FaultKind;
+use project_fox::test_spawn;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// Block until a stop signal has stopped `process`
+fn wait_stopped(process: &Process) {
+    for _ in 0..100 {
+        if process.is_stopped() {
+            return;
+        }
+        thread::sleep_ticks(1);
+    }
+    panic!("process never stopped");
+}
+
+/// A handler runs when the signal is sent, and again once it is unblocked,
+/// and returns to where the process was
+#[test_case]
+fn handler_runs_and_returns() {
+    let process = test_spawn("/bin/sigtest", &["handler"]);
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// Exceptions raise their signals, which handlers can catch and go on from
+#[test_case]
+fn faults_raise_signals() {
+    let status = test_spawn("/bin/sigtest", &["segv"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(42)), "{:?}", status);
+    let status = test_spawn("/bin/sigtest", &["ill"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+
+    // Without a handler the process dies of it
+    let status = test_spawn("/bin/sigtest", &["fpe"]).wait();
+    let ExitStatus::Faulted(fault) = status else {
+        panic!("expected a fault, got {:?}", status);
+    };
+    assert_eq!(fault.kind, FaultKind::DivideError);
+    assert_eq!(status.wait_status(), Signal::SIGFPE.number() as i32);
+}
+
+/// `SIGTERM` ends a process that doesn't handle it, unless it ignores it
+#[test_case]
+fn default_and_ignore() {
+    let status = test_spawn("/bin/sigtest", &["default"]).wait();
+    assert!(
+        matches!(status, ExitStatus::Signaled(Signal::SIGTERM)),
+        "{:?}",
+        status
+    );
+    assert_eq!(status.wait_status(), 15);
+
+    let status = test_spawn("/bin/sigtest", &["ignore"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// Handlers and restorers outside user space are refused
+#[test_case]
+fn bad_handler_refused() {
+    let status = test_spawn("/bin/sigtest", &["bad"]).wait();
+    assert!(matches!(status, ExitStatus::Exited(0)), "{:?}", status);
+}
+
+/// A process that never makes a system call gets its signals when the timer
+/// interrupts it
+#[test_case]
+fn signal_from_the_kernel() {
+    let process = test_spawn("/bin/sigtest", &["wait"]);
+    thread::sleep_ticks(3);
+    assert!(process.exit_status().is_none());
+    process.send_signal(Signal::SIGUSR1);
+    let status = process.wait();
+    assert!(matches!(status, ExitStatus::Exited(7)), "{:?}", status);
+}
+
+/// `SIGSTOP` stops a process until `SIGCONT`, `SIGKILL` ends it even stopped
+#[test_case]
+fn stop_and_continue() {
+    let process = test_spawn("/bin/proctest", &["spin"]);
+    process.send_signal(Signal::SIGSTOP);
+    wait_stopped(&process);
+    // Held back until it is continued
+    process.send_signal(Signal::SIGTERM);
+    thread::sleep_ticks(3);
+    assert!(process.exit_status().is_none());
+
+    process.send_signal(Signal::SIGCONT);
+    let status = process.wait();
+    assert!(
+        matches!(status, ExitStatus::Signaled(Signal::SIGTERM)),
+        "{:?}",
+        status
+    );
+
+    let process = test_spawn("/bin/proctest", &["spin"]);
+    process.send_signal(Signal::SIGSTOP);
+    wait_stopped(&process);
+    process.send_signal(Signal::SIGKILL);
+    assert!(matches!(process.wait(), ExitStatus::Killed));
+}
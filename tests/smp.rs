@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::{acpi, cpu};
+use project_fox::serial_println;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+/// Every CPU in the MADT came online (the runner starts QEMU with several)
+#[test_case]
+fn all_cpus_online() {
+    let processors = acpi::processors();
+    assert!(processors.len() > 1, "smp: QEMU emulates a single CPU");
+    assert_eq!(cpu::count(), processors.len());
+
+    let apic_ids: Vec<u32> = (0..cpu::count())
+        .map(|cpu| cpu::apic_id(cpu).expect("smp: online CPU without an APIC ID"))
+        .collect();
+    for (cpu, apic_id) in apic_ids.iter().enumerate() {
+        serial_println!("\n  cpu {}: APIC ID {}", cpu, apic_id);
+    }
+    for processor in &processors {
+        assert!(apic_ids.contains(&processor.apic_id));
+    }
+}
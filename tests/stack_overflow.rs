@@ -4,6 +4,7 @@
 
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
+use project_fox::kernel::boot::BootInfo;
 use project_fox::kernel::gdt;
 pub use project_fox::{exit_qemu, serial_print, serial_println, QemuExitCode};
 use x86_64::structures::idt::InterruptDescriptorTable;
@@ -35,8 +36,9 @@ pub fn init_test_idt() {
     TEST_IDT.load();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(_boot_info: BootInfo) -> ! {
     serial_print!("start stack_overflow::stack_overflow...\t");
 
     gdt::gdt_init();
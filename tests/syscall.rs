@@ -0,0 +1,151 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::interrupts;
+use project_fox::kernel::syscall::{self, Errno};
+use project_fox::kernel::usermode::{self, UserExit};
+use project_fox::kernel::{memory, thread};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+// One page each of user code, stack and data
+const CODE: u64 = 0x_7800_0000_0000;
+const STACK: u64 = CODE + 0x1000;
+const DATA: u64 = CODE + 0x2000;
+
+const MESSAGE: &str = "hello from ring 3\n";
+
+// The user program, copied to `CODE`. It makes every system call once and
+// stores what each returned in the data page, in order, then exits with 7.
+// `rbx` holds the data page's address throughout, system calls preserve it.
+core::arch::global_asm!(
+    r#"
+.global user_program_start
+.global user_program_end
+user_program_start:
+    mov rbx, {data}
+    mov eax, {write}
+    mov edi, 1
+    lea rsi, [rip + .Lmessage]
+    mov edx, {len}
+    syscall
+    mov [rbx], rax
+    mov eax, {getpid}
+    syscall
+    mov [rbx + 8], rax
+    mov eax, {sched_yield}
+    syscall
+    mov [rbx + 16], rax
+    mov eax, {sleep}
+    mov edi, 2
+    syscall
+    mov [rbx + 24], rax
+    mov eax, {mmap}
+    xor edi, edi
+    mov esi, 4096
+    mov edx, 3
+    syscall
+    mov [rbx + 32], rax
+    mov qword ptr [rax], 42
+    mov eax, {write}
+    mov edi, 1
+    mov rsi, 0xffff800000000000
+    mov edx, 1
+    syscall
+    mov [rbx + 40], rax
+    mov eax, 999
+    syscall
+    mov [rbx + 48], rax
+    mov eax, {getpid}
+    int 0x80
+    mov [rbx + 56], rax
+    mov eax, {exit}
+    mov edi, 7
+    syscall
+    ud2
+.Lmessage:
+    .ascii "hello from ring 3\n"
+user_program_end:
+"#,
+    data = const DATA,
+    len = const MESSAGE.len(),
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    sched_yield = const syscall::SYS_YIELD,
+    sleep = const syscall::SYS_SLEEP,
+    getpid = const syscall::SYS_GETPID,
+    mmap = const syscall::SYS_MMAP,
+);
+
+extern "C" {
+    static user_program_start: u8;
+    static user_program_end: u8;
+}
+
+fn errno(errno: Errno) -> u64 {
+    (-(errno as i64)) as u64
+}
+
+/// The user program makes each system call and exits with its status
+#[test_case]
+fn every_syscall() {
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    memory::map_range(VirtAddr::new(CODE), 0x3000, user | PageTableFlags::WRITABLE)
+        .expect("map_range failed");
+    unsafe {
+        let start = &user_program_start as *const u8;
+        let len = (&user_program_end as *const u8).offset_from(start) as usize;
+        core::ptr::copy_nonoverlapping(start, CODE as *mut u8, len);
+    }
+    memory::protect_range(VirtAddr::new(CODE), 0x1000, user).expect("protect_range failed");
+
+    let start = interrupts::ticks();
+    let exit = unsafe { usermode::enter(VirtAddr::new(CODE), VirtAddr::new(STACK + 0x1000)) };
+    let elapsed = interrupts::ticks() - start;
+    let results: [u64; 8] = unsafe { (DATA as *const [u64; 8]).read_volatile() };
+    memory::unmap_range(VirtAddr::new(CODE), 0x3000).expect("unmap_range failed");
+
+    assert!(matches!(exit, UserExit::Exited(7)), "{:?}", exit);
+    let pid = thread::current().id().as_u64();
+    assert_eq!(results[0], MESSAGE.len() as u64, "write");
+    assert_eq!(results[1], pid, "getpid");
+    assert_eq!(results[2], 0, "yield");
+    assert_eq!(results[3], 0, "sleep");
+    assert!(elapsed >= 2, "slept {} ticks", elapsed);
+    let mapped = results[4];
+    assert!(
+        mapped != 0 && mapped < memory::USER_END,
+        "mmap returned {:#x}",
+        mapped
+    );
+    assert_eq!(unsafe { (mapped as *const u64).read_volatile() }, 42);
+    memory::unmap_range(VirtAddr::new(mapped), 4096).expect("unmap_range failed");
+    assert_eq!(
+        results[5],
+        errno(Errno::EFAULT),
+        "write from a kernel pointer"
+    );
+    assert_eq!(results[6], errno(Errno::ENOSYS), "unknown system call");
+    assert_eq!(results[7], pid, "getpid through int 0x80");
+}
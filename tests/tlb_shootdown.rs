@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::ipi::{self, Target};
+use project_fox::kernel::{cpu, memory};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+const ADDR: u64 = 0x_5555_1000_0000;
+
+/// Read `ADDR` on `cpu`, `None` if it faults there
+fn read_on(cpu: usize) -> Option<u64> {
+    let value = AtomicU64::new(0);
+    let faulted = AtomicBool::new(false);
+    ipi::call(Target::Cpu(cpu), || {
+        match memory::probe_read(VirtAddr::new(ADDR)) {
+            Some(read) => value.store(read, Ordering::Relaxed),
+            None => faulted.store(true, Ordering::Relaxed),
+        }
+    });
+    (!faulted.load(Ordering::Relaxed)).then(|| value.load(Ordering::Relaxed))
+}
+
+/// A page unmapped on the boot CPU is gone on another CPU that had it in its
+/// TLB, rather than still readable through the stale entry
+#[test_case]
+fn unmap_faults_on_other_cpu() {
+    assert!(cpu::count() > 1, "smp: QEMU emulates a single CPU");
+    let other = cpu::count() - 1;
+    let addr = VirtAddr::new(ADDR);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    memory::map_range(addr, 4096, flags).expect("map_range failed");
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(0xF0C5) };
+    // Now cached in the other CPU's TLB
+    assert_eq!(read_on(other), Some(0xF0C5));
+
+    memory::unmap_range(addr, 4096).expect("unmap_range failed");
+    assert_eq!(memory::probe_read(addr), None);
+    assert_eq!(read_on(other), None);
+}
+
+/// Taking a page's access away with `protect_range()` reaches the other CPUs too
+#[test_case]
+fn protect_reaches_other_cpu() {
+    let other = cpu::count() - 1;
+    let addr = VirtAddr::new(ADDR);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    memory::map_range(addr, 4096, flags).expect("map_range failed");
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(0xF0C5) };
+    assert_eq!(read_on(other), Some(0xF0C5));
+    memory::protect_range(addr, 4096, PageTableFlags::empty()).expect("protect_range failed");
+    assert_eq!(read_on(other), None);
+
+    memory::protect_range(addr, 4096, flags).expect("protect_range failed");
+    memory::unmap_range(addr, 4096).expect("unmap_range failed");
+}
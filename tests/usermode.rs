@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(project_fox::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use project_fox::kernel::boot::BootInfo;
+use project_fox::kernel::usermode::{self, FaultKind, UserExit, UserFault};
+use project_fox::kernel::{memory, thread};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+project_fox::entry_point!(kernel_main);
+
+fn kernel_main(boot_info: BootInfo) -> ! {
+    if let Err(()) = project_fox::init(boot_info) {
+        panic!("Kernel Init Failed");
+    }
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    project_fox::test_panic_handler(info)
+}
+
+// One page each of user code, stack and data
+const CODE: u64 = 0x_7800_0000_0000;
+const STACK: u64 = CODE + 0x1000;
+const DATA: u64 = CODE + 0x2000;
+
+/// Map the user pages, copy `code` in and run it until it faults
+///
+/// Returns the fault and the first `u64` of the data page.
+fn run(code: &[u8]) -> (UserFault, u64) {
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let writable = user | PageTableFlags::WRITABLE;
+    memory::map_range(VirtAddr::new(CODE), 0x3000, writable).expect("map_range failed");
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), CODE as *mut u8, code.len());
+    }
+    memory::protect_range(VirtAddr::new(CODE), 0x1000, user).expect("protect_range failed");
+
+    let exit = unsafe { usermode::enter(VirtAddr::new(CODE), VirtAddr::new(STACK + 0x1000)) };
+    let UserExit::Faulted(fault, _) = exit else {
+        panic!("expected a fault, got {:?}", exit);
+    };
+    let data = unsafe { (DATA as *const u64).read_volatile() };
+    memory::unmap_range(VirtAddr::new(CODE), 0x3000).expect("unmap_range failed");
+    (fault, data)
+}
+
+/// `mov rax, value; mov [addr], rax`, returns its length
+fn store(code: &mut [u8], value: u64, addr: u64) -> usize {
+    code[..2].copy_from_slice(&[0x48, 0xb8]);
+    code[2..10].copy_from_slice(&value.to_le_bytes());
+    code[10..12].copy_from_slice(&[0x48, 0xa3]);
+    code[12..20].copy_from_slice(&addr.to_le_bytes());
+    20
+}
+
+const HLT: u8 = 0xf4;
+
+/// User code runs, writes its memory, and a privileged instruction brings us back
+#[test_case]
+fn privileged_instruction() {
+    let mut code = [0; 32];
+    let len = store(&mut code, 0xF0C5, DATA);
+    code[len] = HLT;
+
+    let (fault, data) = run(&code);
+    assert_eq!(fault.kind, FaultKind::GeneralProtection);
+    assert_eq!(fault.instruction_pointer.as_u64(), CODE + len as u64);
+    assert_eq!(data, 0xF0C5);
+}
+
+/// Touching kernel memory is a page fault handed back to us
+#[test_case]
+fn kernel_memory() {
+    static SECRET: AtomicU64 = AtomicU64::new(0);
+    let target = &SECRET as *const _ as u64;
+    let mut code = [0; 32];
+    store(&mut code, 1, target);
+
+    let (fault, _) = run(&code);
+    assert_eq!(fault.kind, FaultKind::PageFault);
+    assert_eq!(fault.address, Some(VirtAddr::new(target)));
+    assert_eq!(SECRET.load(Ordering::Relaxed), 0);
+}
+
+/// Writing to its own read-only code page faults too
+#[test_case]
+fn read_only_code() {
+    let mut code = [0; 32];
+    store(&mut code, 1, CODE);
+    let (fault, _) = run(&code);
+    assert_eq!(fault.kind, FaultKind::PageFault);
+    assert_eq!(fault.address, Some(VirtAddr::new(CODE)));
+}
+
+#[test_case]
+fn invalid_opcode() {
+    // ud2
+    let (fault, _) = run(&[0x0f, 0x0b]);
+    assert_eq!(fault.kind, FaultKind::InvalidOpcode);
+    assert_eq!(fault.instruction_pointer.as_u64(), CODE);
+}
+
+/// User code is preempted like kernel code, other threads get to run meanwhile
+#[test_case]
+fn preempted() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| RAN.store(true, Ordering::Relaxed));
+
+    // mov rcx, 1 << 26; 1: dec rcx; jnz 1b; hlt
+    let mut code = [
+        0x48, 0xb9, 0, 0, 0, 0, 0, 0, 0, 0, 0x48, 0xff, 0xc9, 0x75, 0xfb, HLT,
+    ];
+    code[2..10].copy_from_slice(&(1u64 << 26).to_le_bytes());
+    let (fault, _) = run(&code);
+    assert_eq!(fault.kind, FaultKind::GeneralProtection);
+    assert!(RAN.load(Ordering::Relaxed));
+    handle.join();
+}
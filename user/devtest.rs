@@ -0,0 +1,75 @@
+//! Does what its first argument says, for the device tests, and exits with 0
+//! or the number of the first check that failed:
+//!
+//! - `deny`: read an I/O port without having been given any, which should
+//!   fault
+//! - `outside`: with I/O ports 0x60 to 0x64 on descriptor 3 and 0x70
+//!   read-only on descriptor 4, enable the first, read one of them, then read
+//!   port 0x70, which should fault
+//! - `mmio`: map the read-only device memory on descriptor 3, a local APIC,
+//!   and write its version register to descriptor 4
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/devtest`.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use fox::sys::{self, Errno, PROT_READ, PROT_WRITE};
+use fox::Args;
+
+fox::main!(main);
+
+/// Local APIC version register
+const APIC_VERSION: usize = 0x30;
+
+fn inb(port: u16) -> u8 {
+    let value;
+    unsafe { asm!("in al, dx", out("al") value, in("dx") port) };
+    value
+}
+
+fn deny() -> i32 {
+    inb(0x64);
+    1
+}
+
+fn outside() -> i32 {
+    if sys::ioport_enable(4) != Err(Errno::EACCES) {
+        return 1;
+    }
+    if sys::ioport_enable(3).is_err() {
+        return 2;
+    }
+    inb(0x64);
+    inb(0x70);
+    3
+}
+
+fn mmio() -> i32 {
+    let writable = sys::mmio_map(3, PROT_READ | PROT_WRITE);
+    let Ok(addr) = sys::mmio_map(3, PROT_READ) else {
+        return 100;
+    };
+    let version = unsafe { addr.add(APIC_VERSION).cast::<u32>().read_volatile() }.to_le_bytes();
+    let checks = [
+        writable == Err(Errno::EACCES),
+        sys::write(4, &version) == Ok(4),
+    ];
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => failed as i32 + 1,
+        None => 0,
+    }
+}
+
+fn main(args: Args) -> i32 {
+    let Some(mode) = args.get(1) else {
+        return 100;
+    };
+    match mode.to_bytes() {
+        b"deny" => deny(),
+        b"outside" => outside(),
+        b"mmio" => mmio(),
+        _ => 100,
+    }
+}
@@ -0,0 +1,132 @@
+//! Does what its first argument says, for the fork tests:
+//!
+//! - `tree N`: fork a binary tree of processes N levels deep, each exits with
+//!   the number of processes in its subtree; 200 if a child's write showed
+//!   through
+//! - `exec`: fork a child that runs `proctest exit 5` and one that sleeps,
+//!   check what `waitpid` says about them, exit with 0 or the number of the
+//!   first check that failed
+//! - `orphan`: fork a child that waits to be handed to another parent and
+//!   exits with 9, exit with 0 without waiting for it
+//! - `reap`: wait for a child to be handed over and reap it, exit with its status
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/forktest`.
+
+#![no_std]
+#![no_main]
+
+use core::ffi::CStr;
+use fox::sys::{self, Errno, WNOHANG};
+use fox::Args;
+
+fox::main!(main);
+
+// Each process of the tree writes its own depth here
+static mut DEPTH: u32 = 0;
+
+fn fork() -> u64 {
+    sys::fork().unwrap_or_else(|_| sys::exit(105))
+}
+
+/// The status a child exited with, 0 if a signal ended it
+fn status(wait_status: i32) -> i32 {
+    sys::exit_status(wait_status).unwrap_or(0)
+}
+
+fn parse(digits: &CStr) -> u32 {
+    digits
+        .to_bytes()
+        .iter()
+        .fold(0, |n, digit| n * 10 + u32::from(digit - b'0'))
+}
+
+fn tree(depth: u32) -> i32 {
+    unsafe { DEPTH = depth };
+    if depth == 0 {
+        return 1;
+    }
+    let mut children = [0; 2];
+    for child in children.iter_mut() {
+        *child = fork();
+        if *child == 0 {
+            sys::exit(tree(depth - 1));
+        }
+    }
+    let mut total = 1;
+    for child in children {
+        match sys::waitpid(child as i32, 0) {
+            Ok((pid, wait_status)) if pid == child => total += status(wait_status),
+            _ => return 201,
+        }
+    }
+    if unsafe { DEPTH } != depth {
+        return 200;
+    }
+    total
+}
+
+fn exec() -> i32 {
+    let runner = fork();
+    if runner == 0 {
+        sys::execve(c"/bin/proctest", &[c"proctest", c"exit", c"5"], &[]);
+        sys::exit(102);
+    }
+    let sleeper = fork();
+    if sleeper == 0 {
+        sys::sleep(5);
+        sys::exit(6);
+    }
+    let pending = sys::waitpid(sleeper as i32, WNOHANG);
+    let ran = sys::waitpid(runner as i32, 0);
+    let slept = sys::waitpid(-1, 0);
+    let checks = [
+        matches!(pending, Ok((0, _))),
+        ran == Ok((runner, 5 << 8)),
+        slept == Ok((sleeper, 6 << 8)),
+        sys::waitpid(-1, 0) == Err(Errno::ECHILD),
+    ];
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => failed as i32 + 1,
+        None => 0,
+    }
+}
+
+fn orphan() -> i32 {
+    let parent = sys::getpid();
+    if fork() == 0 {
+        for _ in 0..500 {
+            let now = sys::getppid();
+            if now != parent && now != 0 {
+                sys::exit(9);
+            }
+            sys::sleep(1);
+        }
+        sys::exit(103);
+    }
+    0
+}
+
+fn reap() -> i32 {
+    for _ in 0..500 {
+        if let Ok((pid, wait_status)) = sys::waitpid(-1, WNOHANG) {
+            if pid != 0 {
+                return status(wait_status);
+            }
+        }
+        sys::sleep(1);
+    }
+    104
+}
+
+fn main(args: Args) -> i32 {
+    let Some(mode) = args.get(1) else {
+        return 100;
+    };
+    match mode.to_bytes() {
+        b"tree" if args.len() > 2 => tree(parse(args.get(2).unwrap())),
+        b"exec" => exec(),
+        b"orphan" => orphan(),
+        b"reap" => reap(),
+        _ => 100,
+    }
+}
@@ -0,0 +1,112 @@
+//! The heap behind `alloc`
+//!
+//! Blocks come in powers of two, from 16 bytes, each aligned to its size.
+//! Freed blocks wait on a list for their size to be allocated again; memory
+//! is never given back, there is no `munmap`. Small blocks are cut from
+//! chunks mapped with `mmap`, big ones have memory of their own.
+
+use crate::sys::{self, PROT_READ, PROT_WRITE};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const PAGE_SIZE: usize = 4096;
+/// Smallest block, room for the free list's link
+const MIN_BLOCK: usize = 16;
+/// Mapped at once for small blocks, also the biggest small block
+const CHUNK_SIZE: usize = 64 * 1024;
+/// A free list for each size up to `CHUNK_SIZE`, and each above it
+const CLASSES: usize = usize::BITS as usize;
+
+struct Heap {
+    /// The first free block of each size, each one links to the next
+    free: [*mut u8; CLASSES],
+    /// What is left of the chunk small blocks are cut from
+    next: usize,
+    end: usize,
+}
+
+struct Allocator {
+    locked: AtomicBool,
+    heap: core::cell::UnsafeCell<Heap>,
+}
+
+// Behind `locked`
+unsafe impl Sync for Allocator {}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator {
+    locked: AtomicBool::new(false),
+    heap: core::cell::UnsafeCell::new(Heap {
+        free: [ptr::null_mut(); CLASSES],
+        next: 0,
+        end: 0,
+    }),
+};
+
+impl Allocator {
+    fn with_heap<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.heap.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// The size class of `layout`: its block is `1 << class` bytes
+fn class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK);
+    Some(size.checked_next_power_of_two()?.trailing_zeros() as usize)
+}
+
+impl Heap {
+    fn alloc(&mut self, class: usize) -> *mut u8 {
+        let free = self.free[class];
+        if !free.is_null() {
+            self.free[class] = unsafe { *(free as *mut *mut u8) };
+            return free;
+        }
+        let size = 1 << class;
+        if size > CHUNK_SIZE {
+            // Pages are only so aligned
+            return sys::mmap(size, PROT_READ | PROT_WRITE).unwrap_or(ptr::null_mut());
+        }
+        let mut start = self.next.next_multiple_of(size);
+        if start + size > self.end {
+            let Ok(chunk) = sys::mmap(CHUNK_SIZE, PROT_READ | PROT_WRITE) else {
+                return ptr::null_mut();
+            };
+            // The rest of the old chunk is lost
+            self.next = chunk as usize;
+            self.end = self.next + CHUNK_SIZE;
+            start = self.next;
+        }
+        self.next = start + size;
+        start as *mut u8
+    }
+
+    fn free(&mut self, block: *mut u8, class: usize) {
+        unsafe { *(block as *mut *mut u8) = self.free[class] };
+        self.free[class] = block;
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match class(&layout) {
+            Some(class) if layout.align() <= PAGE_SIZE => self.with_heap(|heap| heap.alloc(class)),
+            _ => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let class = class(&layout).unwrap();
+        self.with_heap(|heap| heap.free(block, class));
+    }
+}
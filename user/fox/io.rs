@@ -0,0 +1,52 @@
+//! `print!` and friends, over `write`
+
+use crate::sys;
+use core::fmt;
+
+/// Writes formatted text to a descriptor, all of it or until `write` fails
+pub struct Writer(pub i32);
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            match sys::write(self.0, bytes) {
+                Ok(written @ 1..) => bytes = &bytes[written..],
+                _ => return Err(fmt::Error),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: i32, args: fmt::Arguments) {
+    // Nowhere to report it
+    fmt::Write::write_fmt(&mut Writer(fd), args).ok();
+}
+
+/// Print to the standard output, descriptor 1
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(1, format_args!($($arg)*)));
+}
+
+/// `print!` with a newline
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Print to the standard error, descriptor 2
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print(2, format_args!($($arg)*)));
+}
+
+/// `eprint!` with a newline
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
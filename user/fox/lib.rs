@@ -0,0 +1,22 @@
+//! `fox`, the runtime of user programs: what a libc would give them.
+//!
+//! - `sys`: a wrapper for each system call, failing with an `Errno`
+//! - `main!`: the program's `_start`, which calls its `main` with its
+//!   arguments and exits with what it returns
+//! - a heap for `alloc`, in memory from `mmap`
+//! - `print!` and friends, over `write` to descriptors 1 and 2
+//! - a panic handler that prints the panic and exits with 101
+//!
+//! Built by `build.rs` before the programs in `user/`, which use it by naming
+//! it: those that don't keep their own `_start` and panic handler.
+
+#![no_std]
+
+extern crate alloc;
+
+mod heap;
+pub mod io;
+pub mod rt;
+pub mod sys;
+
+pub use rt::Args;
@@ -0,0 +1,80 @@
+//! Starting and ending the program
+
+use crate::sys;
+use core::ffi::{c_char, CStr};
+
+/// The program's arguments, or its environment
+#[derive(Clone, Copy)]
+pub struct Args {
+    strings: *const *const c_char,
+    len: usize,
+}
+
+impl Args {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&'static CStr> {
+        (i < self.len).then(|| unsafe { CStr::from_ptr(*self.strings.add(i)) })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static CStr> {
+        let args = *self;
+        (0..self.len).filter_map(move |i| args.get(i))
+    }
+
+    /// The environment that follows the arguments on the stack
+    pub fn env(&self) -> Args {
+        let strings = unsafe { self.strings.add(self.len + 1) };
+        let mut len = 0;
+        while !unsafe { *strings.add(len) }.is_null() {
+            len += 1;
+        }
+        Args { strings, len }
+    }
+}
+
+/// Define `_start`, which runs `main` with the program's arguments and exits
+/// with what it returns
+///
+/// `main` is a `fn(fox::Args) -> i32`.
+#[macro_export]
+macro_rules! main {
+    ($main:path) => {
+        #[unsafe(naked)]
+        #[no_mangle]
+        extern "C" fn _start() -> ! {
+            core::arch::naked_asm!("mov rdi, rsp", "call {start}", "ud2", start = sym __fox_start)
+        }
+
+        unsafe extern "C" fn __fox_start(stack: *const u64) -> ! {
+            $crate::rt::start(stack, $main)
+        }
+    };
+}
+
+/// Run `main` with the arguments on the initial `stack`, see `main!`
+///
+/// # Safety
+///
+/// `stack` must be where the kernel left the stack: `argc` and then the
+/// NULL terminated `argv` and `envp` arrays.
+#[doc(hidden)]
+pub unsafe fn start(stack: *const u64, main: fn(Args) -> i32) -> ! {
+    let args = Args {
+        strings: stack.add(1).cast(),
+        len: *stack as usize,
+    };
+    sys::exit(main(args))
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    crate::eprintln!("{}", info);
+    sys::exit(101)
+}
@@ -0,0 +1,374 @@
+//! The system calls, see `kernel::syscall` for what each one does
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::ffi::CStr;
+
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_EXIT: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+pub const SYS_MMAP: u64 = 5;
+pub const SYS_CLOSE: u64 = 6;
+pub const SYS_SPAWN: u64 = 7;
+pub const SYS_WAITPID: u64 = 8;
+pub const SYS_KILL: u64 = 9;
+pub const SYS_FORK: u64 = 10;
+pub const SYS_EXECVE: u64 = 11;
+pub const SYS_GETPPID: u64 = 12;
+pub const SYS_SIGRETURN: u64 = 13;
+pub const SYS_SIGACTION: u64 = 14;
+pub const SYS_SIGPROCMASK: u64 = 15;
+pub const SYS_READ: u64 = 16;
+pub const SYS_PIPE: u64 = 17;
+pub const SYS_CHANNEL: u64 = 18;
+pub const SYS_SEND: u64 = 19;
+pub const SYS_RECV: u64 = 20;
+pub const SYS_SHM_CREATE: u64 = 21;
+pub const SYS_SHM_MAP: u64 = 22;
+pub const SYS_DUP: u64 = 23;
+pub const SYS_HANDLE_RIGHTS: u64 = 24;
+pub const SYS_PROCESS_OPEN: u64 = 25;
+pub const SYS_PROCESS_KILL: u64 = 26;
+pub const SYS_PROCESS_WAIT: u64 = 27;
+pub const SYS_IOPORT_ENABLE: u64 = 28;
+pub const SYS_MMIO_MAP: u64 = 29;
+pub const SYS_IRQ_BIND: u64 = 30;
+
+/// `mmap`, `shm_map` and `mmio_map` access
+pub const PROT_READ: u32 = 1;
+pub const PROT_WRITE: u32 = 2;
+pub const PROT_EXEC: u32 = 4;
+
+/// `waitpid` option to return instead of waiting
+pub const WNOHANG: u32 = 1;
+
+/// `SigAction::handler` besides the address of a function
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// How `sigprocmask` changes the blocked signals
+pub const SIG_BLOCK: u32 = 0;
+pub const SIG_UNBLOCK: u32 = 1;
+pub const SIG_SETMASK: u32 = 2;
+
+/// Rights of descriptors, see `kernel::process::fd::Rights`
+pub const RIGHT_READ: u32 = 1;
+pub const RIGHT_WRITE: u32 = 2;
+pub const RIGHT_DUPLICATE: u32 = 4;
+pub const RIGHT_TRANSFER: u32 = 8;
+
+/// Why a system call failed, Linux's numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EACCES: Errno = Errno(13);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const EMSGSIZE: Errno = Errno(90);
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// Make system call `number` with `args`, returns what it left in `rax`
+///
+/// # Safety
+///
+/// Pointers among `args` must be valid for what the call does with them.
+pub unsafe fn syscall(number: u64, args: [u64; 5]) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// The value of a call that returned `raw`, an error from -4095 to -1
+fn result(raw: u64) -> Result<u64> {
+    match raw as i64 {
+        -4095..=-1 => Err(Errno(-(raw as i64) as i32)),
+        _ => Ok(raw),
+    }
+}
+
+/// `syscall()`, decoded
+fn call(number: u64, args: [u64; 5]) -> Result<u64> {
+    result(unsafe { syscall(number, args) })
+}
+
+fn call3(number: u64, a: u64, b: u64, c: u64) -> Result<u64> {
+    call(number, [a, b, c, 0, 0])
+}
+
+pub fn write(fd: i32, buf: &[u8]) -> Result<usize> {
+    call3(SYS_WRITE, fd as u64, buf.as_ptr() as u64, buf.len() as u64).map(|n| n as usize)
+}
+
+/// Returns 0 at the end of the stream
+pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize> {
+    call3(
+        SYS_READ,
+        fd as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+    )
+    .map(|n| n as usize)
+}
+
+pub fn close(fd: i32) -> Result<()> {
+    call3(SYS_CLOSE, fd as u64, 0, 0).map(drop)
+}
+
+pub fn exit(status: i32) -> ! {
+    call3(SYS_EXIT, status as u64, 0, 0).ok();
+    unreachable!("fox: exit returned")
+}
+
+pub fn sched_yield() {
+    call3(SYS_YIELD, 0, 0, 0).ok();
+}
+
+/// Sleep for `ticks` timer ticks
+pub fn sleep(ticks: u64) {
+    call3(SYS_SLEEP, ticks, 0, 0).ok();
+}
+
+pub fn getpid() -> u64 {
+    call3(SYS_GETPID, 0, 0, 0).unwrap_or(0)
+}
+
+/// 0 without a parent
+pub fn getppid() -> u64 {
+    call3(SYS_GETPPID, 0, 0, 0).unwrap_or(0)
+}
+
+/// Map `len` bytes of zeroed memory wherever there is room
+pub fn mmap(len: usize, prot: u32) -> Result<*mut u8> {
+    call3(SYS_MMAP, 0, len as u64, u64::from(prot)).map(|addr| addr as *mut u8)
+}
+
+/// The NULL terminated array of pointers to `strings`
+fn c_str_array(strings: &[&CStr]) -> Vec<*const u8> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr().cast())
+        .chain([core::ptr::null()])
+        .collect()
+}
+
+/// Start the program at `path` in a child with the arguments `argv`, returns
+/// its pid
+pub fn spawn(path: &CStr, argv: &[&CStr]) -> Result<u64> {
+    let argv = c_str_array(argv);
+    call3(SYS_SPAWN, path.as_ptr() as u64, argv.as_ptr() as u64, 0)
+}
+
+/// Returns the child's pid to the parent and 0 to the child
+pub fn fork() -> Result<u64> {
+    call3(SYS_FORK, 0, 0, 0)
+}
+
+/// Run the program at `path` instead, only returns if that fails
+pub fn execve(path: &CStr, argv: &[&CStr], envp: &[&CStr]) -> Errno {
+    let argv = c_str_array(argv);
+    let envp = c_str_array(envp);
+    let path = path.as_ptr() as u64;
+    match call3(SYS_EXECVE, path, argv.as_ptr() as u64, envp.as_ptr() as u64) {
+        Err(errno) => errno,
+        Ok(_) => unreachable!("fox: execve returned"),
+    }
+}
+
+/// Wait for the child `pid`, or any with -1, to end and reap it, returns its
+/// pid and wait status (see `exit_status()`)
+///
+/// With `WNOHANG` the pid is 0 if it hasn't ended yet.
+pub fn waitpid(pid: i32, options: u32) -> Result<(u64, i32)> {
+    let mut status = 0i32;
+    let pid = call3(
+        SYS_WAITPID,
+        pid as u64,
+        &mut status as *mut i32 as u64,
+        u64::from(options),
+    )?;
+    Ok((pid, status))
+}
+
+/// The status a process that exited passed to `exit`, `None` if a signal
+/// ended it
+pub fn exit_status(wait_status: i32) -> Option<i32> {
+    (wait_status & 0x7f == 0).then_some((wait_status >> 8) & 0xff)
+}
+
+/// The signal that ended a process, `None` if it exited
+pub fn exit_signal(wait_status: i32) -> Option<u32> {
+    (wait_status & 0x7f != 0).then_some((wait_status & 0x7f) as u32)
+}
+
+/// Send `signal` to the process `pid`, the caller or one of its children, 0 to
+/// only check it is one
+pub fn kill(pid: u64, signal: u32) -> Result<()> {
+    call3(SYS_KILL, pid, u64::from(signal), 0).map(drop)
+}
+
+/// What a process does with a signal, see `kernel::process::signal::SigAction`
+///
+/// A handler is an `extern "C" fn(u64, *mut u64)`, called with the signal
+/// number and the registers it interrupted; without `restorer` it returns
+/// through the kernel's trampoline.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64,
+}
+
+/// Set the action for `signal`, returns the previous one
+pub fn sigaction(signal: u32, action: &SigAction) -> Result<SigAction> {
+    let mut old = SigAction::default();
+    call3(
+        SYS_SIGACTION,
+        u64::from(signal),
+        action as *const SigAction as u64,
+        &mut old as *mut SigAction as u64,
+    )?;
+    Ok(old)
+}
+
+/// Change the blocked signals with the bits of `set` as `how` says, returns
+/// the previous ones
+pub fn sigprocmask(how: u32, set: u64) -> Result<u64> {
+    let mut old = 0u64;
+    call3(
+        SYS_SIGPROCMASK,
+        u64::from(how),
+        &set as *const u64 as u64,
+        &mut old as *mut u64 as u64,
+    )?;
+    Ok(old)
+}
+
+/// The two descriptors `pipe` or `channel` store
+fn pair(number: u64) -> Result<[i32; 2]> {
+    let mut fds = [-1i32; 2];
+    call3(number, fds.as_mut_ptr() as u64, 0, 0)?;
+    Ok(fds)
+}
+
+/// The read and write ends of a new pipe
+pub fn pipe() -> Result<[i32; 2]> {
+    pair(SYS_PIPE)
+}
+
+/// The two endpoints of a new channel
+pub fn channel() -> Result<[i32; 2]> {
+    pair(SYS_CHANNEL)
+}
+
+/// Send `data` and the descriptors `handles` through the channel endpoint `fd`
+pub fn send(fd: i32, data: &[u8], handles: &[i32]) -> Result<()> {
+    let args = [
+        fd as u64,
+        data.as_ptr() as u64,
+        data.len() as u64,
+        handles.as_ptr() as u64,
+        handles.len() as u64,
+    ];
+    call(SYS_SEND, args).map(drop)
+}
+
+/// Take the next message from the channel endpoint `fd` into `buf`, and
+/// descriptors for its handles into `handles`, -1 in the slots left; returns
+/// its length
+pub fn recv(fd: i32, buf: &mut [u8], handles: &mut [i32]) -> Result<usize> {
+    let args = [
+        fd as u64,
+        buf.as_mut_ptr() as u64,
+        buf.len() as u64,
+        handles.as_mut_ptr() as u64,
+        handles.len() as u64,
+    ];
+    call(SYS_RECV, args).map(|n| n as usize)
+}
+
+/// A shared memory region of `size` bytes
+pub fn shm_create(size: usize) -> Result<i32> {
+    call3(SYS_SHM_CREATE, size as u64, 0, 0).map(|fd| fd as i32)
+}
+
+/// Map the shared memory region `fd` wherever there is room
+pub fn shm_map(fd: i32, prot: u32) -> Result<*mut u8> {
+    call3(SYS_SHM_MAP, fd as u64, 0, u64::from(prot)).map(|addr| addr as *mut u8)
+}
+
+/// Another descriptor for what `fd` refers to, with `rights`
+pub fn dup(fd: i32, rights: u32) -> Result<i32> {
+    call3(SYS_DUP, fd as u64, u64::from(rights), 0).map(|fd| fd as i32)
+}
+
+pub fn handle_rights(fd: i32) -> Result<u32> {
+    call3(SYS_HANDLE_RIGHTS, fd as u64, 0, 0).map(|rights| rights as u32)
+}
+
+/// A descriptor for the caller, with `pid` 0, or one of its children
+pub fn process_open(pid: u64) -> Result<i32> {
+    call3(SYS_PROCESS_OPEN, pid, 0, 0).map(|fd| fd as i32)
+}
+
+pub fn process_kill(fd: i32, signal: u32) -> Result<()> {
+    call3(SYS_PROCESS_KILL, fd as u64, u64::from(signal), 0).map(drop)
+}
+
+/// Wait for the process `fd` to end without reaping it, returns its wait status
+pub fn process_wait(fd: i32) -> Result<i32> {
+    let mut status = 0i32;
+    call3(
+        SYS_PROCESS_WAIT,
+        fd as u64,
+        &mut status as *mut i32 as u64,
+        0,
+    )?;
+    Ok(status)
+}
+
+/// Let the caller use the I/O ports `fd` refers to
+pub fn ioport_enable(fd: i32) -> Result<()> {
+    call3(SYS_IOPORT_ENABLE, fd as u64, 0, 0).map(drop)
+}
+
+/// Map the device memory `fd` uncached, wherever there is room
+pub fn mmio_map(fd: i32, prot: u32) -> Result<*mut u8> {
+    call3(SYS_MMIO_MAP, fd as u64, 0, u64::from(prot)).map(|addr| addr as *mut u8)
+}
+
+/// Send the interrupts of the line `fd` through the channel endpoint `endpoint`
+pub fn irq_bind(fd: i32, endpoint: i32) -> Result<()> {
+    call3(SYS_IRQ_BIND, fd as u64, endpoint as u64, 0).map(drop)
+}
@@ -0,0 +1,125 @@
+//! Prints its arguments, checks what the ELF loader set up and exits with 0,
+//! or with the number of the first check that failed
+//!
+//! Written without `fox` because it checks the raw state the loader leaves:
+//! the auxiliary vector past `envp`, which `Args` doesn't give, TLS, `.bss`
+//! and that `AT_ENTRY` is this `_start`. With a runtime's statics in the same
+//! segments, a loader bug would crash the runtime instead of failing a check.
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/hello`.
+
+#![no_std]
+#![no_main]
+#![feature(thread_local)]
+
+use core::arch::{asm, naked_asm};
+
+// See `kernel::syscall`
+const SYS_WRITE: u64 = 0;
+const SYS_EXIT: u64 = 1;
+
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_RANDOM: u64 = 25;
+
+// In `.tdata`, `.tbss` and `.bss`
+#[thread_local]
+static mut TLS_VALUE: u64 = 0x1234_5678;
+#[thread_local]
+static mut TLS_ZERO: u64 = 0;
+static mut BSS: [u64; 2048] = [0; 2048];
+
+#[unsafe(naked)]
+#[no_mangle]
+extern "C" fn _start() -> ! {
+    naked_asm!("mov rdi, rsp", "call {main}", "ud2", main = sym main)
+}
+
+fn syscall3(number: u64, a: u64, b: u64, c: u64) -> u64 {
+    let result;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number => result,
+            in("rdi") a,
+            in("rsi") b,
+            in("rdx") c,
+            out("rcx") _,
+            out("r11") _,
+        );
+    }
+    result
+}
+
+fn print(s: &[u8]) {
+    syscall3(SYS_WRITE, 1, s.as_ptr() as u64, s.len() as u64);
+}
+
+fn exit(status: u64) -> ! {
+    syscall3(SYS_EXIT, status, 0, 0);
+    unreachable!()
+}
+
+/// The NUL terminated string at `s`
+unsafe fn c_str<'a>(s: *const u8) -> &'a [u8] {
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    core::slice::from_raw_parts(s, len)
+}
+
+unsafe extern "C" fn main(stack: *const u64) -> ! {
+    let argc = *stack as usize;
+    let argv = stack.add(1);
+    let envp = argv.add(argc + 1);
+    let mut auxv = envp;
+    while *auxv != 0 {
+        auxv = auxv.add(1);
+    }
+    auxv = auxv.add(1);
+
+    print(b"hello:");
+    for i in 0..argc {
+        print(b" ");
+        print(c_str(*argv.add(i) as *const u8));
+    }
+    print(b"\n");
+
+    let (mut page_size, mut entry, mut random) = (0, 0, 0);
+    while *auxv != AT_NULL {
+        match *auxv {
+            AT_PAGESZ => page_size = *auxv.add(1),
+            AT_ENTRY => entry = *auxv.add(1),
+            AT_RANDOM => random = *auxv.add(1),
+            _ => {}
+        }
+        auxv = auxv.add(2);
+    }
+
+    let tls_value = core::ptr::addr_of_mut!(TLS_VALUE);
+    let tls_zero = core::ptr::addr_of_mut!(TLS_ZERO);
+    let checks = [
+        *argv.add(argc) == 0,
+        tls_value.read_volatile() == 0x1234_5678,
+        tls_zero.read_volatile() == 0,
+        {
+            tls_zero.write_volatile(7);
+            tls_zero.read_volatile() == 7
+        },
+        (0..2048).all(|i| core::ptr::addr_of!(BSS).cast::<u64>().add(i).read_volatile() == 0),
+        page_size == 4096,
+        entry == _start as *const () as u64,
+        random != 0,
+    ];
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => exit(failed as u64 + 1),
+        None => exit(0),
+    }
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    exit(255)
+}
@@ -0,0 +1,27 @@
+//! The init process, the first program the kernel starts: says hello and
+//! reaps the processes handed to it when their parents end, forever.
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/init`.
+
+#![no_std]
+#![no_main]
+
+use fox::sys::{self, Errno};
+use fox::{println, Args};
+
+fox::main!(main);
+
+/// Ticks to sleep while it has no children
+const IDLE_TICKS: u64 = 10;
+
+fn main(_: Args) -> i32 {
+    println!("init: running as pid {}", sys::getpid());
+    loop {
+        match sys::waitpid(-1, 0) {
+            Ok(_) => {}
+            // Orphans come without telling, look again in a while
+            Err(Errno::ECHILD) => sys::sleep(IDLE_TICKS),
+            Err(errno) => panic!("init: waitpid failed: {:?}", errno),
+        }
+    }
+}
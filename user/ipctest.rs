@@ -0,0 +1,196 @@
+//! Does what its first argument says, for the IPC tests, and exits with 0 or
+//! the number of the first check that failed:
+//!
+//! - `pipe`: fork a child that writes to a pipe and exits, read it all back
+//!   until the end of the stream
+//! - `channel`: fork a child, send it a shared memory region through a
+//!   channel, the child maps it, writes to it and answers
+//! - `shm`: map a shared memory region, fork a child that writes to it and
+//!   read what it wrote once it has exited
+//! - `broken`: write to a pipe whose read end is closed, which should kill it
+//!   with `SIGPIPE`
+//! - `rights`: check that descriptors only allow what their rights say, and
+//!   signal and wait for a child through a handle to it
+//! - `stuck`: read from a pipe it holds the write end of, until killed
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/ipctest`.
+
+#![no_std]
+#![no_main]
+
+use fox::sys::{self, Errno, PROT_READ, PROT_WRITE};
+use fox::sys::{RIGHT_DUPLICATE, RIGHT_READ, RIGHT_TRANSFER, RIGHT_WRITE};
+use fox::Args;
+
+fox::main!(main);
+
+const PAGE_SIZE: usize = 4096;
+// See `kernel::process::signal`
+const SIGTERM: u32 = 15;
+
+fn fork() -> u64 {
+    sys::fork().unwrap_or_else(|_| sys::exit(104))
+}
+
+/// Wait for `pid`, returns its wait status
+fn waitpid(pid: u64) -> i32 {
+    sys::waitpid(pid as i32, 0).map_or(-1, |(_, status)| status)
+}
+
+/// Map the shared memory region `fd` for reading and writing
+fn shm_map(fd: i32) -> *mut u64 {
+    match sys::shm_map(fd, PROT_READ | PROT_WRITE) {
+        Ok(addr) => addr.cast(),
+        Err(_) => sys::exit(102),
+    }
+}
+
+/// 0 or the number of the first check that failed
+fn check(checks: &[bool]) -> i32 {
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => failed as i32 + 1,
+        None => 0,
+    }
+}
+
+fn pipe() -> i32 {
+    const TEXT: &[u8] = b"through the pipe";
+    let [reader, writer] = sys::pipe().unwrap_or_else(|_| sys::exit(101));
+    let child = fork();
+    if child == 0 {
+        sys::close(reader).ok();
+        for _ in 0..100 {
+            if sys::write(writer, TEXT) != Ok(TEXT.len()) {
+                sys::exit(1);
+            }
+        }
+        sys::exit(0);
+    }
+    // Or the end of the stream never comes
+    sys::close(writer).ok();
+    let mut buf = [0; 100 * TEXT.len()];
+    let mut total = 0;
+    loop {
+        match sys::read(reader, &mut buf[total..]) {
+            Ok(0) => break,
+            Ok(read) if read <= buf.len() - total => total += read,
+            _ => return 103,
+        }
+    }
+    check(&[
+        total == buf.len(),
+        buf.chunks(TEXT.len()).all(|chunk| chunk == TEXT),
+        waitpid(child) == 0,
+    ])
+}
+
+fn channel() -> i32 {
+    let [parent, child_end] = sys::channel().unwrap_or_else(|_| sys::exit(101));
+    let child = fork();
+    if child == 0 {
+        let mut buf = [0; 16];
+        let mut handles = [-1i32; 2];
+        let len = sys::recv(child_end, &mut buf, &mut handles);
+        if len != Ok(3) || &buf[..3] != b"shm" || handles[1] != -1 {
+            sys::exit(1);
+        }
+        unsafe { shm_map(handles[0]).write_volatile(42) };
+        sys::write(child_end, b"done").ok();
+        sys::exit(0);
+    }
+    // Made after the fork, the child only gets it through the channel
+    let region = sys::shm_create(PAGE_SIZE).unwrap_or(-1);
+    let sent = sys::send(parent, b"shm", &[region]);
+    let mut buf = [0; 16];
+    let len = sys::read(parent, &mut buf);
+    let memory = shm_map(region);
+    check(&[
+        sent.is_ok(),
+        len == Ok(4) && &buf[..4] == b"done",
+        unsafe { memory.read_volatile() } == 42,
+        waitpid(child) == 0,
+    ])
+}
+
+fn shm() -> i32 {
+    let memory = shm_map(sys::shm_create(PAGE_SIZE).unwrap_or(-1));
+    let child = fork();
+    if child == 0 {
+        unsafe { memory.write_volatile(42) };
+        sys::exit(0);
+    }
+    let status = waitpid(child);
+    // Not copied on write, the parent sees the child's write
+    check(&[status == 0, unsafe { memory.read_volatile() } == 42])
+}
+
+fn broken() -> i32 {
+    let [reader, writer] = sys::pipe().unwrap_or_else(|_| sys::exit(101));
+    sys::close(reader).ok();
+    sys::write(writer, b"nobody reads this").ok();
+    1
+}
+
+fn rights() -> i32 {
+    let [reader, writer] = sys::pipe().unwrap_or_else(|_| sys::exit(101));
+    let [channel, _] = sys::channel().unwrap_or_else(|_| sys::exit(101));
+    let reader_rights = sys::handle_rights(reader);
+    // Can't gain a right, can't duplicate without the right to
+    let upgraded = sys::dup(writer, RIGHT_READ);
+    let write_only = sys::dup(writer, RIGHT_WRITE).unwrap_or(-1);
+    let written = sys::write(write_only, b"x");
+    let mut buf = [0; 4];
+    let read_back = sys::read(reader, &mut buf);
+    let duplicated = sys::dup(write_only, RIGHT_WRITE);
+    let sent = sys::send(channel, b"x", &[write_only]);
+
+    let child = fork();
+    if child == 0 {
+        loop {
+            sys::sleep(1);
+        }
+    }
+    let process = sys::process_open(child).unwrap_or(-1);
+    let watcher = sys::dup(process, RIGHT_READ).unwrap_or(-1);
+    let refused = sys::process_kill(watcher, SIGTERM);
+    let killed = sys::process_kill(process, SIGTERM);
+    let waited = sys::process_wait(watcher);
+    // Waiting through the handle leaves the child to be reaped
+    let reaped = waitpid(child);
+    check(&[
+        reader_rights == Ok(RIGHT_READ | RIGHT_DUPLICATE | RIGHT_TRANSFER),
+        upgraded == Err(Errno::EACCES),
+        written == Ok(1) && read_back == Ok(1),
+        sys::read(writer, &mut buf) == Err(Errno::EACCES),
+        duplicated == Err(Errno::EACCES),
+        sent == Err(Errno::EACCES),
+        refused == Err(Errno::EACCES),
+        killed.is_ok(),
+        waited == Ok(SIGTERM as i32),
+        reaped == SIGTERM as i32,
+        sys::process_open(child) == Err(Errno::ESRCH),
+        sys::process_open(0).is_ok(),
+    ])
+}
+
+fn stuck() -> i32 {
+    let [reader, _writer] = sys::pipe().unwrap_or_else(|_| sys::exit(101));
+    let mut buf = [0; 8];
+    sys::read(reader, &mut buf).ok();
+    1
+}
+
+fn main(args: Args) -> i32 {
+    let Some(mode) = args.get(1) else {
+        return 100;
+    };
+    match mode.to_bytes() {
+        b"pipe" => pipe(),
+        b"channel" => channel(),
+        b"shm" => shm(),
+        b"broken" => broken(),
+        b"rights" => rights(),
+        b"stuck" => stuck(),
+        _ => 100,
+    }
+}
@@ -0,0 +1,100 @@
+//! Does what its first argument says, for the process tests:
+//!
+//! - `exit N`: exit with status N
+//! - `fault`: write to an unmapped address
+//! - `spin`: loop until killed
+//! - `kill`: send itself `SIGKILL`
+//! - `signal N`: send `SIGKILL` to process N, which isn't its child, exit with
+//!   0 if that is refused
+//! - `files`: check that closed and unknown descriptors are refused, exit with 0
+//! - `spawn`: run `proctest exit 3` as a child, exit with its status + 1
+//! - `wait`: run `proctest spin` as a child and wait for it, until killed
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/proctest`.
+
+#![no_std]
+#![no_main]
+
+use core::ffi::CStr;
+use fox::sys::{self, Errno};
+use fox::Args;
+
+fox::main!(main);
+
+// See `kernel::process::signal`
+const SIGKILL: u32 = 9;
+
+fn parse(digits: &CStr) -> i32 {
+    digits
+        .to_bytes()
+        .iter()
+        .fold(0, |n, digit| n * 10 + i32::from(digit - b'0'))
+}
+
+fn main(args: Args) -> i32 {
+    let Some(mode) = args.get(1) else {
+        return 100;
+    };
+    match mode.to_bytes() {
+        b"exit" if args.len() > 2 => parse(args.get(2).unwrap()),
+        b"fault" => {
+            unsafe { (0x10 as *mut u64).write_volatile(1) };
+            101
+        }
+        b"spin" => loop {
+            core::hint::spin_loop();
+        },
+        b"kill" => {
+            sys::kill(sys::getpid(), SIGKILL).ok();
+            102
+        }
+        b"signal" if args.len() > 2 => {
+            let pid = parse(args.get(2).unwrap()) as u64;
+            match sys::kill(pid, SIGKILL) {
+                Err(Errno::ESRCH) => 0,
+                _ => 105,
+            }
+        }
+        b"files" => {
+            let message = b"proctest: files\n";
+            let checks = [
+                sys::write(1, message) == Ok(message.len()),
+                sys::write(7, message) == Err(Errno::EBADF),
+                sys::close(1).is_ok(),
+                sys::write(1, message) == Err(Errno::EBADF),
+                sys::close(1) == Err(Errno::EBADF),
+            ];
+            match checks.iter().position(|&ok| !ok) {
+                Some(failed) => failed as i32 + 1,
+                None => 0,
+            }
+        }
+        b"spawn" => {
+            let missing = sys::spawn(c"/bin/missing", &[]);
+            let Ok(pid) = sys::spawn(c"/bin/proctest", &[c"proctest", c"exit", c"3"]) else {
+                return 103;
+            };
+            let waited = sys::waitpid(pid as i32, 0);
+            // Reaped already
+            let again = sys::waitpid(pid as i32, 0);
+            match waited {
+                Ok((waited, status))
+                    if waited == pid
+                        && missing == Err(Errno::ENOENT)
+                        && again == Err(Errno::ECHILD) =>
+                {
+                    sys::exit_status(status).map_or(103, |status| status + 1)
+                }
+                _ => 103,
+            }
+        }
+        b"wait" => {
+            let Ok(pid) = sys::spawn(c"/bin/proctest", &[c"proctest", c"spin"]) else {
+                return 104;
+            };
+            sys::waitpid(pid as i32, 0).ok();
+            104
+        }
+        _ => 100,
+    }
+}
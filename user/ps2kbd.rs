@@ -0,0 +1,115 @@
+//! PS/2 keyboard driver: forwards the scancodes the keyboard sends to
+//! descriptor 5, one byte each, as the kernel's own driver would have them.
+//!
+//! Started by the kernel with the controller's I/O ports (0x60 to 0x64) on
+//! descriptor 3 and its interrupt line (IRQ 1) on descriptor 4, see
+//! `kernel::device`. Its arguments, if any, are scancodes to have the
+//! controller send as if they were typed, one at a time, then it exits once
+//! it has forwarded them all: how the tests drive it without a keyboard.
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/ps2kbd`.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::ffi::CStr;
+use fox::sys;
+use fox::Args;
+
+fox::main!(main);
+
+const PORTS: i32 = 3;
+const IRQ: i32 = 4;
+const OUTPUT: i32 = 5;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+/// Status bits: a byte waits to be read, the controller hasn't taken the last
+/// one written yet
+const OUTPUT_FULL: u8 = 1;
+const INPUT_FULL: u8 = 2;
+/// Controller command sending the next data byte as if the keyboard had
+const WRITE_KEYBOARD_OUTPUT: u8 = 0xd2;
+
+fn inb(port: u16) -> u8 {
+    let value;
+    unsafe { asm!("in al, dx", out("al") value, in("dx") port) };
+    value
+}
+
+fn outb(port: u16, value: u8) {
+    unsafe { asm!("out dx, al", in("dx") port, in("al") value) };
+}
+
+/// The decimal byte `s`
+fn parse_byte(s: &CStr) -> u8 {
+    let mut value = 0u8;
+    for &digit in s.to_bytes() {
+        if !digit.is_ascii_digit() {
+            sys::exit(100);
+        }
+        value = value
+            .checked_mul(10)
+            .and_then(|value| value.checked_add(digit - b'0'))
+            .unwrap_or_else(|| sys::exit(100));
+    }
+    value
+}
+
+/// Write `value` to `port` once the controller has taken the last byte
+fn write_controller(port: u16, value: u8) {
+    while inb(STATUS_PORT) & INPUT_FULL != 0 {}
+    outb(port, value);
+}
+
+/// Forward the scancodes waiting in the controller, returns how many
+fn forward() -> usize {
+    let mut count = 0;
+    while inb(STATUS_PORT) & OUTPUT_FULL != 0 {
+        let scancode = inb(DATA_PORT);
+        if sys::write(OUTPUT, &[scancode]) != Ok(1) {
+            sys::exit(3);
+        }
+        count += 1;
+    }
+    count
+}
+
+/// Wait for the next interrupt, or several that came together
+fn wait_irq(irqs: i32) {
+    let mut buf = [0u8; 1];
+    if sys::recv(irqs, &mut buf, &mut []) != Ok(1) {
+        sys::exit(4);
+    }
+}
+
+fn main(args: Args) -> i32 {
+    let Ok([irqs, bound]) = sys::channel() else {
+        return 1;
+    };
+    // The kernel keeps the end it sends through
+    if sys::irq_bind(IRQ, bound).is_err() || sys::ioport_enable(PORTS).is_err() {
+        return 2;
+    }
+    sys::close(bound).ok();
+    // A byte left unread keeps the line raised, no interrupt would come
+    while inb(STATUS_PORT) & OUTPUT_FULL != 0 {
+        inb(DATA_PORT);
+    }
+
+    if args.len() < 2 {
+        loop {
+            wait_irq(irqs);
+            forward();
+        }
+    }
+    for scancode in args.iter().skip(1) {
+        write_controller(STATUS_PORT, WRITE_KEYBOARD_OUTPUT);
+        write_controller(DATA_PORT, parse_byte(scancode));
+        while forward() == 0 {
+            wait_irq(irqs);
+        }
+    }
+    0
+}
@@ -0,0 +1,77 @@
+//! Checks the `fox` runtime and the system calls under it, and exits with 0
+//! or the number of the first check that failed. With `panic` as its first
+//! argument it panics instead, which should exit with 101.
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/rttest`.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use fox::io::Writer;
+use fox::sys::{self, Errno, PROT_READ, PROT_WRITE};
+use fox::{println, Args};
+
+fox::main!(main);
+
+fn main(args: Args) -> i32 {
+    if args.get(1) == Some(c"panic") {
+        panic!("rttest: asked to");
+    }
+    println!("rttest: {} arguments", args.len());
+
+    // The heap, small and big blocks, reused once freed
+    let numbers: Vec<u64> = (0..10_000).collect();
+    let boxed = Box::new([7u8; 100]);
+    let big = alloc::vec![1u8; 1 << 20];
+    let first = Box::into_raw(Box::new(1u64));
+    unsafe { drop(Box::from_raw(first)) };
+    let second = Box::new(2u64);
+    let reused = core::ptr::eq(first, &*second);
+    let mut text = String::new();
+    write!(text, "{}-{}", numbers[9_999], boxed[99]).unwrap();
+
+    // Formatted output through a pipe
+    let [reader, writer] = sys::pipe().unwrap();
+    write!(Writer(writer), "pid {}", sys::getpid()).unwrap();
+    let mut buf = [0; 32];
+    let read = sys::read(reader, &mut buf).unwrap();
+    let mut expected = String::new();
+    write!(expected, "pid {}", sys::getpid()).unwrap();
+
+    // A child, and how it ended
+    let parent = sys::getpid();
+    let child = sys::fork().unwrap();
+    if child == 0 {
+        sys::exit(if sys::getppid() == parent { 7 } else { 1 });
+    }
+    let waited = sys::waitpid(child as i32, 0);
+
+    // Errors come back as `Errno`
+    let memory = sys::mmap(4096, PROT_READ | PROT_WRITE).unwrap();
+    unsafe { memory.write_volatile(42) };
+
+    let checks = [
+        args.len() == 2 && args.get(1) == Some(c"check") && args.get(2).is_none(),
+        args.env().is_empty(),
+        numbers.iter().sum::<u64>() == 49_995_000,
+        big.iter().all(|&b| b == 1) && big.len() == 1 << 20,
+        reused,
+        text == "9999-7",
+        buf[..read] == *expected.as_bytes(),
+        waited.map(|(pid, status)| (pid, sys::exit_status(status))) == Ok((child, Some(7))),
+        unsafe { memory.read_volatile() } == 42,
+        sys::close(99) == Err(Errno::EBADF),
+        sys::spawn(c"/bin/nothing", &[]) == Err(Errno::ENOENT),
+        sys::waitpid(-1, 0) == Err(Errno::ECHILD),
+    ];
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => failed as i32 + 1,
+        None => 0,
+    }
+}
@@ -0,0 +1,161 @@
+//! Does what its first argument says, for the signal tests:
+//!
+//! - `handler`: catch `SIGUSR1` sent to itself, check that blocking it holds
+//!   it back until it is unblocked, exit with 0 or the number of the first
+//!   check that failed
+//! - `segv`: write to an unmapped address with a `SIGSEGV` handler that exits
+//!   with 42
+//! - `ill`: run `ud2` with a `SIGILL` handler that skips it, exit with 0 if
+//!   the handler ran and returned
+//! - `fpe`: divide by zero without a handler
+//! - `default`: send itself `SIGTERM`
+//! - `ignore`: ignore `SIGTERM`, send it to itself and exit with 0
+//! - `wait`: loop until `SIGUSR1`, whose handler exits with 7
+//! - `bad`: set a handler and a restorer in kernel space, exit with 0 if both
+//!   are refused
+//!
+//! Built by `build.rs` into the initial ramdisk as `/bin/sigtest`.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use fox::sys::{self, Errno, SigAction, SIG_BLOCK, SIG_DFL, SIG_IGN, SIG_UNBLOCK};
+use fox::Args;
+
+fox::main!(main);
+
+// See `kernel::process::signal`
+const SA_RESTORER: u64 = 0x0400_0000;
+const SIGILL: u32 = 4;
+const SIGUSR1: u32 = 10;
+const SIGSEGV: u32 = 11;
+const SIGTERM: u32 = 15;
+/// Index of `rip` in the saved registers a handler gets
+const CONTEXT_RIP: usize = 15;
+
+// Times the `SIGUSR1` and `SIGILL` handlers ran
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Send `signal` to itself
+fn raise(signal: u32) -> sys::Result<()> {
+    sys::kill(sys::getpid(), signal)
+}
+
+/// Set the action for `signal` to `handler`, returns the previous handler
+fn sigaction(signal: u32, handler: u64) -> u64 {
+    let action = SigAction {
+        handler,
+        ..SigAction::default()
+    };
+    sys::sigaction(signal, &action).map_or(u64::MAX, |old| old.handler)
+}
+
+/// The address of the handler `f`
+fn handler(f: extern "C" fn(u64, *mut u64)) -> u64 {
+    f as usize as u64
+}
+
+extern "C" fn count(_: u64, _: *mut u64) {
+    HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "C" fn exit_42(_: u64, _: *mut u64) {
+    sys::exit(42)
+}
+
+extern "C" fn exit_7(_: u64, _: *mut u64) {
+    sys::exit(7)
+}
+
+/// Goes on after the `ud2` that raised the signal
+extern "C" fn skip_ud2(_: u64, context: *mut u64) {
+    HITS.fetch_add(1, Ordering::Relaxed);
+    unsafe { *context.add(CONTEXT_RIP) += 2 };
+}
+
+fn catch() -> i32 {
+    let usr1 = 1 << (SIGUSR1 - 1);
+    let hits = || HITS.load(Ordering::Relaxed);
+    let checks = [
+        sigaction(SIGUSR1, handler(count)) == SIG_DFL,
+        raise(SIGUSR1).is_ok() && hits() == 1,
+        sys::sigprocmask(SIG_BLOCK, usr1) == Ok(0),
+        raise(SIGUSR1).is_ok() && hits() == 1,
+        sys::sigprocmask(SIG_UNBLOCK, usr1) == Ok(usr1) && hits() == 2,
+        sigaction(SIGUSR1, SIG_DFL) == handler(count),
+    ];
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => failed as i32 + 1,
+        None => 0,
+    }
+}
+
+fn bad() -> i32 {
+    const KERNEL: u64 = 0xffff_8000_0000_0000;
+    let bad_handler = SigAction {
+        handler: KERNEL,
+        ..SigAction::default()
+    };
+    let bad_restorer = SigAction {
+        handler: handler(count),
+        flags: SA_RESTORER,
+        restorer: KERNEL,
+        ..SigAction::default()
+    };
+    let checks = [
+        sys::sigaction(SIGUSR1, &bad_handler) == Err(Errno::EINVAL),
+        sys::sigaction(SIGUSR1, &bad_restorer) == Err(Errno::EINVAL),
+        sigaction(SIGUSR1, SIG_DFL) == SIG_DFL,
+    ];
+    match checks.iter().position(|&ok| !ok) {
+        Some(failed) => failed as i32 + 1,
+        None => 0,
+    }
+}
+
+fn main(args: Args) -> i32 {
+    let Some(mode) = args.get(1) else {
+        return 100;
+    };
+    match mode.to_bytes() {
+        b"handler" => catch(),
+        b"segv" => {
+            sigaction(SIGSEGV, handler(exit_42));
+            unsafe { (0x10 as *mut u64).write_volatile(1) };
+            101
+        }
+        b"ill" => {
+            sigaction(SIGILL, handler(skip_ud2));
+            unsafe { asm!("ud2") };
+            match HITS.load(Ordering::Relaxed) {
+                1 => 0,
+                _ => 102,
+            }
+        }
+        b"fpe" => {
+            unsafe {
+                asm!("xor edx, edx", "div {0}", in(reg) 0u64, inout("rax") 1u64 => _, out("rdx") _)
+            };
+            103
+        }
+        b"default" => {
+            raise(SIGTERM).ok();
+            104
+        }
+        b"ignore" => {
+            sigaction(SIGTERM, SIG_IGN);
+            raise(SIGTERM).ok();
+            0
+        }
+        b"bad" => bad(),
+        b"wait" => {
+            sigaction(SIGUSR1, handler(exit_7));
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+        _ => 100,
+    }
+}